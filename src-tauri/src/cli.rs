@@ -0,0 +1,195 @@
+use crate::odisc::main::{self, editor, formats, helpers, logging, midi, EngineOptions};
+use clap::{Parser, Subcommand, ValueEnum};
+use midir::MidiOutput;
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+// Headless entry point: the same engine as the desktop app, driven from the
+// command line and stopped by SIGTERM or Ctrl+C (e.g. as a systemd service).
+
+#[derive(Parser)]
+#[command(
+    name = "odisc",
+    version,
+    about = "OSC <-> MIDI bridge, without the window"
+)]
+struct Cli {
+    /// Data directory holding config.json, mappings, shows/ and logs/
+    /// [default: ~/Documents/odisc]
+    #[arg(long, global = true, env = "ODISC_DIR")]
+    dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the bridge until SIGTERM or Ctrl+C
+    Run {
+        /// Config file [default: <dir>/config.json]
+        #[arg(long, env = "ODISC_CONFIG")]
+        config: Option<PathBuf>,
+        /// Mappings file (csv, toml, yaml or json), used instead of the
+        /// data directory's mappings and the config's ACTIVE_SHOW
+        #[arg(long, env = "ODISC_MAPPINGS")]
+        mappings: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = LogFormat::Text, env = "ODISC_LOG_FORMAT")]
+        log_format: LogFormat,
+        /// Don't write the rotating log file in <dir>/logs
+        #[arg(long)]
+        no_log_file: bool,
+    },
+    /// List MIDI output and input ports
+    ListPorts,
+    /// Check a mappings file and report every invalid row
+    Validate {
+        /// Mappings file (csv, toml, yaml or json)
+        path: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    /// Priority-prefixed lines without timestamps, for systemd/journald
+    Journald,
+    Json,
+}
+
+impl From<LogFormat> for logging::ConsoleFormat {
+    fn from(format: LogFormat) -> Self {
+        match format {
+            LogFormat::Text => logging::ConsoleFormat::Text,
+            LogFormat::Journald => logging::ConsoleFormat::Journald,
+            LogFormat::Json => logging::ConsoleFormat::Json,
+        }
+    }
+}
+
+pub fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Some(dir) = cli.dir {
+        helpers::set_odisc_dir(dir);
+    }
+
+    match cli.command {
+        Command::Run {
+            config,
+            mappings,
+            log_format,
+            no_log_file,
+        } => {
+            let log_dir = (!no_log_file).then(|| helpers::odisc_dir().join("logs"));
+            logging::init(log_dir.as_deref(), log_format.into());
+            let options = EngineOptions {
+                config_path: config,
+                mappings_path: mappings,
+            };
+            let result = match tokio::runtime::Runtime::new() {
+                Ok(runtime) => runtime.block_on(run(options)),
+                Err(e) => Err(e.into()),
+            };
+            let code = match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    error!("Error in backend: {e}");
+                    ExitCode::FAILURE
+                }
+            };
+            logging::shutdown();
+            code
+        }
+        Command::ListPorts => match list_ports() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Could not list MIDI ports: {e}");
+                ExitCode::FAILURE
+            }
+        },
+        Command::Validate { path } => validate(path),
+    }
+}
+
+async fn run(options: EngineOptions) -> Result<(), Box<dyn Error>> {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    // The engine logs its own startup; nobody waits on readiness here
+    let (ready_tx, _ready_rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown requested");
+        let _ = shutdown_tx.send(());
+    });
+
+    main::backend(options, shutdown_rx, ready_tx).await?;
+    info!("Backend stopped");
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = sigterm.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            }
+        }
+        Err(e) => {
+            warn!("Could not listen for SIGTERM: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn list_ports() -> Result<(), Box<dyn Error>> {
+    let print = |title: &str, ports: &[String]| {
+        println!("{title}:");
+        if ports.is_empty() {
+            println!("  (none)");
+        }
+        for (i, name) in ports.iter().enumerate() {
+            println!("  {i}: {name}");
+        }
+    };
+    let midi_out = MidiOutput::new("MIDIOutput")?;
+    print("MIDI outputs", &midi::list_midi_devices(&midi_out));
+    print("MIDI inputs", &midi::list_midi_inputs()?);
+    Ok(())
+}
+
+fn validate(path: PathBuf) -> ExitCode {
+    let mappings = match formats::load_mappings(path.clone()) {
+        Ok(mappings) => mappings,
+        Err(e) => {
+            eprintln!("{}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut invalid = 0;
+    for (row, mapping) in mappings.iter().enumerate() {
+        if let Err(e) = editor::validate_mapping(mapping) {
+            eprintln!("{}: row {row}: {e}", path.display());
+            invalid += 1;
+        }
+    }
+
+    if invalid > 0 {
+        eprintln!("{invalid} of {} mappings invalid", mappings.len());
+        return ExitCode::FAILURE;
+    }
+    println!("{}: {} mappings OK", path.display(), mappings.len());
+    ExitCode::SUCCESS
+}
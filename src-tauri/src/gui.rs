@@ -0,0 +1,400 @@
+// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
+use crate::odisc;
+use odisc::main::editor::Edit;
+use odisc::main::events::{self, EventSink};
+use odisc::main::helpers::Mapping;
+use odisc::service::{BackendService, BackendStatus};
+use once_cell::sync::OnceCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, State};
+
+static APP_HANDLE: OnceCell<AppHandle> = OnceCell::new();
+
+// Forwards engine events to the webview
+struct WebviewSink(AppHandle);
+
+impl EventSink for WebviewSink {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        let _ = self.0.emit(event, payload);
+    }
+}
+
+pub fn set_app_handle(handle: AppHandle) {
+    APP_HANDLE.set(handle).ok();
+}
+
+pub fn get_app_handle() -> Option<&'static AppHandle> {
+    APP_HANDLE.get()
+}
+
+// Kept for the existing frontend; same as start_backend
+#[tauri::command]
+async fn run_backend(
+    app_handle: AppHandle,
+    service: State<'_, BackendService>,
+) -> Result<BackendStatus, String> {
+    service.start(app_handle).await
+}
+
+#[tauri::command]
+async fn start_backend(
+    app_handle: AppHandle,
+    service: State<'_, BackendService>,
+) -> Result<BackendStatus, String> {
+    service.start(app_handle).await
+}
+
+#[tauri::command]
+async fn stop_backend(
+    app_handle: AppHandle,
+    service: State<'_, BackendService>,
+) -> Result<BackendStatus, String> {
+    service.stop(app_handle).await
+}
+
+#[tauri::command]
+async fn restart_backend(
+    app_handle: AppHandle,
+    service: State<'_, BackendService>,
+) -> Result<BackendStatus, String> {
+    service.restart(app_handle).await
+}
+
+#[tauri::command]
+fn backend_status(service: State<'_, BackendService>) -> BackendStatus {
+    service.status()
+}
+
+#[tauri::command]
+fn read_csv_file() -> Result<String, String> {
+    let csv_path = odisc::main::active_mappings_path()
+        .map_err(|e| format!("Failed to resolve mappings path: {e}"))?;
+
+    // The UI edits CSV, so other formats are converted on the way out
+    match odisc::main::formats::Format::from_path(&csv_path) {
+        Ok(odisc::main::formats::Format::Csv) => {
+            fs::read_to_string(&csv_path).map_err(|e| format!("Failed to read CSV file: {e}"))
+        }
+        _ => odisc::main::formats::load_mappings(csv_path)
+            .and_then(|m| {
+                odisc::main::formats::serialize_mappings(&m, odisc::main::formats::Format::Csv)
+            })
+            .map_err(|e| format!("Failed to read mappings file: {e}")),
+    }
+}
+
+// Without a path, writes mappings-export.<format> next to the active mappings
+#[tauri::command]
+fn export_mappings(format: String, path: Option<String>) -> Result<String, String> {
+    let source = odisc::main::active_mappings_path()
+        .map_err(|e| format!("Failed to resolve mappings path: {e}"))?;
+    let format = odisc::main::formats::Format::from_name(&format).map_err(|e| e.to_string())?;
+    let target = path.map(std::path::PathBuf::from);
+
+    odisc::main::formats::export_mappings(&source, format, target.as_deref())
+        .map(|path| path.to_string_lossy().to_string())
+        .map_err(|e| format!("Failed to export mappings: {e}"))
+}
+
+#[tauri::command]
+fn reload_mappings() -> Result<(), String> {
+    odisc::main::reload_mappings().map_err(|e| format!("Failed to reload mappings: {e}"))
+}
+
+#[tauri::command]
+async fn midi_panic() -> Result<(), String> {
+    odisc::main::control::request(|reply| odisc::main::control::ControlCommand::Panic { reply })
+        .await
+        .unwrap_or_else(|| Err("Backend is not running".to_string()))
+}
+
+#[tauri::command]
+fn engine_status() -> odisc::main::EngineStatus {
+    odisc::main::engine_status()
+}
+
+#[tauri::command]
+fn disabled_mappings() -> Vec<usize> {
+    odisc::main::disabled_mappings()
+}
+
+#[tauri::command]
+fn set_mapping_enabled(index: usize, enabled: bool) -> Result<(), String> {
+    odisc::main::set_mapping_enabled(index, enabled)
+}
+
+#[tauri::command]
+async fn set_midi_output(name: String) -> Result<(), String> {
+    odisc::main::control::request(
+        |reply| odisc::main::control::ControlCommand::SetMidiOutput { name, reply },
+    )
+    .await
+    .unwrap_or_else(|| Err("Backend is not running".to_string()))
+}
+
+#[tauri::command]
+fn list_shows() -> Result<Vec<odisc::main::shows::ShowInfo>, String> {
+    odisc::main::shows::list_shows().map_err(|e| format!("Failed to list shows: {e}"))
+}
+
+#[tauri::command]
+fn get_active_show() -> Option<String> {
+    odisc::main::active_show()
+}
+
+#[tauri::command]
+fn get_active_layer() -> Option<String> {
+    odisc::main::active_layer()
+}
+
+#[tauri::command]
+fn set_active_layer(layer: Option<String>) {
+    odisc::main::set_active_layer(layer);
+}
+
+#[tauri::command]
+async fn load_show(name: String) -> Result<(), String> {
+    let request =
+        odisc::main::control::request(|reply| odisc::main::control::ControlCommand::LoadShow {
+            name: name.clone(),
+            reply,
+        });
+    if let Some(result) = request.await {
+        return result;
+    }
+
+    // Backend not running yet: stage the show so it's used on start
+    let show = odisc::main::shows::load_show(&name)
+        .map_err(|e| format!("Failed to load show '{name}': {e}"))?;
+    odisc::main::activate_show(show);
+    Ok(())
+}
+
+// Edits the active mappings file and hot-applies the result
+fn edit_mappings(edit: odisc::main::editor::Edit) -> Result<Vec<Mapping>, String> {
+    let path = odisc::main::active_mappings_path()
+        .map_err(|e| format!("Failed to resolve mappings path: {e}"))?;
+    let mappings = odisc::main::editor::edit_mappings(path, edit)
+        .map_err(|e| format!("Failed to edit mappings: {e}"))?;
+    odisc::main::set_mappings(mappings.clone());
+    Ok(mappings)
+}
+
+#[tauri::command]
+fn list_mappings() -> Result<Vec<Mapping>, String> {
+    let path = odisc::main::active_mappings_path()
+        .map_err(|e| format!("Failed to resolve mappings path: {e}"))?;
+    odisc::main::formats::load_mappings(path).map_err(|e| format!("Failed to load mappings: {e}"))
+}
+
+#[tauri::command]
+fn create_mapping(mapping: Mapping, index: Option<usize>) -> Result<Vec<Mapping>, String> {
+    edit_mappings(Edit::Create { mapping, index })
+}
+
+#[tauri::command]
+fn update_mapping(index: usize, mapping: Mapping) -> Result<Vec<Mapping>, String> {
+    edit_mappings(Edit::Update { index, mapping })
+}
+
+#[tauri::command]
+fn delete_mapping(index: usize) -> Result<Vec<Mapping>, String> {
+    edit_mappings(Edit::Delete { index })
+}
+
+#[tauri::command]
+fn reorder_mapping(from: usize, to: usize) -> Result<Vec<Mapping>, String> {
+    edit_mappings(Edit::Reorder { from, to })
+}
+
+#[tauri::command]
+fn duplicate_mapping(index: usize) -> Result<Vec<Mapping>, String> {
+    edit_mappings(Edit::Duplicate { index })
+}
+
+const LEARN_TIMEOUT_MS: u64 = 30_000;
+
+#[tauri::command]
+async fn learn_osc(timeout_ms: Option<u64>) -> Result<odisc::main::learn::OscLearnResult, String> {
+    let armed = odisc::main::learn::arm_osc();
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(LEARN_TIMEOUT_MS));
+    match tokio::time::timeout(timeout, armed.rx).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(_)) => Err("OSC learn cancelled".to_string()),
+        Err(_) => {
+            odisc::main::learn::expire_osc(armed.id);
+            Err("No OSC message received before the timeout".to_string())
+        }
+    }
+}
+
+#[tauri::command]
+async fn learn_midi(
+    timeout_ms: Option<u64>,
+) -> Result<odisc::main::learn::MidiLearnResult, String> {
+    let armed = odisc::main::learn::arm_midi();
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(LEARN_TIMEOUT_MS));
+    match tokio::time::timeout(timeout, armed.rx).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(_)) => Err("MIDI learn cancelled".to_string()),
+        Err(_) => {
+            odisc::main::learn::expire_midi(armed.id);
+            Err("No MIDI message received before the timeout (is MIDI_INPUT_NAME set?)".to_string())
+        }
+    }
+}
+
+// "osc" or "midi", or both without a kind
+#[tauri::command]
+fn cancel_learn(kind: Option<String>) -> Result<(), String> {
+    match kind.as_deref() {
+        Some("osc") => odisc::main::learn::cancel_osc(),
+        Some("midi") => odisc::main::learn::cancel_midi(),
+        None => {
+            odisc::main::learn::cancel_osc();
+            odisc::main::learn::cancel_midi();
+        }
+        Some(other) => return Err(format!("Unknown learn kind '{other}'")),
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn redundancy_status() -> odisc::main::redundancy::RedundancyStatus {
+    odisc::main::redundancy::status()
+}
+
+#[tauri::command]
+async fn redundancy_switchover() -> Result<odisc::main::redundancy::RedundancyStatus, String> {
+    odisc::main::redundancy::switchover().await
+}
+
+#[tauri::command]
+fn rejected_packets() -> Vec<odisc::main::access::RejectedCount> {
+    odisc::main::access::rejected()
+}
+
+#[tauri::command]
+fn clear_rejected_packets() {
+    odisc::main::access::clear_rejected();
+}
+
+#[tauri::command]
+fn rtp_midi_peers() -> Vec<odisc::main::rtpmidi::PeerStatus> {
+    odisc::main::rtpmidi::peers()
+}
+
+// The state map mapping scripts share
+#[tauri::command]
+fn script_state() -> serde_json::Value {
+    odisc::main::script::state()
+}
+
+#[tauri::command]
+fn variables() -> BTreeMap<String, odisc::main::vars::Value> {
+    odisc::main::vars::all()
+}
+
+#[tauri::command]
+fn set_variable(name: String, value: odisc::main::vars::Value) -> Result<(), String> {
+    odisc::main::vars::set(&name, value)
+}
+
+#[tauri::command]
+fn remove_variable(name: String) {
+    odisc::main::vars::remove(&name)
+}
+
+#[tauri::command]
+fn clear_variables() {
+    odisc::main::vars::clear()
+}
+
+// Restarts toggles, cycles and counters: one row, one variable, or all of them
+#[tauri::command]
+fn reset_behaviors(row: Option<usize>, var: Option<String>) {
+    use odisc::main::behavior::Reset;
+    let target = match (row, var) {
+        (Some(row), _) => Reset::Row(row),
+        (None, Some(var)) => Reset::Var(var),
+        (None, None) => Reset::All,
+    };
+    odisc::main::behavior::reset(target, &odisc::main::mappings());
+}
+
+#[tauri::command]
+fn monitor_history(
+    filter: Option<odisc::main::monitor::MonitorFilter>,
+) -> Vec<odisc::main::monitor::MonitorEvent> {
+    odisc::main::monitor::history(&filter.unwrap_or_default())
+}
+
+#[tauri::command]
+fn clear_monitor() {
+    odisc::main::monitor::clear();
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    odisc::main::logging::init(
+        Some(&odisc::main::helpers::odisc_dir().join("logs")),
+        odisc::main::logging::ConsoleFormat::Text,
+    );
+
+    tauri::Builder::default()
+        .plugin(tauri_plugin_opener::init())
+        .manage(BackendService::default())
+        .invoke_handler(tauri::generate_handler![
+            run_backend,
+            start_backend,
+            stop_backend,
+            restart_backend,
+            backend_status,
+            read_csv_file,
+            reload_mappings,
+            midi_panic,
+            engine_status,
+            disabled_mappings,
+            set_mapping_enabled,
+            set_midi_output,
+            list_shows,
+            get_active_show,
+            load_show,
+            get_active_layer,
+            set_active_layer,
+            export_mappings,
+            list_mappings,
+            create_mapping,
+            update_mapping,
+            delete_mapping,
+            reorder_mapping,
+            duplicate_mapping,
+            learn_osc,
+            learn_midi,
+            cancel_learn,
+            redundancy_status,
+            redundancy_switchover,
+            rejected_packets,
+            clear_rejected_packets,
+            rtp_midi_peers,
+            script_state,
+            variables,
+            set_variable,
+            remove_variable,
+            clear_variables,
+            reset_behaviors,
+            monitor_history,
+            clear_monitor
+        ])
+        .setup(|app| {
+            set_app_handle(app.handle().clone());
+            events::add_sink(Arc::new(WebviewSink(app.handle().clone())));
+            tauri::async_runtime::spawn(odisc::main::monitor::run_flusher());
+            Ok(())
+        })
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
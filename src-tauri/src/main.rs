@@ -1,6 +1,16 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
-#![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
+#![cfg_attr(
+    all(not(debug_assertions), feature = "gui"),
+    windows_subsystem = "windows"
+)]
 
+#[cfg(feature = "gui")]
 fn main() {
     odisc_lib::run()
 }
+
+// Built with `--no-default-features`: the headless `odisc` command
+#[cfg(not(feature = "gui"))]
+fn main() -> std::process::ExitCode {
+    odisc_lib::cli::main()
+}
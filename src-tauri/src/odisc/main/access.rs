@@ -0,0 +1,393 @@
+use crate::odisc::main::monitor::{self, MonitorKind};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use rosc::{encoder, OscBundle, OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+
+// Per-listener access control, applied by the listener tasks before a packet
+// reaches the backend loop (and so before match_mappings):
+// - ALLOW: IPs or CIDR ranges; empty lets everyone in
+// - AUTH: "hmac" expects a trailing HMAC-SHA256 argument (32-byte blob or
+//   64 hex chars) over the message encoded without it; "prefix" expects the
+//   address to start with /<secret>, which is stripped before routing
+// HMAC alone doesn't stop a captured message from being sent again. With
+// REPLAY_WINDOW_MS set, the signed message must end (before the signature) with
+// a timestamp, an int64 of unix milliseconds; messages further than the window
+// from this machine's clock, or repeating a signature seen within it, are
+// rejected. Both ends need roughly synced clocks. Prefix mode sends the secret
+// in the clear and has no replay protection.
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    Hmac,
+    Prefix,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    pub secret: String,
+    // HMAC only, see above
+    #[serde(default)]
+    pub replay_window_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rejection {
+    NotAllowed,
+    Unauthenticated,
+}
+
+impl Rejection {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rejection::NotAllowed => "not_allowed",
+            Rejection::Unauthenticated => "unauthenticated",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RejectedCount {
+    pub listener: String,
+    pub reason: &'static str,
+    pub count: u64,
+}
+
+lazy_static! {
+    static ref REJECTED: Mutex<BTreeMap<(Arc<str>, Rejection), u64>> = Mutex::new(BTreeMap::new());
+}
+
+pub struct AccessPolicy {
+    allow: Vec<IpNet>,
+    auth: Option<AuthConfig>,
+    // Signatures accepted within the replay window, by arrival
+    seen: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl AccessPolicy {
+    pub fn new(allow: &[String], auth: Option<AuthConfig>) -> Result<Self, String> {
+        let allow = allow
+            .iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("Invalid ALLOW entry '{entry}', expected an IP or CIDR"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(auth) = &auth {
+            if auth.secret.is_empty() {
+                return Err("AUTH SECRET must not be empty".to_string());
+            }
+            if auth.mode == AuthMode::Prefix && auth.secret.contains('/') {
+                return Err("AUTH SECRET for prefix mode can't contain '/'".to_string());
+            }
+            if auth.mode == AuthMode::Prefix && auth.replay_window_ms.is_some() {
+                return Err("AUTH REPLAY_WINDOW_MS needs hmac mode".to_string());
+            }
+        }
+        Ok(AccessPolicy {
+            allow,
+            auth,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn allows(&self, source: SocketAddr) -> bool {
+        let ip = source.ip().to_canonical();
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    // Returns the packet as it should be routed, with signatures or the
+    // secret prefix removed. Bundles pass only if every message does.
+    pub fn authenticate(&self, packet: OscPacket) -> Option<OscPacket> {
+        let Some(auth) = &self.auth else {
+            return Some(packet);
+        };
+        match packet {
+            OscPacket::Message(msg) => self.authenticate_message(auth, msg).map(OscPacket::Message),
+            OscPacket::Bundle(bundle) => {
+                let content = bundle
+                    .content
+                    .into_iter()
+                    .map(|p| self.authenticate(p))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(OscPacket::Bundle(OscBundle {
+                    timetag: bundle.timetag,
+                    content,
+                }))
+            }
+        }
+    }
+
+    fn authenticate_message(&self, auth: &AuthConfig, mut msg: OscMessage) -> Option<OscMessage> {
+        match auth.mode {
+            AuthMode::Prefix => {
+                let rest = msg
+                    .addr
+                    .strip_prefix('/')?
+                    .strip_prefix(auth.secret.as_str())?;
+                if !rest.starts_with('/') {
+                    return None;
+                }
+                msg.addr = rest.to_string();
+                Some(msg)
+            }
+            AuthMode::Hmac => {
+                let signature = match msg.args.pop()? {
+                    OscType::Blob(bytes) => bytes,
+                    OscType::String(hex) => decode_hex(&hex)?,
+                    _ => return None,
+                };
+                let unsigned = encoder::encode(&OscPacket::Message(msg.clone())).ok()?;
+                let mut mac = Hmac::<Sha256>::new_from_slice(auth.secret.as_bytes()).ok()?;
+                mac.update(&unsigned);
+                // Constant time, so the signature can't be guessed byte by byte
+                mac.verify_slice(&signature).ok()?;
+                if let Some(window_ms) = auth.replay_window_ms {
+                    let OscType::Long(sent_ms) = msg.args.pop()? else {
+                        return None;
+                    };
+                    self.check_fresh(sent_ms, window_ms, signature)?;
+                }
+                Some(msg)
+            }
+        }
+    }
+
+    fn check_fresh(&self, sent_ms: i64, window_ms: u64, signature: Vec<u8>) -> Option<()> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_millis() as i64;
+        if now_ms.abs_diff(sent_ms) > window_ms {
+            return None;
+        }
+        // Anything older than twice the window fails the clock check anyway
+        let window = Duration::from_millis(window_ms);
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| at.elapsed() <= window * 2);
+        if seen.insert(signature, Instant::now()).is_some() {
+            return None;
+        }
+        Some(())
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn reject(listener: &Arc<str>, source: SocketAddr, reason: Rejection) {
+    *REJECTED
+        .lock()
+        .unwrap()
+        .entry((listener.clone(), reason))
+        .or_default() += 1;
+    monitor::record(MonitorKind::Rejected {
+        listener: listener.clone(),
+        source,
+        reason: reason.name(),
+    });
+}
+
+pub fn rejected() -> Vec<RejectedCount> {
+    REJECTED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((listener, reason), count)| RejectedCount {
+            listener: listener.to_string(),
+            reason: reason.name(),
+            count: *count,
+        })
+        .collect()
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn clear_rejected() {
+    REJECTED.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(mode: AuthMode, replay_window_ms: Option<u64>) -> AccessPolicy {
+        let auth = AuthConfig {
+            mode,
+            secret: "s3cret".to_string(),
+            replay_window_ms,
+        };
+        AccessPolicy::new(&[], Some(auth)).unwrap()
+    }
+
+    fn message(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage {
+            addr: addr.to_string(),
+            args,
+        }
+    }
+
+    fn signature(secret: &str, msg: &OscMessage) -> Vec<u8> {
+        let encoded = encoder::encode(&OscPacket::Message(msg.clone())).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(&encoded);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn signed(secret: &str, mut msg: OscMessage) -> OscPacket {
+        let sig = signature(secret, &msg);
+        msg.args.push(OscType::Blob(sig));
+        OscPacket::Message(msg)
+    }
+
+    fn now_ms() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64
+    }
+
+    #[test]
+    fn hmac_accepts_blob_and_hex_signatures() {
+        let policy = policy(AuthMode::Hmac, None);
+        let msg = message("/go", vec![OscType::Int(1)]);
+        let routed = policy.authenticate(signed("s3cret", msg.clone()));
+        assert_eq!(routed, Some(OscPacket::Message(msg.clone())));
+
+        let hex: String = signature("s3cret", &msg)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let mut with_hex = msg.clone();
+        with_hex.args.push(OscType::String(hex));
+        assert!(policy.authenticate(OscPacket::Message(with_hex)).is_some());
+    }
+
+    #[test]
+    fn hmac_rejects_bad_signatures() {
+        let policy = policy(AuthMode::Hmac, None);
+        let msg = message("/go", vec![OscType::Int(1)]);
+        assert!(policy.authenticate(signed("wrong", msg.clone())).is_none());
+        assert!(policy
+            .authenticate(OscPacket::Message(msg.clone()))
+            .is_none());
+
+        // Tampered after signing
+        let OscPacket::Message(mut tampered) = signed("s3cret", msg.clone()) else {
+            unreachable!()
+        };
+        tampered.args[0] = OscType::Int(2);
+        assert!(policy.authenticate(OscPacket::Message(tampered)).is_none());
+
+        let mut odd_hex = msg;
+        odd_hex.args.push(OscType::String("abc".to_string()));
+        assert!(policy.authenticate(OscPacket::Message(odd_hex)).is_none());
+    }
+
+    #[test]
+    fn hmac_bundles_need_every_message_signed() {
+        let policy = policy(AuthMode::Hmac, None);
+        let good = signed("s3cret", message("/a", vec![]));
+        let bad = OscPacket::Message(message("/b", vec![]));
+        let bundle = |content| {
+            OscPacket::Bundle(OscBundle {
+                timetag: (0, 1).into(),
+                content,
+            })
+        };
+        assert!(policy.authenticate(bundle(vec![good.clone()])).is_some());
+        assert!(policy.authenticate(bundle(vec![good, bad])).is_none());
+    }
+
+    #[test]
+    fn replay_window_rejects_stale_and_repeated_messages() {
+        let policy = policy(AuthMode::Hmac, Some(5_000));
+        let fresh = signed(
+            "s3cret",
+            message("/go", vec![OscType::Int(1), OscType::Long(now_ms())]),
+        );
+        let routed = policy.authenticate(fresh.clone());
+        assert_eq!(
+            routed,
+            Some(OscPacket::Message(message("/go", vec![OscType::Int(1)])))
+        );
+        assert!(policy.authenticate(fresh).is_none());
+
+        let stale = signed(
+            "s3cret",
+            message("/go", vec![OscType::Long(now_ms() - 60_000)]),
+        );
+        assert!(policy.authenticate(stale).is_none());
+        let untimed = signed("s3cret", message("/go", vec![OscType::Int(1)]));
+        assert!(policy.authenticate(untimed).is_none());
+    }
+
+    #[test]
+    fn prefix_secret_is_stripped() {
+        let policy = policy(AuthMode::Prefix, None);
+        let routed = policy.authenticate(OscPacket::Message(message("/s3cret/cue/1", vec![])));
+        assert_eq!(routed, Some(OscPacket::Message(message("/cue/1", vec![]))));
+
+        for addr in [
+            "/cue/1",
+            "/wrong/cue/1",
+            "/s3cretx/cue",
+            "/s3cret",
+            "s3cret/cue",
+        ] {
+            let packet = OscPacket::Message(message(addr, vec![]));
+            assert!(policy.authenticate(packet).is_none(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn allow_list_matches_ips_and_ranges() {
+        let allow = ["10.0.0.0/24".to_string(), "192.168.1.5".to_string()];
+        let policy = AccessPolicy::new(&allow, None).unwrap();
+        let from = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(policy.allows(from("10.0.0.200:9000")));
+        assert!(policy.allows(from("192.168.1.5:1")));
+        // IPv4 seen through a dual-stack socket
+        assert!(policy.allows(from("[::ffff:10.0.0.7]:9000")));
+        assert!(!policy.allows(from("10.0.1.1:9000")));
+        assert!(!policy.allows(from("192.168.1.6:1")));
+
+        assert!(AccessPolicy::new(&[], None)
+            .unwrap()
+            .allows(from("1.2.3.4:5")));
+        assert!(AccessPolicy::new(&["10.0.0.0/33".to_string()], None).is_err());
+        assert!(AccessPolicy::new(&["not-an-ip".to_string()], None).is_err());
+    }
+
+    #[test]
+    fn rejects_unusable_auth_settings() {
+        let auth = |mode, secret: &str, replay_window_ms| {
+            Some(AuthConfig {
+                mode,
+                secret: secret.to_string(),
+                replay_window_ms,
+            })
+        };
+        assert!(AccessPolicy::new(&[], auth(AuthMode::Hmac, "", None)).is_err());
+        assert!(AccessPolicy::new(&[], auth(AuthMode::Prefix, "a/b", None)).is_err());
+        assert!(AccessPolicy::new(&[], auth(AuthMode::Prefix, "ab", Some(1000))).is_err());
+    }
+}
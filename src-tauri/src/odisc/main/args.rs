@@ -0,0 +1,156 @@
+use rosc::OscType;
+
+// OSC argument lists as written in osc_out_args:
+//   1 2.5 hello        untagged numbers are floats, anything else is a string
+//   i:1 f:2 b:true     int, float and bool; s:12 keeps "12" a string
+//   "two words"        double quotes keep spaces together, \" and \\ inside them
+// Placeholders like $value are plain tokens, the caller fills them in.
+
+pub const TAGS: [&str; 4] = ["i:", "f:", "b:", "s:"];
+
+// Tokens and whether every quote was closed
+fn tokenize(text: &str) -> (Vec<String>, bool) {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_token = false;
+    let mut quoted = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_token = true;
+            }
+            '\\' if quoted => {
+                if let Some(next) = chars.next() {
+                    token.push(next);
+                }
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            c => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    (tokens, !quoted)
+}
+
+// An unclosed quote runs to the end
+pub fn split(text: &str) -> Vec<String> {
+    tokenize(text).0
+}
+
+// A token as split() reads it back
+pub fn quote(token: &str) -> String {
+    if !token.is_empty() && !token.contains(|c: char| c.is_whitespace() || c == '"') {
+        return token.to_string();
+    }
+    let escaped = token.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
+
+pub fn join<S: AsRef<str>>(tokens: &[S]) -> String {
+    tokens
+        .iter()
+        .map(|t| quote(t.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn typed(token: &str) -> Option<OscType> {
+    let (tag, value) = token.split_at_checked(2)?;
+    match tag {
+        "i:" => value
+            .parse::<i32>()
+            .map(OscType::Int)
+            .or_else(|_| value.parse::<i64>().map(OscType::Long))
+            .ok(),
+        "f:" => value.parse::<f32>().ok().map(OscType::Float),
+        "b:" => value.parse::<bool>().ok().map(OscType::Bool),
+        "s:" => Some(OscType::String(value.to_string())),
+        _ => None,
+    }
+}
+
+// A tag that doesn't fit its value leaves the whole token a string
+pub fn parse(token: &str) -> OscType {
+    typed(token).unwrap_or_else(|| match token.parse::<f32>() {
+        Ok(n) => OscType::Float(n),
+        Err(_) => OscType::String(token.to_string()),
+    })
+}
+
+pub fn parse_all(text: &str) -> Vec<OscType> {
+    split(text).iter().map(|t| parse(t)).collect()
+}
+
+pub fn validate(text: &str) -> Result<(), String> {
+    let (tokens, closed) = tokenize(text);
+    if !closed {
+        return Err(format!("Unclosed quote in '{text}'"));
+    }
+    for token in tokens {
+        if TAGS.iter().any(|tag| token.starts_with(tag)) && typed(&token).is_none() {
+            return Err(format!("'{token}' doesn't match its type tag"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_quoted_tokens() {
+        assert_eq!(
+            split(r#"1 "two words" s:"a \"b\"""#),
+            ["1", "two words", "s:a \"b\""]
+        );
+        assert_eq!(split("  a   b "), ["a", "b"]);
+        assert_eq!(split(r#""""#), [""]);
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        for token in [
+            "plain",
+            "two words",
+            "",
+            r#"say "hi""#,
+            r"back\slash",
+            "s:x y",
+        ] {
+            assert_eq!(split(&quote(token)), [token]);
+        }
+    }
+
+    #[test]
+    fn parses_tags_and_plain_tokens() {
+        assert_eq!(parse("1"), OscType::Float(1.0));
+        assert_eq!(parse("i:7"), OscType::Int(7));
+        assert_eq!(parse("i:5000000000"), OscType::Long(5_000_000_000));
+        assert_eq!(parse("f:0.5"), OscType::Float(0.5));
+        assert_eq!(parse("b:true"), OscType::Bool(true));
+        assert_eq!(parse("s:12"), OscType::String("12".into()));
+        assert_eq!(parse("hello"), OscType::String("hello".into()));
+        assert_eq!(parse("i:x"), OscType::String("i:x".into()));
+    }
+
+    #[test]
+    fn validate_rejects_bad_tags_and_quotes() {
+        assert!(validate(r#"i:1 "a b" $value"#).is_ok());
+        assert!(validate("i:1.5").is_err());
+        assert!(validate("b:yes").is_err());
+        assert!(validate(r#""open"#).is_err());
+    }
+}
@@ -0,0 +1,521 @@
+use crate::odisc::main::args;
+use crate::odisc::main::handlers;
+use crate::odisc::main::helpers::Mapping;
+use crate::odisc::main::vars::{self, Value};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+// Stateful mappings: each match moves the row to its next value.
+//   behavior         toggle, cycle_up, cycle_down, increment or decrement
+//   behavior_values  toggle: "off on" (empty = "0 127")
+//                    cycle_*: the list, e.g. "1A 2A 3A" or "0 1 2 3"
+//                    increment/decrement: "min max [step]", step 1 by default
+//   behavior_mode    wrap or clamp at the ends; cycles wrap and counters clamp
+//                    unless set
+//   behavior_var     keep the state in this variable (see vars.rs), so rows
+//                    can share it and conditions can test it; empty = per row
+// The first match gives the first value in the direction of travel (a toggle
+// starts from "off"). The value replaces midi_value for cc and pc,
+// midi_velocity for notes, the preset ID for qc_preset and gt1000_preset
+// (toggles and cycles of valid IDs only), and "$value" in osc_out_args, or is
+// the only OSC argument when that is empty.
+// Per-row state is dropped when the mappings change; /odisc/behavior/reset
+// starts rows over.
+
+pub const TYPES: [&str; 5] = ["toggle", "cycle_up", "cycle_down", "increment", "decrement"];
+pub const VALUE_PLACEHOLDER: &str = "$value";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Toggle,
+    Cycle { up: bool },
+    Counter { up: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Wrap,
+    Clamp,
+}
+
+#[derive(Debug)]
+struct Behavior {
+    kind: Kind,
+    values: Vec<Value>,
+    mode: Mode,
+}
+
+// What to start over
+#[derive(Debug, Clone)]
+pub enum Reset {
+    All,
+    Row(usize),
+    Var(String),
+}
+
+lazy_static! {
+    // Rows without a behavior_var
+    static ref STATES: Mutex<HashMap<usize, Value>> = Mutex::new(HashMap::new());
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|v| !v.is_empty())
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn parse(m: &Mapping) -> Result<Option<Behavior>, String> {
+    let Some(kind) = non_empty(&m.behavior) else {
+        return Ok(None);
+    };
+    let kind = match kind {
+        "toggle" => Kind::Toggle,
+        "cycle_up" => Kind::Cycle { up: true },
+        "cycle_down" => Kind::Cycle { up: false },
+        "increment" => Kind::Counter { up: true },
+        "decrement" => Kind::Counter { up: false },
+        _ => {
+            return Err(format!(
+                "Unknown behavior '{kind}', expected one of {}",
+                TYPES.join(", ")
+            ))
+        }
+    };
+    let mode = match non_empty(&m.behavior_mode) {
+        Some("wrap") => Mode::Wrap,
+        Some("clamp") => Mode::Clamp,
+        Some(mode) => return Err(format!("behavior_mode must be wrap or clamp, got '{mode}'")),
+        None if matches!(kind, Kind::Counter { .. }) => Mode::Clamp,
+        None => Mode::Wrap,
+    };
+    let mut values: Vec<Value> = m
+        .behavior_values
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(Value::parse)
+        .collect();
+
+    match kind {
+        Kind::Toggle if values.is_empty() => {
+            values = vec![Value::Number(0.0), Value::Number(127.0)];
+        }
+        Kind::Toggle if values.len() != 2 => {
+            return Err("behavior_values for toggle must be two values, e.g. '0 127'".to_string())
+        }
+        Kind::Cycle { .. } if values.is_empty() => {
+            return Err("behavior_values must list the values to cycle through".to_string())
+        }
+        Kind::Counter { .. } => {
+            let numbers = values.iter().map(number).collect::<Option<Vec<f64>>>();
+            let valid = match numbers.as_deref() {
+                Some([min, max]) => min < max,
+                Some([min, max, step]) => min < max && *step > 0.0,
+                _ => false,
+            };
+            if !valid {
+                return Err(format!(
+                    "behavior_values for {} must be 'min max' or 'min max step', min below max",
+                    non_empty(&m.behavior).unwrap_or_default()
+                ));
+            }
+        }
+        _ => {}
+    }
+
+    Ok(Some(Behavior { kind, values, mode }))
+}
+
+fn step(behavior: &Behavior, current: Option<&Value>) -> Value {
+    let values = &behavior.values;
+    let wrap = behavior.mode == Mode::Wrap;
+    match behavior.kind {
+        Kind::Toggle => {
+            if current.is_none_or(|c| *c == values[0]) {
+                values[1].clone()
+            } else {
+                values[0].clone()
+            }
+        }
+        Kind::Cycle { up } => {
+            let last = values.len() - 1;
+            let index = match current.and_then(|c| values.iter().position(|v| v == c)) {
+                None if up => 0,
+                None => last,
+                Some(i) if up && i == last => {
+                    if wrap {
+                        0
+                    } else {
+                        last
+                    }
+                }
+                Some(0) if !up => {
+                    if wrap {
+                        last
+                    } else {
+                        0
+                    }
+                }
+                Some(i) if up => i + 1,
+                Some(i) => i - 1,
+            };
+            values[index].clone()
+        }
+        Kind::Counter { up } => {
+            let min = number(&values[0]).unwrap_or_default();
+            let max = number(&values[1]).unwrap_or_default();
+            let step = values.get(2).and_then(number).unwrap_or(1.0);
+            let next = match current.and_then(number) {
+                None if up => min,
+                None => max,
+                Some(n) if up => n + step,
+                Some(n) => n - step,
+            };
+            let next = match (next > max, next < min) {
+                (true, _) if wrap => min,
+                (true, _) => max,
+                (_, true) if wrap => max,
+                (_, true) => min,
+                _ => next,
+            };
+            Value::Number(next)
+        }
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Text(s) => s.clone(),
+    }
+}
+
+// 0-127 for MIDI data bytes
+fn data_byte(value: &Value) -> Option<u32> {
+    number(value).map(|n| n.round().clamp(0.0, 127.0) as u32)
+}
+
+// The mapping with its next value filled in, None when it has no behavior
+pub fn apply(row: usize, m: &Mapping) -> Result<Option<Mapping>, String> {
+    let Some(behavior) = parse(m)? else {
+        return Ok(None);
+    };
+
+    let value = match non_empty(&m.behavior_var) {
+        Some(name) => {
+            let value = step(&behavior, vars::get(name).as_ref());
+            vars::set(name, value.clone())?;
+            value
+        }
+        None => {
+            let mut states = STATES.lock().unwrap();
+            let value = step(&behavior, states.get(&row));
+            states.insert(row, value.clone());
+            value
+        }
+    };
+    debug!("Row {row} behavior value: {}", text(&value));
+    Ok(Some(with_value(m, &value)))
+}
+
+// The mapping with `value` put where the output takes it (see the top of this file)
+pub fn with_value(m: &Mapping, value: &Value) -> Mapping {
+    let mut stepped = m.clone();
+    match m.midi_type.as_deref() {
+        Some("cc") | Some("pc") => stepped.midi_value = data_byte(value).or(m.midi_value),
+        Some("note_on") | Some("note_off") => {
+            stepped.midi_velocity = data_byte(value).or(m.midi_velocity)
+        }
+        Some("qc_preset") => stepped.qc_preset_id = Some(text(value)),
+        Some("gt1000_preset") => stepped.gt1000_preset_id = Some(text(value)),
+        _ => {}
+    }
+    if non_empty(&m.osc_out_address).is_some() {
+        stepped.osc_out_args = Some(match non_empty(&m.osc_out_args) {
+            Some(list) => {
+                let tokens: Vec<String> = args::split(list)
+                    .into_iter()
+                    .map(|arg| {
+                        if arg == VALUE_PLACEHOLDER {
+                            text(value)
+                        } else {
+                            arg
+                        }
+                    })
+                    .collect();
+                args::join(&tokens)
+            }
+            None => args::quote(&text(value)),
+        });
+    }
+    stepped
+}
+
+pub fn validate(m: &Mapping) -> Result<(), String> {
+    let Some(behavior) = parse(m)? else {
+        let set = [&m.behavior_values, &m.behavior_mode, &m.behavior_var]
+            .iter()
+            .any(|f| non_empty(f).is_some());
+        if set {
+            return Err("behavior is required with the other behavior_* columns".to_string());
+        }
+        return Ok(());
+    };
+    if let Some(name) = non_empty(&m.behavior_var) {
+        if !vars::is_valid_name(name) {
+            return Err(format!(
+                "behavior_var '{name}' must be letters, digits and _ (not 'args')"
+            ));
+        }
+    }
+    // MIDI data bytes need whole numbers in range
+    if matches!(
+        m.midi_type.as_deref(),
+        Some("cc") | Some("pc") | Some("note_on") | Some("note_off")
+    ) {
+        let fits = |v: &Value| number(v).is_some_and(|n| (0.0..=127.0).contains(&n));
+        let in_range = match behavior.kind {
+            Kind::Counter { .. } => behavior.values[..2].iter().all(fits),
+            _ => behavior.values.iter().all(fits),
+        };
+        if !in_range {
+            return Err("behavior_values must be 0-127 for MIDI messages".to_string());
+        }
+    }
+    // Preset rows send the value as a preset ID
+    let (is_valid, device): (fn(&str) -> bool, &str) = match m.midi_type.as_deref() {
+        Some("qc_preset") => (handlers::is_valid_qc_preset, "Quad Cortex"),
+        Some("gt1000_preset") => (handlers::is_valid_gt1000_preset, "GT-1000"),
+        _ => return Ok(()),
+    };
+    if matches!(behavior.kind, Kind::Counter { .. }) {
+        return Err(format!(
+            "{device} presets can't be counted, use a cycle of preset IDs"
+        ));
+    }
+    if let Some(bad) = behavior.values.iter().map(text).find(|v| !is_valid(v)) {
+        return Err(format!(
+            "behavior_values: '{bad}' is not a {device} preset ID"
+        ));
+    }
+    Ok(())
+}
+
+// The variable a row keeps its state in, if it has a behavior
+fn state_var(m: &Mapping) -> Option<&str> {
+    non_empty(&m.behavior)?;
+    non_empty(&m.behavior_var)
+}
+
+// `mappings` tells which variables hold behavior state; others are left alone
+pub fn reset(target: Reset, mappings: &[Mapping]) {
+    match target {
+        Reset::All => {
+            STATES.lock().unwrap().clear();
+            for name in mappings.iter().filter_map(state_var) {
+                vars::remove(name);
+            }
+        }
+        Reset::Row(row) => {
+            STATES.lock().unwrap().remove(&row);
+            if let Some(name) = mappings.get(row).and_then(state_var) {
+                vars::remove(name);
+            }
+        }
+        Reset::Var(name) => {
+            if !mappings.iter().any(|m| state_var(m) == Some(name.as_str())) {
+                warn!("'{name}' is no mapping's behavior_var, not reset");
+                return;
+            }
+            vars::remove(&name);
+        }
+    }
+    debug!("Behavior state reset");
+}
+
+// Row numbers move when the table changes
+pub fn clear_rows() {
+    STATES.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn behavior(kind: &str, values: &str, mode: &str) -> Behavior {
+        let m = Mapping {
+            behavior: Some(kind.to_string()),
+            behavior_values: Some(values.to_string()),
+            behavior_mode: Some(mode.to_string()),
+            ..Default::default()
+        };
+        parse(&m).unwrap().unwrap()
+    }
+
+    // The values of `count` matches in a row, from no state
+    fn run(behavior: &Behavior, count: usize) -> Vec<String> {
+        let mut current = None;
+        (0..count)
+            .map(|_| {
+                let next = step(behavior, current.as_ref());
+                current = Some(next.clone());
+                text(&next)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn toggle_starts_from_off() {
+        assert_eq!(run(&behavior("toggle", "", ""), 3), ["127", "0", "127"]);
+        assert_eq!(run(&behavior("toggle", "off on", ""), 2), ["on", "off"]);
+        // A value from elsewhere counts as on
+        let toggle = behavior("toggle", "off on", "");
+        assert_eq!(
+            step(&toggle, Some(&Value::Text("x".into()))),
+            Value::Text("off".into())
+        );
+    }
+
+    #[test]
+    fn cycles_wrap_or_clamp_both_ways() {
+        assert_eq!(
+            run(&behavior("cycle_up", "a b c", ""), 4),
+            ["a", "b", "c", "a"]
+        );
+        assert_eq!(
+            run(&behavior("cycle_up", "a b c", "clamp"), 4),
+            ["a", "b", "c", "c"]
+        );
+        assert_eq!(
+            run(&behavior("cycle_down", "a b c", ""), 4),
+            ["c", "b", "a", "c"]
+        );
+        assert_eq!(
+            run(&behavior("cycle_down", "a b c", "clamp"), 4),
+            ["c", "b", "a", "a"]
+        );
+    }
+
+    #[test]
+    fn counters_stop_or_wrap_past_the_ends() {
+        assert_eq!(
+            run(&behavior("increment", "0 10 4", ""), 5),
+            ["0", "4", "8", "10", "10"]
+        );
+        assert_eq!(
+            run(&behavior("increment", "0 10 4", "wrap"), 5),
+            ["0", "4", "8", "0", "4"]
+        );
+        assert_eq!(
+            run(&behavior("decrement", "0 10 4", ""), 5),
+            ["10", "6", "2", "0", "0"]
+        );
+        assert_eq!(
+            run(&behavior("decrement", "0 10 4", "wrap"), 5),
+            ["10", "6", "2", "10", "6"]
+        );
+        assert_eq!(
+            run(&behavior("increment", "1 3", ""), 4),
+            ["1", "2", "3", "3"]
+        );
+    }
+
+    #[test]
+    fn var_reset_only_touches_behavior_vars() {
+        let mappings = [
+            Mapping {
+                behavior: Some("toggle".to_string()),
+                behavior_var: Some("t_behavior_state".to_string()),
+                ..Default::default()
+            },
+            // A leftover column without a behavior
+            Mapping {
+                behavior_var: Some("t_behavior_other".to_string()),
+                ..Default::default()
+            },
+        ];
+        for name in ["t_behavior_state", "t_behavior_other", "t_behavior_song"] {
+            vars::set(name, Value::Number(1.0)).unwrap();
+        }
+        reset(Reset::Var("t_behavior_song".to_string()), &mappings);
+        reset(Reset::Var("t_behavior_other".to_string()), &mappings);
+        reset(Reset::Row(1), &mappings);
+        assert!(vars::get("t_behavior_song").is_some());
+        assert!(vars::get("t_behavior_other").is_some());
+        reset(Reset::Var("t_behavior_state".to_string()), &mappings);
+        assert!(vars::get("t_behavior_state").is_none());
+    }
+
+    fn qc_row(kind: &str, values: &str) -> Mapping {
+        Mapping {
+            osc_in_address: "/scene/next".to_string(),
+            midi_type: Some("qc_preset".to_string()),
+            midi_channel: Some(1),
+            setlist: Some(2),
+            qc_preset_id: Some("1A".to_string()),
+            behavior: Some(kind.to_string()),
+            behavior_values: Some(values.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn steps_a_qc_cycle() {
+        let m = qc_row("cycle_up", "1A 2B 3C");
+        assert!(validate(&m).is_ok());
+        // Row number only this test uses, the per-row state is global
+        let row = 9050;
+        let stepped: Vec<(String, Option<u32>)> = (0..4)
+            .map(|_| {
+                let stepped = apply(row, &m).unwrap().unwrap();
+                let id = stepped.qc_preset_id.unwrap();
+                let pgm = handlers::send_qc_preset(&id, &2, &1);
+                (id, pgm)
+            })
+            .collect();
+        assert_eq!(
+            stepped,
+            [
+                ("1A".to_string(), Some(0)),
+                ("2B".to_string(), Some(9)),
+                ("3C".to_string(), Some(18)),
+                ("1A".to_string(), Some(0)),
+            ]
+        );
+        reset(Reset::Row(row), &[]);
+    }
+
+    #[test]
+    fn preset_rows_need_preset_ids() {
+        assert!(validate(&qc_row("toggle", "1A 1B")).is_ok());
+        for (kind, values) in [
+            ("increment", "1 8"),
+            ("decrement", "1 8 1"),
+            ("toggle", ""),
+            ("cycle_up", "1A 33A"),
+            ("cycle_down", "1A foo"),
+            ("cycle_up", "1 2 3"),
+        ] {
+            assert!(validate(&qc_row(kind, values)).is_err(), "{kind} {values}");
+        }
+
+        let gt = |kind: &str, values: &str| Mapping {
+            midi_type: Some("gt1000_preset".to_string()),
+            gt1000_preset_id: Some("U01-1".to_string()),
+            qc_preset_id: None,
+            setlist: None,
+            ..qc_row(kind, values)
+        };
+        assert!(validate(&gt("cycle_up", "U01-1 U01-2 P50-5")).is_ok());
+        assert!(validate(&gt("cycle_up", "U01-1 1A")).is_err());
+        assert!(validate(&gt("increment", "1 5")).is_err());
+    }
+}
@@ -0,0 +1,427 @@
+use crate::odisc::main::behavior::Reset;
+use crate::odisc::main::helpers::Mapping;
+use rosc::{OscMessage, OscType};
+use std::sync::RwLock;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+use lazy_static::lazy_static;
+
+// Everything below this is handled here and never reaches the mappings
+pub const RESERVED_PREFIX: &str = "/odisc/";
+pub const SHOW_LOAD_ADDRESS: &str = "/odisc/show/load";
+pub const LAYER_SET_ADDRESS: &str = "/odisc/layer/set";
+pub const LAYER_CLEAR_ADDRESS: &str = "/odisc/layer/clear";
+pub const PANIC_ADDRESS: &str = "/odisc/panic";
+pub const RELOAD_ADDRESS: &str = "/odisc/mappings/reload";
+pub const MAPPING_ENABLE_ADDRESS: &str = "/odisc/mapping/enable";
+pub const MAPPING_DISABLE_ADDRESS: &str = "/odisc/mapping/disable";
+pub const MIDI_OUTPUT_ADDRESS: &str = "/odisc/midi/output";
+pub const MIDI_SEND_ADDRESS: &str = "/odisc/midi/send";
+pub const BEHAVIOR_RESET_ADDRESS: &str = "/odisc/behavior/reset";
+pub const STATUS_ADDRESS: &str = "/odisc/status";
+pub const PING_ADDRESS: &str = "/odisc/ping";
+pub const PONG_ADDRESS: &str = "/odisc/pong";
+
+// Advertised over OSCQuery: (address, OSC type tags, description)
+pub const ADDRESSES: &[(&str, &str, &str)] = &[
+    (SHOW_LOAD_ADDRESS, "s", "Load a show by name"),
+    (LAYER_SET_ADDRESS, "s", "Activate a song layer"),
+    (
+        LAYER_CLEAR_ADDRESS,
+        "",
+        "Clear the song layer, global mappings only",
+    ),
+    (
+        PANIC_ADDRESS,
+        "",
+        "All notes and sound off on the MIDI output",
+    ),
+    (RELOAD_ADDRESS, "", "Re-read the active mappings file"),
+    (
+        MAPPING_ENABLE_ADDRESS,
+        "i",
+        "Enable a mapping by row, until mappings change",
+    ),
+    (
+        MAPPING_DISABLE_ADDRESS,
+        "i",
+        "Disable a mapping by row, until mappings change",
+    ),
+    (
+        MIDI_OUTPUT_ADDRESS,
+        "s",
+        "Switch to another MIDI output port",
+    ),
+    (
+        MIDI_SEND_ADDRESS,
+        "iii",
+        "Send raw MIDI bytes, as ints or one blob",
+    ),
+    (
+        BEHAVIOR_RESET_ADDRESS,
+        "",
+        "Restart toggles, cycles and counters: all, a row (i) or a variable (s)",
+    ),
+    (
+        STATUS_ADDRESS,
+        "",
+        "Replies /odisc/status show, layer, MIDI output, mappings, active",
+    ),
+    (
+        PING_ADDRESS,
+        "",
+        "Replies /odisc/pong with the same arguments",
+    ),
+];
+
+pub type Reply = Option<oneshot::Sender<Result<(), String>>>;
+
+// Commands the running backend loop applies between packets
+pub enum ControlCommand {
+    LoadShow {
+        name: String,
+        reply: Reply,
+    },
+    SetLayer(Option<String>),
+    // All notes/sound off on every MIDI output
+    Panic {
+        reply: Reply,
+    },
+    ReloadMappings {
+        reply: Reply,
+    },
+    SetMappingEnabled {
+        row: usize,
+        enabled: bool,
+    },
+    SetMidiOutput {
+        name: String,
+        reply: Reply,
+    },
+    SendMidi(Vec<u8>),
+    // To OSC_SEND_HOST:OSC_SEND_PORT, e.g. a script's delayed output
+    SendOsc(OscMessage),
+    // A delayed step of a matched mapping
+    SendOutputs {
+        mapping: Box<Mapping>,
+        args: Vec<OscType>,
+    },
+    ResetBehaviors(Reset),
+    // Fires a mapping's output as if it had matched
+    Trigger {
+        row: usize,
+        reply: Reply,
+    },
+    // Answered with a message back to whoever asked
+    Status,
+    Ping(Vec<OscType>),
+}
+
+lazy_static! {
+    static ref CONTROL_TX: RwLock<Option<UnboundedSender<ControlCommand>>> = RwLock::new(None);
+}
+
+// Receiving end held by the backend loop; dropping it marks the backend as gone
+pub struct ControlReceiver(UnboundedReceiver<ControlCommand>);
+
+impl ControlReceiver {
+    pub async fn recv(&mut self) -> Option<ControlCommand> {
+        self.0.recv().await
+    }
+}
+
+impl Drop for ControlReceiver {
+    fn drop(&mut self) {
+        *CONTROL_TX.write().unwrap() = None;
+    }
+}
+
+pub fn register() -> ControlReceiver {
+    let (tx, rx) = unbounded_channel();
+    *CONTROL_TX.write().unwrap() = Some(tx);
+    ControlReceiver(rx)
+}
+
+// Hands the command back if the backend isn't running
+pub fn send(cmd: ControlCommand) -> Result<(), ControlCommand> {
+    match CONTROL_TX.read().unwrap().as_ref() {
+        Some(tx) => tx.send(cmd).map_err(|e| e.0),
+        None => Err(cmd),
+    }
+}
+
+pub async fn request(build: impl FnOnce(Reply) -> ControlCommand) -> Option<Result<(), String>> {
+    let (tx, rx) = oneshot::channel();
+    send(build(Some(tx))).ok()?;
+    Some(
+        rx.await
+            .unwrap_or_else(|_| Err("Backend stopped before replying".to_string())),
+    )
+}
+
+// Controllers often send every number as a float
+fn int_arg(arg: &OscType) -> Option<i64> {
+    match arg {
+        OscType::Int(i) => Some(i64::from(*i)),
+        OscType::Long(l) => Some(*l),
+        OscType::Float(f) if f.fract() == 0.0 => Some(*f as i64),
+        _ => None,
+    }
+}
+
+// Data bytes that follow a status byte; None for undefined status bytes and a
+// stray end of SysEx
+fn data_len(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => Some(2),
+        0xC0..=0xDF | 0xF1 | 0xF3 => Some(1),
+        0xF6 | 0xF8 | 0xFA..=0xFC | 0xFE | 0xFF => Some(0),
+        _ => None,
+    }
+}
+
+// A complete message: status byte first, exactly the data bytes it takes, all
+// below 0x80, except inside SysEx
+fn raw_midi(args: &[OscType]) -> Option<Vec<u8>> {
+    let bytes = match args {
+        [OscType::Blob(bytes)] => bytes.clone(),
+        _ => args
+            .iter()
+            .map(|arg| int_arg(arg).and_then(|i| u8::try_from(i).ok()))
+            .collect::<Option<Vec<u8>>>()?,
+    };
+    let (status, data) = bytes.split_first()?;
+    let valid = match status {
+        0xF0 => bytes.last() == Some(&0xF7),
+        0x80.. => data_len(*status) == Some(data.len()) && data.iter().all(|b| *b < 0x80),
+        _ => false,
+    };
+    valid.then_some(bytes)
+}
+
+// Reserved OSC addresses, handled before learn and match_mappings. None for
+// anything outside RESERVED_PREFIX; an error for bad arguments or an unknown
+// reserved address, which the caller logs and drops.
+pub fn from_osc(msg: &OscMessage) -> Option<Result<ControlCommand, String>> {
+    if !msg.addr.starts_with(RESERVED_PREFIX) {
+        return None;
+    }
+    Some(parse(msg).ok_or_else(
+        || match ADDRESSES.iter().find(|(address, ..)| *address == msg.addr) {
+            Some((_, _, description)) => format!(
+                "Bad arguments for {}: {:?} ({description})",
+                msg.addr, msg.args
+            ),
+            None => format!("Unknown control address {}", msg.addr),
+        },
+    ))
+}
+
+fn parse(msg: &OscMessage) -> Option<ControlCommand> {
+    match msg.addr.as_str() {
+        SHOW_LOAD_ADDRESS => match &msg.args[..] {
+            [OscType::String(name)] => Some(ControlCommand::LoadShow {
+                name: name.clone(),
+                reply: None,
+            }),
+            _ => None,
+        },
+        LAYER_SET_ADDRESS => match &msg.args[..] {
+            [OscType::String(name)] => Some(ControlCommand::SetLayer(Some(name.clone()))),
+            _ => None,
+        },
+        LAYER_CLEAR_ADDRESS => Some(ControlCommand::SetLayer(None)),
+        PANIC_ADDRESS => Some(ControlCommand::Panic { reply: None }),
+        RELOAD_ADDRESS => Some(ControlCommand::ReloadMappings { reply: None }),
+        MAPPING_ENABLE_ADDRESS | MAPPING_DISABLE_ADDRESS => match &msg.args[..] {
+            [arg] => Some(ControlCommand::SetMappingEnabled {
+                row: usize::try_from(int_arg(arg)?).ok()?,
+                enabled: msg.addr == MAPPING_ENABLE_ADDRESS,
+            }),
+            _ => None,
+        },
+        MIDI_OUTPUT_ADDRESS => match &msg.args[..] {
+            [OscType::String(name)] => Some(ControlCommand::SetMidiOutput {
+                name: name.clone(),
+                reply: None,
+            }),
+            _ => None,
+        },
+        MIDI_SEND_ADDRESS => raw_midi(&msg.args).map(ControlCommand::SendMidi),
+        BEHAVIOR_RESET_ADDRESS => match &msg.args[..] {
+            [] => Some(ControlCommand::ResetBehaviors(Reset::All)),
+            [OscType::String(name)] => {
+                Some(ControlCommand::ResetBehaviors(Reset::Var(name.clone())))
+            }
+            [arg] => Some(ControlCommand::ResetBehaviors(Reset::Row(
+                usize::try_from(int_arg(arg)?).ok()?,
+            ))),
+            _ => None,
+        },
+        STATUS_ADDRESS => Some(ControlCommand::Status),
+        PING_ADDRESS => Some(ControlCommand::Ping(msg.args.clone())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage {
+            addr: addr.to_string(),
+            args,
+        }
+    }
+
+    fn ints(bytes: &[i32]) -> Vec<OscType> {
+        bytes.iter().map(|b| OscType::Int(*b)).collect()
+    }
+
+    fn byte_args(bytes: &[u8]) -> Vec<OscType> {
+        bytes.iter().map(|b| OscType::Int(i32::from(*b))).collect()
+    }
+
+    fn command(addr: &str, args: Vec<OscType>) -> ControlCommand {
+        match from_osc(&msg(addr, args)) {
+            Some(Ok(cmd)) => cmd,
+            Some(Err(e)) => panic!("{addr}: {e}"),
+            None => panic!("{addr} not handled"),
+        }
+    }
+
+    fn rejected(addr: &str, args: Vec<OscType>) -> String {
+        match from_osc(&msg(addr, args)) {
+            Some(Err(e)) => e,
+            Some(Ok(_)) => panic!("{addr} accepted"),
+            None => panic!("{addr} fell through to the mappings"),
+        }
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let name = || vec![OscType::String("tour".into())];
+        assert!(matches!(
+            command(SHOW_LOAD_ADDRESS, name()),
+            ControlCommand::LoadShow { name, reply: None } if name == "tour"
+        ));
+        assert!(matches!(
+            command(LAYER_SET_ADDRESS, name()),
+            ControlCommand::SetLayer(Some(layer)) if layer == "tour"
+        ));
+        assert!(matches!(
+            command(LAYER_CLEAR_ADDRESS, vec![]),
+            ControlCommand::SetLayer(None)
+        ));
+        // Floats from controllers count as rows when whole
+        assert!(matches!(
+            command(MAPPING_DISABLE_ADDRESS, vec![OscType::Float(3.0)]),
+            ControlCommand::SetMappingEnabled {
+                row: 3,
+                enabled: false
+            }
+        ));
+        assert!(matches!(
+            command(MAPPING_ENABLE_ADDRESS, vec![OscType::Long(2)]),
+            ControlCommand::SetMappingEnabled {
+                row: 2,
+                enabled: true
+            }
+        ));
+        assert!(matches!(
+            command(BEHAVIOR_RESET_ADDRESS, vec![]),
+            ControlCommand::ResetBehaviors(Reset::All)
+        ));
+        assert!(matches!(
+            command(BEHAVIOR_RESET_ADDRESS, vec![OscType::Int(4)]),
+            ControlCommand::ResetBehaviors(Reset::Row(4))
+        ));
+        assert!(matches!(
+            command(BEHAVIOR_RESET_ADDRESS, vec![OscType::String("scene".into())]),
+            ControlCommand::ResetBehaviors(Reset::Var(var)) if var == "scene"
+        ));
+        assert!(matches!(
+            command(PING_ADDRESS, ints(&[7])),
+            ControlCommand::Ping(args) if args == ints(&[7])
+        ));
+    }
+
+    #[test]
+    fn bad_arguments_are_consumed() {
+        for (addr, args) in [
+            (SHOW_LOAD_ADDRESS, vec![]),
+            (SHOW_LOAD_ADDRESS, ints(&[1])),
+            (LAYER_SET_ADDRESS, vec![]),
+            (MIDI_OUTPUT_ADDRESS, ints(&[1])),
+            (MAPPING_ENABLE_ADDRESS, vec![]),
+            (MAPPING_ENABLE_ADDRESS, ints(&[-1])),
+            (MAPPING_DISABLE_ADDRESS, vec![OscType::Float(1.5)]),
+            (BEHAVIOR_RESET_ADDRESS, ints(&[1, 2])),
+            (MIDI_SEND_ADDRESS, ints(&[0x90, 60])),
+        ] {
+            assert!(rejected(addr, args).starts_with("Bad arguments"), "{addr}");
+        }
+        assert!(rejected("/odisc/nothing", vec![]).starts_with("Unknown control address"));
+        assert!(rejected(PONG_ADDRESS, vec![]).starts_with("Unknown control address"));
+    }
+
+    #[test]
+    fn other_addresses_fall_through() {
+        for addr in ["/cue/go", "/odisc", "/odiscx/panic", "/song"] {
+            assert!(from_osc(&msg(addr, vec![])).is_none(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn raw_midi_needs_exact_lengths() {
+        for bytes in [
+            &[0x90, 60, 100][..],
+            &[0x80, 60, 0],
+            &[0xB0, 7, 127],
+            &[0xE0, 0, 64],
+            &[0xC0, 5],
+            &[0xD0, 40],
+            &[0xF1, 0x10],
+            &[0xF2, 0, 1],
+            &[0xF3, 2],
+            &[0xF6],
+            &[0xF8],
+            &[0xFA],
+            &[0xFF],
+            &[0xF0, 0x7F, 0x7F, 0x02, 0x01, 0x01, 0xF7],
+        ] {
+            assert_eq!(
+                raw_midi(&byte_args(bytes)).as_deref(),
+                Some(bytes),
+                "{bytes:?}"
+            );
+            assert_eq!(
+                raw_midi(&[OscType::Blob(bytes.to_vec())]).as_deref(),
+                Some(bytes)
+            );
+        }
+        for bytes in [
+            &[][..] as &[u8],
+            &[60, 100],
+            &[0x90],
+            &[0x90, 60],
+            &[0x90, 60, 100, 1],
+            &[0x90, 60, 128],
+            &[0xC0],
+            &[0xC0, 5, 6],
+            &[0xF2, 0],
+            &[0xF8, 1, 2],
+            &[0xF4],
+            &[0xF9],
+            &[0xFD],
+            &[0xF7],
+            &[0xF0, 0x7F, 0x01],
+        ] {
+            assert_eq!(raw_midi(&byte_args(bytes)), None, "{bytes:?}");
+        }
+        assert_eq!(raw_midi(&ints(&[0x90, 60, 256])), None);
+        assert_eq!(raw_midi(&[OscType::Float(144.5)]), None);
+    }
+}
@@ -0,0 +1,583 @@
+use crate::odisc::main::monitor::{self, MonitorKind};
+use crate::odisc::main::redundancy;
+use rosc::OscType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use lazy_static::lazy_static;
+
+// DMX output over Art-Net or sACN (E1.31), for a few fixtures when there's no
+// lighting operator. A mapping's dmx_values lists "channel=level" entries,
+// the channel 1-512 or a range like "2-4", the level either
+//   255   a fixed value
+//   $1    OSC argument 1: floats 0.0-1.0 scale to 0-255, ints are taken as is
+// e.g. "1=255 2-4=$1". dmx_fade fades there from the current levels, in
+// seconds. Each universe in use gets a loop resending its whole frame at
+// REFRESH_RATE, since receivers expect a steady stream, not just changes.
+// A standby instance of a redundant pair doesn't send: losing control ends
+// the stream (terminated packets for sACN) and taking over resumes it.
+
+pub const UNIVERSE_SIZE: usize = 512;
+pub const MAX_UNIVERSE: u16 = 63999;
+const ARTNET_MAX_UNIVERSE: u16 = 0x7FFF;
+const MAX_REFRESH_RATE: u32 = 44;
+const MAX_PRIORITY: u8 = 200;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DmxProtocol {
+    Artnet,
+    Sacn,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct DmxConfig {
+    #[serde(default = "default_protocol")]
+    pub protocol: DmxProtocol,
+    // Unset: Art-Net broadcasts, sACN uses each universe's multicast group
+    #[serde(default)]
+    pub host: Option<String>,
+    // Unset: 6454 for Art-Net, 5568 for sACN
+    #[serde(default)]
+    pub port: Option<u16>,
+    // Frames per second and universe
+    #[serde(default = "default_refresh_rate")]
+    pub refresh_rate: u32,
+    // sACN only
+    #[serde(default = "default_source_name")]
+    pub source_name: String,
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+}
+
+fn default_protocol() -> DmxProtocol {
+    DmxProtocol::Artnet
+}
+
+fn default_refresh_rate() -> u32 {
+    30
+}
+
+fn default_source_name() -> String {
+    "oDIsc".to_string()
+}
+
+fn default_priority() -> u8 {
+    100
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Fixed(u8),
+    // 0-based OSC argument index
+    Arg(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DmxValue {
+    pub channels: RangeInclusive<u16>,
+    pub level: Level,
+}
+
+fn parse_channel(channel: &str) -> Option<u16> {
+    channel
+        .parse()
+        .ok()
+        .filter(|c| (1..=UNIVERSE_SIZE as u16).contains(c))
+}
+
+fn parse_value(entry: &str) -> Result<DmxValue, String> {
+    let invalid =
+        || format!("Invalid DMX value '{entry}', expected e.g. '1=255', '2-4=128' or '5=$1'");
+    let (channels, level) = entry.split_once('=').ok_or_else(invalid)?;
+    let (first, last) = channels.split_once('-').unwrap_or((channels, channels));
+    let (Some(first), Some(last)) = (parse_channel(first), parse_channel(last)) else {
+        return Err(invalid());
+    };
+    if first > last {
+        return Err(invalid());
+    }
+    let level = match level.strip_prefix('$') {
+        Some(index) => match index.parse::<usize>() {
+            Ok(index) if index >= 1 => Level::Arg(index - 1),
+            _ => return Err(invalid()),
+        },
+        None => Level::Fixed(level.parse().map_err(|_| invalid())?),
+    };
+    Ok(DmxValue {
+        channels: first..=last,
+        level,
+    })
+}
+
+pub fn parse_values(text: &str) -> Result<Vec<DmxValue>, String> {
+    text.split_whitespace().map(parse_value).collect()
+}
+
+fn arg_level(arg: &OscType) -> Option<u8> {
+    match arg {
+        OscType::Float(f) => Some((f.clamp(0.0, 1.0) * 255.0).round() as u8),
+        OscType::Double(d) => Some((d.clamp(0.0, 1.0) * 255.0).round() as u8),
+        OscType::Int(i) => Some((*i).clamp(0, 255) as u8),
+        OscType::Long(l) => Some((*l).clamp(0, 255) as u8),
+        OscType::Bool(b) => Some(if *b { 255 } else { 0 }),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    from: f32,
+    to: u8,
+    start: Instant,
+    fade: Duration,
+}
+
+impl Channel {
+    fn level(&self, now: Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.fade {
+            return f32::from(self.to);
+        }
+        let progress = elapsed.as_secs_f32() / self.fade.as_secs_f32();
+        self.from + (f32::from(self.to) - self.from) * progress
+    }
+}
+
+struct Universe {
+    channels: Vec<Channel>,
+    sequence: u8,
+    wake: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl Universe {
+    fn frame(&self, now: Instant) -> [u8; UNIVERSE_SIZE] {
+        let mut frame = [0; UNIVERSE_SIZE];
+        for (slot, channel) in frame.iter_mut().zip(&self.channels) {
+            *slot = channel.level(now).round() as u8;
+        }
+        frame
+    }
+
+    // 1-255; Art-Net reads 0 as "not sequenced"
+    fn next_sequence(&mut self) -> u8 {
+        self.sequence = self.sequence % 255 + 1;
+        self.sequence
+    }
+}
+
+fn artnet_packet(universe: u16, sequence: u8, frame: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(18 + frame.len());
+    packet.extend_from_slice(b"Art-Net\0");
+    packet.extend_from_slice(&0x5000u16.to_le_bytes()); // OpDmx
+    packet.extend_from_slice(&14u16.to_be_bytes()); // protocol version
+    packet.push(sequence);
+    packet.push(0); // physical port
+    packet.extend_from_slice(&universe.to_le_bytes()); // SubUni, then Net
+    packet.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+    packet.extend_from_slice(frame);
+    packet
+}
+
+// Layer lengths count from the start of their own layer
+fn flags_and_length(length: usize) -> [u8; 2] {
+    (0x7000 | length as u16).to_be_bytes()
+}
+
+struct SacnSource<'a> {
+    cid: &'a [u8; 16],
+    name: &'a str,
+    priority: u8,
+}
+
+fn sacn_packet(
+    source: &SacnSource,
+    universe: u16,
+    sequence: u8,
+    terminated: bool,
+    frame: &[u8],
+) -> Vec<u8> {
+    let length = 126 + frame.len();
+    let mut packet = Vec::with_capacity(length);
+    // Root layer
+    packet.extend_from_slice(&0x0010u16.to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+    packet.extend_from_slice(&flags_and_length(length - 16));
+    packet.extend_from_slice(&4u32.to_be_bytes()); // VECTOR_ROOT_E131_DATA
+    packet.extend_from_slice(source.cid);
+    // Framing layer
+    packet.extend_from_slice(&flags_and_length(length - 38));
+    packet.extend_from_slice(&2u32.to_be_bytes()); // VECTOR_E131_DATA_PACKET
+    let mut name = [0u8; 64];
+    let bytes = source.name.as_bytes();
+    let len = bytes.len().min(63);
+    name[..len].copy_from_slice(&bytes[..len]);
+    packet.extend_from_slice(&name);
+    packet.push(source.priority);
+    packet.extend_from_slice(&0u16.to_be_bytes()); // no synchronization
+    packet.push(sequence);
+    packet.push(if terminated { 0x40 } else { 0 });
+    packet.extend_from_slice(&universe.to_be_bytes());
+    // DMP layer
+    packet.extend_from_slice(&flags_and_length(length - 115));
+    packet.push(0x02); // VECTOR_DMP_SET_PROPERTY
+    packet.push(0xA1);
+    packet.extend_from_slice(&0u16.to_be_bytes()); // first address
+    packet.extend_from_slice(&1u16.to_be_bytes()); // increment
+    packet.extend_from_slice(&(frame.len() as u16 + 1).to_be_bytes());
+    packet.push(0); // DMX start code
+    packet.extend_from_slice(frame);
+    packet
+}
+
+struct Output {
+    config: DmxConfig,
+    host: Option<IpAddr>,
+    port: u16,
+    period: Duration,
+    // Stable per source name, so receivers see the same source after a restart
+    cid: [u8; 16],
+    socket: UdpSocket,
+    universes: Mutex<HashMap<u16, Universe>>,
+}
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Option<Arc<Output>>> = Mutex::new(None);
+}
+
+impl Output {
+    fn destination(&self, universe: u16) -> SocketAddr {
+        let ip = self.host.unwrap_or_else(|| match self.config.protocol {
+            DmxProtocol::Artnet => IpAddr::V4(Ipv4Addr::BROADCAST),
+            DmxProtocol::Sacn => {
+                let [hi, lo] = universe.to_be_bytes();
+                IpAddr::V4(Ipv4Addr::new(239, 255, hi, lo))
+            }
+        });
+        SocketAddr::new(ip, self.port)
+    }
+
+    fn packet(&self, universe: u16, state: &mut Universe, terminated: bool) -> Vec<u8> {
+        let frame = state.frame(Instant::now());
+        let sequence = state.next_sequence();
+        match self.config.protocol {
+            DmxProtocol::Artnet => artnet_packet(universe, sequence, &frame),
+            DmxProtocol::Sacn => {
+                let source = SacnSource {
+                    cid: &self.cid,
+                    name: &self.config.source_name,
+                    priority: self.config.priority,
+                };
+                sacn_packet(&source, universe, sequence, terminated, &frame)
+            }
+        }
+    }
+
+    fn set(self: &Arc<Self>, universe: u16, levels: &[(RangeInclusive<u16>, u8)], fade: Duration) {
+        let now = Instant::now();
+        let mut universes = self.universes.lock().unwrap();
+        let state = universes.entry(universe).or_insert_with(|| {
+            debug!("DMX universe {universe} started");
+            let wake = Arc::new(Notify::new());
+            Universe {
+                channels: vec![
+                    Channel {
+                        from: 0.0,
+                        to: 0,
+                        start: now,
+                        fade: Duration::ZERO,
+                    };
+                    UNIVERSE_SIZE
+                ],
+                sequence: 0,
+                wake: wake.clone(),
+                task: tokio::spawn(refresh(Arc::downgrade(self), universe, wake)),
+            }
+        });
+        for (channels, level) in levels {
+            for channel in channels.clone() {
+                let channel = &mut state.channels[usize::from(channel - 1)];
+                *channel = Channel {
+                    from: channel.level(now),
+                    to: *level,
+                    start: now,
+                    fade,
+                };
+            }
+        }
+        // Send the change now rather than on the next tick
+        state.wake.notify_one();
+    }
+}
+
+async fn refresh(output: Weak<Output>, universe: u16, wake: Arc<Notify>) {
+    let Some(period) = output.upgrade().map(|o| o.period) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut failing = false;
+    let mut sending = true;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = wake.notified() => {}
+        }
+        let Some(output) = output.upgrade() else {
+            return;
+        };
+        let active = redundancy::is_active();
+        if !active && !sending {
+            continue;
+        }
+        let packets = {
+            let mut universes = output.universes.lock().unwrap();
+            let Some(state) = universes.get_mut(&universe) else {
+                return;
+            };
+            match (active, output.config.protocol) {
+                (true, _) => vec![output.packet(universe, state, false)],
+                // E1.31 asks for three terminated packets
+                (false, DmxProtocol::Sacn) => (0..3)
+                    .map(|_| output.packet(universe, state, true))
+                    .collect(),
+                (false, DmxProtocol::Artnet) => Vec::new(),
+            }
+        };
+        if active != sending {
+            let change = if active {
+                "resumed"
+            } else {
+                "stopped on standby"
+            };
+            info!("DMX universe {universe} {change}");
+            sending = active;
+        }
+        for packet in packets {
+            match output
+                .socket
+                .send_to(&packet, output.destination(universe))
+                .await
+            {
+                Ok(_) => failing = false,
+                // Logged once until sending works again
+                Err(e) if !failing => {
+                    warn!("DMX universe {universe}: send failed: {e}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+// Sets a mapping's levels on its universe, starting the universe if needed
+pub fn apply(
+    universe: u16,
+    values: &str,
+    fade: Option<f32>,
+    args: &[OscType],
+) -> Result<(), Box<dyn Error>> {
+    let Some(output) = OUTPUT.lock().unwrap().clone() else {
+        return Err("DMX output is not configured".into());
+    };
+    match output.config.protocol {
+        DmxProtocol::Artnet if universe > ARTNET_MAX_UNIVERSE => {
+            return Err(
+                format!("Art-Net universes are 0-{ARTNET_MAX_UNIVERSE}, got {universe}").into(),
+            )
+        }
+        DmxProtocol::Sacn if !(1..=MAX_UNIVERSE).contains(&universe) => {
+            return Err(format!("sACN universes are 1-{MAX_UNIVERSE}, got {universe}").into())
+        }
+        _ => {}
+    }
+
+    let mut levels = Vec::new();
+    for value in parse_values(values)? {
+        let level = match value.level {
+            Level::Fixed(level) => level,
+            Level::Arg(index) => match args.get(index).and_then(arg_level) {
+                Some(level) => level,
+                None => {
+                    warn!(
+                        "DMX: no numeric OSC argument ${} for channels {value:?}",
+                        index + 1
+                    );
+                    continue;
+                }
+            },
+        };
+        levels.push((value.channels, level));
+    }
+    let fade = Duration::try_from_secs_f32(fade.unwrap_or_default()).unwrap_or_default();
+    output.set(universe, &levels, fade);
+    monitor::record(MonitorKind::DmxOut {
+        universe,
+        levels,
+        fade_ms: fade.as_millis() as u64,
+    });
+    Ok(())
+}
+
+// Stops every universe when dropped; sACN receivers are told the stream ended
+pub struct Dmx;
+
+impl Drop for Dmx {
+    fn drop(&mut self) {
+        let Some(output) = OUTPUT.lock().unwrap().take() else {
+            return;
+        };
+        let mut universes = output.universes.lock().unwrap();
+        for (universe, state) in universes.iter_mut() {
+            state.task.abort();
+            if output.config.protocol == DmxProtocol::Sacn {
+                // E1.31 asks for three terminated packets
+                for _ in 0..3 {
+                    let packet = output.packet(*universe, state, true);
+                    let _ = output
+                        .socket
+                        .try_send_to(&packet, output.destination(*universe));
+                }
+            }
+        }
+    }
+}
+
+pub async fn start(config: &DmxConfig) -> Result<Dmx, Box<dyn Error>> {
+    if !(1..=MAX_REFRESH_RATE).contains(&config.refresh_rate) {
+        return Err(format!("DMX: REFRESH_RATE must be 1-{MAX_REFRESH_RATE}").into());
+    }
+    if config.priority > MAX_PRIORITY {
+        return Err(format!("DMX: PRIORITY must be 0-{MAX_PRIORITY}").into());
+    }
+    let host = match config.host.as_deref().filter(|h| !h.is_empty()) {
+        Some(host) => Some(
+            host.parse::<IpAddr>()
+                .map_err(|_| format!("DMX: invalid host '{host}'"))?,
+        ),
+        None => None,
+    };
+    let port = config.port.unwrap_or(match config.protocol {
+        DmxProtocol::Artnet => 6454,
+        DmxProtocol::Sacn => 5568,
+    });
+    let bind = match host {
+        Some(IpAddr::V6(_)) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        _ => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.set_broadcast(true)?;
+
+    let digest = Sha256::digest(format!("odisc-dmx:{}", config.source_name));
+    let mut cid = [0; 16];
+    cid.copy_from_slice(&digest[..16]);
+
+    *OUTPUT.lock().unwrap() = Some(Arc::new(Output {
+        config: config.clone(),
+        host,
+        port,
+        period: Duration::from_secs(1) / config.refresh_rate,
+        cid,
+        socket,
+        universes: Mutex::new(HashMap::new()),
+    }));
+    info!(
+        "DMX output over {:?} to {}:{port}",
+        config.protocol,
+        host.map_or("default".to_string(), |h| h.to_string())
+    );
+    Ok(Dmx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(
+            parse_values("1=255  2-4=$1 512=0").unwrap(),
+            vec![
+                DmxValue {
+                    channels: 1..=1,
+                    level: Level::Fixed(255)
+                },
+                DmxValue {
+                    channels: 2..=4,
+                    level: Level::Arg(0)
+                },
+                DmxValue {
+                    channels: 512..=512,
+                    level: Level::Fixed(0)
+                },
+            ]
+        );
+        assert!(parse_values("").unwrap().is_empty());
+        for bad in [
+            "0=1", "513=1", "4-2=1", "1=256", "1=-1", "1=$0", "1=$x", "1", "a=1", "1-=5",
+        ] {
+            assert!(parse_values(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn artnet_packet_layout() {
+        let frame = [7u8; UNIVERSE_SIZE];
+        let packet = artnet_packet(0x0123, 9, &frame);
+        assert_eq!(packet.len(), 18 + UNIVERSE_SIZE);
+        assert_eq!(&packet[..8], b"Art-Net\0");
+        assert_eq!(&packet[8..10], &[0x00, 0x50]);
+        assert_eq!(&packet[10..12], &[0, 14]);
+        assert_eq!(packet[12], 9);
+        assert_eq!(&packet[14..16], &[0x23, 0x01]);
+        assert_eq!(&packet[16..18], &[0x02, 0x00]);
+        assert!(packet[18..].iter().all(|b| *b == 7));
+    }
+
+    #[test]
+    fn sacn_packet_layout() {
+        let cid = [0xAB; 16];
+        let source = SacnSource {
+            cid: &cid,
+            name: "oDIsc",
+            priority: 100,
+        };
+        let frame = [3u8; UNIVERSE_SIZE];
+        let packet = sacn_packet(&source, 0x0102, 42, false, &frame);
+        assert_eq!(packet.len(), 638);
+        let u16_at = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        // Flags 0x7 and each layer's length to the end of the packet
+        assert_eq!(u16_at(16), 0x7000 | 622);
+        assert_eq!(u16_at(38), 0x7000 | 600);
+        assert_eq!(u16_at(115), 0x7000 | 523);
+        assert_eq!(&packet[4..16], b"ASC-E1.17\0\0\0");
+        assert_eq!(&packet[22..38], &cid);
+        assert_eq!(&packet[44..49], b"oDIsc");
+        assert_eq!(packet[108], 100);
+        assert_eq!(packet[111], 42);
+        assert_eq!(packet[112], 0);
+        assert_eq!(u16_at(113), 0x0102);
+        // Property count includes the start code
+        assert_eq!(u16_at(123), 513);
+        assert_eq!(packet[125], 0);
+        assert!(packet[126..].iter().all(|b| *b == 3));
+
+        let terminated = sacn_packet(&source, 1, 0, true, &frame);
+        assert_eq!(terminated[112], 0x40);
+    }
+}
@@ -0,0 +1,600 @@
+use crate::odisc::main::args;
+use crate::odisc::main::behavior;
+use crate::odisc::main::dmx;
+use crate::odisc::main::formats::{self, Format};
+use crate::odisc::main::handlers;
+use crate::odisc::main::helpers::{self, Mapping};
+use crate::odisc::main::msc;
+use crate::odisc::main::scale;
+use crate::odisc::main::script;
+use crate::odisc::main::transport::Transport;
+use crate::odisc::main::vars;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+// Structured edits to the active mappings file. Each edit re-reads the file,
+// applies the change, validates the new row and writes the result back atomically.
+
+lazy_static! {
+    // One edit at a time, so two quick UI actions can't interleave read/modify/write
+    static ref EDIT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+const MIDI_TYPES: [&str; 6] = [
+    "note_on",
+    "note_off",
+    "cc",
+    "pc",
+    "qc_preset",
+    "gt1000_preset",
+];
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub enum Edit {
+    Create {
+        mapping: Mapping,
+        index: Option<usize>,
+    },
+    Update {
+        index: usize,
+        mapping: Mapping,
+    },
+    Delete {
+        index: usize,
+    },
+    Reorder {
+        from: usize,
+        to: usize,
+    },
+    Duplicate {
+        index: usize,
+    },
+}
+
+fn check_range(name: &str, value: Option<u32>, min: u32, max: u32) -> Result<(), String> {
+    match value {
+        Some(v) if !(min..=max).contains(&v) => {
+            Err(format!("{name} must be between {min} and {max}, got {v}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn require<T>(name: &str, value: &Option<T>, midi_type: &str) -> Result<(), String> {
+    if value.is_none() {
+        return Err(format!("{name} is required for midi_type '{midi_type}'"));
+    }
+    Ok(())
+}
+
+pub fn validate_mapping(m: &Mapping) -> Result<(), String> {
+    if !m.osc_in_address.starts_with('/') {
+        return Err(format!(
+            "osc_in_address must start with '/', got '{}'",
+            m.osc_in_address
+        ));
+    }
+    if let Some(addr) = m.osc_out_address.as_deref().filter(|a| !a.is_empty()) {
+        if !addr.starts_with('/') {
+            return Err(format!("osc_out_address must start with '/', got '{addr}'"));
+        }
+    }
+    if let Some(list) = m.osc_out_args.as_deref().filter(|a| !a.is_empty()) {
+        args::validate(list).map_err(|e| format!("osc_out_args: {e}"))?;
+    }
+    if let Some(name) = m.osc_out_transport.as_deref().filter(|t| !t.is_empty()) {
+        Transport::from_name(name)?;
+    }
+
+    if let Some(filter) = m.osc_in_source.as_deref().filter(|s| !s.is_empty()) {
+        if !handlers::is_valid_source_filter(filter) {
+            return Err(format!(
+                "osc_in_source must be an IP, IP:port or *:port, got '{filter}'"
+            ));
+        }
+    }
+
+    if let Some(values) = m.dmx_values.as_deref().filter(|v| !v.is_empty()) {
+        dmx::parse_values(values)?;
+        if m.dmx_universe.is_none() {
+            return Err("dmx_universe is required with dmx_values".to_string());
+        }
+    }
+    check_range(
+        "dmx_universe",
+        m.dmx_universe.map(u32::from),
+        0,
+        u32::from(dmx::MAX_UNIVERSE),
+    )?;
+    if let Some(fade) = m.dmx_fade {
+        if !fade.is_finite() || fade < 0.0 {
+            return Err(format!("dmx_fade must be 0 or more seconds, got {fade}"));
+        }
+    }
+
+    if m.msc_command.as_deref().is_some_and(|c| !c.is_empty()) {
+        msc::validate(m)?;
+    } else if [
+        &m.msc_format,
+        &m.msc_device,
+        &m.msc_cue,
+        &m.msc_list,
+        &m.msc_path,
+        &m.msc_time,
+    ]
+    .iter()
+    .any(|f| f.as_deref().is_some_and(|v| !v.is_empty()))
+    {
+        return Err("msc_command is required with the other msc_* columns".to_string());
+    }
+
+    if let Some(name) = m.script.as_deref().filter(|s| !s.is_empty()) {
+        if !script::is_valid_name(name) {
+            return Err(format!(
+                "script must be a file name in {}/, got '{name}'",
+                script::SCRIPTS_DIR
+            ));
+        }
+    }
+
+    if let Some(condition) = m.condition.as_deref().filter(|c| !c.is_empty()) {
+        vars::compile_condition(condition)?;
+    }
+    if let Some(sets) = m.set_vars.as_deref().filter(|s| !s.is_empty()) {
+        vars::validate_sets(sets)?;
+    }
+    behavior::validate(m)?;
+    scale::validate(m)?;
+    for (i, (_, step)) in formats::step_mappings(m)?.iter().enumerate() {
+        validate_mapping(step).map_err(|e| format!("steps[{i}]: {e}"))?;
+    }
+
+    check_range("midi_channel", m.midi_channel, 1, 16)?;
+    check_range("midi_note", m.midi_note, 0, 127)?;
+    check_range("midi_velocity", m.midi_velocity, 0, 127)?;
+    check_range("midi_controller", m.midi_controller, 0, 127)?;
+    check_range("midi_value", m.midi_value, 0, 127)?;
+    check_range("setlist", m.setlist, 0, 127)?;
+
+    let Some(midi_type) = m.midi_type.as_deref().filter(|t| !t.is_empty()) else {
+        return Ok(());
+    };
+    if !MIDI_TYPES.contains(&midi_type) {
+        return Err(format!(
+            "Unknown midi_type '{midi_type}', expected one of {}",
+            MIDI_TYPES.join(", ")
+        ));
+    }
+    require("midi_channel", &m.midi_channel, midi_type)?;
+    match midi_type {
+        "note_on" | "note_off" => {
+            require("midi_note", &m.midi_note, midi_type)?;
+            require("midi_velocity", &m.midi_velocity, midi_type)?;
+        }
+        "cc" => require("midi_controller", &m.midi_controller, midi_type)?,
+        "qc_preset" => {
+            require("setlist", &m.setlist, midi_type)?;
+            match m.qc_preset_id.as_deref() {
+                Some(id) if handlers::is_valid_qc_preset(id) => {}
+                other => {
+                    return Err(format!(
+                        "Invalid Quad Cortex preset '{}', expected e.g. '1A' or '12D'",
+                        other.unwrap_or_default()
+                    ))
+                }
+            }
+        }
+        "gt1000_preset" => match m.gt1000_preset_id.as_deref() {
+            Some(id) if handlers::is_valid_gt1000_preset(id) => {}
+            other => {
+                return Err(format!(
+                    "Invalid GT-1000 preset '{}', expected e.g. 'U01-1' or 'P50-5'",
+                    other.unwrap_or_default()
+                ))
+            }
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+// The first invalid row, numbered from 0
+pub fn validate_all(mappings: &[Mapping]) -> Result<(), String> {
+    for (row, mapping) in mappings.iter().enumerate() {
+        validate_mapping(mapping).map_err(|e| format!("Row {row}: {e}"))?;
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+fn check_index(index: usize, len: usize) -> Result<(), String> {
+    if index >= len {
+        return Err(format!("Mapping index {index} out of range (0..{len})"));
+    }
+    Ok(())
+}
+
+// Cells of columns odisc doesn't know (notes etc.), one map per row
+type ExtraCells = BTreeMap<String, String>;
+
+// `extras` is kept parallel to `mappings` so extra cells move with their row
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+fn apply_edit(
+    mappings: &mut Vec<Mapping>,
+    extras: &mut Vec<ExtraCells>,
+    edit: Edit,
+) -> Result<(), String> {
+    extras.resize_with(mappings.len(), ExtraCells::new);
+    match edit {
+        Edit::Create { mapping, index } => {
+            validate_mapping(&mapping)?;
+            let index = index.unwrap_or(mappings.len());
+            if index > mappings.len() {
+                return Err(format!("Mapping index {index} out of range"));
+            }
+            mappings.insert(index, mapping);
+            extras.insert(index, ExtraCells::new());
+        }
+        Edit::Update { index, mapping } => {
+            check_index(index, mappings.len())?;
+            validate_mapping(&mapping).map_err(|e| format!("Row {index}: {e}"))?;
+            mappings[index] = mapping;
+        }
+        Edit::Delete { index } => {
+            check_index(index, mappings.len())?;
+            mappings.remove(index);
+            extras.remove(index);
+        }
+        Edit::Reorder { from, to } => {
+            check_index(from, mappings.len())?;
+            check_index(to, mappings.len())?;
+            let mapping = mappings.remove(from);
+            mappings.insert(to, mapping);
+            let cells = extras.remove(from);
+            extras.insert(to, cells);
+        }
+        Edit::Duplicate { index } => {
+            check_index(index, mappings.len())?;
+            let copy = mappings[index].clone();
+            mappings.insert(index + 1, copy);
+            let cells = extras[index].clone();
+            extras.insert(index + 1, cells);
+        }
+    }
+    Ok(())
+}
+
+// Mapping as column name -> cell text, via serde so new fields are picked up automatically
+fn mapping_cells(
+    mapping: &Mapping,
+) -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn Error>> {
+    match serde_json::to_value(mapping)? {
+        serde_json::Value::Object(map) => Ok(map),
+        _ => Err("Mapping did not serialize to an object".into()),
+    }
+}
+
+fn cell_text(value: Option<&serde_json::Value>) -> String {
+    match value {
+        None | Some(serde_json::Value::Null) => String::new(),
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+// The existing header, or nothing if the file is missing or unreadable
+fn existing_columns(path: &Path) -> Vec<String> {
+    match csv::Reader::from_path(path) {
+        Ok(mut rdr) => match rdr.headers() {
+            Ok(headers) => headers.iter().map(str::to_string).collect(),
+            Err(_) => Vec::new(),
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+// Non-empty cells of columns that aren't Mapping fields, per row of the existing file
+fn read_extra_cells(path: &Path) -> Result<Vec<ExtraCells>, Box<dyn Error>> {
+    if Format::from_path(path)? != Format::Csv || !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut rdr = csv::Reader::from_path(path)?;
+    let headers = rdr.headers()?.clone();
+    let mut rows = Vec::new();
+    for record in rdr.records() {
+        let record = record?;
+        let cells = headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(c, v)| !v.is_empty() && !helpers::MAPPING_COLUMNS.contains(c))
+            .map(|(c, v)| (c.to_string(), v.to_string()))
+            .collect();
+        rows.push(cells);
+    }
+    Ok(rows)
+}
+
+// Keeps the column order of the existing file; columns it lacks are appended.
+// Columns that aren't Mapping fields are written from `extras`.
+fn serialize_csv(
+    path: &Path,
+    mappings: &[Mapping],
+    extras: &[ExtraCells],
+) -> Result<String, Box<dyn Error>> {
+    let mut columns = existing_columns(path);
+    for column in helpers::MAPPING_COLUMNS {
+        if !columns.iter().any(|c| c == column) {
+            columns.push(column.to_string());
+        }
+    }
+
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    wtr.write_record(&columns)?;
+    for (row, mapping) in mappings.iter().enumerate() {
+        let cells = mapping_cells(mapping)?;
+        let extra = extras.get(row);
+        wtr.write_record(columns.iter().map(|c| match cells.get(c) {
+            Some(value) => cell_text(Some(value)),
+            None => extra.and_then(|e| e.get(c)).cloned().unwrap_or_default(),
+        }))?;
+    }
+    Ok(String::from_utf8(wtr.into_inner()?)?)
+}
+
+// Temp file + rename, keeping the previous version as <file>.bak
+pub fn write_atomically(path: &Path, contents: &str) -> Result<(), Box<dyn Error>> {
+    let file_name = path
+        .file_name()
+        .ok_or("Path has no file name")?
+        .to_string_lossy()
+        .to_string();
+    let tmp_path = path.with_file_name(format!("{file_name}.tmp"));
+    let backup_path = path.with_file_name(format!("{file_name}.bak"));
+
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+    if path.exists() {
+        fs::copy(path, &backup_path)?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn save_mappings(
+    path: &Path,
+    mappings: &[Mapping],
+    extras: &[ExtraCells],
+) -> Result<(), Box<dyn Error>> {
+    let contents = match Format::from_path(path)? {
+        Format::Csv => serialize_csv(path, mappings, extras)?,
+        format => formats::serialize_mappings(mappings, format)?,
+    };
+    write_atomically(path, &contents)
+}
+
+// Replaces the whole file, but only if every row is valid. Extra CSV cells stay
+// with their row position, since the new list carries no row identity.
+pub fn replace_mappings(path: &Path, mappings: &[Mapping]) -> Result<(), Box<dyn Error>> {
+    validate_all(mappings)?;
+    let _guard = EDIT_LOCK.lock().unwrap();
+    let extras = read_extra_cells(path)?;
+    save_mappings(path, mappings, &extras)
+}
+
+// Applies one edit to the file at `path` and returns the full new list
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn edit_mappings(path: PathBuf, edit: Edit) -> Result<Vec<Mapping>, Box<dyn Error>> {
+    let _guard = EDIT_LOCK.lock().unwrap();
+    let mut mappings = formats::load_mappings(path.clone())?;
+    let mut extras = read_extra_cells(&path)?;
+    apply_edit(&mut mappings, &mut extras, edit)?;
+    save_mappings(&path, &mappings, &extras)?;
+    Ok(mappings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(address: &str) -> Mapping {
+        Mapping {
+            osc_in_address: address.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn addresses(mappings: &[Mapping]) -> Vec<&str> {
+        mappings.iter().map(|m| m.osc_in_address.as_str()).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("odisc-editor-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn validates_mappings() {
+        assert!(validate_mapping(&mapping("/go")).is_ok());
+        assert!(validate_mapping(&mapping("go")).is_err());
+
+        let mut m = mapping("/go");
+        m.midi_type = Some("cc".into());
+        assert!(validate_mapping(&m).unwrap_err().contains("midi_channel"));
+        m.midi_channel = Some(17);
+        assert!(validate_mapping(&m)
+            .unwrap_err()
+            .contains("between 1 and 16"));
+        m.midi_channel = Some(1);
+        assert!(validate_mapping(&m)
+            .unwrap_err()
+            .contains("midi_controller"));
+        m.midi_controller = Some(7);
+        assert!(validate_mapping(&m).is_ok());
+
+        m.midi_type = Some("sysex".into());
+        assert!(validate_mapping(&m)
+            .unwrap_err()
+            .contains("Unknown midi_type"));
+
+        m.midi_type = Some("qc_preset".into());
+        m.setlist = Some(1);
+        m.qc_preset_id = Some("33A".into());
+        assert!(validate_mapping(&m).unwrap_err().contains("Quad Cortex"));
+        m.qc_preset_id = Some("12D".into());
+        assert!(validate_mapping(&m).is_ok());
+
+        let mut m = mapping("/go");
+        m.osc_out_address = Some("out".into());
+        assert!(validate_mapping(&m).is_err());
+        let mut m = mapping("/go");
+        m.dmx_values = Some("1=255".into());
+        assert!(validate_mapping(&m).unwrap_err().contains("dmx_universe"));
+        let mut m = mapping("/go");
+        m.msc_cue = Some("1".into());
+        assert!(validate_mapping(&m).unwrap_err().contains("msc_command"));
+    }
+
+    #[test]
+    fn applies_edits() {
+        let mut mappings = vec![mapping("/a"), mapping("/b"), mapping("/c")];
+        let mut extras = Vec::new();
+
+        apply_edit(&mut mappings, &mut extras, Edit::Reorder { from: 0, to: 2 }).unwrap();
+        assert_eq!(addresses(&mappings), ["/b", "/c", "/a"]);
+        apply_edit(&mut mappings, &mut extras, Edit::Reorder { from: 2, to: 0 }).unwrap();
+        assert_eq!(addresses(&mappings), ["/a", "/b", "/c"]);
+
+        apply_edit(&mut mappings, &mut extras, Edit::Duplicate { index: 2 }).unwrap();
+        assert_eq!(addresses(&mappings), ["/a", "/b", "/c", "/c"]);
+        apply_edit(&mut mappings, &mut extras, Edit::Delete { index: 3 }).unwrap();
+
+        let create = |index| Edit::Create {
+            mapping: mapping("/new"),
+            index,
+        };
+        apply_edit(&mut mappings, &mut extras, create(Some(3))).unwrap();
+        assert_eq!(addresses(&mappings), ["/a", "/b", "/c", "/new"]);
+        apply_edit(&mut mappings, &mut extras, create(None)).unwrap();
+        assert_eq!(mappings.len(), 5);
+        assert_eq!(extras.len(), 5);
+
+        // Out of range indexes and invalid rows leave the list alone
+        let before = addresses(&mappings).join(" ");
+        for edit in [
+            create(Some(6)),
+            Edit::Reorder { from: 0, to: 5 },
+            Edit::Reorder { from: 5, to: 0 },
+            Edit::Duplicate { index: 5 },
+            Edit::Delete { index: 5 },
+            Edit::Update {
+                index: 5,
+                mapping: mapping("/x"),
+            },
+            Edit::Update {
+                index: 0,
+                mapping: mapping("x"),
+            },
+        ] {
+            assert!(apply_edit(&mut mappings, &mut extras, edit).is_err());
+        }
+        assert_eq!(addresses(&mappings).join(" "), before);
+
+        let mut empty = Vec::new();
+        assert!(apply_edit(&mut empty, &mut extras, Edit::Duplicate { index: 0 }).is_err());
+    }
+
+    #[test]
+    fn extra_cells_move_with_their_row() {
+        let mut mappings = vec![mapping("/a"), mapping("/b")];
+        let mut extras = vec![
+            ExtraCells::from([("notes".to_string(), "first".to_string())]),
+            ExtraCells::from([("notes".to_string(), "second".to_string())]),
+        ];
+        apply_edit(&mut mappings, &mut extras, Edit::Reorder { from: 0, to: 1 }).unwrap();
+        apply_edit(&mut mappings, &mut extras, Edit::Duplicate { index: 0 }).unwrap();
+        apply_edit(
+            &mut mappings,
+            &mut extras,
+            Edit::Create {
+                mapping: mapping("/c"),
+                index: Some(0),
+            },
+        )
+        .unwrap();
+        let notes: Vec<_> = extras.iter().map(|e| e.get("notes").cloned()).collect();
+        assert_eq!(
+            notes,
+            [
+                None,
+                Some("second".into()),
+                Some("second".into()),
+                Some("first".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_csv_columns_and_extra_cells() {
+        let dir = temp_dir("csv");
+        let path = dir.join("mappings.csv");
+        fs::write(
+            &path,
+            "notes,osc_in_address,midi_channel\nkick,/a,1\n,/b,\nsnare,/c,2\n",
+        )
+        .unwrap();
+
+        let mappings = edit_mappings(path.clone(), Edit::Reorder { from: 2, to: 0 }).unwrap();
+        assert_eq!(addresses(&mappings), ["/c", "/a", "/b"]);
+
+        let mut rdr = csv::Reader::from_path(&path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        assert_eq!(&headers[0], "notes");
+        assert_eq!(&headers[1], "osc_in_address");
+        assert_eq!(&headers[2], "midi_channel");
+        assert_eq!(headers.len(), 1 + helpers::MAPPING_COLUMNS.len());
+        let rows: Vec<Vec<String>> = rdr
+            .records()
+            .map(|r| r.unwrap().iter().take(3).map(str::to_string).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            [["snare", "/c", "2"], ["kick", "/a", "1"], ["", "/b", ""]]
+        );
+
+        // A whole-list replace keeps extra cells by position
+        replace_mappings(&path, &[mapping("/x")]).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.lines().nth(1).unwrap().starts_with("snare,/x,"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writes_atomically_with_backup() {
+        let dir = temp_dir("atomic");
+        let path = dir.join("mappings.csv");
+        write_atomically(&path, "one").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one");
+        assert!(!dir.join("mappings.csv.bak").exists());
+
+        write_atomically(&path, "two").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two");
+        assert_eq!(
+            fs::read_to_string(dir.join("mappings.csv.bak")).unwrap(),
+            "one"
+        );
+        assert!(!dir.join("mappings.csv.tmp").exists());
+
+        assert!(write_atomically(Path::new("/"), "x").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
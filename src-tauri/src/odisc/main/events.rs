@@ -0,0 +1,46 @@
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+
+// Outgoing UI events (`backend-log`, `monitor-events`, `show-loaded`, ...).
+// The engine doesn't know who is listening: the desktop app registers a sink
+// that forwards to the webview, the headless binary registers none.
+
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: &str, payload: serde_json::Value);
+}
+
+lazy_static! {
+    static ref SINKS: RwLock<Vec<Arc<dyn EventSink>>> = RwLock::new(Vec::new());
+}
+
+pub fn add_sink(sink: Arc<dyn EventSink>) {
+    SINKS.write().unwrap().push(sink);
+}
+
+pub fn remove_sink(sink: &Arc<dyn EventSink>) {
+    SINKS.write().unwrap().retain(|s| !Arc::ptr_eq(s, sink));
+}
+
+pub fn has_sinks() -> bool {
+    !SINKS.read().unwrap().is_empty()
+}
+
+// Serializes only when someone is listening
+pub fn emit<T: Serialize>(event: &str, payload: T) {
+    let sinks = SINKS.read().unwrap();
+    if sinks.is_empty() {
+        return;
+    }
+    let payload = match serde_json::to_value(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("Could not serialize '{event}' event: {e}");
+            return;
+        }
+    };
+    for sink in sinks.iter() {
+        sink.emit(event, payload.clone());
+    }
+}
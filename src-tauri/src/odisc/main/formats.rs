@@ -0,0 +1,670 @@
+use crate::odisc::main::args;
+use crate::odisc::main::helpers::{self, Mapping};
+use crate::odisc::main::scale;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Nested mapping schema used by the TOML, YAML and JSON formats. Every CSV
+// column has exactly one home here, so converting either way loses nothing.
+// Typed OSC arguments, the scale ranges and the steps list are plain text in
+// CSV: args as in args.rs, ranges as "min max" and steps as a JSON list in
+// one cell, e.g. [{"delay_ms":500,"osc":{"address":"/go","args":[1]}}].
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct MappingFile {
+    #[serde(default)]
+    pub mappings: Vec<MappingEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct MappingEntry {
+    pub input: InputSpec,
+    #[serde(flatten)]
+    pub outputs: Outputs,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub set_vars: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub behavior: Option<BehaviorSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<ScaleSpec>,
+    // Further outputs after the first, each delayed from the match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct InputSpec {
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+}
+
+// What a mapping or one of its steps sends
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Outputs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub osc: Option<OscOutputSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub midi: Option<MidiSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<PresetSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dmx: Option<DmxSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msc: Option<MscSpec>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OscOutputSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<OscArg>,
+    // udp, tcp_slip or tcp_length; empty = OSC_SEND_TRANSPORT
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<String>,
+}
+
+// Ints, floats and bools keep their type; "$value" stays a placeholder
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum OscArg {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct MidiSpec {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub controller: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PresetSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gt1000: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub setlist: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DmxSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub universe: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fade: Option<f32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct MscSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cue: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BehaviorSpec {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub var: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ScaleSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<[f64; 2]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<[f64; 2]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub curve: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct StepSpec {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub delay_ms: u64,
+    #[serde(flatten)]
+    pub outputs: Outputs,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+// CSV leaves empty cells as either None or Some(""); treat both as unset
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value.as_ref().filter(|v| !v.is_empty()).cloned()
+}
+
+fn words(value: &Option<String>) -> Vec<String> {
+    value
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+fn joined(words: &[String]) -> Option<String> {
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+impl OscArg {
+    // Same reading as args::parse, but keeping full precision
+    fn from_token(token: &str) -> OscArg {
+        let tagged = token
+            .split_at_checked(2)
+            .and_then(|(tag, value)| match tag {
+                "i:" => value.parse().ok().map(OscArg::Int),
+                "f:" => value.parse().ok().map(OscArg::Float),
+                "b:" => value.parse().ok().map(OscArg::Bool),
+                "s:" => Some(OscArg::String(value.to_string())),
+                _ => None,
+            });
+        tagged.unwrap_or_else(|| match token.parse::<f64>() {
+            Ok(n) => OscArg::Float(n),
+            Err(_) => OscArg::String(token.to_string()),
+        })
+    }
+
+    fn to_token(&self) -> String {
+        match self {
+            OscArg::Bool(b) => format!("b:{b}"),
+            OscArg::Int(i) => format!("i:{i}"),
+            OscArg::Float(f) => f.to_string(),
+            OscArg::String(s) => {
+                let ambiguous =
+                    s.parse::<f64>().is_ok() || args::TAGS.iter().any(|tag| s.starts_with(tag));
+                if ambiguous {
+                    format!("s:{s}")
+                } else {
+                    s.clone()
+                }
+            }
+        }
+    }
+}
+
+impl From<&Mapping> for Outputs {
+    fn from(m: &Mapping) -> Self {
+        let out_address = non_empty(&m.osc_out_address);
+        let out_args: Vec<OscArg> = args::split(m.osc_out_args.as_deref().unwrap_or_default())
+            .iter()
+            .map(|t| OscArg::from_token(t))
+            .collect();
+        let out_transport = non_empty(&m.osc_out_transport);
+        let osc = (out_address.is_some() || !out_args.is_empty() || out_transport.is_some())
+            .then_some(OscOutputSpec {
+                address: out_address,
+                args: out_args,
+                transport: out_transport,
+            });
+
+        let midi = MidiSpec {
+            kind: non_empty(&m.midi_type),
+            channel: m.midi_channel,
+            note: m.midi_note,
+            velocity: m.midi_velocity,
+            controller: m.midi_controller,
+            value: m.midi_value,
+        };
+        let has_midi = midi.kind.is_some()
+            || midi.channel.is_some()
+            || midi.note.is_some()
+            || midi.velocity.is_some()
+            || midi.controller.is_some()
+            || midi.value.is_some();
+
+        let preset = PresetSpec {
+            qc: non_empty(&m.qc_preset_id),
+            gt1000: non_empty(&m.gt1000_preset_id),
+            setlist: m.setlist,
+        };
+        let has_preset = preset.qc.is_some() || preset.gt1000.is_some() || preset.setlist.is_some();
+
+        let dmx = DmxSpec {
+            universe: m.dmx_universe,
+            values: words(&m.dmx_values),
+            fade: m.dmx_fade,
+        };
+        let has_dmx = dmx.universe.is_some() || !dmx.values.is_empty() || dmx.fade.is_some();
+
+        let msc = MscSpec {
+            command: non_empty(&m.msc_command),
+            format: non_empty(&m.msc_format),
+            device: non_empty(&m.msc_device),
+            cue: non_empty(&m.msc_cue),
+            list: non_empty(&m.msc_list),
+            path: non_empty(&m.msc_path),
+            time: non_empty(&m.msc_time),
+        };
+        let has_msc = msc.command.is_some()
+            || msc.format.is_some()
+            || msc.device.is_some()
+            || msc.cue.is_some()
+            || msc.list.is_some()
+            || msc.path.is_some()
+            || msc.time.is_some();
+
+        Outputs {
+            osc,
+            midi: has_midi.then_some(midi),
+            preset: has_preset.then_some(preset),
+            dmx: has_dmx.then_some(dmx),
+            msc: has_msc.then_some(msc),
+        }
+    }
+}
+
+impl Outputs {
+    // Sets the output columns of `m`
+    fn fill(&self, m: &mut Mapping) {
+        let osc = self.osc.clone().unwrap_or_default();
+        let midi = self.midi.clone().unwrap_or_default();
+        let preset = self.preset.clone().unwrap_or_default();
+        let dmx = self.dmx.clone().unwrap_or_default();
+        let msc = self.msc.clone().unwrap_or_default();
+        let out_args: Vec<String> = osc.args.iter().map(OscArg::to_token).collect();
+        m.osc_out_address = non_empty(&osc.address);
+        m.osc_out_args = (!out_args.is_empty()).then(|| args::join(&out_args));
+        m.osc_out_transport = non_empty(&osc.transport);
+        m.midi_channel = midi.channel;
+        m.midi_type = non_empty(&midi.kind);
+        m.midi_note = midi.note;
+        m.midi_velocity = midi.velocity;
+        m.midi_controller = midi.controller;
+        m.midi_value = midi.value;
+        m.qc_preset_id = non_empty(&preset.qc);
+        m.gt1000_preset_id = non_empty(&preset.gt1000);
+        m.setlist = preset.setlist;
+        m.dmx_universe = dmx.universe;
+        m.dmx_values = joined(&dmx.values);
+        m.dmx_fade = dmx.fade;
+        m.msc_command = non_empty(&msc.command);
+        m.msc_format = non_empty(&msc.format);
+        m.msc_device = non_empty(&msc.device);
+        m.msc_cue = non_empty(&msc.cue);
+        m.msc_list = non_empty(&msc.list);
+        m.msc_path = non_empty(&msc.path);
+        m.msc_time = non_empty(&msc.time);
+    }
+}
+
+pub fn parse_steps(text: &str) -> Result<Vec<StepSpec>, String> {
+    serde_json::from_str(text).map_err(|e| format!("steps must be a JSON list of outputs: {e}"))
+}
+
+// A mapping's steps as (delay, mapping with only that step's outputs)
+pub fn step_mappings(m: &Mapping) -> Result<Vec<(Duration, Mapping)>, String> {
+    let Some(text) = non_empty(&m.steps) else {
+        return Ok(Vec::new());
+    };
+    Ok(parse_steps(&text)?
+        .iter()
+        .map(|step| {
+            let mut mapping = Mapping {
+                osc_in_address: m.osc_in_address.clone(),
+                ..Default::default()
+            };
+            step.outputs.fill(&mut mapping);
+            (Duration::from_millis(step.delay_ms), mapping)
+        })
+        .collect())
+}
+
+// Fails on cells the nested schema can't hold: bad ranges or steps
+impl TryFrom<&Mapping> for MappingEntry {
+    type Error = String;
+
+    fn try_from(m: &Mapping) -> Result<Self, String> {
+        let behavior = BehaviorSpec {
+            kind: non_empty(&m.behavior),
+            values: words(&m.behavior_values),
+            mode: non_empty(&m.behavior_mode),
+            var: non_empty(&m.behavior_var),
+        };
+        let has_behavior = behavior.kind.is_some()
+            || !behavior.values.is_empty()
+            || behavior.mode.is_some()
+            || behavior.var.is_some();
+
+        let range = |text: &Option<String>| {
+            non_empty(text)
+                .map(|t| scale::parse_range(&t).map(|(min, max)| [min, max]))
+                .transpose()
+        };
+        let scale = ScaleSpec {
+            input: range(&m.scale_in)?,
+            output: range(&m.scale_out)?,
+            curve: non_empty(&m.scale_curve),
+        };
+        let has_scale = scale.input.is_some() || scale.output.is_some() || scale.curve.is_some();
+
+        let steps = match non_empty(&m.steps) {
+            Some(text) => parse_steps(&text)?,
+            None => Vec::new(),
+        };
+
+        Ok(MappingEntry {
+            input: InputSpec {
+                address: m.osc_in_address.clone(),
+                arg: non_empty(&m.osc_in_args),
+                listener: non_empty(&m.osc_in_listener),
+                source: non_empty(&m.osc_in_source),
+                condition: non_empty(&m.condition),
+            },
+            outputs: Outputs::from(m),
+            script: non_empty(&m.script),
+            set_vars: words(&m.set_vars),
+            behavior: has_behavior.then_some(behavior),
+            scale: has_scale.then_some(scale),
+            steps,
+            layer: non_empty(&m.layer),
+            comment: non_empty(&m._comment),
+        })
+    }
+}
+
+impl From<&MappingEntry> for Mapping {
+    fn from(e: &MappingEntry) -> Self {
+        let behavior = e.behavior.clone().unwrap_or_default();
+        let scale = e.scale.clone().unwrap_or_default();
+        let range = |r: Option<[f64; 2]>| r.map(|[min, max]| scale::format_range((min, max)));
+        let mut m = Mapping {
+            osc_in_address: e.input.address.clone(),
+            osc_in_args: non_empty(&e.input.arg),
+            layer: non_empty(&e.layer),
+            osc_in_listener: non_empty(&e.input.listener),
+            osc_in_source: non_empty(&e.input.source),
+            script: non_empty(&e.script),
+            condition: non_empty(&e.input.condition),
+            set_vars: joined(&e.set_vars),
+            behavior: non_empty(&behavior.kind),
+            behavior_values: joined(&behavior.values),
+            behavior_mode: non_empty(&behavior.mode),
+            behavior_var: non_empty(&behavior.var),
+            scale_in: range(scale.input),
+            scale_out: range(scale.output),
+            scale_curve: non_empty(&scale.curve),
+            // Serializing plain data to JSON can't fail
+            steps: (!e.steps.is_empty())
+                .then(|| serde_json::to_string(&e.steps).unwrap_or_default()),
+            _comment: non_empty(&e.comment),
+            ..Default::default()
+        };
+        e.outputs.fill(&mut m);
+        m
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Format, Box<dyn Error>> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        Format::from_name(ext)
+    }
+
+    pub fn from_name(name: &str) -> Result<Format, Box<dyn Error>> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "json" => Ok(Format::Json),
+            other => Err(format!("Unsupported mappings format: '{other}'").into()),
+        }
+    }
+
+    #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+            Format::Json => "json",
+        }
+    }
+}
+
+// Exactly one of these may sit in a show folder (or the odisc folder)
+const MAPPINGS_FILES: [&str; 5] = [
+    "mappings.csv",
+    "mappings.toml",
+    "mappings.yaml",
+    "mappings.yml",
+    "mappings.json",
+];
+
+// Exports go here unless the caller picks a path, outside the lookup list
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+const EXPORT_NAME: &str = "mappings-export";
+
+// Refuses to guess when several formats are present
+pub fn find_mappings_file(dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let found: Vec<PathBuf> = MAPPINGS_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.exists())
+        .collect();
+    match found.as_slice() {
+        [] => Ok(dir.join("mappings.csv")),
+        [path] => Ok(path.clone()),
+        _ => {
+            let names: Vec<String> = found
+                .iter()
+                .filter_map(|p| p.file_name())
+                .map(|n| n.to_string_lossy().to_string())
+                .collect();
+            Err(format!(
+                "Several mappings files in {dir:?} ({}), keep only one",
+                names.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+pub fn load_mappings(path: PathBuf) -> Result<Vec<Mapping>, Box<dyn Error>> {
+    let file: MappingFile = match Format::from_path(&path)? {
+        Format::Csv => return helpers::load_mappings_from_csv(path),
+        Format::Toml => toml::from_str(&fs::read_to_string(&path)?)?,
+        Format::Yaml => serde_yaml::from_str(&fs::read_to_string(&path)?)?,
+        Format::Json => serde_json::from_str(&fs::read_to_string(&path)?)?,
+    };
+    Ok(file.mappings.iter().map(Mapping::from).collect())
+}
+
+fn nested(mappings: &[Mapping]) -> Result<MappingFile, Box<dyn Error>> {
+    let mappings = mappings
+        .iter()
+        .enumerate()
+        .map(|(row, m)| MappingEntry::try_from(m).map_err(|e| format!("Row {row}: {e}")))
+        .collect::<Result<_, _>>()?;
+    Ok(MappingFile { mappings })
+}
+
+pub fn serialize_mappings(mappings: &[Mapping], format: Format) -> Result<String, Box<dyn Error>> {
+    let text = match format {
+        Format::Csv => {
+            let mut wtr = csv::Writer::from_writer(Vec::new());
+            if mappings.is_empty() {
+                wtr.write_record(helpers::MAPPING_COLUMNS)?;
+            }
+            for mapping in mappings {
+                wtr.serialize(mapping)?;
+            }
+            String::from_utf8(wtr.into_inner()?)?
+        }
+        Format::Toml => toml::to_string_pretty(&nested(mappings)?)?,
+        Format::Yaml => serde_yaml::to_string(&nested(mappings)?)?,
+        Format::Json => serde_json::to_string_pretty(&nested(mappings)?)?,
+    };
+    Ok(text)
+}
+
+// Writes to `target`, or mappings-export.<ext> next to `source`, refusing to
+// clobber an existing file. Rename it to mappings.<ext> (and move the old
+// file away) to use it.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn export_mappings(
+    source: &Path,
+    format: Format,
+    target: Option<&Path>,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let mappings = load_mappings(source.to_path_buf())?;
+    let target = match target {
+        Some(path) => path.to_path_buf(),
+        None => source.with_file_name(format!("{EXPORT_NAME}.{}", format.extension())),
+    };
+    if target.exists() {
+        return Err(format!("{target:?} already exists").into());
+    }
+    fs::write(&target, serialize_mappings(&mappings, format)?)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping() -> Mapping {
+        Mapping {
+            osc_in_address: "/song/start".to_string(),
+            osc_out_address: Some("/out".to_string()),
+            osc_out_args: Some(r#"i:1 0.5 b:true s:12 "two words" $value"#.to_string()),
+            osc_out_transport: Some("tcp_length".to_string()),
+            midi_type: Some("cc".to_string()),
+            midi_channel: Some(1),
+            midi_controller: Some(7),
+            midi_value: Some(0),
+            scale_in: Some("0 1".to_string()),
+            scale_out: Some("0 127".to_string()),
+            scale_curve: Some("exp".to_string()),
+            steps: Some(
+                r#"[{"delay_ms":500,"osc":{"address":"/go","args":[1,2.5,"x"]}},{"midi":{"type":"pc","channel":1,"value":3}}]"#
+                    .to_string(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn cells(m: &Mapping) -> serde_json::Value {
+        serde_json::to_value(m).unwrap()
+    }
+
+    #[test]
+    fn nested_formats_round_trip() {
+        let original = mapping();
+        for format in [Format::Toml, Format::Yaml, Format::Json] {
+            let text = serialize_mappings(std::slice::from_ref(&original), format).unwrap();
+            let file: MappingFile = match format {
+                Format::Toml => toml::from_str(&text).unwrap(),
+                Format::Yaml => serde_yaml::from_str(&text).unwrap(),
+                _ => serde_json::from_str(&text).unwrap(),
+            };
+            let back = Mapping::from(&file.mappings[0]);
+            assert_eq!(cells(&back), cells(&original), "{format:?}:\n{text}");
+        }
+    }
+
+    #[test]
+    fn args_keep_their_types() {
+        let entry = MappingEntry::try_from(&mapping()).unwrap();
+        let args = entry.outputs.osc.unwrap().args;
+        assert_eq!(
+            args,
+            [
+                OscArg::Int(1),
+                OscArg::Float(0.5),
+                OscArg::Bool(true),
+                OscArg::String("12".to_string()),
+                OscArg::String("two words".to_string()),
+                OscArg::String("$value".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_become_mappings() {
+        let steps = step_mappings(&mapping()).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].0, Duration::from_millis(500));
+        assert_eq!(steps[0].1.osc_out_args.as_deref(), Some("i:1 2.5 x"));
+        assert!(steps[1].0.is_zero());
+        assert_eq!(steps[1].1.midi_type.as_deref(), Some("pc"));
+
+        let bad = Mapping {
+            steps: Some("[{".to_string()),
+            ..mapping()
+        };
+        assert!(MappingEntry::try_from(&bad).is_err());
+    }
+
+    #[test]
+    fn refuses_several_mappings_files() {
+        let dir = std::env::temp_dir().join(format!("odisc-formats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(find_mappings_file(&dir).unwrap(), dir.join("mappings.csv"));
+        fs::write(dir.join("mappings.toml"), "").unwrap();
+        assert_eq!(find_mappings_file(&dir).unwrap(), dir.join("mappings.toml"));
+        fs::write(dir.join("mappings.csv"), "").unwrap();
+        assert!(find_mappings_file(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
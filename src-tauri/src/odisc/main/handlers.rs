@@ -1,10 +1,14 @@
-use crate::odisc::main::custom_print;
+use crate::odisc::main::args;
 use crate::odisc::main::helpers::Mapping;
-use crate::odisc::main::Output;
+use crate::odisc::main::monitor::{self, MonitorKind};
+use crate::odisc::main::transport::{OscSender, Transport};
+use crate::odisc::main::vars;
 use regex::Regex;
 use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use tokio::net::UdpSocket;
+use tracing::{debug, error, warn};
 use smallvec::SmallVec;
 
 use lazy_static::lazy_static;
@@ -16,35 +20,27 @@ lazy_static! {
 
 // OSC
 
-pub async fn incoming_osc_handler(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<OscPacket> {
-    let (len, _addr) = sock.recv_from(buf).await?;
+pub async fn incoming_osc_handler(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(OscPacket, SocketAddr)> {
+    let (len, addr) = sock.recv_from(buf).await?;
     let (_rest, packet) = decoder::decode_udp(&buf[..len])
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-    Ok(packet)
+    Ok((packet, addr))
 }
 
 pub async fn outgoing_osc_handler(
-    sock: &UdpSocket,
+    osc_out: &mut OscSender,
     osc_out_address: &str,
     osc_out_args: Option<&str>,
     osc_host: &str,
     osc_port: &u16,
+    transport: Option<Transport>,
 ) -> std::io::Result<()> {
     // Stack-allocate for up to 8 args, heap for more
     let final_args: SmallVec<[OscType; 8]> = match osc_out_args {
-        Some(args) if !args.trim().is_empty() => {
-            args.split_whitespace()
-                .filter_map(|arg| {
-                    if let Ok(num) = arg.parse::<f32>() {
-                        Some(OscType::Float(num))
-                    } else if !arg.is_empty() {
-                        Some(OscType::String(arg.to_string()))
-                    } else {
-                        None
-                    }
-                })
-                .collect()
-        }
+        Some(args) if !args.trim().is_empty() => args::parse_all(args).into_iter().collect(),
         _ => SmallVec::new(),
     };
 
@@ -63,18 +59,78 @@ pub async fn outgoing_osc_handler(
     };
     
     let addr = format!("{osc_host}:{final_port}");
-    sock.send_to(&encoded, addr).await?;
+    osc_out.send_with(transport, &addr, &encoded).await?;
+    if let OscPacket::Message(msg) = packet {
+        monitor::record(MonitorKind::OscOut {
+            destination: addr,
+            address: msg.addr,
+            args: msg.args,
+        });
+    }
     
-    // Remove custom_print entirely from this hot path
     Ok(())
 }
 
 // CSV MAPPING
 
-pub fn match_mappings(mappings: &[Mapping], msg: &OscMessage) -> Vec<Mapping> {
-    let found_mappings: Vec<Mapping> = mappings
+// Returns the matching rows with their index in the mappings table
+// "10.0.0.5" matches the host, "10.0.0.5:9000" or "[fe80::1]:9000" the exact
+// socket, "*:9000" any host sending from that port
+pub fn is_valid_source_filter(filter: &str) -> bool {
+    filter.parse::<IpAddr>().is_ok()
+        || filter.parse::<SocketAddr>().is_ok()
+        || filter
+            .strip_prefix("*:")
+            .is_some_and(|port| port.parse::<u16>().is_ok())
+}
+
+fn source_matches(filter: &str, source: SocketAddr) -> bool {
+    // Dual-stack listeners report IPv4 senders as ::ffff:a.b.c.d
+    let ip = source.ip().to_canonical();
+    if let Ok(host) = filter.parse::<IpAddr>() {
+        return host.to_canonical() == ip;
+    }
+    if let Ok(exact) = filter.parse::<SocketAddr>() {
+        return exact.ip().to_canonical() == ip && exact.port() == source.port();
+    }
+    match filter.strip_prefix("*:").map(str::parse::<u16>) {
+        Some(Ok(port)) => port == source.port(),
+        _ => false,
+    }
+}
+
+pub fn match_mappings(
+    mappings: &[Mapping],
+    msg: &OscMessage,
+    active_layer: Option<&str>,
+    listener: &str,
+    source: SocketAddr,
+) -> Vec<(usize, Mapping)> {
+    let found_mappings: Vec<(usize, Mapping)> = mappings
         .iter()
-        .filter(|m| {
+        .enumerate()
+        .filter(|(_, m)| {
+            // Global rows always match, song rows only while their layer is active
+            let layer_match = match m.layer.as_deref() {
+                None | Some("") => true,
+                Some(layer) => active_layer == Some(layer),
+            };
+            if !layer_match {
+                return false;
+            }
+
+            let listener_match = match m.osc_in_listener.as_deref() {
+                None | Some("") => true,
+                Some(name) => name == listener,
+            };
+            let source_match = match m.osc_in_source.as_deref() {
+                None | Some("") => true,
+                Some(filter) => source_matches(filter, source),
+            };
+            if !listener_match || !source_match {
+                return false;
+            }
+
             let addr_match = m.osc_in_address == msg.addr;
 
             let args_match = match &m.osc_in_args {
@@ -86,9 +142,17 @@ pub fn match_mappings(mappings: &[Mapping], msg: &OscMessage) -> Vec<Mapping> {
                 }
             };
 
-            addr_match && args_match
+            if !(addr_match && args_match) {
+                return false;
+            }
+
+            // Conditions last, they are the costly part
+            match m.condition.as_deref() {
+                None | Some("") => true,
+                Some(condition) => vars::condition_holds(condition, &msg.args),
+            }
         })
-        .cloned()
+        .map(|(row, m)| (row, m.clone()))
         .collect();
 
     found_mappings
@@ -103,20 +167,14 @@ fn parse_preset_id(preset_id: &str) -> Option<(u32, char)> {
         if let (Some(number), Some(letter)) = (number, letter) {
             Some((number, letter))
         } else {
-            let _ = custom_print(
-                format!(
-                    "Invalid Quad Cortex preset format: {preset_id}. Expected format like '1A', '12D', etc."
-                ),
-                Output::App,
+            warn!(
+                "Invalid Quad Cortex preset format: {preset_id}. Expected format like '1A', '12D', etc."
             );
             None
         }
     } else {
-        let _ = custom_print(
-            format!(
-                "Invalid Quad Cortex preset format: {preset_id}. Expected format like '1A', '12D', etc."
-            ),
-            Output::App,
+        warn!(
+            "Invalid Quad Cortex preset format: {preset_id}. Expected format like '1A', '12D', etc."
         );
         None
     }
@@ -124,7 +182,7 @@ fn parse_preset_id(preset_id: &str) -> Option<(u32, char)> {
 
 fn parse_preset_midi(number: &u32, letter: &char) -> Option<u32> {
     if !(&1u32..=&32u32).contains(&number) {
-        eprintln!("Invalid bank number: {number}. Must be between 1 and 32.");
+        error!("Invalid bank number: {number}. Must be between 1 and 32.");
         return None;
     }
 
@@ -137,15 +195,9 @@ fn parse_preset_midi(number: &u32, letter: &char) -> Option<u32> {
 pub fn send_qc_preset(preset_id: &String, setlist: &u32, channel: &u32) -> Option<u32> {
     if let Some((number, letter)) = parse_preset_id(preset_id) {
         let program_change_number = parse_preset_midi(&number, &letter);
-        let _ = custom_print(
-            format!(
-                "Sending QC Preset: Setlist {}, Preset {} -> PC: {} @ Ch: {}",
-                setlist,
-                preset_id,
-                program_change_number.unwrap(),
-                channel,
-            ),
-            Output::App,
+        debug!(
+            "Sending QC Preset: Setlist {}, Preset {} -> PC: {:?} @ Ch: {}",
+            setlist, preset_id, program_change_number, channel
         );
         program_change_number
     } else {
@@ -154,6 +206,14 @@ pub fn send_qc_preset(preset_id: &String, setlist: &u32, channel: &u32) -> Optio
     }
 }
 
+// Quiet check used when validating edited mappings
+pub fn is_valid_qc_preset(preset_id: &str) -> bool {
+    QC_PRESET_REGEX
+        .captures(preset_id)
+        .and_then(|caps| caps[1].parse::<u32>().ok())
+        .is_some_and(|bank| (1..=32).contains(&bank))
+}
+
 // HANDLE GT-1000
 
 fn parse_gt1000_preset_id(preset_id: &str) -> Option<(char, u32, u32)> {
@@ -166,23 +226,13 @@ fn parse_gt1000_preset_id(preset_id: &str) -> Option<(char, u32, u32)> {
             if (1..=50).contains(&bn) && (1..=5).contains(&pn) {
                 return Some((pt, bn, pn));
             } else {
-                let _ = custom_print(
-                    format!(
-                        "Invalid GT-1000 preset value: {preset_id}. Bank must be 1-50, patch 1-5."
-                    ),
-                    Output::App,
-                );
+                warn!("Invalid GT-1000 preset value: {preset_id}. Bank must be 1-50, patch 1-5.");
                 return None;
             }
         }
     }
 
-    let _ = custom_print(
-        format!(
-            "Invalid GT-1000 preset format: {preset_id}. Expected format like 'U01-1' or 'P50-5'."
-        ),
-        Output::App,
-    );
+    warn!("Invalid GT-1000 preset format: {preset_id}. Expected format like 'U01-1' or 'P50-5'.");
     None
 }
 
@@ -192,11 +242,11 @@ fn parse_gt1000_preset_midi(
     patch_number: &u32,
 ) -> Option<(u32, u32, u32)> {
     if !(1..=50).contains(bank_number) {
-        eprintln!("Invalid bank number: {bank_number}. Must be between 1 and 50.");
+        error!("Invalid bank number: {bank_number}. Must be between 1 and 50.");
         return None;
     }
     if !(1..=5).contains(patch_number) {
-        eprintln!("Invalid patch number: {patch_number}. Must be between 1 and 5.");
+        error!("Invalid patch number: {patch_number}. Must be between 1 and 5.");
         return None;
     }
 
@@ -205,7 +255,7 @@ fn parse_gt1000_preset_midi(
         'U' => bank_number - 1,
         'P' => bank_number - 1 + 50,
         _ => {
-            eprintln!("Invalid preset type: {preset_type}. Must be 'U' or 'P'.");
+            error!("Invalid preset type: {preset_type}. Must be 'U' or 'P'.");
             return None;
         }
     };
@@ -219,11 +269,8 @@ pub fn send_gt1000_preset(preset_id: &String, channel: &u32) -> Option<(u32, u32
         if let Some((bank_select_msb, bank_select_lsb, program_change_number)) =
             parse_gt1000_preset_midi(&preset_type, &bank_number, &patch_number)
         {
-            let _ = custom_print(
-                format!(
-                    "Sending GT-1000 Preset: {preset_id} -> Bank MSB: {bank_select_msb}, Bank LSB: {bank_select_lsb}, PC: {program_change_number} @ Ch: {channel}",
-                ),
-                Output::App,
+            debug!(
+                "Sending GT-1000 Preset: {preset_id} -> Bank MSB: {bank_select_msb}, Bank LSB: {bank_select_lsb}, PC: {program_change_number} @ Ch: {channel}"
             );
             return Some((bank_select_msb, bank_select_lsb, program_change_number));
         }
@@ -233,3 +280,11 @@ pub fn send_gt1000_preset(preset_id: &String, channel: &u32) -> Option<(u32, u32
         None
     }
 }
+
+pub fn is_valid_gt1000_preset(preset_id: &str) -> bool {
+    GT1000_PRESET_REGEX.captures(preset_id).is_some_and(|caps| {
+        let bank = caps[2].parse::<u32>().unwrap_or(0);
+        let patch = caps[3].parse::<u32>().unwrap_or(0);
+        (1..=50).contains(&bank) && (1..=5).contains(&patch)
+    })
+}
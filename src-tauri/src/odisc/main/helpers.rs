@@ -1,4 +1,15 @@
+use crate::odisc::main::dmx::DmxConfig;
+use crate::odisc::main::formats;
+use crate::odisc::main::http_api::HttpApiConfig;
+use crate::odisc::main::mqtt::MqttConfig;
+use crate::odisc::main::oscquery::OscQueryConfig;
+use crate::odisc::main::redundancy::RedundancyConfig;
+use crate::odisc::main::rtpmidi::{self, RtpMidiConfig};
+use crate::odisc::main::script;
+use crate::odisc::main::transport::{ListenerConfig, Transport};
+use crate::odisc::main::websocket::WebSocketConfig;
 use csv::Reader;
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs;
@@ -6,8 +17,11 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::Write;
 use std::path::PathBuf;
+use tracing::{info, warn};
 
-#[derive(Debug, Deserialize, Clone)]
+static ODISC_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Mapping {
     pub osc_in_address: String,
     pub osc_in_args: Option<String>,
@@ -22,9 +36,102 @@ pub struct Mapping {
     pub qc_preset_id: Option<String>,
     pub gt1000_preset_id: Option<String>,
     pub setlist: Option<u32>,
+    #[serde(default)]
+    pub layer: Option<String>, // empty = global layer, always active
+    #[serde(default)]
+    pub osc_in_listener: Option<String>, // listener name, empty = any
+    #[serde(default)]
+    pub osc_in_source: Option<String>, // "10.0.0.5", "10.0.0.5:9000" or "*:9000", empty = any
+    #[serde(default)]
+    pub dmx_universe: Option<u16>,
+    #[serde(default)]
+    pub dmx_values: Option<String>, // "1=255 2-4=$1", see dmx.rs
+    #[serde(default)]
+    pub dmx_fade: Option<f32>, // seconds, empty = snap
+    #[serde(default)]
+    pub msc_command: Option<String>, // go, stop, ..., see msc.rs
+    #[serde(default)]
+    pub msc_format: Option<String>, // "lighting", "sound", ... or a number, empty = lighting
+    #[serde(default)]
+    pub msc_device: Option<String>, // 0-127, empty = 127 (all call)
+    #[serde(default)]
+    pub msc_cue: Option<String>,
+    #[serde(default)]
+    pub msc_list: Option<String>,
+    #[serde(default)]
+    pub msc_path: Option<String>,
+    #[serde(default)]
+    pub msc_time: Option<String>, // "hh:mm:ss:ff" or seconds
+    #[serde(default)]
+    pub script: Option<String>, // file in scripts/, see script.rs
+    #[serde(default)]
+    pub condition: Option<String>, // e.g. `is_playing && args[0] > 0.5`, see vars.rs
+    #[serde(default)]
+    pub set_vars: Option<String>, // "current_song=$1 is_playing=true"
+    #[serde(default)]
+    pub behavior: Option<String>, // toggle, cycle_up, ..., see behavior.rs
+    #[serde(default)]
+    pub behavior_values: Option<String>, // "0 127", "1A 2A 3A" or "min max step"
+    #[serde(default)]
+    pub behavior_mode: Option<String>, // wrap or clamp
+    #[serde(default)]
+    pub behavior_var: Option<String>, // empty = state per row
+    #[serde(default)]
+    pub scale_in: Option<String>, // "0 1", see scale.rs
+    #[serde(default)]
+    pub scale_out: Option<String>, // "0 127"
+    #[serde(default)]
+    pub scale_curve: Option<String>, // linear, exp or log
+    #[serde(default)]
+    pub steps: Option<String>, // JSON list of delayed outputs, see formats.rs
+    #[serde(default)]
+    pub osc_out_transport: Option<String>, // udp, tcp_slip or tcp_length, empty = OSC_SEND_TRANSPORT
     pub _comment: Option<String>, // just for user reference, not actually used
 }
 
+// Column order of a freshly written mappings CSV, same as the Mapping fields
+pub const MAPPING_COLUMNS: [&str; 39] = [
+    "osc_in_address",
+    "osc_in_args",
+    "osc_out_address",
+    "osc_out_args",
+    "midi_channel",
+    "midi_type",
+    "midi_note",
+    "midi_velocity",
+    "midi_controller",
+    "midi_value",
+    "qc_preset_id",
+    "gt1000_preset_id",
+    "setlist",
+    "layer",
+    "osc_in_listener",
+    "osc_in_source",
+    "dmx_universe",
+    "dmx_values",
+    "dmx_fade",
+    "msc_command",
+    "msc_format",
+    "msc_device",
+    "msc_cue",
+    "msc_list",
+    "msc_path",
+    "msc_time",
+    "script",
+    "condition",
+    "set_vars",
+    "behavior",
+    "behavior_values",
+    "behavior_mode",
+    "behavior_var",
+    "scale_in",
+    "scale_out",
+    "scale_curve",
+    "steps",
+    "osc_out_transport",
+    "_comment",
+];
+
 pub fn load_mappings_from_csv(path: PathBuf) -> Result<Vec<Mapping>, Box<dyn Error>> {
     let file = File::open(path)?;
     let mut rdr = Reader::from_reader(file);
@@ -43,66 +150,173 @@ pub struct Config {
     pub osc_listen_port: u16,
     pub osc_send_host: String,
     pub osc_send_port: u16,
+    // udp, tcp_slip (OSC 1.1) or tcp_length (OSC 1.0)
+    #[serde(default)]
+    pub osc_listen_transport: Transport,
+    // Extra listeners; when empty there is a single "default" listener on
+    // OSC_LISTEN_PORT with OSC_LISTEN_TRANSPORT
+    #[serde(default)]
+    pub osc_listeners: Vec<ListenerConfig>,
+    // Default for mapping output; a mapping's osc_out_transport overrides it
+    #[serde(default)]
+    pub osc_send_transport: Transport,
     pub midi_output_name: String,
     #[serde(default)]
     pub debug_logging: bool,
+    // Per-module tracing directives, e.g. "odisc_lib::odisc::main::midi=trace"
+    #[serde(default)]
+    pub log_filter: Option<String>,
+    #[serde(default)]
+    pub midi_input_name: String,
+    #[serde(default)]
+    pub show_select_channel: Option<u32>,
+    #[serde(default)]
+    pub active_show: Option<String>,
+    // Incoming addresses whose string argument selects the active song layer
+    #[serde(default = "default_layer_addresses")]
+    pub layer_addresses: Vec<String>,
+    // Primary/backup pairing, see redundancy.rs
+    #[serde(default)]
+    pub redundancy: Option<RedundancyConfig>,
+    // Namespace discovery for controllers, see oscquery.rs
+    #[serde(default)]
+    pub oscquery: Option<OscQueryConfig>,
+    // JSON control and monitoring for browsers, see websocket.rs
+    #[serde(default)]
+    pub websocket: Option<WebSocketConfig>,
+    // REST API for scripts, see http_api.rs
+    #[serde(default)]
+    pub http_api: Option<HttpApiConfig>,
+    // Broker connection for venue automation, see mqtt.rs
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    // Art-Net or sACN output for dmx_* mapping columns, see dmx.rs
+    #[serde(default)]
+    pub dmx: Option<DmxConfig>,
+    // Network MIDI session, selected as the "RTP-MIDI" port, see rtpmidi.rs
+    #[serde(default)]
+    pub rtp_midi: Option<RtpMidiConfig>,
+    // Longest a mapping script may run before it is stopped, see script.rs
+    #[serde(default = "default_script_time_limit_ms")]
+    pub script_time_limit_ms: u64,
+    // Keep the variable store in variables.json across restarts, see vars.rs
+    #[serde(default)]
+    pub persist_variables: bool,
+}
+
+impl Config {
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.osc_listeners.is_empty() {
+            return self.osc_listeners.clone();
+        }
+        vec![ListenerConfig {
+            name: "default".to_string(),
+            port: self.osc_listen_port,
+            transport: self.osc_listen_transport,
+            ..Default::default()
+        }]
+    }
+}
+
+fn default_script_time_limit_ms() -> u64 {
+    20
 }
 
+fn default_layer_addresses() -> Vec<String> {
+    vec!["/setlist/activeSongName".to_string()]
+}
+
+// With `write_back`, a missing MIDI output is replaced in the file as well;
+// a config given on the command line is only ever read
 pub fn read_config(
     path: &str,
     midi_outputs: Vec<String>,
+    write_back: bool,
 ) -> Result<Config, Box<dyn std::error::Error>> {
     let file = File::open(path)?;
     let reader = BufReader::new(file);
     let config: Config = serde_json::from_reader(reader)?;
 
-    if !midi_outputs.contains(&config.midi_output_name) {
+    let rtp_output = config.rtp_midi.is_some() && config.midi_output_name == rtpmidi::PORT_NAME;
+    if !rtp_output && !midi_outputs.contains(&config.midi_output_name) {
         let mut new_config = config.clone();
         if let Some(first) = midi_outputs.first() {
-            println!("Configured MIDI device not found. Setting to first available: {first}");
+            warn!("Configured MIDI device not found. Setting to first available: {first}");
             new_config.midi_output_name = first.clone();
         } else {
-            println!("No MIDI output devices available. MIDI output will be disabled.");
+            warn!("No MIDI output devices available. MIDI output will be disabled.");
             new_config.midi_output_name = "".to_string();
         }
-        std::fs::write(path, serde_json::to_string_pretty(&new_config).unwrap())?;
+        if write_back {
+            std::fs::write(path, serde_json::to_string_pretty(&new_config)?)?;
+        }
         return Ok(new_config);
     }
 
     Ok(config)
 }
 
-pub fn ensure_files() -> std::io::Result<(PathBuf, PathBuf)> {
+// Data directory (config, mappings, shows, logs); the headless binary can move it
+pub fn set_odisc_dir(dir: PathBuf) {
+    let _ = ODISC_DIR.set(dir);
+}
+
+pub fn odisc_dir() -> PathBuf {
+    if let Some(dir) = ODISC_DIR.get() {
+        return dir.clone();
+    }
     let home = dirs::home_dir().expect("Could not find home directory");
-    let odisc_dir = home.join("Documents").join("odisc");
+    home.join("Documents").join("odisc")
+}
+
+// Data directory with its shows and scripts folders
+pub fn ensure_dirs() -> std::io::Result<PathBuf> {
+    let odisc_dir = odisc_dir();
     if !odisc_dir.exists() {
         fs::create_dir_all(&odisc_dir)?;
-        println!("Created directory: {odisc_dir:?}");
+        info!("Created directory: {odisc_dir:?}");
     }
 
-    let mappings_path = odisc_dir.join("mappings.csv");
+    let shows_dir = odisc_dir.join("shows");
+    if !shows_dir.exists() {
+        fs::create_dir_all(&shows_dir)?;
+        info!("Created directory: {shows_dir:?}");
+    }
+
+    let scripts_dir = odisc_dir.join(script::SCRIPTS_DIR);
+    if !scripts_dir.exists() {
+        fs::create_dir_all(&scripts_dir)?;
+        info!("Created directory: {scripts_dir:?}");
+    }
+    Ok(odisc_dir)
+}
+
+// An empty mappings.csv, unless the data directory has a mappings file in any format
+pub fn ensure_mappings() -> Result<PathBuf, Box<dyn Error>> {
+    let odisc_dir = ensure_dirs()?;
+    let mappings_path = formats::find_mappings_file(&odisc_dir)?;
     if !mappings_path.exists() {
-        let headers = "osc_in_address,osc_in_args,osc_out_address,osc_out_args,midi_channel,midi_type,midi_note,midi_velocity,midi_controller,midi_value,setlist,qc_preset_id,gt1000_preset_id\ncomment\n";
+        let headers = format!("{}\n", MAPPING_COLUMNS.join(","));
         fs::write(&mappings_path, headers)?;
-        println!("Created default mappings.csv at {mappings_path:?}");
+        info!("Created default mappings.csv at {mappings_path:?}");
     }
+    Ok(mappings_path)
+}
 
-    // Load mappings to confirm file is valid
-    load_mappings_from_csv(mappings_path.clone()).expect("Failed to load mappings");
-
-    let config_path = odisc_dir.join("config.json");
+pub fn ensure_config() -> std::io::Result<PathBuf> {
+    let config_path = ensure_dirs()?.join("config.json");
     if !config_path.exists() {
         let default_config = r#"{
   "OSC_LISTEN_PORT": 8000,
   "OSC_SEND_HOST": "127.0.0.1",
   "OSC_SEND_PORT": 7001,
   "MIDI_OUTPUT_NAME": "",
+  "MIDI_INPUT_NAME": "",
   "DEBUG_LOGGING": false
 }"#;
         let mut file = fs::File::create(&config_path)?;
         file.write_all(default_config.as_bytes())?;
-        println!("Created default config.json at {config_path:?}");
+        info!("Created default config.json at {config_path:?}");
     }
-
-    Ok((mappings_path, config_path))
+    Ok(config_path)
 }
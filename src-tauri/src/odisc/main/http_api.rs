@@ -0,0 +1,547 @@
+use crate::odisc::main::control::{self, ControlCommand};
+use crate::odisc::main::helpers::Mapping;
+use crate::odisc::main::monitor::{self, MonitorEvent, MonitorFilter};
+use crate::odisc::main::transport::Inbound;
+use crate::odisc::main::websocket::osc_arg;
+use crate::odisc::main::{active_mappings_path, editor, engine_status, mappings, set_mappings};
+use axum::extract::{ConnectInfo, Query, Request, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rosc::{OscMessage, OscPacket};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+// REST API for scripts and Stream Deck plugins:
+//   GET  /api/status              engine_status(), counters included
+//   GET  /api/mappings            the live table
+//   PUT  /api/mappings            replace the active file (all rows validated)
+//   POST /api/trigger             {"row": 3} or {"address": "/x", "args": [...]}
+//   POST /api/panic
+//   GET  /api/history             ?kinds=osc_in,midi_out&search=&since_seq=&limit=
+// Requests need "Authorization: Bearer <TOKEN>" when a token is set, which
+// it must be to bind anywhere but loopback. Anything but GET from a page on
+// another site is refused, unless its origin is in ALLOWED_ORIGINS.
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct HttpApiConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_bind_address")]
+    pub address: String,
+    #[serde(default)]
+    pub token: Option<String>,
+    // Extra browser origins let in, e.g. "http://dashboard.local:3000"
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    // Listener name simulated messages arrive on, for osc_in_listener filters
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+fn default_port() -> u16 {
+    8092
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_name() -> String {
+    "http".to_string()
+}
+
+struct Shared {
+    token: Option<String>,
+    allowed_origins: Vec<String>,
+    name: Arc<str>,
+    inbound: mpsc::Sender<Inbound>,
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+fn bad_request(message: impl Into<String>) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, message.into())
+}
+
+// Same time whatever the first wrong byte is
+pub fn token_matches(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn host_part(authority: &str) -> &str {
+    match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    }
+}
+
+fn is_loopback_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+// Keeps web pages on other sites away from a server the operator's browser
+// can reach. Passes requests without an Origin (curl, scripts, apps), "null"
+// (pages opened from a file), loopback hosts, an IP that is also the Host
+// header (a name there could be DNS rebinding) and `allowed` entries.
+pub fn origin_allowed(headers: &HeaderMap, allowed: &[String]) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        // Browsers always send Origin on cross-site POSTs and upgrades
+        return headers
+            .get("sec-fetch-site")
+            .is_none_or(|site| site != "cross-site");
+    };
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    if origin == "null" || allowed.iter().any(|a| a == origin) {
+        return true;
+    }
+    let Some((_, authority)) = origin.split_once("://") else {
+        return false;
+    };
+    let host = host_part(authority);
+    if is_loopback_host(host) {
+        return true;
+    }
+    let request_host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(host_part);
+    host.parse::<IpAddr>().is_ok() && request_host == Some(host)
+}
+
+// Plain GETs can't change anything and their responses aren't readable cross-site
+async fn check_origin(State(shared): State<Arc<Shared>>, request: Request, next: Next) -> Response {
+    let safe = matches!(*request.method(), Method::GET | Method::HEAD);
+    if !safe && !origin_allowed(request.headers(), &shared.allowed_origins) {
+        warn!(
+            origin = ?request.headers().get(header::ORIGIN),
+            "HTTP API request refused, origin not allowed"
+        );
+        return ApiError(StatusCode::FORBIDDEN, "Origin not allowed".to_string()).into_response();
+    }
+    next.run(request).await
+}
+
+async fn require_token(
+    State(shared): State<Arc<Shared>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(token) = &shared.token {
+        let given = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !token_matches(given.as_bytes(), token.as_bytes()) {
+            return ApiError(
+                StatusCode::UNAUTHORIZED,
+                "Missing or wrong token".to_string(),
+            )
+            .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+// Runs a command in the backend loop and waits for its result
+async fn request(build: impl FnOnce(control::Reply) -> ControlCommand) -> ApiResult<Json<Value>> {
+    match control::request(build).await {
+        Some(Ok(())) => Ok(Json(json!({ "ok": true }))),
+        Some(Err(e)) => Err(bad_request(e)),
+        None => Err(ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Backend is not running".to_string(),
+        )),
+    }
+}
+
+async fn status() -> Json<Value> {
+    Json(json!(engine_status()))
+}
+
+async fn get_mappings() -> Json<Value> {
+    Json(json!(*mappings()))
+}
+
+async fn put_mappings(Json(new): Json<Vec<Mapping>>) -> ApiResult<Json<Value>> {
+    editor::validate_all(&new).map_err(bad_request)?;
+    let path = active_mappings_path().map_err(|e| {
+        ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to resolve mappings path: {e}"),
+        )
+    })?;
+    editor::replace_mappings(&path, &new).map_err(|e| bad_request(e.to_string()))?;
+    info!(count = new.len(), "Mappings replaced over HTTP");
+    set_mappings(new);
+    Ok(get_mappings().await)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Trigger {
+    Row {
+        row: usize,
+    },
+    Message {
+        address: String,
+        #[serde(default)]
+        args: Vec<Value>,
+    },
+}
+
+async fn trigger(
+    State(shared): State<Arc<Shared>>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    Json(trigger): Json<Trigger>,
+) -> ApiResult<Response> {
+    match trigger {
+        Trigger::Row { row } => Ok(request(|reply| ControlCommand::Trigger { row, reply })
+            .await?
+            .into_response()),
+        // Goes through control addresses and the mappings like any OSC
+        Trigger::Message { address, args } => {
+            if !address.starts_with('/') {
+                return Err(bad_request("address must start with '/'"));
+            }
+            let args = args
+                .iter()
+                .map(osc_arg)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| bad_request("args must be strings, numbers, booleans or null"))?;
+            let inbound = Inbound {
+                packet: OscPacket::Message(OscMessage {
+                    addr: address,
+                    args,
+                }),
+                source,
+                listener: shared.name.clone(),
+            };
+            shared.inbound.send(inbound).await.map_err(|_| {
+                ApiError(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Backend is not running".to_string(),
+                )
+            })?;
+            Ok((StatusCode::ACCEPTED, Json(json!({ "ok": true }))).into_response())
+        }
+    }
+}
+
+async fn panic() -> ApiResult<Json<Value>> {
+    request(|reply| ControlCommand::Panic { reply }).await
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    // Comma separated, e.g. "osc_in,midi_out"
+    kinds: Option<String>,
+    search: Option<String>,
+    since_seq: Option<u64>,
+    limit: Option<usize>,
+}
+
+async fn history(Query(query): Query<HistoryQuery>) -> Json<Vec<MonitorEvent>> {
+    let filter = MonitorFilter {
+        kinds: query
+            .kinds
+            .map(|kinds| kinds.split(',').map(|k| k.trim().to_string()).collect()),
+        search: query.search,
+        since_seq: query.since_seq,
+        limit: query.limit,
+    };
+    Json(monitor::history(&filter))
+}
+
+// Origin first, so another site can't probe for the token
+fn router(shared: Arc<Shared>) -> Router {
+    Router::new()
+        .route("/api/status", get(status))
+        .route("/api/mappings", get(get_mappings).put(put_mappings))
+        .route("/api/trigger", post(trigger))
+        .route("/api/panic", post(panic))
+        .route("/api/history", get(history))
+        .layer(middleware::from_fn_with_state(
+            shared.clone(),
+            require_token,
+        ))
+        .layer(middleware::from_fn_with_state(shared.clone(), check_origin))
+        .with_state(shared)
+}
+
+// Stops serving when dropped
+pub struct HttpApi {
+    task: JoinHandle<()>,
+}
+
+impl Drop for HttpApi {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+pub async fn start(
+    config: &HttpApiConfig,
+    inbound: mpsc::Sender<Inbound>,
+) -> Result<HttpApi, Box<dyn Error>> {
+    let ip: IpAddr = config
+        .address
+        .parse()
+        .map_err(|_| format!("HTTP API: invalid address '{}'", config.address))?;
+    let token = config.token.clone().filter(|t| !t.is_empty());
+    if token.is_none() && !ip.is_loopback() {
+        return Err(format!("HTTP API: a TOKEN is required to listen on {ip}").into());
+    }
+    let listener = TcpListener::bind(SocketAddr::new(ip, config.port)).await?;
+
+    let shared = Arc::new(Shared {
+        token,
+        allowed_origins: config.allowed_origins.clone(),
+        name: config.name.as_str().into(),
+        inbound,
+    });
+    let router = router(shared);
+    let task = tokio::spawn(async move {
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        if let Err(e) = axum::serve(listener, service).await {
+            warn!("HTTP API stopped: {e}");
+        }
+    });
+    info!("HTTP API on {}:{}", config.address, config.port);
+    Ok(HttpApi { task })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::extract::connect_info::MockConnectInfo;
+    use tower::ServiceExt;
+
+    fn api(token: Option<&str>) -> (Router, mpsc::Receiver<Inbound>) {
+        let (inbound, inbound_rx) = mpsc::channel(4);
+        let shared = Arc::new(Shared {
+            token: token.map(str::to_string),
+            allowed_origins: Vec::new(),
+            name: "http".into(),
+            inbound,
+        });
+        let router =
+            router(shared).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 50000))));
+        (router, inbound_rx)
+    }
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)], body: &str) -> Request {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    fn json_post(uri: &str, body: &str) -> Request {
+        request("POST", uri, &[("content-type", "application/json")], body)
+    }
+
+    async fn send(router: &Router, request: Request) -> (StatusCode, Value) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let (router, _rx) = api(Some("s3cret"));
+        let get = |auth: Option<&str>| match auth {
+            Some(auth) => request("GET", "/api/status", &[("authorization", auth)], ""),
+            None => request("GET", "/api/status", &[], ""),
+        };
+        let (status, body) = send(&router, get(None)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "Missing or wrong token");
+        for wrong in ["Bearer s3cre", "Bearer s3cret2", "s3cret", "Basic s3cret"] {
+            assert_eq!(
+                send(&router, get(Some(wrong))).await.0,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            send(&router, get(Some("Bearer s3cret"))).await.0,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_other_sites() {
+        let (router, mut rx) = api(Some("s3cret"));
+        let auth = ("authorization", "Bearer s3cret");
+        // Bodyless, so a no-cors fetch from any page could send it
+        for headers in [
+            &[auth, ("origin", "https://evil.example")][..],
+            &[auth, ("sec-fetch-site", "cross-site")],
+        ] {
+            let (status, body) = send(&router, request("POST", "/api/panic", headers, "")).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(body["error"], "Origin not allowed");
+        }
+        // Reading is left to CORS, which this server never grants
+        let get = request(
+            "GET",
+            "/api/status",
+            &[auth, ("origin", "https://evil.example")],
+            "",
+        );
+        assert_eq!(send(&router, get).await.0, StatusCode::OK);
+
+        let (open, _rx) = api(None);
+        let evil = request(
+            "POST",
+            "/api/panic",
+            &[("origin", "https://evil.example")],
+            "",
+        );
+        assert_eq!(send(&open, evil).await.0, StatusCode::FORBIDDEN);
+
+        let local = request(
+            "POST",
+            "/api/trigger",
+            &[
+                auth,
+                ("origin", "http://localhost:5173"),
+                ("content-type", "application/json"),
+            ],
+            r#"{"address": "/cue/go", "args": [1]}"#,
+        );
+        assert_eq!(send(&router, local).await.0, StatusCode::ACCEPTED);
+        let inbound = rx.recv().await.unwrap();
+        let OscPacket::Message(msg) = inbound.packet else {
+            panic!("expected a message");
+        };
+        assert_eq!(msg.addr, "/cue/go");
+        assert_eq!(&*inbound.listener, "http");
+    }
+
+    #[tokio::test]
+    async fn triggers_rows_through_the_backend() {
+        let (router, _rx) = api(None);
+        let (status, body) = send(&router, json_post("/api/trigger", r#"{"row": 0}"#)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"], "Backend is not running");
+
+        let mut control_rx = control::register();
+        let backend = tokio::spawn(async move {
+            while let Some(cmd) = control_rx.recv().await {
+                if let ControlCommand::Trigger {
+                    row,
+                    reply: Some(reply),
+                } = cmd
+                {
+                    let _ = reply.send(if row < 2 {
+                        Ok(())
+                    } else {
+                        Err(format!("No mapping at row {row}"))
+                    });
+                }
+            }
+        });
+
+        let (status, body) = send(&router, json_post("/api/trigger", r#"{"row": 1}"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "ok": true }));
+        let (status, body) = send(&router, json_post("/api/trigger", r#"{"row": 5}"#)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "No mapping at row 5");
+        backend.abort();
+
+        for bad in [r#"{"row": -1}"#, r#"{"address": 3}"#, "row=1"] {
+            assert!(send(&router, json_post("/api/trigger", bad))
+                .await
+                .0
+                .is_client_error());
+        }
+        let (status, body) =
+            send(&router, json_post("/api/trigger", r#"{"address": "cue"}"#)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "address must start with '/'");
+        let (status, _) = send(
+            &router,
+            json_post("/api/trigger", r#"{"address": "/cue", "args": [{"a": 1}]}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        // A form post can't pass for JSON
+        let form = request("POST", "/api/trigger", &[], r#"{"row": 1}"#);
+        assert_eq!(
+            send(&router, form).await.0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[tokio::test]
+    async fn put_mappings_rejects_invalid_rows() {
+        let (router, _rx) = api(None);
+        let put = |body: &str| {
+            request(
+                "PUT",
+                "/api/mappings",
+                &[("content-type", "application/json")],
+                body,
+            )
+        };
+        let (status, body) = send(
+            &router,
+            put(r#"[{"osc_in_address": "/ok"}, {"osc_in_address": "no-slash"}]"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("Row 1: "));
+
+        let (status, body) = send(
+            &router,
+            put(r#"[{"osc_in_address": "/cc", "midi_type": "cc", "midi_channel": 1}]"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["error"],
+            "Row 0: midi_controller is required for midi_type 'cc'"
+        );
+
+        for malformed in [
+            r#"{"osc_in_address": "/x"}"#,
+            r#"[{"midi_channel": 1}]"#,
+            "[",
+        ] {
+            assert!(send(&router, put(malformed)).await.0.is_client_error());
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::odisc::main::helpers::Mapping;
+use rosc::{OscMessage, OscType};
+use serde::Serialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+use lazy_static::lazy_static;
+
+// Learn mode: a Tauri command arms a one-shot slot, and the backend loop hands
+// the next incoming OSC message (or MIDI message) to it instead of routing it.
+
+#[derive(Debug, Serialize, Clone)]
+pub struct LearnedArg {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct OscLearnResult {
+    pub listener: String,
+    pub source: String,
+    pub address: String,
+    pub args: Vec<LearnedArg>,
+    pub draft: Mapping,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct MidiLearnResult {
+    pub kind: String,
+    pub channel: u32,
+    pub number: Option<u32>,
+    pub value: Option<u32>,
+    pub raw: Vec<u8>,
+    pub draft: Mapping,
+}
+
+// A pending learn, told apart from later ones by its id
+struct Slot<T> {
+    #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+    id: u64,
+    tx: oneshot::Sender<T>,
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub struct Armed<T> {
+    pub id: u64,
+    pub rx: oneshot::Receiver<T>,
+}
+
+type SlotLock<T> = Mutex<Option<Slot<T>>>;
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    static ref OSC_LEARN: SlotLock<OscLearnResult> = Mutex::new(None);
+    static ref MIDI_LEARN: SlotLock<MidiLearnResult> = Mutex::new(None);
+}
+
+// Arming again replaces (and so cancels) any pending learn of that kind
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+fn arm<T>(slot: &SlotLock<T>) -> Armed<T> {
+    let (tx, rx) = oneshot::channel();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    *slot.lock().unwrap() = Some(Slot { id, tx });
+    Armed { id, rx }
+}
+
+// Clears the slot only while it still holds learn `id`
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+fn expire<T>(slot: &SlotLock<T>, id: u64) {
+    let mut slot = slot.lock().unwrap();
+    if slot.as_ref().is_some_and(|s| s.id == id) {
+        slot.take();
+    }
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn arm_osc() -> Armed<OscLearnResult> {
+    arm(&OSC_LEARN)
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn arm_midi() -> Armed<MidiLearnResult> {
+    arm(&MIDI_LEARN)
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn cancel_osc() {
+    OSC_LEARN.lock().unwrap().take();
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn cancel_midi() {
+    MIDI_LEARN.lock().unwrap().take();
+}
+
+// For a timed out learn, so it can't cancel one armed after it
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn expire_osc(id: u64) {
+    expire(&OSC_LEARN, id);
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn expire_midi(id: u64) {
+    expire(&MIDI_LEARN, id);
+}
+
+pub fn learned_arg(arg: &OscType) -> LearnedArg {
+    let (kind, value) = match arg {
+        OscType::Int(i) => ("i", json!(i)),
+        OscType::Float(f) => ("f", json!(f)),
+        OscType::String(s) => ("s", json!(s)),
+        OscType::Long(l) => ("h", json!(l)),
+        OscType::Double(d) => ("d", json!(d)),
+        OscType::Char(c) => ("c", json!(c.to_string())),
+        OscType::Bool(true) => ("T", json!(true)),
+        OscType::Bool(false) => ("F", json!(false)),
+        OscType::Blob(b) => ("b", json!(b)),
+        OscType::Nil => ("N", serde_json::Value::Null),
+        OscType::Inf => ("I", serde_json::Value::Null),
+        other => ("?", json!(format!("{other:?}"))),
+    };
+    LearnedArg {
+        kind: kind.to_string(),
+        value,
+    }
+}
+
+// Returns true when the message was captured and should not be routed
+pub fn capture_osc(msg: &OscMessage, source: SocketAddr, listener: &str) -> bool {
+    let Some(Slot { tx, .. }) = OSC_LEARN.lock().unwrap().take() else {
+        return false;
+    };
+
+    // Mappings can only match on a single string argument
+    let osc_in_args = match &msg.args[..] {
+        [OscType::String(s)] => Some(s.clone()),
+        _ => None,
+    };
+    let result = OscLearnResult {
+        listener: listener.to_string(),
+        source: source.to_string(),
+        address: msg.addr.clone(),
+        args: msg.args.iter().map(learned_arg).collect(),
+        draft: Mapping {
+            osc_in_address: msg.addr.clone(),
+            osc_in_args,
+            ..Default::default()
+        },
+    };
+    let _ = tx.send(result);
+    true
+}
+
+pub fn capture_midi(message: &[u8]) -> bool {
+    let mut slot = MIDI_LEARN.lock().unwrap();
+    if slot.is_none() {
+        return false;
+    }
+
+    let channel = match message.first() {
+        Some(status) => u32::from(status & 0x0F) + 1,
+        None => return false,
+    };
+    let data = |i: usize| message.get(i).map(|b| u32::from(*b));
+    let mut draft = Mapping {
+        midi_channel: Some(channel),
+        ..Default::default()
+    };
+    let kind = match message[0] & 0xF0 {
+        // Note on with velocity 0 is a note off by convention
+        0x90 if data(2) != Some(0) => {
+            draft.midi_note = data(1);
+            draft.midi_velocity = data(2);
+            "note_on"
+        }
+        0x80 | 0x90 => {
+            draft.midi_note = data(1);
+            draft.midi_velocity = data(2);
+            "note_off"
+        }
+        0xB0 => {
+            draft.midi_controller = data(1);
+            draft.midi_value = data(2);
+            "cc"
+        }
+        0xC0 => {
+            draft.midi_value = data(1);
+            "pc"
+        }
+        // Clock, sysex and the rest aren't learnable, keep waiting
+        _ => return false,
+    };
+    draft.midi_type = Some(kind.to_string());
+
+    let result = MidiLearnResult {
+        kind: kind.to_string(),
+        channel,
+        number: data(1),
+        value: if kind == "pc" { None } else { data(2) },
+        raw: message.to_vec(),
+        draft,
+    };
+    if let Some(Slot { tx, .. }) = slot.take() {
+        let _ = tx.send(result);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiring_an_old_learn_keeps_the_new_one() {
+        let first = arm_midi();
+        let second = arm_midi();
+        expire_midi(first.id);
+        assert!(MIDI_LEARN.lock().unwrap().is_some());
+        expire_midi(second.id);
+        assert!(MIDI_LEARN.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn cancelling_one_kind_leaves_the_other() {
+        let mut osc = arm_osc();
+        cancel_midi();
+        assert!(osc.rx.try_recv().is_err());
+        assert!(OSC_LEARN.lock().unwrap().is_some());
+        cancel_osc();
+        assert!(OSC_LEARN.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::odisc::main::{events, monitor};
+use once_cell::sync::OnceCell;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{Builder, Rotation};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::{FormatEvent, FormatFields, Writer};
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use lazy_static::lazy_static;
+
+// Every backend message goes through `tracing`. Subscribers:
+// - console (stdout), filtered by DEBUG_LOGGING / LOG_FILTER / RUST_LOG
+//   and written as plain text, journald lines or JSON (see ConsoleFormat)
+// - rotating log file in <odisc dir>/logs, info and up plus every message in
+//   and out, whatever DEBUG_LOGGING says
+// - the UI log feed (`backend-log` event), filtered like the console
+
+// Target used for every message in and out, logged at debug so the console and
+// UI only show it with DEBUG_LOGGING; see monitor::record
+pub const TRAFFIC: &str = "odisc::traffic";
+
+const LOG_FILE_PREFIX: &str = "odisc";
+const MAX_LOG_FILES: usize = 14;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConsoleFormat {
+    #[default]
+    Text,
+    // One line per event with an sd-daemon priority prefix, for systemd services
+    Journald,
+    Json,
+}
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+static CONSOLE_FILTER: OnceCell<FilterHandle> = OnceCell::new();
+static UI_FILTER: OnceCell<FilterHandle> = OnceCell::new();
+static BASE_DEBUG: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // LOG_FILTER directives currently applied, kept for set_debug_override
+    static ref DIRECTIVES: RwLock<Option<String>> = RwLock::new(None);
+    // Dropping the guard flushes the log file, see shutdown
+    static ref FILE_GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+}
+
+fn build_filter(debug: bool, directives: Option<&str>, quiet_traffic: bool) -> EnvFilter {
+    let mut spec = String::from(if debug { "debug" } else { "info" });
+    if let Ok(env) = std::env::var("RUST_LOG") {
+        let _ = write!(spec, ",{env}");
+    }
+    if let Some(directives) = directives.filter(|d| !d.trim().is_empty()) {
+        let _ = write!(spec, ",{directives}");
+    }
+    if quiet_traffic {
+        // The monitor stream already covers traffic for the UI
+        let _ = write!(spec, ",{TRAFFIC}=off");
+    }
+    EnvFilter::try_new(&spec).unwrap_or_else(|e| {
+        eprintln!("Invalid log filter '{spec}': {e}");
+        EnvFilter::new("info")
+    })
+}
+
+// The show log: traffic and errors, always
+fn file_filter() -> EnvFilter {
+    EnvFilter::new(format!("info,{TRAFFIC}=debug"))
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={value}", field.name());
+        }
+    }
+}
+
+// Errors also go to the traffic monitor
+struct MonitorLayer;
+
+impl<S: Subscriber> Layer<S> for MonitorLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        monitor::record_error(format!("{}{}", visitor.message, visitor.fields));
+    }
+}
+
+// `<priority>target: message fields`; journald strips the prefix and sets the
+// priority from it, and adds its own timestamps
+struct JournaldFormat;
+
+impl<S, N> FormatEvent<S, N> for JournaldFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let priority = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        write!(writer, "<{priority}>{}: ", event.metadata().target())?;
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+// Forwards events to the UI as the `backend-log` feed
+struct UiLayer;
+
+impl<S: Subscriber> Layer<S> for UiLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if !events::has_sinks() {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let prefix = match *event.metadata().level() {
+            Level::ERROR => "❌",
+            Level::WARN => "⚠️",
+            _ => "📥",
+        };
+        events::emit(
+            "backend-log",
+            format!("{prefix} {}{}", visitor.message, visitor.fields),
+        );
+    }
+}
+
+// Installs the global subscriber. Safe to call once; later calls are ignored.
+pub fn init(log_dir: Option<&Path>, console: ConsoleFormat) {
+    let (console_filter, console_handle) = reload::Layer::new(build_filter(false, None, false));
+    let (ui_filter, ui_handle) = reload::Layer::new(build_filter(false, None, true));
+
+    let console_layer = match console {
+        ConsoleFormat::Text => fmt::layer().with_filter(console_filter).boxed(),
+        ConsoleFormat::Journald => fmt::layer()
+            .with_ansi(false)
+            .event_format(JournaldFormat)
+            .with_filter(console_filter)
+            .boxed(),
+        ConsoleFormat::Json => fmt::layer().json().with_filter(console_filter).boxed(),
+    };
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![
+        console_layer,
+        UiLayer.with_filter(ui_filter).boxed(),
+        MonitorLayer.with_filter(LevelFilter::ERROR).boxed(),
+    ];
+
+    if let Some(dir) = log_dir {
+        // The appender scans the directory for old files, so it has to exist
+        let _ = std::fs::create_dir_all(dir);
+        let appender = Builder::new()
+            .rotation(Rotation::DAILY)
+            .filename_prefix(LOG_FILE_PREFIX)
+            .filename_suffix("log")
+            .max_log_files(MAX_LOG_FILES)
+            .build(dir);
+        match appender {
+            Ok(appender) => {
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                *FILE_GUARD.lock().unwrap() = Some(guard);
+                layers.push(
+                    fmt::layer()
+                        .with_writer(writer)
+                        .with_ansi(false)
+                        .with_filter(file_filter())
+                        .boxed(),
+                );
+            }
+            Err(e) => eprintln!("Could not open log directory {dir:?}: {e}"),
+        }
+    }
+
+    if tracing_subscriber::registry()
+        .with(layers)
+        .try_init()
+        .is_ok()
+    {
+        let _ = CONSOLE_FILTER.set(console_handle);
+        let _ = UI_FILTER.set(ui_handle);
+    }
+}
+
+// Applies DEBUG_LOGGING and LOG_FILTER from the base config
+pub fn configure(debug: bool, directives: Option<&str>) {
+    *DIRECTIVES.write().unwrap() = directives.map(str::to_string);
+    BASE_DEBUG.store(debug, Ordering::Relaxed);
+    reload_filters(debug, directives);
+}
+
+fn reload_filters(debug: bool, directives: Option<&str>) {
+    if let Some(handle) = CONSOLE_FILTER.get() {
+        let _ = handle.reload(build_filter(debug, directives, false));
+    }
+    if let Some(handle) = UI_FILTER.get() {
+        let _ = handle.reload(build_filter(debug, directives, true));
+    }
+}
+
+// A show's DEBUG_LOGGING, or the base config's when the show has none
+pub fn set_debug_override(debug: Option<bool>) {
+    let debug = debug.unwrap_or_else(|| BASE_DEBUG.load(Ordering::Relaxed));
+    let directives = DIRECTIVES.read().unwrap().clone();
+    reload_filters(debug, directives.as_deref());
+}
+
+// Flushes the log file. Statics aren't dropped on exit, so call this last.
+pub fn shutdown() {
+    FILE_GUARD.lock().unwrap().take();
+}
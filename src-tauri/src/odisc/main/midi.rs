@@ -1,7 +1,102 @@
+use crate::odisc::main::handlers;
 use crate::odisc::main::helpers::Mapping;
-use crate::odisc::main::{custom_print, handlers, Output};
-use midir::{MidiOutput, MidiOutputConnection};
+use crate::odisc::main::monitor::{self, MonitorKind};
+use crate::odisc::main::redundancy;
+use crate::odisc::main::rtpmidi;
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection, SendError};
+use std::collections::HashSet;
 use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, warn};
+
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+enum Connection {
+    Port(MidiOutputConnection),
+    Rtp(rtpmidi::Output),
+}
+
+// Output connection that reports everything it sends to the monitor and
+// remembers which notes are still held, so a panic can release them
+pub struct MidiPort {
+    conn: Connection,
+    pub name: Arc<str>,
+    // (0-based channel, note) for every note on without a matching note off
+    active_notes: HashSet<(u8, u8)>,
+}
+
+impl MidiPort {
+    pub fn send(&mut self, message: &[u8]) -> Result<(), SendError> {
+        match &mut self.conn {
+            Connection::Port(conn) => conn.send(message)?,
+            Connection::Rtp(output) => output.send(message)?,
+        }
+        self.track_notes(message);
+        monitor::record(MonitorKind::MidiOut {
+            port: self.name.clone(),
+            bytes: message.into(),
+        });
+        Ok(())
+    }
+
+    fn track_notes(&mut self, message: &[u8]) {
+        if let [status, note, velocity] = *message {
+            let channel = status & 0x0F;
+            match status & 0xF0 {
+                0x90 if velocity > 0 => {
+                    self.active_notes.insert((channel, note));
+                }
+                // Note on with velocity 0 is a note off by convention
+                0x80 | 0x90 => {
+                    self.active_notes.remove(&(channel, note));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Note offs for every held note, then All Notes Off and All Sound Off on
+    // all 16 channels. Keeps going past send errors so one bad message can't
+    // leave the rest of the rig hanging.
+    pub fn panic(&mut self) -> Result<(), SendError> {
+        let mut result = Ok(());
+        let held: Vec<(u8, u8)> = self.active_notes.drain().collect();
+        for (channel, note) in held {
+            if let Err(e) = self.send(&[0x80 | channel, note, 0]) {
+                result = Err(e);
+            }
+        }
+        for channel in 0..16 {
+            for controller in [CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF] {
+                if let Err(e) = self.send(&[0xB0 | channel, controller, 0]) {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+// Notes still held when the port goes away (shutdown, an error on the way out,
+// a switch to another port) are released, unless the redundancy peer has
+// taken over the rig
+impl Drop for MidiPort {
+    fn drop(&mut self) {
+        if !self.active_notes.is_empty() && redundancy::is_active() {
+            panic(self, "port closed");
+        }
+    }
+}
+
+// Logs instead of failing: a panic is always a side step on the way to something else
+pub fn panic(conn_out: &mut MidiPort, reason: &str) {
+    match conn_out.panic() {
+        Ok(()) => info!(port = %conn_out.name, reason, "MIDI panic sent"),
+        Err(e) => warn!(port = %conn_out.name, reason, "MIDI panic incomplete: {e}"),
+    }
+}
 
 pub fn list_midi_devices(midi_out: &MidiOutput) -> Vec<String> {
     midi_out
@@ -11,10 +106,29 @@ pub fn list_midi_devices(midi_out: &MidiOutput) -> Vec<String> {
         .collect()
 }
 
+pub fn list_midi_inputs() -> Result<Vec<String>, Box<dyn Error>> {
+    let midi_in = MidiInput::new("MIDIInput")?;
+    Ok(midi_in
+        .ports()
+        .iter()
+        .map(|p| midi_in.port_name(p).unwrap_or_default())
+        .collect())
+}
+
 pub fn connect_to_midi_port(
     midi_out: MidiOutput,
     port_name_to_find: &str,
-) -> Result<MidiOutputConnection, Box<dyn Error>> {
+) -> Result<MidiPort, Box<dyn Error>> {
+    if port_name_to_find == rtpmidi::PORT_NAME {
+        let output = rtpmidi::output().ok_or("RTP-MIDI is not configured")?;
+        info!("Successfully connected to MIDI output port: {port_name_to_find}");
+        return Ok(MidiPort {
+            conn: Connection::Rtp(output),
+            name: port_name_to_find.into(),
+            active_notes: HashSet::new(),
+        });
+    }
+
     let out_ports = midi_out.ports();
     let port = out_ports.iter().find(|p| {
         midi_out
@@ -26,21 +140,69 @@ pub fn connect_to_midi_port(
         Some(port) => {
             let port_name = midi_out.port_name(port)?;
             let conn = midi_out.connect(port, "midir-connection")?;
-            println!("Successfully connected to MIDI output port: {port_name}");
-            Ok(conn)
+            info!("Successfully connected to MIDI output port: {port_name}");
+            Ok(MidiPort {
+                conn: Connection::Port(conn),
+                name: port_name.into(),
+                active_notes: HashSet::new(),
+            })
         }
         None => {
-            let _ = custom_print(
-                format!("No port found with name '{port_name_to_find}'"),
-                Output::AppError,
-            );
+            error!("No port found with name '{port_name_to_find}'");
             Err(format!("No port found with name '{port_name_to_find}'").into())
         }
     }
 }
 
+// Receives until dropped
+pub enum MidiInputPort {
+    Port {
+        _connection: MidiInputConnection<()>,
+    },
+    Rtp {
+        _input: rtpmidi::Input,
+    },
+}
+
+pub fn connect_to_midi_input(
+    port_name_to_find: &str,
+    tx: UnboundedSender<Vec<u8>>,
+) -> Result<MidiInputPort, Box<dyn Error>> {
+    if port_name_to_find == rtpmidi::PORT_NAME {
+        let input = rtpmidi::connect_input(tx)?;
+        info!("Successfully connected to MIDI input port: {port_name_to_find}");
+        return Ok(MidiInputPort::Rtp { _input: input });
+    }
+
+    let midi_in = MidiInput::new("MIDIInput")?;
+    let in_ports = midi_in.ports();
+    let port = in_ports.iter().find(|p| {
+        midi_in
+            .port_name(p)
+            .is_ok_and(|name| name == port_name_to_find)
+    });
+
+    match port {
+        Some(port) => {
+            let port_name = midi_in.port_name(port)?;
+            // midir calls back on its own thread; forward into the backend loop
+            let conn = midi_in.connect(
+                port,
+                "midir-input",
+                move |_stamp, message, _| {
+                    let _ = tx.send(message.to_vec());
+                },
+                (),
+            )?;
+            info!("Successfully connected to MIDI input port: {port_name}");
+            Ok(MidiInputPort::Port { _connection: conn })
+        }
+        None => Err(format!("No input port found with name '{port_name_to_find}'").into()),
+    }
+}
+
 pub fn handle_midi_message(
-    conn_out: &mut MidiOutputConnection,
+    conn_out: &mut MidiPort,
     found_map: &Mapping,
 ) -> Result<(), Box<dyn Error>> {
     match found_map.midi_type.as_deref() {
@@ -53,14 +215,11 @@ pub fn handle_midi_message(
                 let channel = (channel as u8).saturating_sub(1); // 0-based
                 let msg = [0x90 | channel, note as u8, velocity as u8];
                 conn_out.send(&msg)?;
-                let _ = custom_print(
-                    format!(
-                        "Sent MIDI note_on: ch={}, note={}, vel={}",
-                        channel + 1,
-                        note,
-                        velocity
-                    ),
-                    Output::App,
+                debug!(
+                    "Sent MIDI note_on: ch={}, note={}, vel={}",
+                    channel + 1,
+                    note,
+                    velocity
                 );
             }
         }
@@ -73,14 +232,11 @@ pub fn handle_midi_message(
                 let channel = (channel as u8).saturating_sub(1);
                 let msg = [0x80 | channel, note as u8, velocity as u8];
                 conn_out.send(&msg)?;
-                let _ = custom_print(
-                    format!(
-                        "Sent MIDI note_off: ch={}, note={}, vel={}",
-                        channel + 1,
-                        note,
-                        velocity
-                    ),
-                    Output::App,
+                debug!(
+                    "Sent MIDI note_off: ch={}, note={}, vel={}",
+                    channel + 1,
+                    note,
+                    velocity
                 );
             }
         }
@@ -93,14 +249,11 @@ pub fn handle_midi_message(
                 let channel = (channel as u8).saturating_sub(1);
                 let msg = [0xB0 | channel, controller as u8, value as u8];
                 conn_out.send(&msg)?;
-                let _ = custom_print(
-                    format!(
-                        "Sent MIDI CC: ch={}, controller={}, value={}",
-                        channel + 1,
-                        controller,
-                        value
-                    ),
-                    Output::App,
+                debug!(
+                    "Sent MIDI CC: ch={}, controller={}, value={}",
+                    channel + 1,
+                    controller,
+                    value
                 );
             }
         }
@@ -110,30 +263,28 @@ pub fn handle_midi_message(
                 let channel = (channel as u8).saturating_sub(1);
                 let msg = [0xC0 | channel, value as u8];
                 conn_out.send(&msg)?;
-                let _ = custom_print(
-                    format!(
-                        "Sent MIDI Program Change: ch={}, program={}",
-                        channel + 1,
-                        value
-                    ),
-                    Output::App,
+                debug!(
+                    "Sent MIDI Program Change: ch={}, program={}",
+                    channel + 1,
+                    value
                 );
             }
         }
         Some("qc_preset") => {
-            let pgm = handlers::send_qc_preset(
-                found_map.qc_preset_id.as_ref().unwrap(),
-                &found_map.setlist.unwrap(),
-                &found_map.midi_channel.unwrap(),
-            );
-
-            let channel = found_map.midi_channel.unwrap() as u8 - 1;
-            let setlist: u8 = found_map.setlist.unwrap() as u8;
-
-            // Send all three messages without delay - MIDI is fast enough
-            conn_out.send(&[0xB0 | channel, 0, 0])?;
-            conn_out.send(&[0xB0 | channel, 32, setlist])?;
-            conn_out.send(&[0xC0 | channel, pgm.unwrap() as u8])?;
+            if let (Some(preset_id), Some(setlist), Some(channel)) = (
+                found_map.qc_preset_id.as_ref(),
+                found_map.setlist,
+                found_map.midi_channel,
+            ) {
+                if let Some(pgm) = handlers::send_qc_preset(preset_id, &setlist, &channel) {
+                    let channel = (channel as u8).saturating_sub(1);
+
+                    // Send all three messages without delay - MIDI is fast enough
+                    conn_out.send(&[0xB0 | channel, 0, 0])?;
+                    conn_out.send(&[0xB0 | channel, 32, setlist as u8])?;
+                    conn_out.send(&[0xC0 | channel, pgm as u8])?;
+                }
+            }
         }
         Some("gt1000_preset") => {
             if let (Some(preset_id), Some(channel)) =
@@ -1,194 +1,967 @@
+pub mod access;
+pub mod args;
+pub mod behavior;
+pub mod control;
+pub mod dmx;
+pub mod editor;
+pub mod events;
+pub mod formats;
 mod handlers;
-mod helpers;
-mod midi;
-use crate::get_app_handle;
+pub mod helpers;
+pub mod http_api;
+pub mod learn;
+pub mod logging;
+pub mod midi;
+pub mod monitor;
+pub mod mqtt;
+pub mod msc;
+pub mod oscquery;
+pub mod redundancy;
+pub mod rtpmidi;
+pub mod scale;
+pub mod script;
+pub mod shows;
+pub mod transport;
+pub mod vars;
+pub mod websocket;
+use control::ControlCommand;
 use midir::MidiOutput;
-use rosc::OscPacket;
+use rosc::{OscMessage, OscPacket, OscType};
+use serde::Serialize;
 use serde_json::json;
-use std::sync::Arc;
-use tauri::{AppHandle, Emitter};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::signal;
+use tokio::sync::mpsc::{self, unbounded_channel};
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+use tracing::{debug, error, info, instrument, trace, warn};
 
 use lazy_static::lazy_static;
 use std::sync::RwLock;
 
+// Decoded packets waiting for the backend loop, across all listeners
+const INBOUND_QUEUE: usize = 1024;
+
 lazy_static! {
     static ref MAPPINGS: RwLock<Arc<Vec<helpers::Mapping>>> = RwLock::new(Arc::new(Vec::new()));
-    static ref DEBUG_LOGGING: RwLock<bool> = RwLock::new(false);
+    static ref ACTIVE_SHOW: RwLock<Option<String>> = RwLock::new(None);
+    static ref ACTIVE_LAYER: RwLock<Option<String>> = RwLock::new(None);
+    // Root mappings file given on the command line, used when no show is active
+    static ref MAPPINGS_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
+    // Rows switched off at runtime; cleared whenever the table changes
+    static ref DISABLED_ROWS: RwLock<HashSet<usize>> = RwLock::new(HashSet::new());
+    // Set while the backend runs, from the config it is connected with
+    static ref CONNECTIONS: RwLock<Option<Connections>> = RwLock::new(None);
+    // Delayed script output and steps not yet due, see send_later
+    static ref PENDING: Mutex<Vec<AbortHandle>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Connections {
+    pub midi_output: String,
+    pub midi_input: Option<String>,
+    pub osc_listeners: Vec<serde_json::Value>,
+    pub osc_send: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EngineStatus {
+    pub running: bool,
+    pub show: Option<String>,
+    pub layer: Option<String>,
+    pub mappings: usize,
+    pub disabled_mappings: Vec<usize>,
+    // false on a standby redundancy instance
+    pub active: bool,
+    pub connections: Option<Connections>,
+    // Monitor events per type since start, e.g. osc_in, mapping_matched
+    pub counters: BTreeMap<&'static str, u64>,
+    pub rejected: Vec<access::RejectedCount>,
+}
+
+// Where the engine reads its files from. Defaults to the odisc data directory.
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    pub config_path: Option<PathBuf>,
+    pub mappings_path: Option<PathBuf>,
+}
+
+pub fn load_and_log_mappings(
+    mappings_path: std::path::PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mappings = formats::load_mappings(mappings_path)?;
+    store_mappings(mappings);
+    debug!("Mappings loaded!");
+    Ok(())
+}
+
+// Re-reads the active mappings file. Notes the old table left sounding are
+// released by the running backend, if there is one.
+pub fn reload_mappings() -> Result<(), Box<dyn std::error::Error>> {
+    load_and_log_mappings(active_mappings_path()?)?;
+    info!("Reloaded mappings");
+    let _ = control::send(ControlCommand::Panic { reply: None });
+    Ok(())
+}
+
+pub fn set_mappings(mappings: Vec<helpers::Mapping>) {
+    store_mappings(mappings);
+    debug!("Mappings updated!");
+}
+
+pub fn mappings() -> Arc<Vec<helpers::Mapping>> {
+    MAPPINGS.read().unwrap().clone()
+}
+
+fn store_mappings(mappings: Vec<helpers::Mapping>) {
+    let count = mappings.len();
+    // Steps and script output of the old table don't fire into the new one
+    cancel_pending();
+    script::load(&mappings);
+    vars::load_conditions(&mappings);
+    behavior::clear_rows();
+    *MAPPINGS.write().unwrap() = Arc::new(mappings);
+    DISABLED_ROWS.write().unwrap().clear();
+    events::emit("mappings-changed", count);
+    oscquery::path_changed();
+}
+
+pub fn disabled_mappings() -> Vec<usize> {
+    let mut rows: Vec<usize> = DISABLED_ROWS.read().unwrap().iter().copied().collect();
+    rows.sort_unstable();
+    rows
+}
+
+pub fn set_mapping_enabled(row: usize, enabled: bool) -> Result<(), String> {
+    let count = MAPPINGS.read().unwrap().len();
+    if row >= count {
+        return Err(format!("No mapping at row {row}, there are {count}"));
+    }
+    {
+        let mut disabled = DISABLED_ROWS.write().unwrap();
+        let changed = if enabled {
+            disabled.remove(&row)
+        } else {
+            disabled.insert(row)
+        };
+        if !changed {
+            return Ok(());
+        }
+    }
+    info!(row, enabled, "Mapping toggled");
+    events::emit("mappings-disabled", disabled_mappings());
+    Ok(())
+}
+
+pub fn engine_status() -> EngineStatus {
+    let connections = CONNECTIONS.read().unwrap().clone();
+    EngineStatus {
+        running: connections.is_some(),
+        show: active_show(),
+        layer: active_layer(),
+        mappings: MAPPINGS.read().unwrap().len(),
+        disabled_mappings: disabled_mappings(),
+        active: redundancy::is_active(),
+        connections,
+        counters: monitor::counters(),
+        rejected: access::rejected(),
+    }
+}
+
+pub fn active_show() -> Option<String> {
+    ACTIVE_SHOW.read().unwrap().clone()
+}
+
+pub fn active_layer() -> Option<String> {
+    ACTIVE_LAYER.read().unwrap().clone()
+}
+
+pub fn set_active_layer(layer: Option<String>) {
+    // An empty song name (e.g. Ableset between songs) means no song layer
+    let layer = layer.filter(|l| !l.is_empty());
+    {
+        let mut active = ACTIVE_LAYER.write().unwrap();
+        if *active == layer {
+            return;
+        }
+        active.clone_from(&layer);
+    }
+    info!(
+        "Active layer: {}",
+        layer.as_deref().unwrap_or("(global only)")
+    );
+    events::emit("layer-changed", layer);
+}
+
+pub fn active_mappings_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let show = active_show();
+    if show.is_none() {
+        if let Some(path) = MAPPINGS_OVERRIDE.read().unwrap().clone() {
+            return Ok(path);
+        }
+    }
+    shows::mappings_path(show.as_deref())
+}
+
+// Swaps in a fully loaded show. The mappings table is replaced in one write,
+// so packets in flight see either the old show or the new one, never a mix.
+pub fn activate_show(show: shows::Show) -> shows::ShowOverrides {
+    store_mappings(show.mappings);
+    *ACTIVE_SHOW.write().unwrap() = Some(show.name.clone());
+    logging::set_debug_override(show.overrides.debug_logging);
+    info!("Show loaded: {}", show.name);
+    events::emit("show-loaded", show.name);
+    show.overrides
+}
+
+// Also keeps engine_status() current, so call it after every (re)connect
+fn emit_network_data(config: &helpers::Config) {
+    let listeners: Vec<_> = config
+        .listeners()
+        .into_iter()
+        .map(|l| json!({ "name": l.name, "address": l.address, "port": l.port, "transport": l.transport }))
+        .collect();
+    *CONNECTIONS.write().unwrap() = Some(Connections {
+        midi_output: config.midi_output_name.clone(),
+        midi_input: (!config.midi_input_name.is_empty()).then(|| config.midi_input_name.clone()),
+        osc_listeners: listeners.clone(),
+        osc_send: format!("{}:{}", config.osc_send_host, config.osc_send_port),
+    });
+    let network_payload = json!({
+        "osc_listen_port": config.osc_listen_port.to_string(),
+        "osc_listeners": listeners,
+        "osc_send_port": config.osc_send_port.to_string(),
+        "osc_send_host": config.osc_send_host,
+    });
+    events::emit("network-data", network_payload.to_string());
+}
+
+// A standby instance stays silent, panics included: the active one may be
+// holding notes on the same rig
+fn release_notes(conn_out: &mut midi::MidiPort, reason: &str) {
+    // Whatever was still due would start notes again
+    cancel_pending();
+    if redundancy::is_active() {
+        midi::panic(conn_out, reason);
+    }
+}
+
+#[instrument(skip(base_config, config, conn_out))]
+fn switch_show(
+    name: &str,
+    base_config: &helpers::Config,
+    config: &mut helpers::Config,
+    conn_out: &mut midi::MidiPort,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let show = shows::load_show(name)?;
+    // Nothing triggered by the old show may keep sounding, and the old port is
+    // released before a reconnect below
+    release_notes(conn_out, "show change");
+    let overrides = activate_show(show);
+    let mut new_config = overrides.apply(base_config);
+
+    if new_config.midi_output_name != config.midi_output_name {
+        if let Err(e) = connect_midi_output(&new_config.midi_output_name, conn_out) {
+            // Keep the current port rather than going silent mid-show
+            error!("Error connecting to MIDI port, keeping current: {e}");
+            new_config.midi_output_name = config.midi_output_name.clone();
+        }
+    }
+
+    *config = new_config;
+    emit_network_data(config);
+    Ok(())
 }
 
-pub enum Output {
-    Console,
-    // Error,
-    App,
-    AppError,
+// Replaces the port only once the new one is open
+fn connect_midi_output(
+    name: &str,
+    conn_out: &mut midi::MidiPort,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let midi_out = MidiOutput::new("MIDIOutput")?;
+    *conn_out = midi::connect_to_midi_port(midi_out, name)?;
+    info!("MIDI device connected: {name}");
+    Ok(())
+}
+
+fn status_message(config: &helpers::Config) -> OscMessage {
+    OscMessage {
+        addr: control::STATUS_ADDRESS.to_string(),
+        args: vec![
+            OscType::String(active_show().unwrap_or_default()),
+            OscType::String(active_layer().unwrap_or_default()),
+            OscType::String(config.midi_output_name.clone()),
+            OscType::Int(MAPPINGS.read().unwrap().len() as i32),
+            OscType::Int(i32::from(redundancy::is_active())),
+        ],
+    }
 }
 
-pub fn custom_print(msg: String, type_output: Output) -> Result<(), Box<dyn std::error::Error>> {
-    let debug_enabled = *DEBUG_LOGGING.read().unwrap(); // Use .read() not .lock()
+// Sends a matched (or triggered) mapping's OSC and MIDI
+async fn fire_mapping(
+    row: usize,
+    address: &str,
+    args: &[OscType],
+    mapping: &helpers::Mapping,
+    config: &helpers::Config,
+    osc_out: &mut transport::OscSender,
+    conn_out: &mut midi::MidiPort,
+) {
+    monitor::record(monitor::MonitorKind::MappingMatched {
+        row,
+        address: address.to_string(),
+    });
+
+    // Toggles, cycles and counters move on, standby included
+    let stepped = behavior::apply(row, mapping).unwrap_or_else(|e| {
+        error!("Error stepping behavior: {e}");
+        None
+    });
+    let mapping = stepped.as_ref().unwrap_or(mapping);
+    let scaled = scale::apply(mapping, args).unwrap_or_else(|e| {
+        error!("Error scaling row {row}: {e}");
+        None
+    });
+    let mapping = scaled.as_ref().unwrap_or(mapping);
+
+    // A standby instance keeps its variables in step, ready to take over
+    if let Some(sets) = mapping.set_vars.as_deref().filter(|s| !s.is_empty()) {
+        if let Err(e) = vars::apply(sets, args) {
+            error!("Error setting variables: {e}");
+        }
+    }
 
-    match type_output {
-        Output::Console => {
-            if debug_enabled {
-                println!("{msg}");
+    // Scripts go first, they may hold back the rest. A standby instance runs
+    // them too, so their state and variables are in step, but drops their output.
+    let outcome = mapping
+        .script
+        .as_deref()
+        .filter(|s| !s.is_empty())
+        .and_then(|name| {
+            let time_limit = Duration::from_millis(config.script_time_limit_ms);
+            match script::run(name, row, address, args, time_limit) {
+                Ok(outcome) => Some((name, outcome)),
+                Err(e) => {
+                    error!("{e}");
+                    None
+                }
             }
-            Ok(())
+        });
+
+    if !redundancy::is_active() {
+        trace!("Standby, output suppressed");
+        return;
+    }
+
+    if let Some((name, outcome)) = outcome {
+        for action in outcome.actions {
+            send_script_action(action, config, osc_out, conn_out).await;
         }
-        Output::App => {
-            if debug_enabled {
-                if let Some(app_handle) = get_app_handle() {
-                    app_handle.emit("backend-log", format!("📥 {}", &msg))?;
-                    println!("{msg}");
+        if !outcome.pass {
+            trace!("Script '{name}' skipped the mapping's output");
+            return;
+        }
+    }
+
+    send_outputs(mapping, args, config, osc_out, conn_out).await;
+
+    // Further steps, each delayed from the match
+    match formats::step_mappings(mapping) {
+        Ok(steps) => {
+            for (delay, step) in steps {
+                if delay.is_zero() {
+                    send_outputs(&step, args, config, osc_out, conn_out).await;
                 } else {
-                    eprintln!("App handle not set!");
+                    send_later(
+                        delay,
+                        ControlCommand::SendOutputs {
+                            mapping: Box::new(step),
+                            args: args.to_vec(),
+                        },
+                    );
                 }
             }
-            Ok(())
         }
-        Output::AppError => {
-            if let Some(app_handle) = get_app_handle() {
-                app_handle.emit("backend-log", format!("❌ {}", &msg))?;
-                eprintln!("{msg}");
-            } else {
-                eprintln!("App handle not set!");
+        Err(e) => error!("Error reading steps of row {row}: {e}"),
+    }
+}
+
+// A mapping's OSC, MIDI, DMX and MSC output
+async fn send_outputs(
+    mapping: &helpers::Mapping,
+    args: &[OscType],
+    config: &helpers::Config,
+    osc_out: &mut transport::OscSender,
+    conn_out: &mut midi::MidiPort,
+) {
+    // Handle outgoing OSC
+    if let Some(addr) = mapping.osc_out_address.as_deref().filter(|a| !a.is_empty()) {
+        let transport = mapping
+            .osc_out_transport
+            .as_deref()
+            .filter(|t| !t.is_empty())
+            .map(transport::Transport::from_name)
+            .transpose();
+        match transport {
+            Ok(transport) => {
+                if let Err(e) = handlers::outgoing_osc_handler(
+                    osc_out,
+                    addr,
+                    mapping.osc_out_args.as_deref(),
+                    &config.osc_send_host,
+                    &config.osc_send_port,
+                    transport,
+                )
+                .await
+                {
+                    error!("Error sending OSC message: {e}");
+                }
             }
-            Ok(())
+            Err(e) => error!("Error sending OSC message: {e}"),
+        }
+    }
+
+    // Handle MIDI message
+    if let Err(e) = midi::handle_midi_message(conn_out, mapping) {
+        error!("Error sending MIDI message: {e}");
+    }
+
+    // Handle DMX levels
+    if let Some(values) = mapping.dmx_values.as_deref().filter(|v| !v.is_empty()) {
+        let universe = mapping.dmx_universe.unwrap_or_default();
+        if let Err(e) = dmx::apply(universe, values, mapping.dmx_fade, args) {
+            error!("Error sending DMX: {e}");
+        }
+    }
+
+    // Handle MIDI Show Control
+    if mapping
+        .msc_command
+        .as_deref()
+        .is_some_and(|c| !c.is_empty())
+    {
+        match msc::build(mapping, args) {
+            Ok(sysex) => {
+                if let Err(e) = conn_out.send(&sysex) {
+                    error!("Error sending MSC message: {e}");
+                }
+            }
+            Err(e) => error!("Error building MSC message: {e}"),
         }
     }
 }
 
-pub fn load_and_log_mappings(
-    mappings_path: std::path::PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mappings = helpers::load_mappings_from_csv(mappings_path)?;
-    let mut mappings_guard = MAPPINGS.write().unwrap();
-    *mappings_guard = Arc::new(mappings);
-    let _ = custom_print("Mappings loaded!".to_string(), Output::App);
-    Ok(())
+// Sends an OSC message as built, to OSC_SEND_HOST:OSC_SEND_PORT
+async fn send_osc_message(
+    msg: OscMessage,
+    config: &helpers::Config,
+    osc_out: &mut transport::OscSender,
+) {
+    let destination = format!("{}:{}", config.osc_send_host, config.osc_send_port);
+    let packet = match rosc::encoder::encode(&OscPacket::Message(msg.clone())) {
+        Ok(packet) => packet,
+        Err(e) => {
+            error!("Could not encode OSC message: {e:?}");
+            return;
+        }
+    };
+    match osc_out.send(&destination, &packet).await {
+        Ok(()) => monitor::record(monitor::MonitorKind::OscOut {
+            destination,
+            address: msg.addr,
+            args: msg.args,
+        }),
+        Err(e) => error!("Error sending OSC message: {e}"),
+    }
+}
+
+// Delayed output comes back through the control channel when it's due,
+// unless cancel_pending() runs first
+fn send_later(delay: Duration, cmd: ControlCommand) {
+    let task = tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let _ = control::send(cmd);
+    });
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|task| !task.is_finished());
+    pending.push(task.abort_handle());
+}
+
+fn cancel_pending() {
+    for task in PENDING.lock().unwrap().drain(..) {
+        task.abort();
+    }
 }
 
-pub async fn backend(app_handle: AppHandle) -> Result<(), Box<dyn std::error::Error>> {
-    // Check/create files
-    let (mappings_path, config_path) = helpers::ensure_files()?;
+async fn send_script_action(
+    action: script::Action,
+    config: &helpers::Config,
+    osc_out: &mut transport::OscSender,
+    conn_out: &mut midi::MidiPort,
+) {
+    if !action.delay.is_zero() {
+        send_later(
+            action.delay,
+            match action.output {
+                script::Output::Osc(msg) => ControlCommand::SendOsc(msg),
+                script::Output::Midi(bytes) => ControlCommand::SendMidi(bytes),
+            },
+        );
+        return;
+    }
+    match action.output {
+        script::Output::Osc(msg) => send_osc_message(msg, config, osc_out).await,
+        script::Output::Midi(bytes) => {
+            if let Err(e) = conn_out.send(&bytes) {
+                error!("Error sending MIDI message: {e}");
+            }
+        }
+    }
+}
 
-    // Load mappings
-    if let Err(e) = load_and_log_mappings(mappings_path) {
-        let _ = custom_print(format!("Error loading mappings: {e}"), Output::AppError);
-        return Err(e);
+// Returns the answer for Status and Ping, to be sent back to the requester
+async fn apply_control(
+    cmd: ControlCommand,
+    base_config: &helpers::Config,
+    config: &mut helpers::Config,
+    osc_out: &mut transport::OscSender,
+    conn_out: &mut midi::MidiPort,
+) -> Option<OscMessage> {
+    let reply_with = |reply: control::Reply, result: Result<(), String>| {
+        if let Err(e) = &result {
+            error!("{e}");
+        }
+        if let Some(reply) = reply {
+            let _ = reply.send(result);
+        }
     };
+    match cmd {
+        ControlCommand::LoadShow { name, reply } => {
+            let result = switch_show(&name, base_config, config, conn_out)
+                .map_err(|e| format!("Failed to load show '{name}': {e}"));
+            reply_with(reply, result);
+        }
+        ControlCommand::SetLayer(layer) => set_active_layer(layer),
+        ControlCommand::Panic { reply } => {
+            release_notes(conn_out, "requested");
+            if let Some(reply) = reply {
+                let _ = reply.send(Ok(()));
+            }
+        }
+        ControlCommand::ReloadMappings { reply } => {
+            let result = reload_mappings().map_err(|e| format!("Failed to reload mappings: {e}"));
+            reply_with(reply, result);
+        }
+        ControlCommand::SetMappingEnabled { row, enabled } => {
+            if let Err(e) = set_mapping_enabled(row, enabled) {
+                warn!("{e}");
+            }
+        }
+        ControlCommand::SetMidiOutput { name, reply } => {
+            let result = if name == config.midi_output_name {
+                Ok(())
+            } else {
+                release_notes(conn_out, "MIDI output change");
+                connect_midi_output(&name, conn_out)
+                    .map(|()| {
+                        config.midi_output_name = name.clone();
+                        emit_network_data(config);
+                    })
+                    .map_err(|e| format!("Failed to switch MIDI output to '{name}': {e}"))
+            };
+            reply_with(reply, result);
+        }
+        ControlCommand::SendMidi(bytes) => {
+            if redundancy::is_active() {
+                if let Err(e) = conn_out.send(&bytes) {
+                    error!("Error sending MIDI message: {e}");
+                }
+            }
+        }
+        ControlCommand::SendOsc(msg) => {
+            if redundancy::is_active() {
+                send_osc_message(msg, config, osc_out).await;
+            }
+        }
+        ControlCommand::SendOutputs { mapping, args } => {
+            if redundancy::is_active() {
+                send_outputs(&mapping, &args, config, osc_out, conn_out).await;
+            }
+        }
+        ControlCommand::ResetBehaviors(target) => {
+            let mappings = MAPPINGS.read().unwrap().clone();
+            behavior::reset(target, &mappings);
+        }
+        ControlCommand::Trigger { row, reply } => {
+            let mapping = MAPPINGS.read().unwrap().get(row).cloned();
+            let result = match mapping {
+                Some(_) if DISABLED_ROWS.read().unwrap().contains(&row) => {
+                    Err(format!("Mapping at row {row} is disabled"))
+                }
+                Some(mapping) => {
+                    fire_mapping(
+                        row,
+                        &mapping.osc_in_address,
+                        &[],
+                        &mapping,
+                        config,
+                        osc_out,
+                        conn_out,
+                    )
+                    .await;
+                    Ok(())
+                }
+                None => Err(format!("No mapping at row {row}")),
+            };
+            reply_with(reply, result);
+        }
+        ControlCommand::Status => return Some(status_message(config)),
+        ControlCommand::Ping(args) => {
+            return Some(OscMessage {
+                addr: control::PONG_ADDRESS.to_string(),
+                args,
+            })
+        }
+    }
+    None
+}
+
+// Answers go back from the UDP socket the request came in on, so they reach
+// the sender's source port. TCP and WebSocket senders don't get one.
+fn send_reply(socket: Option<&UdpSocket>, to: SocketAddr, msg: OscMessage) {
+    let Some(socket) = socket else {
+        debug!("No UDP socket to answer {} on", msg.addr);
+        return;
+    };
+    match rosc::encoder::encode(&OscPacket::Message(msg)) {
+        Ok(packet) => {
+            if let Err(e) = socket.try_send_to(&packet, to) {
+                warn!(%to, "Could not send reply: {e}");
+            }
+        }
+        Err(e) => error!("Could not encode reply: {e:?}"),
+    }
+}
+
+// Runs until `shutdown` fires. `ready` is signalled once the socket
+// and MIDI port are open; both are released when this returns.
+#[instrument(name = "backend", skip_all)]
+pub async fn backend(
+    options: EngineOptions,
+    mut shutdown: oneshot::Receiver<()>,
+    ready: oneshot::Sender<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Default files, only for what wasn't given explicitly
+    let explicit_config = options.config_path.is_some();
+    let config_path = match options.config_path {
+        Some(path) => path,
+        None => helpers::ensure_config()?,
+    };
+    if options.mappings_path.is_none() {
+        helpers::ensure_mappings()?;
+    }
+    *MAPPINGS_OVERRIDE.write().unwrap() = options.mappings_path.clone();
 
     // Initialize MIDI
     let midi_out = MidiOutput::new("MIDIOutput")?;
     let midi_outputs_list = midi::list_midi_devices(&midi_out);
-    let _ = custom_print("Available MIDI devices:".to_string(), Output::App);
+    debug!("Available MIDI devices:");
     for (i, name) in midi_outputs_list.iter().enumerate() {
-        let _ = custom_print(format!("{i}: {name}"), Output::App);
+        debug!("{i}: {name}");
     }
 
     // Load config
-    let config = helpers::read_config(config_path.to_str().unwrap(), midi_outputs_list)?;
+    let base_config = helpers::read_config(
+        config_path
+            .to_str()
+            .ok_or("Config path is not valid UTF-8")?,
+        midi_outputs_list,
+        !explicit_config,
+    )
+    .map_err(|e| format!("Failed to read config {config_path:?}: {e}"))?;
+    let mut config = base_config.clone();
 
-    // Set debug logging flag
-    {
-        let mut debug = DEBUG_LOGGING.write().unwrap();
-        *debug = config.debug_logging;
+    // Apply log levels and per-module filters
+    logging::configure(config.debug_logging, config.log_filter.as_deref());
+
+    // Load mappings, from the selected show if there is one. An explicit
+    // mappings file wins over the show saved in the config.
+    let show_name = active_show().or_else(|| {
+        options
+            .mappings_path
+            .is_none()
+            .then(|| base_config.active_show.clone())
+            .flatten()
+    });
+    match show_name {
+        Some(name) => match shows::load_show(&name) {
+            Ok(show) => config = activate_show(show).apply(&base_config),
+            Err(e) => {
+                error!("Error loading show '{name}': {e}");
+                return Err(e);
+            }
+        },
+        None => {
+            if let Err(e) = active_mappings_path().and_then(load_and_log_mappings) {
+                error!("Error loading mappings: {e}");
+                return Err(e);
+            };
+        }
     }
 
-    println!("{config:?}");
+    debug!(?config, "Config loaded");
+    let _variables = if config.persist_variables {
+        Some(vars::start())
+    } else {
+        None
+    };
+    let _redundancy = match &base_config.redundancy {
+        Some(redundancy_config) => Some(redundancy::start(redundancy_config).await?),
+        None => None,
+    };
+    // Create OSC listeners
+    let (inbound_tx, mut inbound_rx) = mpsc::channel::<transport::Inbound>(INBOUND_QUEUE);
+    let listener_configs = config.listeners();
+    let mut listeners = Vec::with_capacity(listener_configs.len());
+    for (i, listener_config) in listener_configs.iter().enumerate() {
+        if listener_configs[..i]
+            .iter()
+            .any(|l| l.name == listener_config.name)
+        {
+            return Err(format!("Duplicate OSC listener name '{}'", listener_config.name).into());
+        }
+        let listener = transport::listen(listener_config, inbound_tx.clone())
+            .await
+            .map_err(|e| format!("OSC listener '{}': {e}", listener_config.name))?;
+        debug!(
+            "OSC listener '{}' on {}:{} ({:?})",
+            listener_config.name,
+            listener_config.address,
+            listener_config.port,
+            listener_config.transport
+        );
+        listeners.push(listener);
+    }
+    let _websocket = match &config.websocket {
+        Some(websocket_config) => {
+            Some(websocket::start(websocket_config, inbound_tx.clone()).await?)
+        }
+        None => None,
+    };
+    let _mqtt = match &config.mqtt {
+        Some(mqtt_config) => Some(mqtt::start(mqtt_config, inbound_tx.clone())?),
+        None => None,
+    };
+    let _http_api = match &config.http_api {
+        Some(http_config) => Some(http_api::start(http_config, inbound_tx.clone()).await?),
+        None => None,
+    };
+    // Incoming MIDI Show Control goes through the same path as OSC
+    let msc_inbound = inbound_tx.clone();
+    let msc_listener: Arc<str> = Arc::from(msc::LISTENER);
+    drop(inbound_tx);
+
+    let _oscquery = match &config.oscquery {
+        Some(oscquery_config) => Some(oscquery::start(oscquery_config, &config).await?),
+        None => None,
+    };
 
-    // Create OSC listener
-    let addr = format!("0.0.0.0:{}", config.osc_listen_port);
-    let sock = UdpSocket::bind(addr).await?;
-    let mut buf = [0u8; 2048];
-    let _ = custom_print(
-        format!("OSC server listening on port {}", &config.osc_listen_port),
-        Output::App,
-    );
-    let _ = custom_print(
-        format!(
-            "OSC server sending on {}:{}",
-            &config.osc_send_host, &config.osc_send_port
-        ),
-        Output::App,
+    let _dmx = match &config.dmx {
+        Some(dmx_config) => Some(dmx::start(dmx_config).await?),
+        None => None,
+    };
+
+    // Outgoing UDP shares the first plain IPv4 UDP listen socket, as before
+    let shared = listeners
+        .iter()
+        .filter_map(|l| l.udp.as_ref())
+        .find(|sock| {
+            sock.local_addr()
+                .is_ok_and(|a| a.ip() == std::net::Ipv4Addr::UNSPECIFIED)
+        });
+    let udp = match shared {
+        Some(sock) => sock.clone(),
+        None => Arc::new(UdpSocket::bind("0.0.0.0:0").await?),
+    };
+    let mut osc_out = transport::OscSender::new(udp, config.osc_send_transport);
+    osc_out.open(&format!(
+        "{}:{}",
+        config.osc_send_host, config.osc_send_port
+    ));
+    debug!(
+        "OSC server sending on {}:{} ({:?})",
+        &config.osc_send_host, &config.osc_send_port, config.osc_send_transport
     );
 
+    // Before the MIDI ports, which may select it
+    let _rtp_midi = match &config.rtp_midi {
+        Some(rtp_config) => Some(rtpmidi::start(rtp_config).await?),
+        None => None,
+    };
+
     // Connect to the chosen MIDI port
     let mut conn_out = match midi::connect_to_midi_port(midi_out, &config.midi_output_name) {
         Ok(conn) => conn,
         Err(e) => {
-            let _ = custom_print(format!("Error connecting to MIDI port: {e}"), Output::App);
-            return Ok(());
+            error!("Error connecting to MIDI port: {e}");
+            return Err(e);
         }
     };
-    let _ = custom_print(
-        format!("MIDI device connected: {}", &config.midi_output_name),
-        Output::App,
-    );
+    debug!("MIDI device connected: {}", &config.midi_output_name);
 
-    let network_payload = json!({
-        "osc_listen_port": config.osc_listen_port.to_string(),
-        "osc_send_port": config.osc_send_port.to_string(),
-        "osc_send_host": config.osc_send_host,
-    });
-    app_handle.emit("network-data", network_payload.to_string())?;
+    emit_network_data(&config);
+
+    // Optional MIDI input, used to switch shows by program change
+    let (midi_in_tx, mut midi_in_rx) = unbounded_channel::<Vec<u8>>();
+    let _midi_in = if config.midi_input_name.is_empty() {
+        None
+    } else {
+        match midi::connect_to_midi_input(&config.midi_input_name, midi_in_tx.clone()) {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                error!("Error connecting to MIDI input: {e}");
+                None
+            }
+        }
+    };
 
-    println!("Application started. Press Ctrl+C to exit.");
+    let mut control_rx = control::register();
+    let _ = ready.send(());
+
+    info!("Application started. Press Ctrl+C to exit.");
     // Listen for OSC packets
     loop {
         tokio::select! {
-            packet_result = handlers::incoming_osc_handler(&sock, &mut buf) => {
-                let packet = packet_result?;
+            Some(transport::Inbound { packet, source, listener }) = inbound_rx.recv() => {
                 match packet {
                     OscPacket::Message(msg) => {
+                        monitor::record(monitor::MonitorKind::OscIn {
+                            listener: listener.clone(),
+                            source,
+                            address: msg.addr.clone(),
+                            args: msg.args.clone(),
+                        });
+                        oscquery::publish(&msg);
+
+                        match control::from_osc(&msg) {
+                            Some(Ok(cmd)) => {
+                                if let Some(response) = apply_control(cmd, &base_config, &mut config, &mut osc_out, &mut conn_out).await {
+                                    let socket = listener_configs
+                                        .iter()
+                                        .position(|l| *l.name == *listener)
+                                        .and_then(|i| listeners[i].udp.as_deref());
+                                    send_reply(socket, source, response);
+                                }
+                                continue;
+                            }
+                            Some(Err(e)) => {
+                                warn!(%source, "{e}");
+                                continue;
+                            }
+                            None => {}
+                        }
+
+                        if learn::capture_osc(&msg, source, &listener) {
+                            debug!("Learned OSC: {}", msg.addr);
+                            continue;
+                        }
+
                         // println!("Address: {}", msg.addr);
                         // println!("Arguments: {:?}", msg.args);
                         // let cloned_mappings = MAPPINGS.lock().unwrap().clone();
                         // let found_maps = handlers::match_mappings(&cloned_mappings, &msg);
+                        // Song changes switch the layer, then still go through the mappings
+                        if config.layer_addresses.contains(&msg.addr) {
+                            if let Some(rosc::OscType::String(song)) = msg.args.first() {
+                                set_active_layer(Some(song.clone()));
+                            }
+                        }
+
                         let found_maps = {
                             let mappings = MAPPINGS.read().unwrap();
-                            handlers::match_mappings(&mappings, &msg)
+                            let layer = ACTIVE_LAYER.read().unwrap();
+                            let mut found = handlers::match_mappings(
+                                &mappings,
+                                &msg,
+                                layer.as_deref(),
+                                &listener,
+                                source,
+                            );
+                            let disabled = DISABLED_ROWS.read().unwrap();
+                            found.retain(|(row, _)| !disabled.contains(row));
+                            found
                         };
 
                         if !found_maps.is_empty() {
-                            for found_map in &found_maps {  // Borrow instead of move
-                                // Handle outgoing OSC
-                                if let Some(addr) = &found_map.osc_out_address {
-                                    if !addr.is_empty() {
-                                        handlers::outgoing_osc_handler(
-                                            &sock,
-                                            addr.as_str(),
-                                            found_map.osc_out_args.as_deref(),
-                                            &config.osc_send_host,
-                                            &config.osc_send_port,
-                                        )
-                                        .await?;
-                                    }
-                                }
-
-                                // Handle MIDI message
-                                if let Err(e) = midi::handle_midi_message(&mut conn_out, found_map) {
-                                    let _ = custom_print(
-                                        format!("Error sending MIDI message: {e}"),
-                                        Output::AppError,
-                                    );
-                                }
+                            for (row, found_map) in &found_maps {  // Borrow instead of move
+                                fire_mapping(*row, &msg.addr, &msg.args, found_map, &config, &mut osc_out, &mut conn_out).await;
                             }
                         } else {
-                            let _ = custom_print("Mapping not found.".to_string(), Output::Console);
+                            trace!("Mapping not found.");
                         }
                     }
                     _ => {
-                        println!("Received a non-message packet");
+                        debug!("Received a non-message packet");
+                    }
+                }
+            },
+            Some(cmd) = control_rx.recv() => {
+                let _ = apply_control(cmd, &base_config, &mut config, &mut osc_out, &mut conn_out).await;
+            },
+            Some(message) = midi_in_rx.recv() => {
+                if learn::capture_midi(&message) {
+                    debug!("Learned MIDI: {message:02X?}");
+                    continue;
+                }
+
+                if let Some(msg) = msc::to_osc(&message) {
+                    debug!("Received MSC: {}", msc::describe(&message).unwrap_or_default());
+                    let inbound = transport::Inbound {
+                        packet: OscPacket::Message(msg),
+                        source: SocketAddr::from(([0, 0, 0, 0], 0)),
+                        listener: msc_listener.clone(),
+                    };
+                    if msc_inbound.try_send(inbound).is_err() {
+                        warn!("Inbound queue full, MSC message dropped");
+                    }
+                    continue;
+                }
+
+                // Program change on the show select channel loads the show bound to it
+                if let ([status, program], Some(channel)) = (&message[..], config.show_select_channel) {
+                    if status & 0xF0 == 0xC0 && u32::from(status & 0x0F) + 1 == channel {
+                        match shows::find_show_by_program(*program) {
+                            Some(name) => {
+                                let _ = apply_control(
+                                    ControlCommand::LoadShow { name, reply: None },
+                                    &base_config,
+                                    &mut config,
+                                    &mut osc_out,
+                                    &mut conn_out,
+                                )
+                                .await;
+                            }
+                            None => {
+                                warn!("No show bound to program {program}");
+                            }
+                        }
                     }
                 }
             },
-            _ = signal::ctrl_c() => {
+            _ = &mut shutdown => {
                 break;
             }
         }
     }
 
-    println!("Exiting main loop. Cleaning up...");
+    drop(listeners);
+    drop(midi_in_tx);
+    CONNECTIONS.write().unwrap().take();
+    info!("Exiting main loop. Cleaning up...");
+    release_notes(&mut conn_out, "shutdown");
     Ok(())
 }
@@ -0,0 +1,425 @@
+use crate::odisc::main::events;
+use crate::odisc::main::learn::learned_arg;
+use crate::odisc::main::logging::TRAFFIC;
+use crate::odisc::main::msc;
+use rosc::OscType;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+use smallvec::SmallVec;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, Level};
+
+use lazy_static::lazy_static;
+
+// Structured traffic monitor. The hot path only stores raw data in a ring
+// buffer; formatting happens when the UI is sent a batch or asks for history.
+
+const HISTORY_CAPACITY: usize = 5000;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+// Upper bound per flush, anything beyond is reported as dropped
+const MAX_BATCH: usize = 500;
+
+#[derive(Debug, Clone)]
+pub enum MonitorKind {
+    OscIn {
+        listener: Arc<str>,
+        source: SocketAddr,
+        address: String,
+        args: Vec<OscType>,
+    },
+    MappingMatched {
+        row: usize,
+        address: String,
+    },
+    OscOut {
+        destination: String,
+        address: String,
+        args: Vec<OscType>,
+    },
+    MidiOut {
+        port: Arc<str>,
+        bytes: SmallVec<[u8; 3]>,
+    },
+    Rejected {
+        listener: Arc<str>,
+        source: SocketAddr,
+        reason: &'static str,
+    },
+    DmxOut {
+        universe: u16,
+        levels: Vec<(RangeInclusive<u16>, u8)>,
+        fade_ms: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+// Indexed by MonitorKind::index
+const KIND_NAMES: [&str; 7] = [
+    "osc_in",
+    "mapping_matched",
+    "osc_out",
+    "midi_out",
+    "rejected",
+    "dmx_out",
+    "error",
+];
+
+impl MonitorKind {
+    fn index(&self) -> usize {
+        match self {
+            MonitorKind::OscIn { .. } => 0,
+            MonitorKind::MappingMatched { .. } => 1,
+            MonitorKind::OscOut { .. } => 2,
+            MonitorKind::MidiOut { .. } => 3,
+            MonitorKind::Rejected { .. } => 4,
+            MonitorKind::DmxOut { .. } => 5,
+            MonitorKind::Error { .. } => 6,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        KIND_NAMES[self.index()]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MonitorEvent {
+    pub seq: u64,
+    // Microseconds since the monitor started, from a monotonic clock
+    pub t_us: u64,
+    pub kind: MonitorKind,
+}
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+    static ref HISTORY: Mutex<VecDeque<MonitorEvent>> =
+        Mutex::new(VecDeque::with_capacity(HISTORY_CAPACITY));
+}
+
+// Events per type since start; unlike the history, never trimmed or cleared
+static COUNTERS: [AtomicU64; KIND_NAMES.len()] = [const { AtomicU64::new(0) }; KIND_NAMES.len()];
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+static FLUSHER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Traffic is logged at debug as well, which the log file always records;
+// errors are already logged by whoever raised them. Skipped outright when
+// nothing records it, to keep the formatting off the hot path.
+fn log_traffic(kind: &MonitorKind) {
+    if !tracing::enabled!(target: TRAFFIC, Level::DEBUG) {
+        return;
+    }
+    match kind {
+        MonitorKind::OscIn {
+            listener,
+            source,
+            address,
+            args,
+        } => debug!(
+            target: TRAFFIC,
+            listener = listener.as_ref(),
+            %source,
+            address,
+            ?args,
+            "osc in"
+        ),
+        MonitorKind::MappingMatched { row, address } => {
+            debug!(target: TRAFFIC, row, address, "mapping matched")
+        }
+        MonitorKind::OscOut {
+            destination,
+            address,
+            args,
+        } => debug!(target: TRAFFIC, destination, address, ?args, "osc out"),
+        MonitorKind::MidiOut { port, bytes } => debug!(
+            target: TRAFFIC,
+            port = port.as_ref(),
+            bytes = ?bytes.as_slice(),
+            decoded = describe_midi(bytes),
+            "midi out"
+        ),
+        MonitorKind::Rejected {
+            listener,
+            source,
+            reason,
+        } => debug!(
+            target: TRAFFIC,
+            listener = listener.as_ref(),
+            %source,
+            reason,
+            "osc rejected"
+        ),
+        MonitorKind::DmxOut {
+            universe,
+            levels,
+            fade_ms,
+        } => debug!(
+            target: TRAFFIC,
+            universe,
+            levels = describe_dmx(levels),
+            fade_ms,
+            "dmx out"
+        ),
+        MonitorKind::Error { .. } => {}
+    }
+}
+
+pub fn record(kind: MonitorKind) {
+    log_traffic(&kind);
+    COUNTERS[kind.index()].fetch_add(1, Ordering::Relaxed);
+    // Numbered under the lock so the history stays sorted by seq, which the
+    // flusher relies on
+    let mut history = HISTORY.lock().unwrap();
+    let event = MonitorEvent {
+        seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+        t_us: START.elapsed().as_micros() as u64,
+        kind,
+    };
+    if history.len() == HISTORY_CAPACITY {
+        history.pop_front();
+    }
+    history.push_back(event);
+}
+
+pub fn record_error(message: String) {
+    record(MonitorKind::Error { message });
+}
+
+pub fn describe_midi(bytes: &[u8]) -> String {
+    let Some(status) = bytes.first() else {
+        return "empty".to_string();
+    };
+    let channel = (status & 0x0F) + 1;
+    let data = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    match status & 0xF0 {
+        0x80 => format!("note_off ch={channel} note={} vel={}", data(1), data(2)),
+        0x90 => format!("note_on ch={channel} note={} vel={}", data(1), data(2)),
+        0xA0 => format!("aftertouch ch={channel} note={} value={}", data(1), data(2)),
+        0xB0 => format!("cc ch={channel} controller={} value={}", data(1), data(2)),
+        0xC0 => format!("pc ch={channel} program={}", data(1)),
+        0xD0 => format!("channel_pressure ch={channel} value={}", data(1)),
+        0xE0 => format!(
+            "pitch_bend ch={channel} value={}",
+            u16::from(data(1)) | (u16::from(data(2)) << 7)
+        ),
+        _ if *status == 0xF0 => {
+            msc::describe(bytes).unwrap_or_else(|| format!("sysex len={}", bytes.len()))
+        }
+        _ => format!("system {status:02X}"),
+    }
+}
+
+// "1-4=255 5=128"
+pub fn describe_dmx(levels: &[(RangeInclusive<u16>, u8)]) -> String {
+    levels
+        .iter()
+        .map(
+            |(channels, level)| match channels.start() == channels.end() {
+                true => format!("{}={level}", channels.start()),
+                false => format!("{}-{}={level}", channels.start(), channels.end()),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Serialize for MonitorEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut value = match &self.kind {
+            MonitorKind::OscIn {
+                listener,
+                source,
+                address,
+                args,
+            } => json!({
+                "listener": listener.as_ref(),
+                "source": source.to_string(),
+                "address": address,
+                "args": args.iter().map(learned_arg).collect::<Vec<_>>(),
+            }),
+            MonitorKind::MappingMatched { row, address } => json!({
+                "row": row,
+                "address": address,
+            }),
+            MonitorKind::OscOut {
+                destination,
+                address,
+                args,
+            } => json!({
+                "destination": destination,
+                "address": address,
+                "args": args.iter().map(learned_arg).collect::<Vec<_>>(),
+            }),
+            MonitorKind::MidiOut { port, bytes } => json!({
+                "port": port.as_ref(),
+                "bytes": bytes.as_slice(),
+                "decoded": describe_midi(bytes),
+            }),
+            MonitorKind::Rejected {
+                listener,
+                source,
+                reason,
+            } => json!({
+                "listener": listener.as_ref(),
+                "source": source.to_string(),
+                "reason": reason,
+            }),
+            MonitorKind::DmxOut {
+                universe,
+                levels,
+                fade_ms,
+            } => json!({
+                "universe": universe,
+                "levels": describe_dmx(levels),
+                "fade_ms": fade_ms,
+            }),
+            MonitorKind::Error { message } => json!({ "message": message }),
+        };
+        value["seq"] = json!(self.seq);
+        value["t_us"] = json!(self.t_us);
+        value["type"] = json!(self.kind.name());
+        value.serialize(serializer)
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct MonitorFilter {
+    // Event type names as in MonitorKind::name
+    pub kinds: Option<Vec<String>>,
+    // Case-insensitive substring over addresses, sources, ports and messages
+    pub search: Option<String>,
+    pub since_seq: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+fn matches_search(event: &MonitorEvent, needle: &str) -> bool {
+    let contains = |s: &str| s.to_lowercase().contains(needle);
+    match &event.kind {
+        MonitorKind::OscIn {
+            listener,
+            source,
+            address,
+            ..
+        } => contains(address) || contains(listener) || contains(&source.to_string()),
+        MonitorKind::MappingMatched { row, address } => {
+            contains(address) || row.to_string() == needle
+        }
+        MonitorKind::OscOut {
+            destination,
+            address,
+            ..
+        } => contains(address) || contains(destination),
+        MonitorKind::MidiOut { port, bytes } => contains(port) || contains(&describe_midi(bytes)),
+        MonitorKind::Rejected {
+            listener,
+            source,
+            reason,
+        } => contains(listener) || contains(reason) || contains(&source.to_string()),
+        MonitorKind::DmxOut { universe, .. } => universe.to_string() == needle,
+        MonitorKind::Error { message } => contains(message),
+    }
+}
+
+// Newest events last; `limit` keeps the most recent ones
+pub fn history(filter: &MonitorFilter) -> Vec<MonitorEvent> {
+    let needle = filter.search.as_ref().map(|s| s.to_lowercase());
+    let history = HISTORY.lock().unwrap();
+    let mut events: Vec<MonitorEvent> = history
+        .iter()
+        .filter(|e| filter.since_seq.is_none_or(|since| e.seq > since))
+        .filter(|e| {
+            filter
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.iter().any(|k| k == e.kind.name()))
+        })
+        .filter(|e| needle.as_deref().is_none_or(|n| matches_search(e, n)))
+        .cloned()
+        .collect();
+    if let Some(limit) = filter.limit {
+        let skip = events.len().saturating_sub(limit);
+        events.drain(..skip);
+    }
+    events
+}
+
+// Only the types seen so far
+pub fn counters() -> BTreeMap<&'static str, u64> {
+    KIND_NAMES
+        .iter()
+        .zip(&COUNTERS)
+        .map(|(name, count)| (*name, count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn clear() {
+    HISTORY.lock().unwrap().clear();
+}
+
+// Sends new events to the UI in batches, at most every FLUSH_INTERVAL.
+// Only the first call runs; the app and the WebSocket server both start it.
+pub async fn run_flusher() {
+    if FLUSHER_RUNNING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let mut last_seq = 0;
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let batch = {
+            let history = HISTORY.lock().unwrap();
+            let start = history.partition_point(|e| e.seq <= last_seq);
+            history.range(start..).cloned().collect::<Vec<_>>()
+        };
+        let Some(last) = batch.last() else {
+            continue;
+        };
+        last_seq = last.seq;
+
+        let dropped = batch.len().saturating_sub(MAX_BATCH);
+        let events = &batch[dropped..];
+        events::emit(
+            "monitor-events",
+            json!({ "events": events, "dropped": dropped }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_stays_sorted_under_concurrent_records() {
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    for row in 0..200 {
+                        record(MonitorKind::MappingMatched {
+                            row,
+                            address: format!("/thread/{t}"),
+                        });
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let history = HISTORY.lock().unwrap();
+        assert!(history.len() >= 1600);
+        assert!(history
+            .iter()
+            .zip(history.iter().skip(1))
+            .all(|(a, b)| a.seq < b.seq));
+        assert!(counters()["mapping_matched"] >= 1600);
+    }
+}
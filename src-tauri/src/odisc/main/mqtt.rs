@@ -0,0 +1,332 @@
+use crate::odisc::main::engine_status;
+use crate::odisc::main::events::{self, EventSink};
+use crate::odisc::main::monitor;
+use crate::odisc::main::redundancy;
+use crate::odisc::main::transport::Inbound;
+use crate::odisc::main::websocket::osc_arg;
+use rosc::{OscMessage, OscPacket, OscType};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+// MQTT client for venue automation (house lights, haze, ...).
+// Incoming: every message on a SUBSCRIBE filter becomes an OSC message on a
+// listener named NAME. The topic, minus STRIP_PREFIX, is the address
+// ("stage/song/next" -> "/stage/song/next"); the payload gives the args: a
+// JSON array or scalar, otherwise the text as one string, empty for none.
+// Outgoing, under PUBLISH_PREFIX:
+//   <prefix>/cue      every matched mapping {row, address, ...}
+//   <prefix>/status   engine_status() after each state change (retained)
+//   <prefix>/online   "true"/"false", the latter as last will (retained)
+// Cues and status come only from the active instance of a redundant pair.
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct MqttConfig {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // Brokers drop a client when another connects with its id, so the default
+    // is per machine and redundancy role, see client_id()
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // Topic filters, "+" and "#" wildcards allowed
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+    #[serde(default = "default_publish_prefix")]
+    pub publish_prefix: String,
+    // Listener name for osc_in_listener filters
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_publish_prefix() -> String {
+    "odisc".to_string()
+}
+
+fn default_name() -> String {
+    "mqtt".to_string()
+}
+
+fn host_name() -> Option<String> {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+// "odisc-<host>", plus "-primary"/"-backup" in a redundant pair
+fn client_id(config: &MqttConfig) -> String {
+    if let Some(id) = config.client_id.as_ref().filter(|id| !id.is_empty()) {
+        return id.clone();
+    }
+    let host = host_name().unwrap_or_else(|| std::process::id().to_string());
+    match redundancy::status().role {
+        Some(role) => format!("odisc-{host}-{}", role.as_str()),
+        None => format!("odisc-{host}"),
+    }
+}
+
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn topic_to_address(topic: &str, strip_prefix: Option<&str>) -> String {
+    let topic = strip_prefix
+        .and_then(|prefix| topic.strip_prefix(prefix.trim_end_matches('/')))
+        .unwrap_or(topic);
+    format!("/{}", topic.trim_start_matches('/'))
+}
+
+fn payload_args(payload: &[u8]) -> Vec<OscType> {
+    let Ok(text) = std::str::from_utf8(payload) else {
+        return vec![OscType::Blob(payload.to_vec())];
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return Vec::new();
+    }
+    match serde_json::from_str::<Value>(text) {
+        Ok(Value::Array(values)) => {
+            if let Some(args) = values.iter().map(osc_arg).collect::<Option<Vec<_>>>() {
+                return args;
+            }
+        }
+        Ok(value) => {
+            // Quoted strings included, so "\"intro\"" and "intro" match alike
+            if let Some(arg) = osc_arg(&value) {
+                return vec![arg];
+            }
+        }
+        Err(_) => {}
+    }
+    vec![OscType::String(text.to_string())]
+}
+
+// Turns engine events into publications. try_publish only queues, so this
+// never blocks whoever emitted the event.
+struct Publisher {
+    client: AsyncClient,
+    prefix: String,
+}
+
+impl Publisher {
+    fn publish(&self, topic: &str, retain: bool, payload: &Value) {
+        let topic = format!("{}/{topic}", self.prefix);
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtMostOnce, retain, payload.to_string())
+        {
+            debug!("MQTT publish dropped: {e}");
+        }
+    }
+}
+
+impl EventSink for Publisher {
+    fn emit(&self, event: &str, payload: Value) {
+        // The standby sees the same cues; publishing them would double them up
+        if !redundancy::is_active() {
+            return;
+        }
+        match event {
+            "monitor-events" => {
+                let matched = payload["events"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter(|e| e["type"] == "mapping_matched");
+                for cue in matched {
+                    self.publish("cue", false, cue);
+                }
+            }
+            "backend-log" => {}
+            _ => self.publish("status", true, &json!(engine_status())),
+        }
+    }
+}
+
+async fn run(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    config: MqttConfig,
+    inbound: mpsc::Sender<Inbound>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let listener: Arc<str> = config.name.as_str().into();
+    // MQTT doesn't say who published; mappings can't filter MQTT by source
+    let source = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let online = format!("{}/online", config.publish_prefix);
+    let mut connected = false;
+    loop {
+        let event = tokio::select! {
+            _ = &mut stop => break,
+            event = eventloop.poll() => event,
+        };
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT connected to {}:{}", config.host, config.port);
+                connected = true;
+                // Clean sessions forget subscriptions, so renew them on every connect
+                for filter in &config.subscribe {
+                    if let Err(e) = client.subscribe(filter.as_str(), QoS::AtMostOnce).await {
+                        warn!("MQTT subscribe to '{filter}' failed: {e}");
+                    }
+                }
+                let _ = client
+                    .publish(online.as_str(), QoS::AtLeastOnce, true, "true")
+                    .await;
+                if redundancy::is_active() {
+                    let _ = client
+                        .publish(
+                            format!("{}/status", config.publish_prefix),
+                            QoS::AtLeastOnce,
+                            true,
+                            json!(engine_status()).to_string(),
+                        )
+                        .await;
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let msg = OscMessage {
+                    addr: topic_to_address(&publish.topic, config.strip_prefix.as_deref()),
+                    args: payload_args(&publish.payload),
+                };
+                let inbound_msg = Inbound {
+                    packet: OscPacket::Message(msg),
+                    source,
+                    listener: listener.clone(),
+                };
+                if inbound.send(inbound_msg).await.is_err() {
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // Logged once per outage; the next poll reconnects
+                if connected {
+                    warn!("MQTT connection lost: {e}");
+                    connected = false;
+                } else {
+                    debug!("MQTT connect failed: {e}");
+                }
+                tokio::select! {
+                    _ = &mut stop => break,
+                    _ = tokio::time::sleep(RETRY_DELAY) => {}
+                }
+            }
+        }
+    }
+
+    // A clean disconnect skips the last will, so say offline first
+    if connected {
+        let _ = client.try_publish(online, QoS::AtLeastOnce, true, "false");
+        let _ = client.try_disconnect();
+        let _ = tokio::time::timeout(GOODBYE_TIMEOUT, async {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        })
+        .await;
+        info!("MQTT disconnected");
+    }
+}
+
+// Publishes online = false and disconnects when dropped
+pub struct Mqtt {
+    _stop: oneshot::Sender<()>,
+    sink: Arc<dyn EventSink>,
+}
+
+impl Drop for Mqtt {
+    fn drop(&mut self) {
+        events::remove_sink(&self.sink);
+    }
+}
+
+pub fn start(config: &MqttConfig, inbound: mpsc::Sender<Inbound>) -> Result<Mqtt, Box<dyn Error>> {
+    let prefix = config.publish_prefix.trim_end_matches('/').to_string();
+    if prefix.is_empty() || prefix.contains(['+', '#']) {
+        return Err(format!("MQTT: invalid PUBLISH_PREFIX '{}'", config.publish_prefix).into());
+    }
+    let mut options = MqttOptions::new(client_id(config), &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(10));
+    options.set_last_will(LastWill::new(
+        format!("{prefix}/online"),
+        "false",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    let (client, eventloop) = AsyncClient::new(options, 64);
+
+    let sink: Arc<dyn EventSink> = Arc::new(Publisher {
+        client: client.clone(),
+        prefix: prefix.clone(),
+    });
+    events::add_sink(sink.clone());
+    // Cues come from the monitor batches
+    tokio::spawn(monitor::run_flusher());
+
+    let config = MqttConfig {
+        publish_prefix: prefix,
+        ..config.clone()
+    };
+    info!("MQTT connecting to {}:{}", config.host, config.port);
+    let (stop, stopped) = oneshot::channel();
+    tokio::spawn(run(eventloop, client, config, inbound, stopped));
+    Ok(Mqtt { _stop: stop, sink })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(client_id: Option<&str>) -> MqttConfig {
+        MqttConfig {
+            host: default_host(),
+            port: default_port(),
+            client_id: client_id.map(str::to_string),
+            username: None,
+            password: None,
+            subscribe: Vec::new(),
+            strip_prefix: None,
+            publish_prefix: default_publish_prefix(),
+            name: default_name(),
+        }
+    }
+
+    #[test]
+    fn client_id_defaults_per_machine() {
+        assert_eq!(client_id(&config(Some("desk"))), "desk");
+        let default = client_id(&config(None));
+        assert!(default.starts_with("odisc-") && default.len() > "odisc-".len());
+        assert_eq!(client_id(&config(Some(""))), default);
+    }
+}
@@ -0,0 +1,533 @@
+use crate::odisc::main::helpers::Mapping;
+use rosc::{OscMessage, OscType};
+
+// MIDI Show Control, sent as a mapping action and read from the MIDI input.
+// Outgoing, from the msc_* columns:
+//   msc_command  go, stop, resume, timed_go, load, set, fire or all_off
+//   msc_format   a FORMATS name or a number, empty = lighting
+//   msc_device   device ID 0-127, empty = 127 (all call)
+//   msc_cue      cue number, e.g. "12.5"; the control number for set, the
+//                macro number for fire
+//   msc_list     cue list; the control value for set
+//   msc_path     cue path
+//   msc_time     "hh:mm:ss:ff" at 30 fps or seconds, for timed_go and set
+// Every field but msc_command can be "$N", OSC argument N of the incoming
+// message, so Ableset's song position can pick the cue.
+// Incoming MSC arrives on a listener named LISTENER as /msc/<command>, with
+// the fields in one string: "12.5 1" for cue 12.5 in list 1, "3 255" for
+// control 3 set to 255.
+
+pub const LISTENER: &str = "msc";
+pub const ADDRESS_PREFIX: &str = "/msc/";
+
+pub const COMMANDS: [(&str, u8); 8] = [
+    ("go", 0x01),
+    ("stop", 0x02),
+    ("resume", 0x03),
+    ("timed_go", 0x04),
+    ("load", 0x05),
+    ("set", 0x06),
+    ("fire", 0x07),
+    ("all_off", 0x08),
+];
+
+pub const FORMATS: [(&str, u8); 10] = [
+    ("lighting", 0x01),
+    ("moving_lights", 0x02),
+    ("sound", 0x10),
+    ("machinery", 0x20),
+    ("video", 0x30),
+    ("projection", 0x40),
+    ("process_control", 0x50),
+    ("pyro", 0x60),
+    ("fireworks", 0x61),
+    ("all", 0x7F),
+];
+
+const ALL_CALL: u8 = 0x7F;
+const FRAME_RATE: f64 = 30.0;
+// 30 fps non-drop in the hours byte
+const RATE_BITS: u8 = 0x60;
+
+fn command_byte(name: &str) -> Option<u8> {
+    COMMANDS.iter().find(|(n, _)| *n == name).map(|(_, b)| *b)
+}
+
+fn command_name(byte: u8) -> Option<&'static str> {
+    COMMANDS.iter().find(|(_, b)| *b == byte).map(|(n, _)| *n)
+}
+
+fn format_byte(format: &str) -> Option<u8> {
+    FORMATS
+        .iter()
+        .find(|(n, _)| *n == format)
+        .map(|(_, b)| *b)
+        .or_else(|| format.parse().ok().filter(|b| *b <= 0x7F))
+}
+
+fn format_name(byte: u8) -> String {
+    FORMATS
+        .iter()
+        .find(|(_, b)| *b == byte)
+        .map_or_else(|| format!("{byte:#04x}"), |(n, _)| n.to_string())
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|v| !v.is_empty())
+}
+
+// "$2" -> Some(1)
+fn arg_index(field: &str) -> Option<usize> {
+    field
+        .strip_prefix('$')
+        .and_then(|i| i.parse::<usize>().ok())
+        .filter(|i| *i >= 1)
+        .map(|i| i - 1)
+}
+
+fn arg_text(arg: &OscType) -> Option<String> {
+    match arg {
+        OscType::String(s) => Some(s.clone()),
+        OscType::Int(i) => Some(i.to_string()),
+        OscType::Long(l) => Some(l.to_string()),
+        OscType::Float(f) => Some(f.to_string()),
+        OscType::Double(d) => Some(d.to_string()),
+        _ => None,
+    }
+}
+
+fn resolve(name: &str, field: &str, args: &[OscType]) -> Result<String, String> {
+    match arg_index(field) {
+        Some(index) => args
+            .get(index)
+            .and_then(arg_text)
+            .ok_or_else(|| format!("{name}: no OSC argument {field}")),
+        None => Ok(field.to_string()),
+    }
+}
+
+// Cue numbers, lists and paths are ASCII digits with optional dots
+fn is_cue_number(text: &str) -> bool {
+    !text.is_empty()
+        && text.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+        && !text.starts_with('.')
+        && !text.ends_with('.')
+}
+
+fn cue_number(name: &str, text: &str) -> Result<String, String> {
+    if is_cue_number(text) {
+        Ok(text.to_string())
+    } else {
+        Err(format!(
+            "{name} must be a number like '12' or '12.5', got '{text}'"
+        ))
+    }
+}
+
+fn number(name: &str, text: &str, max: u32) -> Result<u32, String> {
+    text.parse::<u32>()
+        .ok()
+        .filter(|n| *n <= max)
+        .ok_or_else(|| format!("{name} must be 0-{max}, got '{text}'"))
+}
+
+// hours (with the rate bits), minutes, seconds, frames, subframes
+fn time_bytes(text: &str) -> Result<[u8; 5], String> {
+    let invalid = || format!("msc_time must be 'hh:mm:ss:ff' or seconds, got '{text}'");
+    let parts: Vec<&str> = text.split(':').collect();
+    let (hours, minutes, seconds, frames) = match parts[..] {
+        [h, m, s, f] => {
+            let part = |p: &str, max: u8| p.parse::<u8>().ok().filter(|v| *v <= max);
+            (
+                part(h, 23).ok_or_else(invalid)?,
+                part(m, 59).ok_or_else(invalid)?,
+                part(s, 59).ok_or_else(invalid)?,
+                part(f, 29).ok_or_else(invalid)?,
+            )
+        }
+        [seconds] => {
+            let total = seconds
+                .parse::<f64>()
+                .ok()
+                .filter(|s| s.is_finite() && *s >= 0.0 && *s < 86400.0)
+                .ok_or_else(invalid)?;
+            let frames = (total * FRAME_RATE).round() as u64;
+            let whole = frames / FRAME_RATE as u64;
+            (
+                (whole / 3600) as u8,
+                (whole / 60 % 60) as u8,
+                (whole % 60) as u8,
+                (frames % FRAME_RATE as u64) as u8,
+            )
+        }
+        _ => return Err(invalid()),
+    };
+    Ok([RATE_BITS | hours, minutes, seconds, frames, 0])
+}
+
+// Q_number [00 Q_list [00 Q_path]]
+fn cue_data(
+    m: &Mapping,
+    args: &[OscType],
+    required: bool,
+    data: &mut Vec<u8>,
+) -> Result<(), String> {
+    let fields = [
+        ("msc_cue", non_empty(&m.msc_cue)),
+        ("msc_list", non_empty(&m.msc_list)),
+        ("msc_path", non_empty(&m.msc_path)),
+    ];
+    let mut cues = Vec::new();
+    for (name, field) in fields {
+        let Some(field) = field else { break };
+        cues.push(cue_number(name, &resolve(name, field, args)?)?);
+    }
+    if required && cues.is_empty() {
+        return Err("msc_cue is required for msc_command 'load'".to_string());
+    }
+    data.extend_from_slice(cues.join("\0").as_bytes());
+    Ok(())
+}
+
+fn required<'a>(name: &str, value: &'a Option<String>, command: &str) -> Result<&'a str, String> {
+    non_empty(value).ok_or_else(|| format!("{name} is required for msc_command '{command}'"))
+}
+
+// The SysEx for a mapping's msc_* columns, with args filled in
+pub fn build(m: &Mapping, args: &[OscType]) -> Result<Vec<u8>, String> {
+    let command = non_empty(&m.msc_command).unwrap_or_default();
+    let command_byte = command_byte(command).ok_or_else(|| {
+        let names: Vec<_> = COMMANDS.iter().map(|(n, _)| *n).collect();
+        format!(
+            "Unknown msc_command '{command}', expected one of {}",
+            names.join(", ")
+        )
+    })?;
+    let format = match non_empty(&m.msc_format) {
+        Some(format) => {
+            let format = resolve("msc_format", format, args)?;
+            format_byte(&format).ok_or_else(|| format!("Unknown msc_format '{format}'"))?
+        }
+        None => FORMATS[0].1,
+    };
+    let device = match non_empty(&m.msc_device) {
+        Some(device) => number("msc_device", &resolve("msc_device", device, args)?, 0x7F)? as u8,
+        None => ALL_CALL,
+    };
+
+    let mut data = Vec::new();
+    match command {
+        "go" | "stop" | "resume" => cue_data(m, args, false, &mut data)?,
+        "load" => cue_data(m, args, true, &mut data)?,
+        "timed_go" => {
+            let time = required("msc_time", &m.msc_time, command)?;
+            data.extend(time_bytes(&resolve("msc_time", time, args)?)?);
+            cue_data(m, args, false, &mut data)?;
+        }
+        "set" => {
+            let control = required("msc_cue", &m.msc_cue, command)?;
+            let value = required("msc_list", &m.msc_list, command)?;
+            for (name, field) in [("msc_cue", control), ("msc_list", value)] {
+                let n = number(name, &resolve(name, field, args)?, 0x3FFF)?;
+                data.extend_from_slice(&[(n & 0x7F) as u8, (n >> 7) as u8]);
+            }
+            if let Some(time) = non_empty(&m.msc_time) {
+                data.extend(time_bytes(&resolve("msc_time", time, args)?)?);
+            }
+        }
+        "fire" => {
+            let macro_number = required("msc_cue", &m.msc_cue, command)?;
+            let macro_number = resolve("msc_cue", macro_number, args)?;
+            data.push(number("msc_cue", &macro_number, 0x7F)? as u8);
+        }
+        _ => {}
+    }
+
+    let mut sysex = vec![0xF0, 0x7F, device, 0x02, format, command_byte];
+    sysex.extend(data);
+    sysex.push(0xF7);
+    Ok(sysex)
+}
+
+// Checks what can be checked without the incoming message
+pub fn validate(m: &Mapping) -> Result<(), String> {
+    let placeholder = |name: &str, field: &Option<String>| -> Result<(), String> {
+        match non_empty(field) {
+            Some(f) if f.starts_with('$') && arg_index(f).is_none() => {
+                Err(format!("{name}: '{f}' is not an argument like '$1'"))
+            }
+            _ => Ok(()),
+        }
+    };
+    let fields = [
+        ("msc_format", &m.msc_format),
+        ("msc_device", &m.msc_device),
+        ("msc_cue", &m.msc_cue),
+        ("msc_list", &m.msc_list),
+        ("msc_path", &m.msc_path),
+        ("msc_time", &m.msc_time),
+    ];
+    for (name, field) in fields {
+        placeholder(name, field)?;
+    }
+    // Stand-ins for the arguments, so only literal fields are judged
+    let args: Vec<OscType> = fields
+        .iter()
+        .filter_map(|(name, field)| arg_index(non_empty(field)?).map(|i| (i, *name)))
+        .fold(Vec::new(), |mut args, (index, name)| {
+            if args.len() <= index {
+                args.resize(index + 1, OscType::Nil);
+            }
+            args[index] = OscType::String(match name {
+                "msc_format" => "lighting".to_string(),
+                "msc_time" => "0".to_string(),
+                _ => "1".to_string(),
+            });
+            args
+        });
+    build(m, &args).map(|_| ())
+}
+
+struct Msc<'a> {
+    device: u8,
+    format: u8,
+    command: &'static str,
+    data: &'a [u8],
+}
+
+fn parse(message: &[u8]) -> Option<Msc<'_>> {
+    match message {
+        [0xF0, 0x7F, device, 0x02, format, command, data @ .., 0xF7] => Some(Msc {
+            device: *device,
+            format: *format,
+            command: command_name(*command)?,
+            data,
+        }),
+        _ => None,
+    }
+}
+
+// The fields as one string: "12.5 1", "3 255", ...
+fn fields(msc: &Msc) -> String {
+    let cues = |data: &[u8]| {
+        data.split(|b| *b == 0)
+            .map(|cue| String::from_utf8_lossy(cue).into_owned())
+            .filter(|cue| !cue.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    match (msc.command, msc.data) {
+        ("timed_go", data) => cues(data.get(5..).unwrap_or_default()),
+        ("set", [c1, c2, v1, v2, ..]) => {
+            let control = u16::from(*c1) | (u16::from(*c2) << 7);
+            let value = u16::from(*v1) | (u16::from(*v2) << 7);
+            format!("{control} {value}")
+        }
+        ("fire", [macro_number, ..]) => macro_number.to_string(),
+        ("all_off", _) => String::new(),
+        (_, data) => cues(data),
+    }
+}
+
+// Incoming MSC as the message the mappings see
+pub fn to_osc(message: &[u8]) -> Option<OscMessage> {
+    let msc = parse(message)?;
+    let fields = fields(&msc);
+    Some(OscMessage {
+        addr: format!("{ADDRESS_PREFIX}{}", msc.command),
+        args: if fields.is_empty() {
+            Vec::new()
+        } else {
+            vec![OscType::String(fields)]
+        },
+    })
+}
+
+// For the monitor: "msc go lighting dev=127 12.5 1"
+pub fn describe(message: &[u8]) -> Option<String> {
+    let msc = parse(message)?;
+    let mut text = format!(
+        "msc {} {} dev={}",
+        msc.command,
+        format_name(msc.format),
+        msc.device
+    );
+    let fields = fields(&msc);
+    if !fields.is_empty() {
+        text.push(' ');
+        text.push_str(&fields);
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Fields<'a> = &'a [(&'a str, &'a str)];
+
+    fn mapping(command: &str, fields: Fields) -> Mapping {
+        let mut m = Mapping {
+            msc_command: Some(command.to_string()),
+            ..Default::default()
+        };
+        for (name, value) in fields {
+            let value = Some(value.to_string());
+            match *name {
+                "format" => m.msc_format = value,
+                "device" => m.msc_device = value,
+                "cue" => m.msc_cue = value,
+                "list" => m.msc_list = value,
+                "path" => m.msc_path = value,
+                "time" => m.msc_time = value,
+                _ => unreachable!("{name}"),
+            }
+        }
+        m
+    }
+
+    fn sysex(command: &str, fields: Fields) -> Vec<u8> {
+        build(&mapping(command, fields), &[]).unwrap()
+    }
+
+    #[test]
+    fn builds_go_with_cue_list_and_path() {
+        assert_eq!(
+            sysex("go", &[("cue", "12.5"), ("list", "1"), ("path", "2")]),
+            b"\xF0\x7F\x7F\x02\x01\x0112.5\x001\x002\xF7"
+        );
+        assert_eq!(sysex("go", &[]), [0xF0, 0x7F, 0x7F, 0x02, 0x01, 0x01, 0xF7]);
+        assert_eq!(
+            sysex(
+                "stop",
+                &[("format", "sound"), ("device", "5"), ("cue", "3")]
+            ),
+            b"\xF0\x7F\x05\x02\x10\x023\xF7"
+        );
+        assert_eq!(
+            sysex("resume", &[("format", "48")]),
+            [0xF0, 0x7F, 0x7F, 0x02, 0x30, 0x03, 0xF7]
+        );
+        assert_eq!(
+            sysex("load", &[("cue", "7")]),
+            b"\xF0\x7F\x7F\x02\x01\x057\xF7"
+        );
+    }
+
+    #[test]
+    fn builds_timed_go_at_30_fps() {
+        assert_eq!(
+            sysex("timed_go", &[("time", "01:02:03:04"), ("cue", "5")]),
+            b"\xF0\x7F\x7F\x02\x01\x04\x61\x02\x03\x04\x005\xF7"
+        );
+        // 61.5 s is 1 minute, 1 second and 15 frames
+        assert_eq!(
+            sysex("timed_go", &[("time", "61.5")]),
+            [0xF0, 0x7F, 0x7F, 0x02, 0x01, 0x04, 0x60, 1, 1, 15, 0, 0xF7]
+        );
+    }
+
+    #[test]
+    fn builds_set_lsb_first() {
+        // 300 = 0x02 << 7 | 0x2C, 1000 = 0x07 << 7 | 0x68
+        assert_eq!(
+            sysex("set", &[("cue", "300"), ("list", "1000")]),
+            [0xF0, 0x7F, 0x7F, 0x02, 0x01, 0x06, 0x2C, 0x02, 0x68, 0x07, 0xF7]
+        );
+        assert_eq!(
+            sysex("set", &[("cue", "1"), ("list", "2"), ("time", "0:0:1:0")]),
+            [0xF0, 0x7F, 0x7F, 0x02, 0x01, 0x06, 1, 0, 2, 0, 0x60, 0, 1, 0, 0, 0xF7]
+        );
+    }
+
+    #[test]
+    fn builds_fire_and_all_off() {
+        assert_eq!(
+            sysex("fire", &[("cue", "9")]),
+            [0xF0, 0x7F, 0x7F, 0x02, 0x01, 0x07, 9, 0xF7]
+        );
+        assert_eq!(
+            sysex("all_off", &[]),
+            [0xF0, 0x7F, 0x7F, 0x02, 0x01, 0x08, 0xF7]
+        );
+    }
+
+    #[test]
+    fn incoming_reads_back_what_was_built() {
+        let cases: [(&str, Fields, &str, Option<&str>); 5] = [
+            (
+                "go",
+                &[("cue", "12.5"), ("list", "1")],
+                "/msc/go",
+                Some("12.5 1"),
+            ),
+            (
+                "timed_go",
+                &[("time", "2"), ("cue", "5")],
+                "/msc/timed_go",
+                Some("5"),
+            ),
+            (
+                "set",
+                &[("cue", "300"), ("list", "1000")],
+                "/msc/set",
+                Some("300 1000"),
+            ),
+            ("fire", &[("cue", "9")], "/msc/fire", Some("9")),
+            ("all_off", &[], "/msc/all_off", None),
+        ];
+        for (command, fields, address, text) in cases {
+            let msg = to_osc(&sysex(command, fields)).unwrap();
+            assert_eq!(msg.addr, address);
+            let args: Vec<_> = text
+                .map(|t| OscType::String(t.to_string()))
+                .into_iter()
+                .collect();
+            assert_eq!(msg.args, args, "{command}");
+        }
+        assert_eq!(
+            describe(&sysex("go", &[("cue", "12.5"), ("list", "1")])).unwrap(),
+            "msc go lighting dev=127 12.5 1"
+        );
+        assert!(to_osc(&[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7]).is_none());
+    }
+
+    #[test]
+    fn fills_in_arguments() {
+        let m = mapping("go", &[("device", "$1"), ("cue", "$2"), ("list", "3")]);
+        let args = [OscType::Int(5), OscType::Float(7.5)];
+        assert_eq!(
+            build(&m, &args).unwrap(),
+            b"\xF0\x7F\x05\x02\x01\x017.5\x003\xF7"
+        );
+        assert!(build(&m, &args[..1]).is_err());
+        assert!(build(&m, &[OscType::Int(5), OscType::Bool(true)]).is_err());
+        assert!(build(&m, &[OscType::Int(5), OscType::String("x".into())]).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_bad_fields() {
+        assert!(validate(&mapping("go", &[("cue", "$1"), ("time", "$2")])).is_ok());
+        assert!(validate(&mapping("set", &[("cue", "$1"), ("list", "$2")])).is_ok());
+        let bad: [(&str, Fields); 12] = [
+            ("jump", &[]),
+            ("go", &[("cue", "$0")]),
+            ("go", &[("cue", "$x")]),
+            ("go", &[("cue", "1a")]),
+            ("go", &[("cue", ".5")]),
+            ("go", &[("device", "128")]),
+            ("go", &[("format", "laser")]),
+            ("load", &[]),
+            ("timed_go", &[("cue", "1")]),
+            ("timed_go", &[("time", "24:00:00:00")]),
+            ("set", &[("cue", "1"), ("list", "16384")]),
+            ("fire", &[("cue", "128")]),
+        ];
+        for (command, fields) in bad {
+            assert!(
+                validate(&mapping(command, fields)).is_err(),
+                "{command} {fields:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,583 @@
+use crate::odisc::main::helpers::Mapping;
+use crate::odisc::main::transport::{ListenerConfig, Transport};
+use crate::odisc::main::{control, helpers, mappings, scale, shows};
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use rosc::{encoder, OscMessage, OscPacket};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use lazy_static::lazy_static;
+
+// OSCQuery (https://github.com/Vidvox/OSCQueryProposal): an HTTP server that
+// describes the addresses oDIsc accepts, so controllers can discover them.
+// - GET /<path> returns the node as JSON, GET /?HOST_INFO the OSC port
+// - every osc_in_address becomes a write-only node; string arguments seen in
+//   the mappings are listed as RANGE values, scale_in gives a numeric TYPE
+//   and a MIN/MAX RANGE, _comment becomes DESCRIPTION
+// - WebSocket clients can LISTEN to addresses and get each matching incoming
+//   message as binary OSC, plus PATH_CHANGED whenever mappings change
+// The namespace is built from the live mappings on every request; show names
+// come from the shows cache.
+// It listens on 127.0.0.1 unless ADDRESS says otherwise; controllers on other
+// machines need e.g. ADDRESS = "0.0.0.0", which also makes mDNS worthwhile.
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct OscQueryConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // Loopback only by default, exposing the namespace is opt-in
+    #[serde(default = "default_bind_address")]
+    pub address: String,
+    // Shown in controllers' device lists and used as the mDNS instance name
+    #[serde(default = "default_name")]
+    pub name: String,
+    // OSC listener to advertise, defaults to the first one
+    #[serde(default)]
+    pub listener: Option<String>,
+    // Announce _oscjson._tcp and _osc._udp over mDNS/Bonjour
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+}
+
+fn default_port() -> u16 {
+    8090
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_name() -> String {
+    "oDIsc".to_string()
+}
+
+fn default_mdns() -> bool {
+    true
+}
+
+// Access values from the proposal
+const ACCESS_NONE: u8 = 0;
+const ACCESS_WRITE: u8 = 2;
+
+#[derive(Clone)]
+enum Update {
+    Value(Arc<OscMessage>),
+    PathChanged,
+}
+
+lazy_static! {
+    static ref UPDATES: broadcast::Sender<Update> = broadcast::channel(256).0;
+}
+
+// Called for every incoming message; costs nothing without WebSocket clients
+pub fn publish(msg: &OscMessage) {
+    if UPDATES.receiver_count() > 0 {
+        let _ = UPDATES.send(Update::Value(Arc::new(msg.clone())));
+    }
+}
+
+pub fn path_changed() {
+    if UPDATES.receiver_count() > 0 {
+        let _ = UPDATES.send(Update::PathChanged);
+    }
+}
+
+struct ServerInfo {
+    name: String,
+    listener: ListenerConfig,
+    layer_addresses: Vec<String>,
+}
+
+#[derive(Default)]
+struct Entry {
+    args: BTreeSet<String>,
+    // Union of the rows' scale_in ranges
+    range: Option<(f64, f64)>,
+    comments: Vec<String>,
+}
+
+impl Entry {
+    // String arguments win over a numeric range. Whole-number ranges wider
+    // than 0-1 are ints (e.g. 0-127); anything else, faders included, is a float.
+    fn type_and_range(self) -> (&'static str, Option<Value>) {
+        if !self.args.is_empty() {
+            return ("s", vals(self.args.into_iter().collect()));
+        }
+        match self.range {
+            Some((min, max)) => {
+                let whole = min.fract() == 0.0 && max.fract() == 0.0 && max - min > 1.0;
+                let range = json!([{ "MIN": min, "MAX": max }]);
+                (if whole { "i" } else { "f" }, Some(range))
+            }
+            None => ("", None),
+        }
+    }
+}
+
+fn vals(values: Vec<String>) -> Option<Value> {
+    (!values.is_empty()).then(|| json!([{ "VALS": values }]))
+}
+
+fn insert(root: &mut Map<String, Value>, address: &str, attributes: Map<String, Value>) {
+    let mut node = root;
+    let mut full_path = String::new();
+    for segment in address.split('/').filter(|s| !s.is_empty()) {
+        full_path.push('/');
+        full_path.push_str(segment);
+        let contents = node
+            .entry("CONTENTS")
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap();
+        node = contents
+            .entry(segment)
+            .or_insert_with(|| json!({ "FULL_PATH": full_path, "ACCESS": ACCESS_NONE }))
+            .as_object_mut()
+            .unwrap();
+    }
+    node.extend(attributes);
+}
+
+fn method(types: &str, range: Option<Value>, description: Option<String>) -> Map<String, Value> {
+    let mut attributes = Map::new();
+    attributes.insert("ACCESS".into(), json!(ACCESS_WRITE));
+    if !types.is_empty() {
+        attributes.insert("TYPE".into(), json!(types));
+    }
+    if let Some(range) = range {
+        attributes.insert("RANGE".into(), range);
+    }
+    if let Some(description) = description {
+        attributes.insert("DESCRIPTION".into(), json!(description));
+    }
+    attributes
+}
+
+fn namespace(info: &ServerInfo) -> Value {
+    build_namespace(info, &mappings(), shows::show_names())
+}
+
+fn build_namespace(info: &ServerInfo, mappings: &[Mapping], show_names: Vec<String>) -> Value {
+    let mut entries: BTreeMap<&str, Entry> = BTreeMap::new();
+    for m in mappings.iter() {
+        // Rows bound to another listener can't be reached through this one
+        if m.osc_in_listener
+            .as_deref()
+            .is_some_and(|l| !l.is_empty() && l != info.listener.name)
+        {
+            continue;
+        }
+        let entry = entries.entry(&m.osc_in_address).or_default();
+        if let Some(arg) = m.osc_in_args.as_deref().filter(|a| !a.is_empty()) {
+            entry.args.insert(arg.to_string());
+        }
+        if let Some(Ok((a, b))) = m
+            .scale_in
+            .as_deref()
+            .filter(|s| !s.is_empty())
+            .map(scale::parse_range)
+        {
+            let (min, max) = (a.min(b), a.max(b));
+            entry.range = Some(match entry.range {
+                Some((lo, hi)) => (lo.min(min), hi.max(max)),
+                None => (min, max),
+            });
+        }
+        if let Some(comment) = m._comment.as_deref().filter(|c| !c.is_empty()) {
+            if !entry.comments.iter().any(|c| c == comment) {
+                entry.comments.push(comment.to_string());
+            }
+        }
+    }
+
+    let mut root = Map::new();
+    root.insert("FULL_PATH".into(), json!("/"));
+    root.insert("ACCESS".into(), json!(ACCESS_NONE));
+    root.insert("CONTENTS".into(), json!({}));
+    for (address, entry) in entries {
+        let description = (!entry.comments.is_empty()).then(|| entry.comments.join("; "));
+        let (types, range) = entry.type_and_range();
+        insert(&mut root, address, method(types, range, description));
+    }
+
+    let layers: BTreeSet<String> = mappings
+        .iter()
+        .filter_map(|m| m.layer.clone())
+        .filter(|l| !l.is_empty())
+        .collect();
+    for address in &info.layer_addresses {
+        let description = Some("Selects the active song layer".to_string());
+        insert(
+            &mut root,
+            address,
+            method("s", vals(layers.iter().cloned().collect()), description),
+        );
+    }
+    for (address, types, description) in control::ADDRESSES {
+        let values = match *address {
+            control::SHOW_LOAD_ADDRESS => show_names.clone(),
+            control::LAYER_SET_ADDRESS => layers.iter().cloned().collect(),
+            _ => Vec::new(),
+        };
+        insert(
+            &mut root,
+            address,
+            method(types, vals(values), Some(description.to_string())),
+        );
+    }
+    Value::Object(root)
+}
+
+fn find<'a>(root: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .try_fold(root, |node, segment| node.get("CONTENTS")?.get(segment))
+}
+
+fn host_info(info: &ServerInfo) -> Value {
+    let mut host = json!({
+        "NAME": info.name,
+        "OSC_PORT": info.listener.port,
+        "OSC_TRANSPORT": if info.listener.transport == Transport::Udp { "UDP" } else { "TCP" },
+        "EXTENSIONS": {
+            "ACCESS": true,
+            "TYPE": true,
+            "RANGE": true,
+            "DESCRIPTION": true,
+            "VALUE": false,
+            "LISTEN": true,
+            "PATH_CHANGED": true,
+        },
+    });
+    // Without OSC_IP clients send to the host they queried
+    if let Ok(ip) = info.listener.address.parse::<IpAddr>() {
+        if !ip.is_unspecified() {
+            host["OSC_IP"] = json!(ip.to_string());
+        }
+    }
+    host
+}
+
+async fn handle(
+    State(info): State<Arc<ServerInfo>>,
+    uri: Uri,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    if let Ok(ws) = ws {
+        return ws.on_upgrade(serve_socket);
+    }
+    if uri.query() == Some("HOST_INFO") {
+        return Json(host_info(&info)).into_response();
+    }
+
+    let root = namespace(&info);
+    let Some(node) = find(&root, uri.path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match uri.query() {
+        None | Some("") => Json(node).into_response(),
+        // A single attribute, e.g. ?TYPE; 204 when the node doesn't have it
+        Some(attribute) => match node.get(attribute) {
+            Some(value) => Json(json!({ attribute: value })).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        },
+    }
+}
+
+fn apply_command(text: &str, listening: &mut HashSet<String>) {
+    let Ok(command) = serde_json::from_str::<Value>(text) else {
+        debug!("Ignoring OSCQuery WebSocket text: {text}");
+        return;
+    };
+    let path = command["DATA"].as_str().unwrap_or_default().to_string();
+    match command["COMMAND"].as_str() {
+        Some("LISTEN") => {
+            listening.insert(path);
+        }
+        Some("IGNORE") => {
+            listening.remove(&path);
+        }
+        other => debug!("Unsupported OSCQuery command {other:?}"),
+    }
+}
+
+async fn serve_socket(mut socket: WebSocket) {
+    let mut updates = UPDATES.subscribe();
+    let mut listening = HashSet::new();
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => apply_command(&text, &mut listening),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            update = updates.recv() => {
+                let message = match update {
+                    Ok(Update::Value(msg)) if listening.contains(&msg.addr) => {
+                        match encoder::encode(&OscPacket::Message((*msg).clone())) {
+                            Ok(bytes) => Message::Binary(bytes.into()),
+                            Err(_) => continue,
+                        }
+                    }
+                    Ok(Update::Value(_)) => continue,
+                    Ok(Update::PathChanged) => Message::Text(
+                        json!({ "COMMAND": "PATH_CHANGED", "DATA": "/" }).to_string().into(),
+                    ),
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("OSCQuery client fell behind, skipped {skipped} updates");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(message).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn advertise(
+    config: &OscQueryConfig,
+    listener: &ListenerConfig,
+) -> Result<ServiceDaemon, Box<dyn Error>> {
+    let daemon = ServiceDaemon::new()?;
+    let host: String = config
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let host_name = format!("{host}.local.");
+    let properties: &[(&str, &str)] = &[];
+    daemon.register(
+        ServiceInfo::new(
+            "_oscjson._tcp.local.",
+            &config.name,
+            &host_name,
+            "",
+            config.port,
+            properties,
+        )?
+        .enable_addr_auto(),
+    )?;
+    if listener.transport == Transport::Udp {
+        daemon.register(
+            ServiceInfo::new(
+                "_osc._udp.local.",
+                &config.name,
+                &host_name,
+                "",
+                listener.port,
+                properties,
+            )?
+            .enable_addr_auto(),
+        )?;
+    }
+    Ok(daemon)
+}
+
+// Stops serving and withdraws the mDNS announcements when dropped
+pub struct OscQuery {
+    task: JoinHandle<()>,
+    mdns: Option<ServiceDaemon>,
+}
+
+impl Drop for OscQuery {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Some(daemon) = self.mdns.take() {
+            let _ = daemon.shutdown();
+        }
+    }
+}
+
+pub async fn start(
+    config: &OscQueryConfig,
+    app: &helpers::Config,
+) -> Result<OscQuery, Box<dyn Error>> {
+    let listeners = app.listeners();
+    let listener = match &config.listener {
+        Some(name) => listeners
+            .into_iter()
+            .find(|l| &l.name == name)
+            .ok_or_else(|| format!("OSCQuery: no OSC listener named '{name}'"))?,
+        None => listeners
+            .into_iter()
+            .next()
+            .ok_or("OSCQuery: no OSC listener")?,
+    };
+    let ip: IpAddr = config
+        .address
+        .parse()
+        .map_err(|_| format!("OSCQuery: invalid address '{}'", config.address))?;
+    let http = TcpListener::bind(SocketAddr::new(ip, config.port)).await?;
+
+    let mdns = if config.mdns && ip.is_loopback() {
+        // Nothing else could reach it
+        info!("OSCQuery bound to {ip}, not announcing it over mDNS");
+        None
+    } else if config.mdns {
+        match advertise(config, &listener) {
+            Ok(daemon) => Some(daemon),
+            // Discovery is a convenience; the server still works by address
+            Err(e) => {
+                warn!("Could not announce OSCQuery over mDNS: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let info = Arc::new(ServerInfo {
+        name: config.name.clone(),
+        listener,
+        layer_addresses: app.layer_addresses.clone(),
+    });
+    let router = Router::new().fallback(handle).with_state(info);
+    let task = tokio::spawn(async move {
+        if let Err(e) = axum::serve(http, router).await {
+            warn!("OSCQuery server stopped: {e}");
+        }
+    });
+
+    info!("OSCQuery server on {}:{}", config.address, config.port);
+    Ok(OscQuery { task, mdns })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> ServerInfo {
+        ServerInfo {
+            name: "oDIsc".to_string(),
+            listener: ListenerConfig {
+                name: "desk".to_string(),
+                port: 8000,
+                ..Default::default()
+            },
+            layer_addresses: vec!["/song".to_string()],
+        }
+    }
+
+    fn mapping(address: &str) -> Mapping {
+        Mapping {
+            osc_in_address: address.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn scaled(address: &str, input: &str) -> Mapping {
+        Mapping {
+            scale_in: Some(input.to_string()),
+            scale_out: Some("0 127".to_string()),
+            ..mapping(address)
+        }
+    }
+
+    fn with_arg(address: &str, arg: &str, layer: &str) -> Mapping {
+        Mapping {
+            osc_in_args: Some(arg.to_string()),
+            layer: Some(layer.to_string()),
+            ..mapping(address)
+        }
+    }
+
+    #[test]
+    fn builds_nested_nodes() {
+        let mut go = mapping("/cue/go");
+        go._comment = Some("Next cue".into());
+        let mut other = mapping("/cue/back");
+        other.osc_in_listener = Some("stage".into());
+        let root = build_namespace(&info(), &[go, other], Vec::new());
+
+        let cue = find(&root, "/cue").unwrap();
+        assert_eq!(cue["FULL_PATH"], "/cue");
+        assert_eq!(cue["ACCESS"], ACCESS_NONE);
+        let node = find(&root, "/cue/go").unwrap();
+        assert_eq!(node["FULL_PATH"], "/cue/go");
+        assert_eq!(node["ACCESS"], ACCESS_WRITE);
+        assert_eq!(node["DESCRIPTION"], "Next cue");
+        assert!(node.get("TYPE").is_none());
+        // Bound to another listener
+        assert!(find(&root, "/cue/back").is_none());
+        assert!(find(&root, "/nothing").is_none());
+    }
+
+    #[test]
+    fn lists_string_arguments() {
+        let rows = [
+            with_arg("/scene", "intro", "a"),
+            with_arg("/scene", "chorus", "b"),
+        ];
+        let root = build_namespace(&info(), &rows, Vec::new());
+        let node = find(&root, "/scene").unwrap();
+        assert_eq!(node["TYPE"], "s");
+        assert_eq!(node["RANGE"], json!([{ "VALS": ["chorus", "intro"] }]));
+        // Layers show up on the layer address
+        assert_eq!(
+            find(&root, "/song").unwrap()["RANGE"],
+            json!([{ "VALS": ["a", "b"] }])
+        );
+    }
+
+    #[test]
+    fn scale_in_gives_numeric_type_and_range() {
+        let rows = [
+            scaled("/fader", "0 1"),
+            scaled("/knob", "0 127"),
+            scaled("/knob", "200 100"),
+            scaled("/pan", "-1.5 1.5"),
+        ];
+        let root = build_namespace(&info(), &rows, Vec::new());
+        let fader = find(&root, "/fader").unwrap();
+        assert_eq!(fader["TYPE"], "f");
+        assert_eq!(fader["RANGE"], json!([{ "MIN": 0.0, "MAX": 1.0 }]));
+        let knob = find(&root, "/knob").unwrap();
+        assert_eq!(knob["TYPE"], "i");
+        assert_eq!(knob["RANGE"], json!([{ "MIN": 0.0, "MAX": 200.0 }]));
+        assert_eq!(find(&root, "/pan").unwrap()["TYPE"], "f");
+    }
+
+    #[test]
+    fn control_addresses_list_shows() {
+        let shows = vec!["matinee".to_string(), "tour".to_string()];
+        let root = build_namespace(&info(), &[], shows);
+        let load = find(&root, control::SHOW_LOAD_ADDRESS).unwrap();
+        assert_eq!(load["TYPE"], "s");
+        assert_eq!(load["RANGE"], json!([{ "VALS": ["matinee", "tour"] }]));
+        assert!(find(&root, control::PANIC_ADDRESS)
+            .unwrap()
+            .get("RANGE")
+            .is_none());
+    }
+
+    #[test]
+    fn host_info_advertises_the_listener() {
+        let host = host_info(&info());
+        assert_eq!(host["OSC_PORT"], 8000);
+        assert_eq!(host["OSC_TRANSPORT"], "UDP");
+    }
+}
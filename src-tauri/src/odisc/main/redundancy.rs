@@ -0,0 +1,567 @@
+use crate::odisc::main::events;
+use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use lazy_static::lazy_static;
+
+// Primary/backup pair. Both instances receive the same OSC, but only the
+// active one sends MIDI and OSC. They exchange heartbeats over UDP and the
+// standby takes over once the active one has been quiet for the timeout.
+//
+// Every change of hands bumps an epoch carried in the heartbeat. If both end
+// up active (e.g. after a network split heals) the higher epoch keeps
+// control; on a tie the primary does. There is no automatic fail-back: a
+// primary that comes back stays standby until someone switches over.
+
+const HEARTBEAT_ADDRESS: &str = "/odisc/heartbeat";
+const TAKEOVER_ADDRESS: &str = "/odisc/redundancy/takeover";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Primary,
+    Backup,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Primary => "primary",
+            Role::Backup => "backup",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct RedundancyConfig {
+    pub role: Role,
+    // host:port of the other instance's heartbeat socket
+    pub peer: String,
+    #[serde(default = "default_heartbeat_port")]
+    pub heartbeat_port: u16,
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u64,
+    #[serde(default = "default_failover_timeout_ms")]
+    pub failover_timeout_ms: u64,
+}
+
+fn default_heartbeat_port() -> u16 {
+    9100
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    250
+}
+
+fn default_failover_timeout_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PeerStatus {
+    pub address: String,
+    pub role: Role,
+    pub active: bool,
+    pub alive: bool,
+    pub last_seen_ms: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RedundancyStatus {
+    pub enabled: bool,
+    pub role: Option<Role>,
+    pub active: bool,
+    pub epoch: u64,
+    pub peer: Option<PeerStatus>,
+}
+
+struct Peer {
+    role: Role,
+    active: bool,
+    epoch: u64,
+    last_seen: Instant,
+}
+
+struct State {
+    role: Role,
+    epoch: u64,
+    timeout: Duration,
+    started: Instant,
+    #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+    socket: Arc<UdpSocket>,
+    peer_addr: SocketAddr,
+    peer: Option<Peer>,
+    peer_alive: bool,
+}
+
+impl State {
+    fn peer_alive(&self) -> bool {
+        self.peer
+            .as_ref()
+            .is_some_and(|p| p.last_seen.elapsed() < self.timeout)
+    }
+
+    fn status(&self) -> RedundancyStatus {
+        RedundancyStatus {
+            enabled: true,
+            role: Some(self.role),
+            active: is_active(),
+            epoch: self.epoch,
+            peer: self.peer.as_ref().map(|p| PeerStatus {
+                address: self.peer_addr.to_string(),
+                role: p.role,
+                active: p.active,
+                alive: self.peer_alive(),
+                last_seen_ms: p.last_seen.elapsed().as_millis() as u64,
+            }),
+        }
+    }
+}
+
+// Without a redundancy config the instance is always active
+static ACTIVE: AtomicBool = AtomicBool::new(true);
+
+lazy_static! {
+    static ref STATE: Mutex<Option<State>> = Mutex::new(None);
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn status() -> RedundancyStatus {
+    match STATE.lock().unwrap().as_ref() {
+        Some(state) => state.status(),
+        None => RedundancyStatus {
+            enabled: false,
+            role: None,
+            active: true,
+            epoch: 0,
+            peer: None,
+        },
+    }
+}
+
+fn emit_status(state: &State) {
+    events::emit("redundancy-status", state.status());
+}
+
+fn become_active(state: &mut State, reason: &str) {
+    let peer_epoch = state.peer.as_ref().map_or(0, |p| p.epoch);
+    state.epoch = state.epoch.max(peer_epoch) + 1;
+    ACTIVE.store(true, Ordering::Relaxed);
+    warn!(
+        role = state.role.as_str(),
+        epoch = state.epoch,
+        "Now ACTIVE: {reason}"
+    );
+    emit_status(state);
+}
+
+fn become_standby(state: &mut State, reason: &str) {
+    ACTIVE.store(false, Ordering::Relaxed);
+    warn!(role = state.role.as_str(), "Now STANDBY: {reason}");
+    emit_status(state);
+}
+
+fn encode(addr: &str, args: Vec<OscType>) -> Option<Vec<u8>> {
+    encoder::encode(&OscPacket::Message(OscMessage {
+        addr: addr.to_string(),
+        args,
+    }))
+    .ok()
+}
+
+fn heartbeat(state: &State) -> Option<Vec<u8>> {
+    encode(
+        HEARTBEAT_ADDRESS,
+        vec![
+            OscType::String(state.role.as_str().to_string()),
+            OscType::Int(i32::from(is_active())),
+            OscType::Long(state.epoch as i64),
+        ],
+    )
+}
+
+fn handle_packet(state: &mut State, msg: &OscMessage) {
+    match (msg.addr.as_str(), &msg.args[..]) {
+        (
+            HEARTBEAT_ADDRESS,
+            [OscType::String(role), OscType::Int(active), OscType::Long(epoch)],
+        ) => {
+            let role = if role == "primary" {
+                Role::Primary
+            } else {
+                Role::Backup
+            };
+            if role == state.role {
+                warn!("Peer is also configured as {}", role.as_str());
+            }
+            state.peer = Some(Peer {
+                role,
+                active: *active != 0,
+                epoch: *epoch as u64,
+                last_seen: Instant::now(),
+            });
+            if !state.peer_alive {
+                state.peer_alive = true;
+                info!("Redundancy peer is up");
+                emit_status(state);
+            }
+
+            let peer_wins = *epoch as u64 > state.epoch
+                || (*epoch as u64 == state.epoch && role == Role::Primary);
+            if *active != 0 && is_active() && peer_wins {
+                become_standby(state, "peer holds a newer epoch");
+            }
+        }
+        (TAKEOVER_ADDRESS, []) => {
+            if !is_active() {
+                become_active(state, "peer handed over");
+            }
+        }
+        _ => debug!("Ignoring {} on the heartbeat socket", msg.addr),
+    }
+}
+
+fn check_failover(state: &mut State) {
+    let alive = state.peer_alive();
+    if state.peer_alive && !alive {
+        state.peer_alive = false;
+        warn!("Redundancy peer heartbeat lost");
+        emit_status(state);
+    }
+    if is_active() {
+        return;
+    }
+
+    let peer_active = alive && state.peer.as_ref().is_some_and(|p| p.active);
+    if peer_active {
+        return;
+    }
+    // At startup both are standby: a backup gives a live primary the first go
+    let defer = state.role == Role::Backup
+        && alive
+        && state.peer.as_ref().is_some_and(|p| p.role == Role::Primary);
+    let wait = if defer {
+        state.timeout * 2
+    } else {
+        state.timeout
+    };
+    let quiet_since = state
+        .peer
+        .as_ref()
+        .filter(|p| p.active)
+        .map_or(state.started, |p| p.last_seen);
+    if quiet_since.elapsed() >= wait {
+        become_active(state, "no active peer");
+    }
+}
+
+async fn run(socket: Arc<UdpSocket>, interval: Duration) {
+    let mut tick = tokio::time::interval(interval);
+    let mut buf = vec![0u8; 1024];
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                let (packet, peer_addr) = {
+                    let mut guard = STATE.lock().unwrap();
+                    let Some(state) = guard.as_mut() else { return };
+                    check_failover(state);
+                    (heartbeat(state), state.peer_addr)
+                };
+                if let Some(packet) = packet {
+                    if let Err(e) = socket.send_to(&packet, peer_addr).await {
+                        debug!("Heartbeat send failed: {e}");
+                    }
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (size, source) = match received {
+                    Ok(received) => received,
+                    // e.g. ICMP port unreachable while the peer is down
+                    Err(_) => continue,
+                };
+                let mut guard = STATE.lock().unwrap();
+                let Some(state) = guard.as_mut() else { return };
+                if source.ip() != state.peer_addr.ip() {
+                    debug!(%source, "Ignoring heartbeat from unknown host");
+                    continue;
+                }
+                if let Ok((_, OscPacket::Message(msg))) = decoder::decode_udp(&buf[..size]) {
+                    handle_packet(state, &msg);
+                }
+            }
+        }
+    }
+}
+
+// Stops heartbeats when the backend stops; the instance is active again
+// (i.e. unrestricted) until redundancy is started next time
+pub struct Redundancy {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Redundancy {
+    fn drop(&mut self) {
+        self.task.abort();
+        STATE.lock().unwrap().take();
+        ACTIVE.store(true, Ordering::Relaxed);
+        events::emit("redundancy-status", status());
+    }
+}
+
+pub async fn start(config: &RedundancyConfig) -> Result<Redundancy, Box<dyn Error>> {
+    let peer_addr = lookup_host(&config.peer)
+        .await?
+        .next()
+        .ok_or_else(|| format!("Could not resolve redundancy peer '{}'", config.peer))?;
+    let bind_addr = if peer_addr.is_ipv6() {
+        format!("[::]:{}", config.heartbeat_port)
+    } else {
+        format!("0.0.0.0:{}", config.heartbeat_port)
+    };
+    let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+
+    ACTIVE.store(false, Ordering::Relaxed);
+    let state = State {
+        role: config.role,
+        epoch: 0,
+        timeout: Duration::from_millis(config.failover_timeout_ms),
+        started: Instant::now(),
+        socket: socket.clone(),
+        peer_addr,
+        peer: None,
+        peer_alive: false,
+    };
+    emit_status(&state);
+    *STATE.lock().unwrap() = Some(state);
+    info!(
+        role = config.role.as_str(),
+        peer = %peer_addr,
+        "Redundancy started, standing by until the peer is heard from or times out"
+    );
+
+    let interval = Duration::from_millis(config.heartbeat_interval_ms.max(10));
+    Ok(Redundancy {
+        task: tokio::spawn(run(socket, interval)),
+    })
+}
+
+// Manual switchover: a standby instance takes control, an active one hands
+// control to the peer (which must be alive to accept it)
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub async fn switchover() -> Result<RedundancyStatus, String> {
+    let handover = {
+        let mut guard = STATE.lock().unwrap();
+        let state = guard.as_mut().ok_or("Redundancy is not enabled")?;
+        if !is_active() {
+            become_active(state, "manual switchover");
+            return Ok(state.status());
+        }
+        if !state.peer_alive() {
+            return Err("Peer is not responding, staying active".to_string());
+        }
+        (state.socket.clone(), state.peer_addr)
+    };
+
+    let (socket, peer_addr) = handover;
+    let packet = encode(TAKEOVER_ADDRESS, Vec::new()).ok_or("Could not encode takeover")?;
+    socket
+        .send_to(&packet, peer_addr)
+        .await
+        .map_err(|e| format!("Could not reach peer: {e}"))?;
+    info!("Asked peer to take over");
+    // We go standby once the peer's heartbeat shows it active with a newer epoch
+    Ok(status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    // ACTIVE is global, so these tests take turns
+    static TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    // A state started `age` ago, standby unless `active`
+    async fn state(role: Role, age: Duration, active: bool) -> State {
+        ACTIVE.store(active, Ordering::Relaxed);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        State {
+            role,
+            epoch: 0,
+            timeout: TIMEOUT,
+            started: Instant::now() - age,
+            socket: Arc::new(socket),
+            peer_addr: ([127, 0, 0, 1], 9).into(),
+            peer: None,
+            peer_alive: false,
+        }
+    }
+
+    fn peer(role: Role, active: bool, epoch: u64, age: Duration) -> Option<Peer> {
+        Some(Peer {
+            role,
+            active,
+            epoch,
+            last_seen: Instant::now() - age,
+        })
+    }
+
+    fn beat(role: Role, active: bool, epoch: u64) -> OscMessage {
+        OscMessage {
+            addr: HEARTBEAT_ADDRESS.to_string(),
+            args: vec![
+                OscType::String(role.as_str().to_string()),
+                OscType::Int(i32::from(active)),
+                OscType::Long(epoch as i64),
+            ],
+        }
+    }
+
+    fn finish() {
+        ACTIVE.store(true, Ordering::Relaxed);
+    }
+
+    #[tokio::test]
+    async fn takes_over_once_the_timeout_passes_without_a_peer() {
+        let _lock = TEST_LOCK.lock().await;
+        let mut s = state(Role::Primary, TIMEOUT / 2, false).await;
+        check_failover(&mut s);
+        assert!(!is_active());
+
+        s.started = Instant::now() - TIMEOUT;
+        check_failover(&mut s);
+        assert!(is_active());
+        assert_eq!(s.epoch, 1);
+        finish();
+    }
+
+    #[tokio::test]
+    async fn backup_defers_to_a_live_primary_at_startup() {
+        let _lock = TEST_LOCK.lock().await;
+        let mut s = state(Role::Backup, TIMEOUT * 3 / 2, false).await;
+        s.peer = peer(Role::Primary, false, 0, Duration::ZERO);
+        check_failover(&mut s);
+        assert!(!is_active());
+
+        // The primary gets twice the timeout, then the backup goes anyway
+        s.started = Instant::now() - TIMEOUT * 2;
+        s.peer = peer(Role::Primary, false, 0, Duration::ZERO);
+        check_failover(&mut s);
+        assert!(is_active());
+
+        // A primary in the same spot doesn't wait for a live backup
+        let mut s = state(Role::Primary, TIMEOUT, false).await;
+        s.peer = peer(Role::Backup, false, 0, Duration::ZERO);
+        check_failover(&mut s);
+        assert!(is_active());
+        finish();
+    }
+
+    #[tokio::test]
+    async fn standby_takes_over_when_the_active_peer_goes_quiet() {
+        let _lock = TEST_LOCK.lock().await;
+        let mut s = state(Role::Backup, TIMEOUT * 10, false).await;
+        s.peer = peer(Role::Primary, true, 4, TIMEOUT / 2);
+        s.peer_alive = true;
+        check_failover(&mut s);
+        assert!(!is_active(), "active peer is still within the timeout");
+
+        s.peer = peer(Role::Primary, true, 4, TIMEOUT);
+        check_failover(&mut s);
+        assert!(!s.peer_alive);
+        assert!(is_active());
+        // Past the peer's epoch, so our heartbeat wins when it comes back
+        assert_eq!(s.epoch, 5);
+        finish();
+    }
+
+    #[tokio::test]
+    async fn heartbeats_record_the_peer() {
+        let _lock = TEST_LOCK.lock().await;
+        let mut s = state(Role::Primary, Duration::ZERO, false).await;
+        let mut other = state(Role::Backup, Duration::ZERO, true).await;
+        other.epoch = 3;
+        let packet = heartbeat(&other).unwrap();
+        let Ok((_, OscPacket::Message(msg))) = decoder::decode_udp(&packet) else {
+            panic!("heartbeat did not decode");
+        };
+
+        ACTIVE.store(false, Ordering::Relaxed);
+        handle_packet(&mut s, &msg);
+        let peer = s.peer.as_ref().unwrap();
+        assert_eq!(peer.role, Role::Backup);
+        assert!(peer.active);
+        assert_eq!(peer.epoch, 3);
+        assert!(s.peer_alive);
+        assert!(s.status().peer.unwrap().alive);
+        finish();
+    }
+
+    #[tokio::test]
+    async fn split_brain_goes_to_the_newer_epoch() {
+        let _lock = TEST_LOCK.lock().await;
+        let mut s = state(Role::Backup, Duration::ZERO, true).await;
+        s.epoch = 5;
+        handle_packet(&mut s, &beat(Role::Primary, true, 4));
+        assert!(is_active(), "older epoch loses");
+        handle_packet(&mut s, &beat(Role::Primary, false, 9));
+        assert!(is_active(), "a standby peer never demotes us");
+        handle_packet(&mut s, &beat(Role::Primary, true, 6));
+        assert!(!is_active(), "newer epoch wins");
+        finish();
+    }
+
+    #[tokio::test]
+    async fn split_brain_tie_goes_to_the_primary() {
+        let _lock = TEST_LOCK.lock().await;
+        let mut backup = state(Role::Backup, Duration::ZERO, true).await;
+        backup.epoch = 2;
+        handle_packet(&mut backup, &beat(Role::Primary, true, 2));
+        assert!(!is_active());
+
+        let mut primary = state(Role::Primary, Duration::ZERO, true).await;
+        primary.epoch = 2;
+        handle_packet(&mut primary, &beat(Role::Backup, true, 2));
+        assert!(is_active());
+        finish();
+    }
+
+    #[tokio::test]
+    async fn takeover_request_makes_standby_active() {
+        let _lock = TEST_LOCK.lock().await;
+        let mut s = state(Role::Primary, Duration::ZERO, false).await;
+        s.epoch = 3;
+        s.peer = peer(Role::Backup, true, 7, Duration::ZERO);
+        let takeover = OscMessage {
+            addr: TAKEOVER_ADDRESS.to_string(),
+            args: Vec::new(),
+        };
+        handle_packet(&mut s, &takeover);
+        assert!(is_active());
+        assert_eq!(s.epoch, 8);
+
+        // Already active: nothing changes
+        handle_packet(&mut s, &takeover);
+        assert_eq!(s.epoch, 8);
+
+        // The handing-over peer then sees our newer epoch and stands by
+        let mut peer_state = state(Role::Backup, Duration::ZERO, true).await;
+        peer_state.epoch = 7;
+        handle_packet(&mut peer_state, &beat(Role::Primary, true, 8));
+        assert!(!is_active());
+        finish();
+    }
+}
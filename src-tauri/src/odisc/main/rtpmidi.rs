@@ -0,0 +1,1486 @@
+use crate::odisc::main::events;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use midir::SendError;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use lazy_static::lazy_static;
+
+// RTP-MIDI (AppleMIDI) network sessions, used as the MIDI port named
+// PORT_NAME in MIDI_OUTPUT_NAME and MIDI_INPUT_NAME. A session takes two UDP
+// ports, PORT for control and PORT + 1 for data. oDIsc invites every PEERS
+// entry ("host:control port") and, with ACCEPT, joins sessions started
+// elsewhere (macOS Audio MIDI Setup, rtpMIDI, network MIDI interfaces, or a
+// second instance on another PORT; list a pair of instances on one side only).
+// Invitations, clock sync (CK) and receiver feedback (RS) follow the AppleMIDI
+// protocol. Every packet carries an RFC 6295 recovery journal of the program,
+// controller, pitch bend and note state sent since the receivers' last
+// feedback, and lost packets from peers are repaired from theirs.
+
+pub const PORT_NAME: &str = "RTP-MIDI";
+
+const PROTOCOL_VERSION: u32 = 2;
+const PAYLOAD_TYPE: u8 = 0x61;
+const TICK: Duration = Duration::from_millis(250);
+const INVITE_INTERVAL: Duration = Duration::from_secs(1);
+// Unanswered invitations before slowing down to INVITE_BACKOFF
+const INVITE_ATTEMPTS: u32 = 12;
+const INVITE_BACKOFF: Duration = Duration::from_secs(10);
+// A few quick clock syncs after connecting, then a slow keepalive
+const FAST_SYNCS: u32 = 6;
+const FAST_SYNC_INTERVAL: Duration = Duration::from_millis(1500);
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(1);
+// Sent commands kept for the journal until every peer acknowledges them
+const JOURNAL_HISTORY: usize = 256;
+const MAX_COMMAND_SECTION: usize = 0x0FFF;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct RtpMidiConfig {
+    // Session name shown to peers
+    #[serde(default = "default_name")]
+    pub name: String,
+    #[serde(default = "default_bind_address")]
+    pub address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(default = "default_true")]
+    pub accept: bool,
+    // Announce the session as _apple-midi._udp
+    #[serde(default = "default_true")]
+    pub mdns: bool,
+}
+
+fn default_name() -> String {
+    "oDIsc".to_string()
+}
+
+fn default_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_port() -> u16 {
+    5004
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PeerStatus {
+    pub name: String,
+    pub address: String,
+    pub connected: bool,
+    pub latency_ms: Option<f64>,
+}
+
+lazy_static! {
+    static ref OUTPUT: RwLock<Option<UnboundedSender<Vec<u8>>>> = RwLock::new(None);
+    static ref INPUT: RwLock<Option<UnboundedSender<Vec<u8>>>> = RwLock::new(None);
+    static ref PEERS: RwLock<Vec<PeerStatus>> = RwLock::new(Vec::new());
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn peers() -> Vec<PeerStatus> {
+    PEERS.read().unwrap().clone()
+}
+
+// Sending end of the session, for MidiPort
+pub struct Output(UnboundedSender<Vec<u8>>);
+
+impl Output {
+    pub fn send(&self, message: &[u8]) -> Result<(), SendError> {
+        self.0
+            .send(message.to_vec())
+            .map_err(|_| SendError::Other("RTP-MIDI session is closed"))
+    }
+}
+
+pub fn output() -> Option<Output> {
+    OUTPUT.read().unwrap().clone().map(Output)
+}
+
+// Forwards received MIDI to `tx` until dropped
+pub struct Input;
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        *INPUT.write().unwrap() = None;
+    }
+}
+
+pub fn connect_input(tx: UnboundedSender<Vec<u8>>) -> Result<Input, Box<dyn Error>> {
+    if OUTPUT.read().unwrap().is_none() {
+        return Err("RTP-MIDI is not configured".into());
+    }
+    *INPUT.write().unwrap() = Some(tx);
+    Ok(Input)
+}
+
+fn random_u32() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(since_epoch) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(since_epoch.as_nanos());
+    }
+    hasher.finish() as u32
+}
+
+// Sequence numbers wrap; `a` is newer if it is less than half the range ahead
+fn newer(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+// AppleMIDI session packets
+
+fn session_packet(command: &[u8; 2], token: u32, ssrc: u32, name: Option<&str>) -> Vec<u8> {
+    let mut packet = vec![0xFF, 0xFF, command[0], command[1]];
+    packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    packet.extend_from_slice(&token.to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    if let Some(name) = name {
+        packet.extend_from_slice(name.as_bytes());
+        packet.push(0);
+    }
+    packet
+}
+
+fn sync_packet(ssrc: u32, count: u8, timestamps: [u64; 3]) -> Vec<u8> {
+    let mut packet = vec![0xFF, 0xFF, b'C', b'K'];
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.extend_from_slice(&[count, 0, 0, 0]);
+    for timestamp in timestamps {
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+    }
+    packet
+}
+
+fn feedback_packet(ssrc: u32, seq: u16) -> Vec<u8> {
+    let mut packet = vec![0xFF, 0xFF, b'R', b'S'];
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet
+}
+
+enum Packet<'a> {
+    Session {
+        command: [u8; 2],
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Sync {
+        ssrc: u32,
+        count: u8,
+        timestamps: [u64; 3],
+    },
+    Feedback {
+        ssrc: u32,
+        seq: u16,
+    },
+    Rtp(&'a [u8]),
+}
+
+fn parse(bytes: &[u8]) -> Option<Packet<'_>> {
+    if bytes.get(..2) != Some(&[0xFF, 0xFF]) {
+        return Some(Packet::Rtp(bytes));
+    }
+    let command = [*bytes.get(2)?, *bytes.get(3)?];
+    match &command {
+        b"CK" => Some(Packet::Sync {
+            ssrc: be_u32(bytes, 4)?,
+            count: *bytes.get(8)?,
+            timestamps: [be_u64(bytes, 12)?, be_u64(bytes, 20)?, be_u64(bytes, 28)?],
+        }),
+        b"RS" => Some(Packet::Feedback {
+            ssrc: be_u32(bytes, 4)?,
+            seq: be_u16(bytes, 8)?,
+        }),
+        _ => {
+            let name = bytes.get(16..).unwrap_or_default();
+            let name = name.split(|b| *b == 0).next().unwrap_or_default();
+            Some(Packet::Session {
+                command,
+                token: be_u32(bytes, 8)?,
+                ssrc: be_u32(bytes, 12)?,
+                name: String::from_utf8_lossy(name).into_owned(),
+            })
+        }
+    }
+}
+
+// RTP-MIDI command section
+
+fn data_length(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        _ => 0,
+    }
+}
+
+// Complete commands from a MIDI list. SysEx split over several packets is
+// dropped rather than reassembled.
+fn parse_commands(list: &[u8], first_has_delta: bool) -> Vec<Vec<u8>> {
+    let mut commands = Vec::new();
+    let mut at = 0;
+    let mut running = None;
+    let mut first = true;
+    while at < list.len() {
+        if !first || first_has_delta {
+            // Delta time, up to four bytes
+            for _ in 0..4 {
+                let Some(byte) = list.get(at) else { break };
+                at += 1;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+        first = false;
+        let Some(&byte) = list.get(at) else { break };
+        let status = if byte & 0x80 != 0 {
+            at += 1;
+            byte
+        } else {
+            match running {
+                Some(status) => status,
+                None => break,
+            }
+        };
+        if matches!(status, 0xF0 | 0xF7) {
+            running = None;
+            let Some(end) = list[at..].iter().position(|b| b & 0x80 != 0) else {
+                break;
+            };
+            if status == 0xF0 && list[at + end] == 0xF7 {
+                let mut sysex = vec![0xF0];
+                sysex.extend_from_slice(&list[at..=at + end]);
+                commands.push(sysex);
+            }
+            at += end + 1;
+            continue;
+        }
+        match status {
+            0x80..=0xEF => running = Some(status),
+            0xF1..=0xF6 => running = None,
+            _ => {}
+        }
+        let length = data_length(status);
+        let Some(data) = list.get(at..at + length) else {
+            break;
+        };
+        let mut command = vec![status];
+        command.extend_from_slice(data);
+        commands.push(command);
+        at += length;
+    }
+    commands
+}
+
+// Recovery journal, sender side: what changed since the checkpoint, per channel
+
+#[derive(Default)]
+struct ChannelLog {
+    bank: (Option<u8>, Option<u8>),
+    program: Option<(u8, Option<(u8, u8)>)>,
+    controllers: BTreeMap<u8, u8>,
+    pitch: Option<(u8, u8)>,
+    notes: BTreeMap<u8, u8>,
+    offs: BTreeSet<u8>,
+}
+
+impl ChannelLog {
+    fn add(&mut self, status: u8, data: &[u8]) {
+        match (status & 0xF0, data) {
+            (0x90, &[note, velocity]) if velocity > 0 => {
+                self.notes.insert(note, velocity);
+                self.offs.remove(&note);
+            }
+            (0x80 | 0x90, &[note, _]) => {
+                self.notes.remove(&note);
+                self.offs.insert(note);
+            }
+            (0xB0, &[controller, value]) => {
+                self.controllers.insert(controller, value);
+                match controller {
+                    0 => self.bank.0 = Some(value),
+                    32 => self.bank.1 = Some(value),
+                    _ => {}
+                }
+            }
+            (0xC0, &[program]) => {
+                let bank = self.bank.0.map(|msb| (msb, self.bank.1.unwrap_or(0)));
+                self.program = Some((program, bank));
+            }
+            (0xE0, &[lsb, msb]) => self.pitch = Some((lsb, msb)),
+            _ => {}
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.program.is_none()
+            && self.controllers.is_empty()
+            && self.pitch.is_none()
+            && self.notes.is_empty()
+            && self.offs.is_empty()
+    }
+
+    // Chapters P, C, W and N, in that order
+    fn encode(&self, channel: u8) -> Vec<u8> {
+        let mut flags = 0;
+        let mut chapters = Vec::new();
+        if let Some((program, bank)) = self.program {
+            flags |= 0x80;
+            let (msb, lsb) = bank.unwrap_or_default();
+            chapters.extend_from_slice(&[program, (u8::from(bank.is_some()) << 7) | msb, lsb]);
+        }
+        if !self.controllers.is_empty() {
+            flags |= 0x40;
+            chapters.push(self.controllers.len() as u8 - 1);
+            for (controller, value) in &self.controllers {
+                chapters.extend_from_slice(&[*controller, *value]);
+            }
+        }
+        if let Some((lsb, msb)) = self.pitch {
+            flags |= 0x10;
+            chapters.extend_from_slice(&[lsb, msb]);
+        }
+        if !self.notes.is_empty() || !self.offs.is_empty() {
+            flags |= 0x08;
+            // LEN 127 with LOW 15 and HIGH 0 would read as 128 logs
+            let logs: Vec<_> = self.notes.iter().take(126).collect();
+            let (low, high) = match (self.offs.first(), self.offs.last()) {
+                (Some(first), Some(last)) => (first / 8, last / 8),
+                _ => (15, 0),
+            };
+            chapters.extend_from_slice(&[logs.len() as u8, (low << 4) | high]);
+            for (note, velocity) in logs {
+                // Y set: play the note on recovery
+                chapters.extend_from_slice(&[*note, 0x80 | velocity]);
+            }
+            for octet in low..=high {
+                let bits = (0..8)
+                    .filter(|bit| self.offs.contains(&(octet * 8 + bit)))
+                    .fold(0u8, |bits, bit| bits | (0x80 >> bit));
+                chapters.push(bits);
+            }
+        }
+        let length = 3 + chapters.len();
+        let mut journal = vec![(channel << 3) | (length >> 8) as u8, length as u8, flags];
+        journal.extend(chapters);
+        journal
+    }
+}
+
+fn encode_journal<'a>(history: impl Iterator<Item = &'a [u8]>, checkpoint: u16) -> Option<Vec<u8>> {
+    let mut channels: BTreeMap<u8, ChannelLog> = BTreeMap::new();
+    for message in history {
+        if let [status @ 0x80..=0xEF, data @ ..] = message {
+            channels
+                .entry(status & 0x0F)
+                .or_default()
+                .add(*status, data);
+        }
+    }
+    channels.retain(|_, log| !log.is_empty());
+    if channels.is_empty() {
+        return None;
+    }
+    // A set, TOTCHAN = channel journals - 1
+    let mut journal = vec![0x20 | (channels.len() as u8 - 1)];
+    journal.extend_from_slice(&checkpoint.to_be_bytes());
+    for (channel, log) in &channels {
+        journal.extend(log.encode(*channel));
+    }
+    Some(journal)
+}
+
+// Recovery journal, receiver side: the state received so far, and the
+// commands that bring it in line with a peer's journal
+
+#[derive(Default, Clone)]
+struct MidiState {
+    notes: HashSet<(u8, u8)>,
+    controllers: HashMap<(u8, u8), u8>,
+    programs: HashMap<u8, u8>,
+    pitch: HashMap<u8, (u8, u8)>,
+}
+
+impl MidiState {
+    fn track(&mut self, message: &[u8]) {
+        let [status @ 0x80..=0xEF, data @ ..] = message else {
+            return;
+        };
+        let channel = status & 0x0F;
+        match (status & 0xF0, data) {
+            (0x90, &[note, velocity]) if velocity > 0 => {
+                self.notes.insert((channel, note));
+            }
+            (0x80 | 0x90, &[note, _]) => {
+                self.notes.remove(&(channel, note));
+            }
+            (0xB0, &[controller, value]) => {
+                self.controllers.insert((channel, controller), value);
+            }
+            (0xC0, &[program]) => {
+                self.programs.insert(channel, program);
+            }
+            (0xE0, &[lsb, msb]) => {
+                self.pitch.insert(channel, (lsb, msb));
+            }
+            _ => {}
+        }
+    }
+
+    // Recovered commands count as received, so later chapters don't repeat them
+    fn emit(&mut self, message: Vec<u8>, out: &mut Vec<Vec<u8>>) {
+        self.track(&message);
+        out.push(message);
+    }
+
+    fn recover_channel(
+        &mut self,
+        channel: u8,
+        flags: u8,
+        chapters: &[u8],
+        out: &mut Vec<Vec<u8>>,
+    ) -> Option<()> {
+        let mut at = 0;
+        if flags & 0x80 != 0 {
+            let p = chapters.get(at..at + 3)?;
+            at += 3;
+            let program = p[0] & 0x7F;
+            if self.programs.get(&channel) != Some(&program) {
+                if p[1] & 0x80 != 0 {
+                    self.emit(vec![0xB0 | channel, 0, p[1] & 0x7F], out);
+                    self.emit(vec![0xB0 | channel, 32, p[2] & 0x7F], out);
+                }
+                self.emit(vec![0xC0 | channel, program], out);
+            }
+        }
+        if flags & 0x40 != 0 {
+            let count = usize::from(chapters.get(at)? & 0x7F) + 1;
+            let logs = chapters.get(at + 1..at + 1 + 2 * count)?;
+            at += 1 + 2 * count;
+            for log in logs.chunks(2) {
+                // A set: toggle and count forms, not tracked here
+                if log[1] & 0x80 != 0 {
+                    continue;
+                }
+                let (controller, value) = (log[0] & 0x7F, log[1]);
+                if self.controllers.get(&(channel, controller)) != Some(&value) {
+                    self.emit(vec![0xB0 | channel, controller, value], out);
+                }
+            }
+        }
+        if flags & 0x20 != 0 {
+            // Chapter M (parameter numbers), skipped by its length
+            at +=
+                (usize::from(chapters.get(at)? & 0x03) << 8) | usize::from(*chapters.get(at + 1)?);
+        }
+        if flags & 0x10 != 0 {
+            let w = chapters.get(at..at + 2)?;
+            at += 2;
+            let bend = (w[0] & 0x7F, w[1] & 0x7F);
+            if self.pitch.get(&channel) != Some(&bend) {
+                self.emit(vec![0xE0 | channel, bend.0, bend.1], out);
+            }
+        }
+        if flags & 0x08 != 0 {
+            let header = chapters.get(at..at + 2)?;
+            let (low, high) = (header[1] >> 4, header[1] & 0x0F);
+            let mut count = usize::from(header[0] & 0x7F);
+            if count == 127 && low == 15 && high == 0 {
+                count = 128;
+            }
+            let logs = chapters.get(at + 2..at + 2 + 2 * count)?;
+            at += 2 + 2 * count;
+            for log in logs.chunks(2) {
+                let (note, velocity) = (log[0] & 0x7F, log[1] & 0x7F);
+                let play = log[1] & 0x80 != 0;
+                if play && velocity > 0 && !self.notes.contains(&(channel, note)) {
+                    self.emit(vec![0x90 | channel, note, velocity], out);
+                }
+            }
+            if low <= high {
+                let offbits = chapters.get(at..at + usize::from(high - low) + 1)?;
+                for (octet, bits) in (low..=high).zip(offbits) {
+                    for bit in 0..8 {
+                        let note = octet * 8 + bit;
+                        if bits & (0x80 >> bit) != 0 && self.notes.contains(&(channel, note)) {
+                            self.emit(vec![0x80 | channel, note, 0], out);
+                        }
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn recover(&self, journal: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let mut state = self.clone();
+        let header = *journal.first()?;
+        let mut at = 3;
+        if header & 0x40 != 0 {
+            // System journal, skipped by its length
+            at += (usize::from(journal.get(at)? & 0x03) << 8) | usize::from(*journal.get(at + 1)?);
+        }
+        let mut out = Vec::new();
+        if header & 0x20 == 0 {
+            return Some(out);
+        }
+        for _ in 0..=(header & 0x0F) {
+            let channel_header = journal.get(at..at + 3)?;
+            let channel = (channel_header[0] >> 3) & 0x0F;
+            let length =
+                (usize::from(channel_header[0] & 0x03) << 8) | usize::from(channel_header[1]);
+            if length < 3 {
+                return None;
+            }
+            let chapters = journal.get(at + 3..at + length)?;
+            state.recover_channel(channel, channel_header[2], chapters, &mut out)?;
+            at += length;
+        }
+        Some(out)
+    }
+}
+
+// Session
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    InviteControl,
+    InviteData,
+    Connected,
+}
+
+struct Peer {
+    control: SocketAddr,
+    data: SocketAddr,
+    ssrc: Option<u32>,
+    name: String,
+    token: u32,
+    stage: Stage,
+    // Listed in PEERS: we invite, drive clock sync and re-invite after a drop
+    initiator: bool,
+    attempts: u32,
+    next_attempt: Instant,
+    last_seen: Instant,
+    syncs: u32,
+    next_sync: Instant,
+    latency: Option<Duration>,
+    // Last of our packets the peer confirmed with RS
+    acked: Option<u16>,
+    expected: Option<u16>,
+    feedback_due: bool,
+    last_feedback: Option<Instant>,
+    received: MidiState,
+}
+
+impl Peer {
+    fn new(control: SocketAddr, token: u32, initiator: bool, now: Instant) -> Peer {
+        Peer {
+            control,
+            data: SocketAddr::new(control.ip(), control.port().wrapping_add(1)),
+            ssrc: None,
+            name: control.to_string(),
+            token,
+            stage: Stage::InviteControl,
+            initiator,
+            attempts: 0,
+            next_attempt: now,
+            last_seen: now,
+            syncs: 0,
+            next_sync: now,
+            latency: None,
+            acked: None,
+            expected: None,
+            feedback_due: false,
+            last_feedback: None,
+            received: MidiState::default(),
+        }
+    }
+
+    // Back to inviting, as if just configured
+    fn reset(&mut self, now: Instant) {
+        *self = Peer {
+            name: self.name.clone(),
+            next_attempt: now + INVITE_BACKOFF,
+            ..Peer::new(self.control, self.token, true, now)
+        };
+    }
+
+    fn status(&self) -> PeerStatus {
+        PeerStatus {
+            name: self.name.clone(),
+            address: self.control.to_string(),
+            connected: self.stage == Stage::Connected,
+            latency_ms: self.latency.map(|l| l.as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+fn send(socket: &UdpSocket, packet: &[u8], to: SocketAddr) {
+    if let Err(e) = socket.try_send_to(packet, to) {
+        debug!(%to, "RTP-MIDI send failed: {e}");
+    }
+}
+
+struct Session {
+    name: String,
+    accept: bool,
+    control: Arc<UdpSocket>,
+    data: Arc<UdpSocket>,
+    ssrc: u32,
+    start: Instant,
+    peers: Vec<Peer>,
+    seq: u16,
+    history: VecDeque<(u16, Vec<u8>)>,
+}
+
+impl Session {
+    // Session clock, in the 100 microsecond units of CK and RTP timestamps
+    fn timestamp(&self) -> u64 {
+        (self.start.elapsed().as_micros() / 100) as u64
+    }
+
+    fn peers_changed(&self) {
+        let statuses: Vec<_> = self.peers.iter().map(Peer::status).collect();
+        *PEERS.write().unwrap() = statuses.clone();
+        events::emit("rtp-midi-peers", statuses);
+    }
+
+    fn on_control(&mut self, bytes: &[u8], from: SocketAddr) {
+        match parse(bytes) {
+            Some(Packet::Session {
+                command,
+                token,
+                ssrc,
+                name,
+            }) => match &command {
+                b"IN" => self.on_invitation(token, ssrc, name, from, true),
+                b"OK" => self.on_accepted(token, ssrc, name, true),
+                b"NO" => self.on_rejected(token),
+                b"BY" => self.on_bye(ssrc),
+                _ => {}
+            },
+            Some(Packet::Feedback { ssrc, seq }) => self.on_feedback(ssrc, seq),
+            _ => {}
+        }
+    }
+
+    fn on_data(&mut self, bytes: &[u8], from: SocketAddr) {
+        match parse(bytes) {
+            Some(Packet::Session {
+                command,
+                token,
+                ssrc,
+                name,
+            }) => match &command {
+                b"IN" => self.on_invitation(token, ssrc, name, from, false),
+                b"OK" => self.on_accepted(token, ssrc, name, false),
+                b"NO" => self.on_rejected(token),
+                b"BY" => self.on_bye(ssrc),
+                _ => {}
+            },
+            Some(Packet::Sync {
+                ssrc,
+                count,
+                timestamps,
+            }) => self.on_sync(ssrc, count, timestamps, from),
+            Some(Packet::Rtp(packet)) => self.on_rtp(packet),
+            _ => {}
+        }
+    }
+
+    fn on_invitation(
+        &mut self,
+        token: u32,
+        ssrc: u32,
+        name: String,
+        from: SocketAddr,
+        control: bool,
+    ) {
+        let socket = if control { &self.control } else { &self.data };
+        if !self.accept {
+            debug!(%from, "RTP-MIDI invitation from '{name}' declined");
+            send(socket, &session_packet(b"NO", token, self.ssrc, None), from);
+            return;
+        }
+        let now = Instant::now();
+        if control {
+            // A peer that reconnects replaces its old entry
+            self.peers.retain(|p| p.initiator || p.ssrc != Some(ssrc));
+            let mut peer = Peer::new(from, token, false, now);
+            peer.ssrc = Some(ssrc);
+            peer.name = name;
+            peer.stage = Stage::InviteData;
+            self.peers.push(peer);
+        } else {
+            let Some(peer) = self
+                .peers
+                .iter_mut()
+                .find(|p| !p.initiator && p.ssrc == Some(ssrc))
+            else {
+                // The control port comes first
+                send(socket, &session_packet(b"NO", token, self.ssrc, None), from);
+                return;
+            };
+            peer.data = from;
+            peer.stage = Stage::Connected;
+            peer.last_seen = now;
+            info!(%from, "RTP-MIDI session joined by '{}'", peer.name);
+        }
+        send(
+            socket,
+            &session_packet(b"OK", token, self.ssrc, Some(&self.name)),
+            from,
+        );
+        if !control {
+            self.peers_changed();
+        }
+    }
+
+    fn on_accepted(&mut self, token: u32, ssrc: u32, name: String, control: bool) {
+        let now = Instant::now();
+        let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|p| p.initiator && p.token == token)
+        else {
+            return;
+        };
+        match (peer.stage, control) {
+            (Stage::InviteControl, true) => {
+                peer.ssrc = Some(ssrc);
+                peer.name = name;
+                peer.stage = Stage::InviteData;
+                peer.attempts = 0;
+                peer.next_attempt = now + INVITE_INTERVAL;
+                send(
+                    &self.data,
+                    &session_packet(b"IN", token, self.ssrc, Some(&self.name)),
+                    peer.data,
+                );
+            }
+            (Stage::InviteData, false) => {
+                peer.stage = Stage::Connected;
+                peer.last_seen = now;
+                peer.syncs = 0;
+                peer.next_sync = now;
+                info!(address = %peer.control, "RTP-MIDI session started with '{}'", peer.name);
+                self.peers_changed();
+            }
+            _ => {}
+        }
+    }
+
+    fn on_rejected(&mut self, token: u32) {
+        let now = Instant::now();
+        if let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|p| p.initiator && p.token == token && p.stage != Stage::Connected)
+        {
+            warn!(address = %peer.control, "RTP-MIDI invitation declined");
+            peer.stage = Stage::InviteControl;
+            peer.next_attempt = now + INVITE_BACKOFF;
+        }
+    }
+
+    fn on_bye(&mut self, ssrc: u32) {
+        let now = Instant::now();
+        let Some(index) = self.peers.iter().position(|p| p.ssrc == Some(ssrc)) else {
+            return;
+        };
+        info!("RTP-MIDI session ended by '{}'", self.peers[index].name);
+        if self.peers[index].initiator {
+            self.peers[index].reset(now);
+        } else {
+            self.peers.remove(index);
+        }
+        self.peers_changed();
+        self.trim_history();
+    }
+
+    fn on_feedback(&mut self, ssrc: u32, seq: u16) {
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.ssrc == Some(ssrc)) {
+            peer.acked = Some(seq);
+            self.trim_history();
+        }
+    }
+
+    fn on_sync(&mut self, ssrc: u32, count: u8, timestamps: [u64; 3], from: SocketAddr) {
+        let now = self.timestamp();
+        let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|p| p.stage == Stage::Connected && p.ssrc == Some(ssrc))
+        else {
+            return;
+        };
+        peer.last_seen = Instant::now();
+        let [ts1, ts2, ts3] = timestamps;
+        // Half the round trip, in 100 microsecond units
+        let latency = |from: u64, to: u64| Duration::from_micros(to.saturating_sub(from) * 50);
+        match count {
+            0 => send(&self.data, &sync_packet(self.ssrc, 1, [ts1, now, 0]), from),
+            1 => {
+                send(
+                    &self.data,
+                    &sync_packet(self.ssrc, 2, [ts1, ts2, now]),
+                    from,
+                );
+                peer.latency = Some(latency(ts1, now));
+            }
+            2 => peer.latency = Some(latency(ts1, ts3)),
+            _ => {}
+        }
+    }
+
+    fn on_rtp(&mut self, packet: &[u8]) {
+        if packet.len() < 12 || packet[0] & 0xC0 != 0x80 || packet[1] & 0x7F != PAYLOAD_TYPE {
+            return;
+        }
+        let (Some(seq), Some(ssrc)) = (be_u16(packet, 2), be_u32(packet, 8)) else {
+            return;
+        };
+        let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|p| p.stage == Stage::Connected && p.ssrc == Some(ssrc))
+        else {
+            return;
+        };
+        peer.last_seen = Instant::now();
+
+        let csrc = usize::from(packet[0] & 0x0F) * 4;
+        let Some(section) = packet.get(12 + csrc..) else {
+            return;
+        };
+        let Some(&header) = section.first() else {
+            return;
+        };
+        let (length, list_start) = if header & 0x80 != 0 {
+            let Some(&low) = section.get(1) else { return };
+            ((usize::from(header & 0x0F) << 8) | usize::from(low), 2)
+        } else {
+            (usize::from(header & 0x0F), 1)
+        };
+        let Some(list) = section.get(list_start..list_start + length) else {
+            return;
+        };
+        let journal = (header & 0x40 != 0).then(|| &section[list_start + length..]);
+
+        let mut commands = Vec::new();
+        if let Some(expected) = peer.expected {
+            if seq != expected {
+                if !newer(seq, expected) {
+                    debug!(
+                        "RTP-MIDI: late or duplicate packet {seq} from '{}'",
+                        peer.name
+                    );
+                    return;
+                }
+                let lost = seq.wrapping_sub(expected);
+                match journal.map(|j| peer.received.recover(j)) {
+                    Some(Some(recovered)) => {
+                        debug!(
+                            "RTP-MIDI: {lost} packet(s) from '{}' lost, recovered {} command(s)",
+                            peer.name,
+                            recovered.len()
+                        );
+                        commands.extend(recovered);
+                    }
+                    Some(None) => warn!(
+                        "RTP-MIDI: {lost} packet(s) from '{}' lost, journal unreadable",
+                        peer.name
+                    ),
+                    None => warn!(
+                        "RTP-MIDI: {lost} packet(s) from '{}' lost, no journal",
+                        peer.name
+                    ),
+                }
+            }
+        }
+        peer.expected = Some(seq.wrapping_add(1));
+        peer.feedback_due = true;
+        commands.extend(parse_commands(list, header & 0x20 != 0));
+
+        let input = INPUT.read().unwrap();
+        for command in commands {
+            peer.received.track(&command);
+            if let Some(tx) = input.as_ref() {
+                let _ = tx.send(command);
+            }
+        }
+    }
+
+    // Next RTP packet for one command, with the journal of those before it
+    fn rtp_packet(&mut self, message: &[u8]) -> Vec<u8> {
+        let checkpoint = self
+            .history
+            .front()
+            .map_or(self.seq, |(seq, _)| *seq)
+            .wrapping_sub(1);
+        let journal = encode_journal(self.history.iter().map(|(_, m)| m.as_slice()), checkpoint);
+
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let mut packet = vec![0x80, PAYLOAD_TYPE];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&(self.timestamp() as u32).to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        let j = if journal.is_some() { 0x40 } else { 0 };
+        if message.len() <= 0x0F {
+            packet.push(j | message.len() as u8);
+        } else {
+            packet.push(0x80 | j | (message.len() >> 8) as u8);
+            packet.push(message.len() as u8);
+        }
+        packet.extend_from_slice(message);
+        if let Some(journal) = journal {
+            packet.extend(journal);
+        }
+
+        self.history.push_back((seq, message.to_vec()));
+        if self.history.len() > JOURNAL_HISTORY {
+            self.history.pop_front();
+        }
+        packet
+    }
+
+    fn send_midi(&mut self, message: &[u8]) {
+        if message.len() > MAX_COMMAND_SECTION {
+            warn!("RTP-MIDI: {} byte message too long to send", message.len());
+            return;
+        }
+        let packet = self.rtp_packet(message);
+        for peer in self.peers.iter().filter(|p| p.stage == Stage::Connected) {
+            send(&self.data, &packet, peer.data);
+        }
+    }
+
+    // Forgets what every connected peer has confirmed
+    fn trim_history(&mut self) {
+        let mut checkpoint: Option<u16> = None;
+        for peer in self.peers.iter().filter(|p| p.stage == Stage::Connected) {
+            let Some(acked) = peer.acked else {
+                return;
+            };
+            checkpoint = Some(match checkpoint {
+                Some(oldest) if newer(acked, oldest) => oldest,
+                _ => acked,
+            });
+        }
+        let Some(checkpoint) = checkpoint else {
+            return;
+        };
+        while self
+            .history
+            .front()
+            .is_some_and(|(seq, _)| !newer(*seq, checkpoint))
+        {
+            self.history.pop_front();
+        }
+    }
+
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let timestamp = self.timestamp();
+        let mut changed = false;
+        let Session {
+            name,
+            control,
+            data,
+            ssrc,
+            peers,
+            ..
+        } = self;
+        peers.retain_mut(|peer| {
+            match peer.stage {
+                Stage::InviteControl | Stage::InviteData
+                    if peer.initiator && now >= peer.next_attempt =>
+                {
+                    if peer.attempts == INVITE_ATTEMPTS {
+                        info!(address = %peer.control, "RTP-MIDI peer not answering, still trying");
+                    }
+                    let invitation = session_packet(b"IN", peer.token, *ssrc, Some(name));
+                    match peer.stage {
+                        Stage::InviteControl => send(control, &invitation, peer.control),
+                        _ => send(data, &invitation, peer.data),
+                    }
+                    peer.attempts += 1;
+                    peer.next_attempt = now
+                        + if peer.attempts >= INVITE_ATTEMPTS {
+                            INVITE_BACKOFF
+                        } else {
+                            INVITE_INTERVAL
+                        };
+                }
+                // Accepted on the control port, but the data invitation never came
+                Stage::InviteData if !peer.initiator => {
+                    return now.duration_since(peer.last_seen) < PEER_TIMEOUT;
+                }
+                Stage::Connected => {
+                    if now.duration_since(peer.last_seen) > PEER_TIMEOUT {
+                        warn!("RTP-MIDI peer '{}' timed out", peer.name);
+                        changed = true;
+                        if !peer.initiator {
+                            return false;
+                        }
+                        peer.reset(now);
+                        return true;
+                    }
+                    if peer.initiator && now >= peer.next_sync {
+                        send(data, &sync_packet(*ssrc, 0, [timestamp, 0, 0]), peer.data);
+                        peer.syncs += 1;
+                        peer.next_sync = now
+                            + if peer.syncs < FAST_SYNCS {
+                                FAST_SYNC_INTERVAL
+                            } else {
+                                SYNC_INTERVAL
+                            };
+                    }
+                    let feedback_ready = peer
+                        .last_feedback
+                        .is_none_or(|t| now.duration_since(t) >= FEEDBACK_INTERVAL);
+                    if peer.feedback_due && feedback_ready {
+                        if let Some(expected) = peer.expected {
+                            let last = expected.wrapping_sub(1);
+                            send(control, &feedback_packet(*ssrc, last), peer.control);
+                        }
+                        peer.feedback_due = false;
+                        peer.last_feedback = Some(now);
+                    }
+                }
+                _ => {}
+            }
+            true
+        });
+        if changed {
+            self.peers_changed();
+        } else {
+            *PEERS.write().unwrap() = self.peers.iter().map(Peer::status).collect();
+        }
+    }
+
+    fn goodbye(&self) {
+        for peer in self.peers.iter().filter(|p| p.stage == Stage::Connected) {
+            send(
+                &self.control,
+                &session_packet(b"BY", peer.token, self.ssrc, None),
+                peer.control,
+            );
+        }
+    }
+}
+
+async fn run(
+    mut session: Session,
+    mut outgoing: UnboundedReceiver<Vec<u8>>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let control = session.control.clone();
+    let data = session.data.clone();
+    let mut control_buf = vec![0u8; 0x4000];
+    let mut data_buf = vec![0u8; 0x4000];
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            received = control.recv_from(&mut control_buf) => {
+                // Errors here are ICMP echoes of earlier sends, nothing to act on
+                if let Ok((len, from)) = received {
+                    session.on_control(&control_buf[..len], from);
+                }
+            }
+            received = data.recv_from(&mut data_buf) => {
+                if let Ok((len, from)) = received {
+                    session.on_data(&data_buf[..len], from);
+                }
+            }
+            Some(message) = outgoing.recv() => session.send_midi(&message),
+            _ = tick.tick() => session.tick(),
+        }
+    }
+    session.goodbye();
+    PEERS.write().unwrap().clear();
+}
+
+fn advertise(config: &RtpMidiConfig) -> Result<ServiceDaemon, Box<dyn Error>> {
+    let daemon = ServiceDaemon::new()?;
+    let host: String = config
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let properties: &[(&str, &str)] = &[];
+    daemon.register(
+        ServiceInfo::new(
+            "_apple-midi._udp.local.",
+            &config.name,
+            &format!("{host}.local."),
+            "",
+            config.port,
+            properties,
+        )?
+        .enable_addr_auto(),
+    )?;
+    Ok(daemon)
+}
+
+// Says goodbye to every peer and withdraws the announcement when dropped
+pub struct RtpMidi {
+    _stop: oneshot::Sender<()>,
+    mdns: Option<ServiceDaemon>,
+}
+
+impl Drop for RtpMidi {
+    fn drop(&mut self) {
+        *OUTPUT.write().unwrap() = None;
+        if let Some(daemon) = self.mdns.take() {
+            let _ = daemon.shutdown();
+        }
+    }
+}
+
+pub async fn start(config: &RtpMidiConfig) -> Result<RtpMidi, Box<dyn Error>> {
+    let ip: IpAddr = config
+        .address
+        .parse()
+        .map_err(|_| format!("RTP-MIDI: invalid address '{}'", config.address))?;
+    let data_port = config
+        .port
+        .checked_add(1)
+        .ok_or("RTP-MIDI: PORT + 1 is the data port, so PORT must be below 65535")?;
+    let control = UdpSocket::bind(SocketAddr::new(ip, config.port))
+        .await
+        .map_err(|e| format!("RTP-MIDI control port {}: {e}", config.port))?;
+    let data = UdpSocket::bind(SocketAddr::new(ip, data_port))
+        .await
+        .map_err(|e| format!("RTP-MIDI data port {data_port}: {e}"))?;
+
+    let now = Instant::now();
+    let mut peers = Vec::new();
+    for peer in &config.peers {
+        let address = tokio::net::lookup_host(peer.as_str())
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("RTP-MIDI: invalid peer '{peer}', expected host:port"))?;
+        peers.push(Peer::new(address, random_u32(), true, now));
+    }
+
+    let (tx, rx) = unbounded_channel();
+    *OUTPUT.write().unwrap() = Some(tx);
+    let session = Session {
+        name: config.name.clone(),
+        accept: config.accept,
+        control: Arc::new(control),
+        data: Arc::new(data),
+        ssrc: random_u32(),
+        start: now,
+        peers,
+        seq: random_u32() as u16,
+        history: VecDeque::new(),
+    };
+    session.peers_changed();
+
+    let mdns = if config.mdns {
+        match advertise(config) {
+            Ok(daemon) => Some(daemon),
+            // Peers can still connect by address
+            Err(e) => {
+                warn!("RTP-MIDI mDNS announcement failed: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let (stop, stopped) = oneshot::channel();
+    tokio::spawn(run(session, rx, stopped));
+    info!(
+        "RTP-MIDI session '{}' on {}:{} and {data_port}",
+        config.name, config.address, config.port
+    );
+    Ok(RtpMidi { _stop: stop, mdns })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(history: &[&[u8]]) -> Vec<u8> {
+        encode_journal(history.iter().copied(), 0).unwrap()
+    }
+
+    fn state(messages: &[&[u8]]) -> MidiState {
+        let mut state = MidiState::default();
+        for message in messages {
+            state.track(message);
+        }
+        state
+    }
+
+    #[test]
+    fn journal_recovers_every_chapter() {
+        let history: &[&[u8]] = &[
+            &[0xB2, 0, 1],
+            &[0xB2, 32, 5],
+            &[0xC2, 7],
+            &[0xB2, 7, 100],
+            &[0xE2, 0x10, 0x40],
+            &[0x92, 60, 80],
+            &[0x92, 64, 0],
+        ];
+        let journal = journal(history);
+        // The receiver missed everything, but had note 64 from before
+        let recovered = state(&[&[0x92, 64, 90]]).recover(&journal).unwrap();
+        assert_eq!(
+            recovered,
+            [
+                vec![0xB2, 0, 1],
+                vec![0xB2, 32, 5],
+                vec![0xC2, 7],
+                vec![0xB2, 7, 100],
+                vec![0xE2, 0x10, 0x40],
+                vec![0x92, 60, 80],
+                vec![0x82, 64, 0],
+            ]
+        );
+        // Nothing to repair once the state matches
+        assert!(state(history).recover(&journal).unwrap().is_empty());
+    }
+
+    #[test]
+    fn journal_covers_the_whole_note_range() {
+        let mut log = ChannelLog::default();
+        log.add(0x80, &[0, 0]);
+        log.add(0x80, &[127, 0]);
+        let encoded = log.encode(0);
+        // N header: no logs, LOW 0 and HIGH 15, then 16 offbit octets
+        assert_eq!(&encoded[3..5], &[0, 0x0F]);
+        assert_eq!(encoded.len(), 3 + 2 + 16);
+
+        let journal = journal(&[&[0x80, 0, 0], &[0x80, 127, 0]]);
+        let held = state(&[&[0x90, 0, 1], &[0x90, 5, 1], &[0x90, 127, 1]]);
+        assert_eq!(
+            held.recover(&journal).unwrap(),
+            [vec![0x80, 0, 0], vec![0x80, 127, 0]]
+        );
+    }
+
+    #[test]
+    fn journal_without_offs_skips_offbits() {
+        let notes: Vec<[u8; 3]> = (0..127).map(|note| [0x90, note, 100]).collect();
+        let history: Vec<&[u8]> = notes.iter().map(|n| n.as_slice()).collect();
+        let mut log = ChannelLog::default();
+        for message in &history {
+            log.add(message[0], &message[1..]);
+        }
+        let encoded = log.encode(0);
+        // At most 126 logs, so LEN 127 never meets LOW 15 / HIGH 0
+        assert_eq!(&encoded[3..5], &[126, 0xF0]);
+        assert_eq!(encoded.len(), 3 + 2 + 2 * 126);
+
+        let recovered = MidiState::default().recover(&journal(&history)).unwrap();
+        assert_eq!(recovered.len(), 126);
+        assert_eq!(recovered[0], [0x90, 0, 100]);
+    }
+
+    #[test]
+    fn parses_running_status_and_deltas() {
+        let list = [0x90, 60, 64, 0x00, 62, 64, 0x00, 0x80, 60, 0];
+        assert_eq!(
+            parse_commands(&list, false),
+            [vec![0x90, 60, 64], vec![0x90, 62, 64], vec![0x80, 60, 0]]
+        );
+        // Two byte delta before the first command
+        assert_eq!(
+            parse_commands(&[0x81, 0x00, 0xB0, 7, 100], true),
+            [vec![0xB0, 7, 100]]
+        );
+        // Running status without a status byte before it
+        assert!(parse_commands(&[60, 64], false).is_empty());
+    }
+
+    #[test]
+    fn parses_sysex() {
+        let list = [0xF0, 0x7F, 0x01, 0xF7, 0x00, 0xC0, 5];
+        assert_eq!(
+            parse_commands(&list, false),
+            [vec![0xF0, 0x7F, 0x01, 0xF7], vec![0xC0, 5]]
+        );
+        // The first part of a split SysEx is dropped
+        assert_eq!(
+            parse_commands(&[0xF0, 0x01, 0xF0, 0x00, 0xC0, 5], false),
+            [vec![0xC0, 5]]
+        );
+        // SysEx ends running status
+        let list = [0x90, 60, 64, 0x00, 0xF0, 1, 0xF7, 0x00, 62, 64];
+        assert_eq!(
+            parse_commands(&list, false),
+            [vec![0x90, 60, 64], vec![0xF0, 1, 0xF7]]
+        );
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(newer(1, 0));
+        assert!(!newer(0, 1));
+        assert!(!newer(5, 5));
+        assert!(newer(0, 0xFFFF));
+        assert!(!newer(0xFFFF, 0));
+        assert!(newer(0x7FFF, 0));
+        assert!(!newer(0x8000, 0));
+    }
+
+    // Control and data ports next to each other, as peers expect
+    async fn sockets() -> (UdpSocket, UdpSocket) {
+        loop {
+            let control = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = control.local_addr().unwrap().port();
+            if let Some(data_port) = port.checked_add(1) {
+                if let Ok(data) = UdpSocket::bind(("127.0.0.1", data_port)).await {
+                    // try_send_to needs the reactor to have seen them writable
+                    control.writable().await.unwrap();
+                    data.writable().await.unwrap();
+                    return (control, data);
+                }
+            }
+        }
+    }
+
+    async fn session(name: &str, accept: bool) -> Session {
+        let (control, data) = sockets().await;
+        Session {
+            name: name.to_string(),
+            accept,
+            control: Arc::new(control),
+            data: Arc::new(data),
+            ssrc: random_u32(),
+            start: Instant::now(),
+            peers: Vec::new(),
+            seq: 0,
+            history: VecDeque::new(),
+        }
+    }
+
+    fn connected(ssrc: u32) -> Peer {
+        let mut peer = Peer::new(([127, 0, 0, 1], 9).into(), ssrc, false, Instant::now());
+        peer.ssrc = Some(ssrc);
+        peer.stage = Stage::Connected;
+        peer
+    }
+
+    fn history_front(session: &Session) -> Option<u16> {
+        session.history.front().map(|(seq, _)| *seq)
+    }
+
+    #[tokio::test]
+    async fn history_waits_for_every_peer() {
+        let mut session = session("a", true).await;
+        session.peers = vec![connected(1), connected(2)];
+        session.seq = 0xFFFC;
+        for _ in 0..8 {
+            session.rtp_packet(&[0x90, 60, 100]);
+        }
+        assert_eq!(history_front(&session), Some(0xFFFC));
+
+        // Peer 2 hasn't confirmed anything yet
+        session.on_feedback(1, 0xFFFE);
+        assert_eq!(history_front(&session), Some(0xFFFC));
+        // The older confirmation counts, across the wrap
+        session.on_feedback(2, 1);
+        assert_eq!(history_front(&session), Some(0xFFFF));
+        session.on_feedback(1, 2);
+        assert_eq!(history_front(&session), Some(2));
+        session.on_feedback(2, 3);
+        assert_eq!(history_front(&session), Some(3));
+    }
+
+    async fn deliver(to: &mut Session, control: bool) {
+        let socket = if control {
+            to.control.clone()
+        } else {
+            to.data.clone()
+        };
+        let mut buf = [0u8; 512];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+            .await
+            .expect("no packet")
+            .unwrap();
+        if control {
+            to.on_control(&buf[..len], from);
+        } else {
+            to.on_data(&buf[..len], from);
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_connect_and_sync_on_loopback() {
+        let mut initiator = session("front", false).await;
+        let mut acceptor = session("desk", true).await;
+        let address = acceptor.control.local_addr().unwrap();
+        initiator
+            .peers
+            .push(Peer::new(address, random_u32(), true, Instant::now()));
+
+        // IN and OK on the control ports, then on the data ports
+        initiator.tick();
+        deliver(&mut acceptor, true).await;
+        deliver(&mut initiator, true).await;
+        assert_eq!(initiator.peers[0].stage, Stage::InviteData);
+        deliver(&mut acceptor, false).await;
+        deliver(&mut initiator, false).await;
+        assert_eq!(initiator.peers[0].stage, Stage::Connected);
+        assert_eq!(initiator.peers[0].name, "desk");
+        assert_eq!(acceptor.peers[0].stage, Stage::Connected);
+        assert_eq!(acceptor.peers[0].name, "front");
+
+        // CK 0, 1 and 2 give both sides a latency
+        initiator.tick();
+        deliver(&mut acceptor, false).await;
+        deliver(&mut initiator, false).await;
+        deliver(&mut acceptor, false).await;
+        assert!(initiator.peers[0].latency.is_some());
+        assert!(acceptor.peers[0].latency.is_some());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::odisc::main::behavior;
+use crate::odisc::main::helpers::Mapping;
+use crate::odisc::main::vars::Value;
+use rosc::OscType;
+
+// Scales the first numeric OSC argument into the output:
+//   scale_in     input range "min max", e.g. "0 1"
+//   scale_out    output range "min max", e.g. "0 127"; "127 0" inverts
+//   scale_curve  linear (default), exp (slow start) or log (fast start)
+// Input outside the range is clamped. The result goes where a behavior value
+// goes (see behavior.rs): midi_value for cc and pc, midi_velocity for notes,
+// "$value" in osc_out_args.
+
+pub const CURVES: [&str; 3] = ["linear", "exp", "log"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Curve {
+    Linear,
+    Exp,
+    Log,
+}
+
+#[derive(Debug)]
+struct Scale {
+    input: (f64, f64),
+    output: (f64, f64),
+    curve: Curve,
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|v| !v.is_empty())
+}
+
+// "min max"
+pub fn parse_range(text: &str) -> Result<(f64, f64), String> {
+    let numbers = text
+        .split_whitespace()
+        .map(|n| n.parse::<f64>().ok().filter(|n| n.is_finite()))
+        .collect::<Option<Vec<f64>>>();
+    match numbers.as_deref() {
+        Some([min, max]) => Ok((*min, *max)),
+        _ => Err(format!("'{text}' is not a range like '0 127'")),
+    }
+}
+
+pub fn format_range((min, max): (f64, f64)) -> String {
+    format!("{min} {max}")
+}
+
+fn parse(m: &Mapping) -> Result<Option<Scale>, String> {
+    let (input, output) = match (non_empty(&m.scale_in), non_empty(&m.scale_out)) {
+        (None, None) => {
+            return match non_empty(&m.scale_curve) {
+                Some(_) => Err("scale_curve needs scale_in and scale_out".to_string()),
+                None => Ok(None),
+            }
+        }
+        (Some(input), Some(output)) => (parse_range(input)?, parse_range(output)?),
+        _ => return Err("scale_in and scale_out go together".to_string()),
+    };
+    if input.0 == input.1 {
+        return Err("scale_in must not be an empty range".to_string());
+    }
+    let curve = match non_empty(&m.scale_curve) {
+        None | Some("linear") => Curve::Linear,
+        Some("exp") => Curve::Exp,
+        Some("log") => Curve::Log,
+        Some(curve) => {
+            return Err(format!(
+                "Unknown scale_curve '{curve}', expected one of {}",
+                CURVES.join(", ")
+            ))
+        }
+    };
+    Ok(Some(Scale {
+        input,
+        output,
+        curve,
+    }))
+}
+
+fn scaled(scale: &Scale, x: f64) -> f64 {
+    let (in_min, in_max) = scale.input;
+    let t = ((x - in_min) / (in_max - in_min)).clamp(0.0, 1.0);
+    let t = match scale.curve {
+        Curve::Linear => t,
+        Curve::Exp => t * t,
+        Curve::Log => t.sqrt(),
+    };
+    let (out_min, out_max) = scale.output;
+    out_min + t * (out_max - out_min)
+}
+
+fn first_number(args: &[OscType]) -> Option<f64> {
+    args.iter().find_map(|arg| match arg {
+        OscType::Int(i) => Some(f64::from(*i)),
+        OscType::Long(l) => Some(*l as f64),
+        OscType::Float(f) => Some(f64::from(*f)),
+        OscType::Double(d) => Some(*d),
+        _ => None,
+    })
+}
+
+// The mapping with the scaled value filled in, None when it doesn't scale
+pub fn apply(m: &Mapping, args: &[OscType]) -> Result<Option<Mapping>, String> {
+    let Some(scale) = parse(m)? else {
+        return Ok(None);
+    };
+    let x = first_number(args).ok_or("No numeric OSC argument to scale")?;
+    Ok(Some(behavior::with_value(
+        m,
+        &Value::Number(scaled(&scale, x)),
+    )))
+}
+
+pub fn validate(m: &Mapping) -> Result<(), String> {
+    if parse(m)?.is_none() {
+        return Ok(());
+    }
+    if non_empty(&m.behavior).is_some() {
+        return Err("A mapping can scale or have a behavior, not both".to_string());
+    }
+    if matches!(
+        m.midi_type.as_deref(),
+        Some("qc_preset") | Some("gt1000_preset")
+    ) {
+        return Err("Presets can't be scaled".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(input: &str, output: &str, curve: &str) -> Mapping {
+        Mapping {
+            osc_in_address: "/fader".to_string(),
+            scale_in: Some(input.to_string()),
+            scale_out: Some(output.to_string()),
+            scale_curve: Some(curve.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn value(m: &Mapping, x: f64) -> f64 {
+        scaled(&parse(m).unwrap().unwrap(), x)
+    }
+
+    #[test]
+    fn scales_and_clamps() {
+        let m = mapping("0 1", "0 127", "");
+        assert_eq!(value(&m, 0.5), 63.5);
+        assert_eq!(value(&m, -1.0), 0.0);
+        assert_eq!(value(&m, 2.0), 127.0);
+        assert_eq!(value(&mapping("0 1", "127 0", ""), 0.25), 95.25);
+    }
+
+    #[test]
+    fn curves_bend_the_middle() {
+        assert_eq!(value(&mapping("0 1", "0 100", "exp"), 0.5), 25.0);
+        assert_eq!(value(&mapping("0 1", "0 100", "log"), 0.25), 50.0);
+    }
+
+    #[test]
+    fn fills_in_the_output() {
+        let mut m = mapping("0 1", "0 127", "linear");
+        m.midi_type = Some("cc".to_string());
+        m.midi_value = Some(0);
+        m.osc_out_address = Some("/out".to_string());
+        m.osc_out_args = Some("level $value".to_string());
+        let scaled = apply(&m, &[OscType::Float(1.0)]).unwrap().unwrap();
+        assert_eq!(scaled.midi_value, Some(127));
+        assert_eq!(scaled.osc_out_args.as_deref(), Some("level 127"));
+        assert!(apply(&m, &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(parse(&mapping("0", "0 127", "")).is_err());
+        assert!(parse(&mapping("1 1", "0 127", "")).is_err());
+        assert!(parse(&mapping("0 1", "0 127", "cubic")).is_err());
+        let mut m = mapping("0 1", "0 127", "");
+        m.behavior = Some("toggle".to_string());
+        assert!(validate(&m).is_err());
+    }
+}
@@ -0,0 +1,555 @@
+use crate::odisc::main::handlers;
+use crate::odisc::main::helpers::{self, Mapping};
+use crate::odisc::main::vars::{self, Value};
+use lazy_static::lazy_static;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST, INT};
+use rosc::{OscMessage, OscType};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+// Rhai scripts for logic the mapping columns can't express. A mapping's
+// `script` column names a file in scripts/ ("fader" or "fader.rhai"). When the
+// mapping matches, the script runs with these variables:
+//   address  incoming OSC address
+//   args     incoming OSC arguments
+//   row      the mapping's row
+//   state    a map kept between runs and across reloads, shared by all scripts
+// and can call:
+//   send_osc(address, [args])       to OSC_SEND_HOST:OSC_SEND_PORT
+//   send_midi([bytes])
+//   note_on(ch, note, vel), note_off(ch, note, vel), cc(ch, controller, value),
+//   program_change(ch, program)
+//   qc_preset("12A", setlist, ch), gt1000_preset("U01-1", ch)
+//   delay(ms)                       holds back everything sent after it
+//   get_var(name), set_var(name, value) the variable store, see vars.rs
+// Returning false skips the mapping's own OSC, MIDI, DMX and MSC output.
+// A run that fails or exceeds SCRIPT_TIME_LIMIT_MS is stopped and nothing it
+// sent goes out. A standby instance runs scripts too, keeping state and the
+// variables in step for a takeover, but drops what they send.
+
+pub const SCRIPTS_DIR: &str = "scripts";
+const EXTENSION: &str = "rhai";
+
+// Checked every this many operations
+const PROGRESS_INTERVAL: u64 = 256;
+
+#[derive(Debug, Clone)]
+pub enum Output {
+    Osc(OscMessage),
+    Midi(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct Action {
+    pub delay: Duration,
+    pub output: Output,
+}
+
+pub struct Outcome {
+    pub actions: Vec<Action>,
+    // false when the script asked to skip the mapping's own output
+    pub pass: bool,
+}
+
+// What the host functions write to while a script runs on this thread
+struct Run {
+    deadline: Instant,
+    delay: Duration,
+    actions: Vec<Action>,
+}
+
+thread_local! {
+    static RUN: RefCell<Option<Run>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    static ref ENGINE: Engine = engine();
+    // Compiled scripts by name, from the last mappings load
+    static ref SCRIPTS: RwLock<HashMap<String, Result<Arc<AST>, String>>> =
+        RwLock::new(HashMap::new());
+    static ref STATE: Mutex<Map> = Mutex::new(Map::new());
+}
+
+type HostResult = Result<(), Box<EvalAltResult>>;
+
+fn push(output: Output) -> HostResult {
+    RUN.with(|run| match run.borrow_mut().as_mut() {
+        Some(run) => {
+            run.actions.push(Action {
+                delay: run.delay,
+                output,
+            });
+            Ok(())
+        }
+        None => Err("Not inside a mapping script".into()),
+    })
+}
+
+fn data_byte(name: &str, value: INT) -> Result<u8, Box<EvalAltResult>> {
+    u8::try_from(value)
+        .ok()
+        .filter(|v| *v <= 127)
+        .ok_or_else(|| format!("{name} must be 0-127, got {value}").into())
+}
+
+// 1-16 -> 0-15
+fn channel(value: INT) -> Result<u8, Box<EvalAltResult>> {
+    u8::try_from(value)
+        .ok()
+        .filter(|c| (1..=16).contains(c))
+        .map(|c| c - 1)
+        .ok_or_else(|| format!("MIDI channel must be 1-16, got {value}").into())
+}
+
+fn osc_arg(value: &Dynamic) -> Result<OscType, Box<EvalAltResult>> {
+    if let Ok(i) = value.as_int() {
+        Ok(OscType::Int(i as i32))
+    } else if let Ok(f) = value.as_float() {
+        Ok(OscType::Float(f as f32))
+    } else if let Ok(b) = value.as_bool() {
+        Ok(OscType::Bool(b))
+    } else if value.is_string() {
+        Ok(OscType::String(value.clone().into_string()?))
+    } else {
+        Err(format!("Can't send a {} as an OSC argument", value.type_name()).into())
+    }
+}
+
+pub fn to_dynamic(arg: &OscType) -> Dynamic {
+    match arg {
+        OscType::Int(i) => Dynamic::from_int(INT::from(*i)),
+        OscType::Long(l) => Dynamic::from_int(*l),
+        OscType::Float(f) => Dynamic::from_float(f64::from(*f)),
+        OscType::Double(d) => Dynamic::from_float(*d),
+        OscType::String(s) => Dynamic::from(s.clone()),
+        OscType::Bool(b) => Dynamic::from_bool(*b),
+        OscType::Char(c) => Dynamic::from_char(*c),
+        _ => Dynamic::UNIT,
+    }
+}
+
+fn send_osc(address: &str, args: Array) -> HostResult {
+    let args = args.iter().map(osc_arg).collect::<Result<_, _>>()?;
+    push(Output::Osc(OscMessage {
+        addr: address.to_string(),
+        args,
+    }))
+}
+
+fn send_midi(bytes: Array) -> HostResult {
+    let bytes = bytes
+        .iter()
+        .map(|b| {
+            let b = b.as_int().map_err(|t| format!("MIDI byte is a {t}"))?;
+            u8::try_from(b).map_err(|_| format!("MIDI byte {b} is out of range").into())
+        })
+        .collect::<Result<Vec<u8>, Box<EvalAltResult>>>()?;
+    if bytes.is_empty() {
+        return Err("send_midi needs at least one byte".into());
+    }
+    push(Output::Midi(bytes))
+}
+
+fn qc_preset(id: &str, setlist: INT, ch: INT) -> HostResult {
+    let setlist = data_byte("setlist", setlist)?;
+    let midi_channel = channel(ch)?;
+    let program = handlers::send_qc_preset(
+        &id.to_string(),
+        &u32::from(setlist),
+        &(u32::from(midi_channel) + 1),
+    )
+    .ok_or_else(|| format!("Invalid QC preset '{id}'"))?;
+    push(Output::Midi(vec![0xB0 | midi_channel, 0, 0]))?;
+    push(Output::Midi(vec![0xB0 | midi_channel, 32, setlist]))?;
+    push(Output::Midi(vec![0xC0 | midi_channel, program as u8]))
+}
+
+fn gt1000_preset(id: &str, ch: INT) -> HostResult {
+    let midi_channel = channel(ch)?;
+    let (bank_msb, bank_lsb, program) =
+        handlers::send_gt1000_preset(&id.to_string(), &(u32::from(midi_channel) + 1))
+            .ok_or_else(|| format!("Invalid GT-1000 preset '{id}'"))?;
+    push(Output::Midi(vec![0xB0 | midi_channel, 0, bank_msb as u8]))?;
+    push(Output::Midi(vec![0xB0 | midi_channel, 32, bank_lsb as u8]))?;
+    push(Output::Midi(vec![0xC0 | midi_channel, program as u8]))
+}
+
+fn delay(ms: INT) -> HostResult {
+    let ms = u64::try_from(ms).map_err(|_| format!("delay must be 0 or more ms, got {ms}"))?;
+    RUN.with(|run| match run.borrow_mut().as_mut() {
+        Some(run) => {
+            run.delay += Duration::from_millis(ms);
+            Ok(())
+        }
+        None => Err("Not inside a mapping script".into()),
+    })
+}
+
+fn set_var(name: &str, value: Dynamic) -> HostResult {
+    let value = Value::from_dynamic(&value)
+        .ok_or_else(|| format!("Can't store a {} in variable {name}", value.type_name()))?;
+    vars::set(name, value).map_err(Into::into)
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+
+    // Sandbox: no file imports or eval, bounded sizes
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(64 * 1024);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+
+    engine.on_progress(|ops| {
+        if ops % PROGRESS_INTERVAL != 0 {
+            return None;
+        }
+        let expired = RUN.with(|run| {
+            run.borrow()
+                .as_ref()
+                .is_none_or(|run| Instant::now() >= run.deadline)
+        });
+        expired.then(|| Dynamic::from("time limit exceeded"))
+    });
+    engine.on_print(|text| info!("{text}"));
+    engine.on_debug(|text, source, pos| debug!(?source, %pos, "{text}"));
+
+    engine
+        .register_fn("send_osc", |address: &str| send_osc(address, Array::new()))
+        .register_fn("send_osc", send_osc)
+        .register_fn("send_midi", send_midi)
+        .register_fn("note_on", |ch: INT, note: INT, vel: INT| {
+            let msg = vec![
+                0x90 | channel(ch)?,
+                data_byte("note", note)?,
+                data_byte("velocity", vel)?,
+            ];
+            push(Output::Midi(msg))
+        })
+        .register_fn("note_off", |ch: INT, note: INT, vel: INT| {
+            let msg = vec![
+                0x80 | channel(ch)?,
+                data_byte("note", note)?,
+                data_byte("velocity", vel)?,
+            ];
+            push(Output::Midi(msg))
+        })
+        .register_fn("cc", |ch: INT, controller: INT, value: INT| {
+            let msg = vec![
+                0xB0 | channel(ch)?,
+                data_byte("controller", controller)?,
+                data_byte("value", value)?,
+            ];
+            push(Output::Midi(msg))
+        })
+        .register_fn("program_change", |ch: INT, program: INT| {
+            let msg = vec![0xC0 | channel(ch)?, data_byte("program", program)?];
+            push(Output::Midi(msg))
+        })
+        .register_fn("qc_preset", qc_preset)
+        .register_fn("gt1000_preset", gt1000_preset)
+        .register_fn("delay", delay)
+        // "var" is a reserved word in Rhai
+        .register_fn("get_var", |name: &str| {
+            vars::get(name).map_or(Dynamic::UNIT, |v| v.to_dynamic())
+        })
+        .register_fn("set_var", set_var);
+
+    engine
+}
+
+pub fn scripts_dir() -> PathBuf {
+    helpers::odisc_dir().join(SCRIPTS_DIR)
+}
+
+fn script_path(name: &str) -> PathBuf {
+    let path = scripts_dir().join(name);
+    if path.extension().is_some() {
+        path
+    } else {
+        path.with_extension(EXTENSION)
+    }
+}
+
+// Names stay inside scripts/
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+        && !name.contains("..")
+}
+
+pub fn compile(name: &str) -> Result<AST, String> {
+    if !is_valid_name(name) {
+        return Err(format!(
+            "Script name '{name}' must be a file name in {SCRIPTS_DIR}/"
+        ));
+    }
+    let path = script_path(name);
+    let source = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Could not read script {}: {e}", path.display()))?;
+    ENGINE
+        .compile(source)
+        .map_err(|e| format!("Script '{name}': {e}"))
+}
+
+// Compiles every script the mappings use; called whenever the table changes
+pub fn load(mappings: &[Mapping]) {
+    let mut scripts = HashMap::new();
+    for name in mappings
+        .iter()
+        .filter_map(|m| m.script.as_deref().filter(|s| !s.is_empty()))
+    {
+        if scripts.contains_key(name) {
+            continue;
+        }
+        let compiled = compile(name).map(Arc::new);
+        if let Err(e) = &compiled {
+            error!("{e}");
+        }
+        scripts.insert(name.to_string(), compiled);
+    }
+    if !scripts.is_empty() {
+        debug!("Compiled {} script(s)", scripts.len());
+    }
+    *SCRIPTS.write().unwrap() = scripts;
+}
+
+// The shared state as JSON, for inspection
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn state() -> serde_json::Value {
+    let state = Dynamic::from_map(STATE.lock().unwrap().clone());
+    serde_json::to_value(state).unwrap_or_default()
+}
+
+pub fn run(
+    name: &str,
+    row: usize,
+    address: &str,
+    args: &[OscType],
+    time_limit: Duration,
+) -> Result<Outcome, String> {
+    let ast = match SCRIPTS.read().unwrap().get(name) {
+        Some(Ok(ast)) => ast.clone(),
+        Some(Err(e)) => return Err(e.clone()),
+        None => return Err(format!("Script '{name}' is not loaded")),
+    };
+
+    let mut scope = Scope::new();
+    scope.push_constant("address", address.to_string());
+    scope.push_constant("args", args.iter().map(to_dynamic).collect::<Array>());
+    scope.push_constant("row", row as INT);
+    // Held for the whole run: runs from the backend loop, HTTP and WebSocket
+    // take turns instead of losing each other's changes. The time limit keeps
+    // the wait short.
+    let mut state = STATE.lock().unwrap();
+    scope.push("state", std::mem::take(&mut *state));
+
+    let started = Instant::now();
+    RUN.with(|run| {
+        *run.borrow_mut() = Some(Run {
+            deadline: started + time_limit,
+            delay: Duration::ZERO,
+            actions: Vec::new(),
+        })
+    });
+    let result = ENGINE.eval_ast_with_scope::<Dynamic>(&mut scope, &ast);
+    let actions = RUN
+        .with(|run| run.borrow_mut().take())
+        .map(|run| run.actions)
+        .unwrap_or_default();
+
+    // Keep whatever the script left in state, even when it failed
+    match scope.get_value::<Map>("state") {
+        Some(new_state) => *state = new_state,
+        None => warn!("Script '{name}' replaced state with a non-map, state cleared"),
+    }
+    drop(state);
+
+    let elapsed = started.elapsed();
+    debug!(
+        "Script '{name}' ran in {elapsed:?}, {} action(s)",
+        actions.len()
+    );
+    match result {
+        Ok(value) => Ok(Outcome {
+            actions,
+            pass: value.as_bool().unwrap_or(true),
+        }),
+        Err(e) => match *e {
+            EvalAltResult::ErrorTerminated(..) => Err(format!(
+                "Script '{name}' stopped after {elapsed:?}, over its {time_limit:?} limit"
+            )),
+            e => Err(format!("Script '{name}': {e}")),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Duration = Duration::from_secs(5);
+
+    // Scripts are shared globals; each test uses its own name and state keys
+    fn load_source(name: &str, source: &str) {
+        let ast = ENGINE.compile(source).unwrap();
+        SCRIPTS
+            .write()
+            .unwrap()
+            .insert(name.to_string(), Ok(Arc::new(ast)));
+    }
+
+    fn run_source(name: &str, source: &str, args: &[OscType]) -> Result<Outcome, String> {
+        load_source(name, source);
+        run(name, 3, "/in", args, LIMIT)
+    }
+
+    fn midi(outcome: &Outcome) -> Vec<(u64, Vec<u8>)> {
+        outcome
+            .actions
+            .iter()
+            .filter_map(|a| match &a.output {
+                Output::Midi(bytes) => Some((a.delay.as_millis() as u64, bytes.clone())),
+                Output::Osc(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn host_functions_queue_output() {
+        let outcome = run_source(
+            "test_host",
+            r#"
+                send_osc(address + "/out", [row, args[0], 2.5, "x", true]);
+                note_on(1, 60, 100);
+                delay(50);
+                cc(16, 7, 127);
+                program_change(2, 5);
+                delay(25);
+                send_midi([0xF0, 0x7E, 0xF7]);
+                note_off(1, 60, 0);
+            "#,
+            &[OscType::Int(9)],
+        )
+        .unwrap();
+        assert!(outcome.pass);
+        let Output::Osc(msg) = &outcome.actions[0].output else {
+            panic!("expected OSC first");
+        };
+        assert_eq!(msg.addr, "/in/out");
+        assert_eq!(
+            msg.args,
+            [
+                OscType::Int(3),
+                OscType::Int(9),
+                OscType::Float(2.5),
+                OscType::String("x".into()),
+                OscType::Bool(true),
+            ]
+        );
+        assert_eq!(
+            midi(&outcome),
+            [
+                (0, vec![0x90, 60, 100]),
+                (50, vec![0xBF, 7, 127]),
+                (50, vec![0xC1, 5]),
+                (75, vec![0xF0, 0x7E, 0xF7]),
+                (75, vec![0x80, 60, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_host_calls_fail_the_run() {
+        for (i, call) in [
+            "note_on(17, 60, 100)",
+            "note_on(1, 128, 100)",
+            "cc(0, 7, 1)",
+            "send_midi([])",
+            "send_midi([256])",
+            "delay(-1)",
+            "send_osc(\"/x\", [[1]])",
+            "qc_preset(\"nope\", 0, 1)",
+            "gt1000_preset(\"nope\", 1)",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let source = format!("note_on(1, 1, 1); {call};");
+            assert!(
+                run_source(&format!("test_bad_{i}"), &source, &[]).is_err(),
+                "{call}"
+            );
+        }
+    }
+
+    #[test]
+    fn returning_false_skips_the_mapping() {
+        let outcome = run_source("test_skip", "cc(1, 1, 1); false", &[]).unwrap();
+        assert!(!outcome.pass);
+        assert_eq!(outcome.actions.len(), 1);
+    }
+
+    #[test]
+    fn state_survives_runs_and_failures() {
+        let source = r#"
+            if "test_count" in state { state.test_count += 1 } else { state.test_count = 1 }
+            if args.len() > 0 { throw "failed" }
+        "#;
+        run_source("test_state", source, &[]).unwrap();
+        assert!(run_source("test_state", source, &[OscType::Int(1)]).is_err());
+        run_source("test_state", source, &[]).unwrap();
+        assert_eq!(state()["test_count"], 3);
+    }
+
+    #[test]
+    fn concurrent_runs_keep_every_change() {
+        load_source(
+            "test_concurrent",
+            r#"if "test_hits" in state { state.test_hits += 1 } else { state.test_hits = 1 }"#,
+        );
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..50 {
+                        run("test_concurrent", 0, "/in", &[], LIMIT).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(state()["test_hits"], 400);
+    }
+
+    #[test]
+    fn runs_are_stopped_at_the_time_limit() {
+        load_source("test_loop", "cc(1, 1, 1); loop {}");
+        let started = Instant::now();
+        let result = run("test_loop", 0, "/in", &[], Duration::from_millis(50));
+        assert!(result.err().unwrap().contains("over its"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn scripts_read_and_write_variables() {
+        let source = r#"
+            set_var("test_script_level", 0.5);
+            if get_var("test_script_missing") == () { set_var("test_script_seen", true) }
+        "#;
+        run_source("test_vars", source, &[]).unwrap();
+        assert_eq!(vars::get("test_script_level"), Some(Value::Number(0.5)));
+        assert_eq!(vars::get("test_script_seen"), Some(Value::Bool(true)));
+        assert!(run("test_not_loaded", 0, "/in", &[], LIMIT).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::odisc::main::formats;
+use crate::odisc::main::helpers::{self, Config, Mapping};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+
+// A show lives in Documents/odisc/shows/<name>/ and contains its own
+// mappings file (CSV, TOML, YAML or JSON) plus an optional config.json with overrides for the base config.
+
+lazy_static! {
+    // Program change -> show name, filled by list_shows and kept current by load_show
+    static ref PROGRAMS: RwLock<Option<HashMap<u8, String>>> = RwLock::new(None);
+    // Show names in order, filled by list_shows and kept current by load_show
+    static ref NAMES: RwLock<Option<Vec<String>>> = RwLock::new(None);
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct ShowOverrides {
+    pub osc_send_host: Option<String>,
+    pub osc_send_port: Option<u16>,
+    pub midi_output_name: Option<String>,
+    pub debug_logging: Option<bool>,
+    // Program change number that selects this show over MIDI
+    pub midi_program: Option<u8>,
+}
+
+impl ShowOverrides {
+    pub fn apply(&self, base: &Config) -> Config {
+        let mut config = base.clone();
+        if let Some(host) = &self.osc_send_host {
+            config.osc_send_host = host.clone();
+        }
+        if let Some(port) = self.osc_send_port {
+            config.osc_send_port = port;
+        }
+        if let Some(name) = &self.midi_output_name {
+            config.midi_output_name = name.clone();
+        }
+        if let Some(debug) = self.debug_logging {
+            config.debug_logging = debug;
+        }
+        config
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Show {
+    pub name: String,
+    pub mappings: Vec<Mapping>,
+    pub overrides: ShowOverrides,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ShowInfo {
+    pub name: String,
+    pub midi_program: Option<u8>,
+}
+
+pub fn shows_dir() -> PathBuf {
+    helpers::odisc_dir().join("shows")
+}
+
+fn show_dir(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    // Show names are directory names, never paths
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!("Invalid show name: '{name}'").into());
+    }
+    let dir = shows_dir().join(name);
+    if !dir.is_dir() {
+        return Err(format!("Show '{name}' not found in {:?}", shows_dir()).into());
+    }
+    Ok(dir)
+}
+
+// Path of the mappings file for a show, or the root mappings file when no show is active.
+pub fn mappings_path(show: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
+    match show {
+        Some(name) => formats::find_mappings_file(&show_dir(name)?),
+        None => formats::find_mappings_file(&helpers::odisc_dir()),
+    }
+}
+
+fn read_overrides(name: &str) -> Result<ShowOverrides, Box<dyn Error>> {
+    let config_path = show_dir(name)?.join("config.json");
+    if !config_path.exists() {
+        return Ok(ShowOverrides::default());
+    }
+    let reader = BufReader::new(File::open(config_path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+// Reads and validates everything a show needs without touching the live state,
+// so a broken show never replaces a working one.
+pub fn load_show(name: &str) -> Result<Show, Box<dyn Error>> {
+    let mappings = formats::load_mappings(mappings_path(Some(name))?)?;
+    let overrides = read_overrides(name)?;
+    if let Some(programs) = PROGRAMS.write().unwrap().as_mut() {
+        programs.retain(|_, show| show != name);
+        if let Some(program) = overrides.midi_program {
+            programs.entry(program).or_insert_with(|| name.to_string());
+        }
+    }
+    if let Some(names) = NAMES.write().unwrap().as_mut() {
+        if let Err(at) = names.binary_search_by(|n| n.as_str().cmp(name)) {
+            names.insert(at, name.to_string());
+        }
+    }
+    Ok(Show {
+        name: name.to_string(),
+        mappings,
+        overrides,
+    })
+}
+
+pub fn list_shows() -> Result<Vec<ShowInfo>, Box<dyn Error>> {
+    let dir = shows_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut shows = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let midi_program = read_overrides(&name).ok().and_then(|o| o.midi_program);
+        shows.push(ShowInfo { name, midi_program });
+    }
+    shows.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // First show in name order wins a shared program number
+    let mut programs = HashMap::new();
+    for show in &shows {
+        if let Some(program) = show.midi_program {
+            programs.entry(program).or_insert_with(|| show.name.clone());
+        }
+    }
+    *PROGRAMS.write().unwrap() = Some(programs);
+    *NAMES.write().unwrap() = Some(shows.iter().map(|s| s.name.clone()).collect());
+    Ok(shows)
+}
+
+// Show names as of the last scan; the shows directory is only scanned the first time
+pub fn show_names() -> Vec<String> {
+    if NAMES.read().unwrap().is_none() && list_shows().is_err() {
+        return Vec::new();
+    }
+    NAMES.read().unwrap().clone().unwrap_or_default()
+}
+
+// Uses the cached program map; the shows directory is only scanned the first time
+pub fn find_show_by_program(program: u8) -> Option<String> {
+    if PROGRAMS.read().unwrap().is_none() {
+        list_shows().ok()?;
+    }
+    PROGRAMS.read().unwrap().as_ref()?.get(&program).cloned()
+}
@@ -0,0 +1,702 @@
+use crate::odisc::main::access::{self, AccessPolicy, AuthConfig, Rejection};
+use crate::odisc::main::handlers;
+use rosc::{decoder, OscPacket};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, info, warn};
+
+// OSC transports. UDP carries one packet per datagram; over TCP packets are
+// framed with SLIP (OSC 1.1) or a 32-bit big-endian length prefix (OSC 1.0).
+// Listeners hand decoded packets to the backend loop through a channel.
+
+// Largest UDP payload, so datagrams are never cut off
+const MAX_DATAGRAM: usize = 65_536;
+// A TCP peer that never finishes a frame gets disconnected past this
+const MAX_FRAME: usize = 1 << 20;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+// Frames queued per TCP destination while a write is in progress
+const SEND_QUEUE: usize = 256;
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    #[default]
+    Udp,
+    #[serde(alias = "tcp")]
+    TcpSlip,
+    TcpLength,
+}
+
+impl Transport {
+    // Names as in OSC_SEND_TRANSPORT, for the osc_out_transport column
+    pub fn from_name(name: &str) -> Result<Transport, String> {
+        match name {
+            "udp" => Ok(Transport::Udp),
+            "tcp" | "tcp_slip" => Ok(Transport::TcpSlip),
+            "tcp_length" => Ok(Transport::TcpLength),
+            other => Err(format!(
+                "Unknown transport '{other}', expected udp, tcp_slip or tcp_length"
+            )),
+        }
+    }
+}
+
+// One entry of OSC_LISTENERS
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct ListenerConfig {
+    // Mappings can filter on this (osc_in_listener)
+    pub name: String,
+    pub port: u16,
+    // Interface address to bind, IPv4 or IPv6; "::" listens on both families
+    #[serde(default = "default_bind_address")]
+    pub address: String,
+    #[serde(default)]
+    pub transport: Transport,
+    // UDP only: multicast group to join, e.g. "239.255.0.1" or "ff15::4f53"
+    #[serde(default)]
+    pub multicast_group: Option<IpAddr>,
+    // Interface for the group: an IPv4 address, or an IPv6 interface index.
+    // Defaults to letting the OS pick.
+    #[serde(default)]
+    pub multicast_interface: Option<String>,
+    // Source IPs or CIDR ranges let in; empty allows everyone
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            name: String::new(),
+            port: 0,
+            address: default_bind_address(),
+            transport: Transport::Udp,
+            multicast_group: None,
+            multicast_interface: None,
+            allow: Vec::new(),
+            auth: None,
+        }
+    }
+}
+
+fn default_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+pub struct Inbound {
+    pub packet: OscPacket,
+    pub source: SocketAddr,
+    pub listener: Arc<str>,
+}
+
+pub fn frame(transport: Transport, packet: &[u8]) -> Vec<u8> {
+    match transport {
+        Transport::Udp => packet.to_vec(),
+        Transport::TcpLength => {
+            let mut out = Vec::with_capacity(packet.len() + 4);
+            out.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            out.extend_from_slice(packet);
+            out
+        }
+        Transport::TcpSlip => {
+            // The leading END flushes whatever line noise the receiver holds
+            let mut out = Vec::with_capacity(packet.len() + 2);
+            out.push(SLIP_END);
+            for &byte in packet {
+                match byte {
+                    SLIP_END => out.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+                    SLIP_ESC => out.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+                    byte => out.push(byte),
+                }
+            }
+            out.push(SLIP_END);
+            out
+        }
+    }
+}
+
+// Reassembles frames from a TCP byte stream
+struct FrameReader {
+    transport: Transport,
+    buf: Vec<u8>,
+    escaped: bool,
+}
+
+impl FrameReader {
+    fn new(transport: Transport) -> Self {
+        FrameReader {
+            transport,
+            buf: Vec::new(),
+            escaped: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8], frames: &mut Vec<Vec<u8>>) -> Result<(), String> {
+        match self.transport {
+            Transport::TcpSlip => {
+                for &byte in data {
+                    match (self.escaped, byte) {
+                        (false, SLIP_END) => {
+                            // Empty frames are just separators
+                            if !self.buf.is_empty() {
+                                frames.push(std::mem::take(&mut self.buf));
+                            }
+                        }
+                        (false, SLIP_ESC) => self.escaped = true,
+                        (false, byte) => self.buf.push(byte),
+                        (true, byte) => {
+                            self.escaped = false;
+                            self.buf.push(match byte {
+                                SLIP_ESC_END => SLIP_END,
+                                SLIP_ESC_ESC => SLIP_ESC,
+                                // Protocol violation; keep the byte as sent
+                                byte => byte,
+                            });
+                        }
+                    }
+                }
+                if self.buf.len() > MAX_FRAME {
+                    return Err(format!("SLIP frame exceeds {MAX_FRAME} bytes"));
+                }
+            }
+            Transport::TcpLength => {
+                self.buf.extend_from_slice(data);
+                while let Some(header) = self.buf.get(..4) {
+                    let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+                    if len > MAX_FRAME {
+                        return Err(format!("Frame length {len} exceeds {MAX_FRAME} bytes"));
+                    }
+                    if self.buf.len() < 4 + len {
+                        break;
+                    }
+                    frames.push(self.buf[4..4 + len].to_vec());
+                    self.buf.drain(..4 + len);
+                }
+            }
+            Transport::Udp => frames.push(data.to_vec()),
+        }
+        Ok(())
+    }
+}
+
+// Aborts the listener, and with it every open connection, when dropped
+pub struct Listener {
+    task: JoinHandle<()>,
+    // The bound socket, so replies and outgoing UDP can share the listen port
+    pub udp: Option<Arc<UdpSocket>>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// "::" takes IPv4 as well, as mapped addresses. Linux does that by default,
+// Windows and the BSDs don't, so it's set explicitly.
+fn new_socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP)?;
+    // Same as TcpListener::bind, so a restart can take the port straight back
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_multicast(
+    addr: SocketAddr,
+    group: IpAddr,
+    interface: Option<&str>,
+) -> io::Result<UdpSocket> {
+    if !group.is_multicast() {
+        return Err(invalid_input(format!("{group} is not a multicast group")));
+    }
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP)?;
+    // Other apps on this machine may be listening to the same group
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+
+    match group {
+        IpAddr::V4(group) => {
+            let interface = match interface {
+                Some(i) => i
+                    .parse()
+                    .map_err(|_| invalid_input(format!("Invalid IPv4 interface '{i}'")))?,
+                None => Ipv4Addr::UNSPECIFIED,
+            };
+            socket.join_multicast_v4(group, interface)?;
+        }
+        IpAddr::V6(group) => {
+            let index = match interface {
+                Some(i) => i
+                    .parse()
+                    .map_err(|_| invalid_input(format!("Invalid IPv6 interface index '{i}'")))?,
+                None => 0,
+            };
+            socket.join_multicast_v6(&group, index)?;
+        }
+    }
+    Ok(socket)
+}
+
+// Binds before returning, so a port that's taken fails the backend start
+pub async fn listen(config: &ListenerConfig, tx: mpsc::Sender<Inbound>) -> io::Result<Listener> {
+    let ip: IpAddr = config.address.parse().map_err(|_| {
+        invalid_input(format!(
+            "Listener '{}': invalid address '{}'",
+            config.name, config.address
+        ))
+    })?;
+    let addr = SocketAddr::new(ip, config.port);
+    let name: Arc<str> = config.name.as_str().into();
+    let policy = AccessPolicy::new(&config.allow, config.auth.clone())
+        .map_err(|e| invalid_input(format!("Listener '{}': {e}", config.name)))?;
+    let policy = Arc::new(policy);
+
+    match config.transport {
+        Transport::Udp => {
+            let sock = match config.multicast_group {
+                Some(group) => bind_multicast(addr, group, config.multicast_interface.as_deref())?,
+                None => bind_udp(addr)?,
+            };
+            let sock = Arc::new(sock);
+            let task = tokio::spawn(run_udp_listener(sock.clone(), name, policy, tx));
+            Ok(Listener {
+                task,
+                udp: Some(sock),
+            })
+        }
+        framing => {
+            if config.multicast_group.is_some() {
+                return Err(invalid_input(format!(
+                    "Listener '{}': multicast needs the udp transport",
+                    config.name
+                )));
+            }
+            let listener = bind_tcp(addr)?;
+            let task = tokio::spawn(run_tcp_listener(listener, framing, name, policy, tx));
+            Ok(Listener { task, udp: None })
+        }
+    }
+}
+
+async fn run_udp_listener(
+    sock: Arc<UdpSocket>,
+    listener: Arc<str>,
+    policy: Arc<AccessPolicy>,
+    tx: mpsc::Sender<Inbound>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        match handlers::incoming_osc_handler(&sock, &mut buf).await {
+            Ok((packet, source)) => {
+                if !policy.allows(source) {
+                    access::reject(&listener, source, Rejection::NotAllowed);
+                    continue;
+                }
+                let Some(packet) = policy.authenticate(packet) else {
+                    access::reject(&listener, source, Rejection::Unauthenticated);
+                    continue;
+                };
+                let inbound = Inbound {
+                    packet,
+                    source,
+                    listener: listener.clone(),
+                };
+                if tx.send(inbound).await.is_err() {
+                    return;
+                }
+            }
+            // A bad datagram (or an ICMP error surfacing on Windows) shouldn't stop listening
+            Err(e) => warn!("Dropped OSC datagram: {e}"),
+        }
+    }
+}
+
+async fn run_tcp_listener(
+    listener: TcpListener,
+    transport: Transport,
+    name: Arc<str>,
+    policy: Arc<AccessPolicy>,
+    tx: mpsc::Sender<Inbound>,
+) {
+    // Owned here so aborting the listener closes every connection too
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, source)) => {
+                    if !policy.allows(source) {
+                        access::reject(&name, source, Rejection::NotAllowed);
+                        continue;
+                    }
+                    info!(%source, "OSC TCP client connected");
+                    connections.spawn(read_connection(
+                        stream,
+                        source,
+                        transport,
+                        name.clone(),
+                        policy.clone(),
+                        tx.clone(),
+                    ));
+                }
+                Err(e) => warn!("OSC TCP accept failed: {e}"),
+            },
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+async fn read_connection(
+    mut stream: TcpStream,
+    source: SocketAddr,
+    transport: Transport,
+    listener: Arc<str>,
+    policy: Arc<AccessPolicy>,
+    tx: mpsc::Sender<Inbound>,
+) {
+    let mut reader = FrameReader::new(transport);
+    let mut buf = vec![0u8; 8192];
+    let mut frames = Vec::new();
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                debug!(%source, "OSC TCP read failed: {e}");
+                break;
+            }
+        };
+        if let Err(e) = reader.push(&buf[..n], &mut frames) {
+            warn!(%source, "Closing OSC TCP connection: {e}");
+            break;
+        }
+        for frame in frames.drain(..) {
+            match decoder::decode_udp(&frame) {
+                Ok((_, packet)) => {
+                    let Some(packet) = policy.authenticate(packet) else {
+                        access::reject(&listener, source, Rejection::Unauthenticated);
+                        continue;
+                    };
+                    let inbound = Inbound {
+                        packet,
+                        source,
+                        listener: listener.clone(),
+                    };
+                    if tx.send(inbound).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!(%source, "Dropped invalid OSC packet: {e:?}"),
+            }
+        }
+    }
+    info!(%source, "OSC TCP client disconnected");
+}
+
+// Sends encoded packets with the configured transport, or one picked per
+// packet. TCP destinations each get a task that keeps the connection open and
+// reconnects with backoff.
+pub struct OscSender {
+    udp: Arc<UdpSocket>,
+    transport: Transport,
+    tcp: HashMap<(Transport, String), mpsc::Sender<Vec<u8>>>,
+    // Dropping the sender closes all TCP connections
+    tasks: JoinSet<()>,
+}
+
+impl OscSender {
+    pub fn new(udp: Arc<UdpSocket>, transport: Transport) -> Self {
+        OscSender {
+            udp,
+            transport,
+            tcp: HashMap::new(),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    // Opens the TCP connection ahead of the first packet; no-op for UDP
+    pub fn open(&mut self, destination: &str) {
+        if self.transport != Transport::Udp {
+            self.tcp_queue(self.transport, destination);
+        }
+    }
+
+    // The same host and port can be reached over more than one framing
+    fn tcp_queue(&mut self, framing: Transport, destination: &str) -> &mpsc::Sender<Vec<u8>> {
+        let key = (framing, destination.to_string());
+        if !self.tcp.contains_key(&key) {
+            let (tx, rx) = mpsc::channel(SEND_QUEUE);
+            self.tasks
+                .spawn(run_tcp_destination(destination.to_string(), rx));
+            self.tcp.insert(key.clone(), tx);
+        }
+        &self.tcp[&key]
+    }
+
+    pub async fn send(&mut self, destination: &str, packet: &[u8]) -> io::Result<()> {
+        self.send_with(None, destination, packet).await
+    }
+
+    // `transport` overrides the configured one for this packet
+    pub async fn send_with(
+        &mut self,
+        transport: Option<Transport>,
+        destination: &str,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        match transport.unwrap_or(self.transport) {
+            Transport::Udp => {
+                self.udp.send_to(packet, destination).await?;
+            }
+            framing => {
+                let frame = frame(framing, packet);
+                self.tcp_queue(framing, destination)
+                    .try_send(frame)
+                    .map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            format!("Send queue to {destination} is full"),
+                        )
+                    })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn run_tcp_destination(destination: String, mut rx: mpsc::Receiver<Vec<u8>>) {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        let mut stream =
+            match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&destination)).await {
+                Ok(Ok(stream)) => stream,
+                result => {
+                    let reason = match result {
+                        Ok(Err(e)) => e.to_string(),
+                        _ => "timed out".to_string(),
+                    };
+                    warn!(
+                        destination,
+                        "OSC TCP connect failed ({reason}), retrying in {delay:?}"
+                    );
+                    // Cues that come in while the link is down are dropped, not fired late
+                    let retry = tokio::time::sleep(delay);
+                    tokio::pin!(retry);
+                    let mut dropped = 0;
+                    loop {
+                        tokio::select! {
+                            _ = &mut retry => break,
+                            frame = rx.recv() => match frame {
+                                Some(_) => dropped += 1,
+                                None => return,
+                            },
+                        }
+                    }
+                    if dropped > 0 {
+                        warn!(
+                            destination,
+                            "Dropped {dropped} OSC packets while disconnected"
+                        );
+                    }
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+        let _ = stream.set_nodelay(true);
+        info!(destination, "OSC TCP connected");
+        delay = RECONNECT_DELAY;
+
+        let mut discard = [0u8; 1024];
+        loop {
+            tokio::select! {
+                frame = rx.recv() => {
+                    let Some(frame) = frame else { return };
+                    if let Err(e) = stream.write_all(&frame).await {
+                        warn!(destination, "OSC TCP send failed: {e}");
+                        break;
+                    }
+                }
+                // Replies aren't used, but reading notices when the peer hangs up
+                read = stream.read(&mut discard) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        warn!(destination, "OSC TCP connection closed by peer");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(transport: Transport, chunks: &[&[u8]]) -> Result<Vec<Vec<u8>>, String> {
+        let mut reader = FrameReader::new(transport);
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            reader.push(chunk, &mut frames)?;
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn slip_escapes_end_and_esc() {
+        let packet = [1, SLIP_END, 2, SLIP_ESC, 3];
+        let framed = frame(Transport::TcpSlip, &packet);
+        assert_eq!(
+            framed,
+            [
+                SLIP_END,
+                1,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                2,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                3,
+                SLIP_END
+            ]
+        );
+        assert_eq!(read_all(Transport::TcpSlip, &[&framed]).unwrap(), [packet]);
+    }
+
+    #[test]
+    fn slip_survives_split_reads() {
+        let packet = [SLIP_ESC, SLIP_END, 7];
+        let mut stream = frame(Transport::TcpSlip, &packet);
+        stream.extend(frame(Transport::TcpSlip, &[8, 9]));
+        // Every split point, including between ESC and its escaped byte
+        for at in 0..stream.len() {
+            let (a, b) = stream.split_at(at);
+            assert_eq!(
+                read_all(Transport::TcpSlip, &[a, b]).unwrap(),
+                [packet.to_vec(), vec![8, 9]],
+                "split at {at}"
+            );
+        }
+    }
+
+    #[test]
+    fn slip_rejects_endless_frames() {
+        let chunk = vec![1u8; MAX_FRAME + 1];
+        assert!(read_all(Transport::TcpSlip, &[&chunk]).is_err());
+    }
+
+    #[test]
+    fn length_prefix_waits_for_header_and_body() {
+        let mut stream = frame(Transport::TcpLength, &[1, 2, 3]);
+        stream.extend(frame(Transport::TcpLength, &[]));
+        stream.extend(frame(Transport::TcpLength, &[4]));
+        assert_eq!(&stream[..4], &[0, 0, 0, 3]);
+        for at in 0..stream.len() {
+            let (a, b) = stream.split_at(at);
+            assert_eq!(
+                read_all(Transport::TcpLength, &[a, b]).unwrap(),
+                [vec![1, 2, 3], vec![], vec![4]],
+                "split at {at}"
+            );
+        }
+        // Half a header is kept for the next read
+        assert!(read_all(Transport::TcpLength, &[&[0, 0]])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn length_prefix_limits_frame_size() {
+        // Bigger than any UDP datagram is fine over TCP
+        let big = vec![0u8; MAX_DATAGRAM + 1];
+        let frames = read_all(Transport::TcpLength, &[&frame(Transport::TcpLength, &big)]);
+        assert_eq!(frames.unwrap()[0].len(), MAX_DATAGRAM + 1);
+        // Past MAX_FRAME the header alone is enough to give up
+        let header = ((MAX_FRAME + 1) as u32).to_be_bytes();
+        assert!(read_all(Transport::TcpLength, &[&header]).is_err());
+    }
+
+    #[test]
+    fn transport_names() {
+        assert_eq!(Transport::from_name("tcp").unwrap(), Transport::TcpSlip);
+        assert_eq!(
+            Transport::from_name("tcp_length").unwrap(),
+            Transport::TcpLength
+        );
+        assert!(Transport::from_name("sctp").is_err());
+    }
+
+    #[tokio::test]
+    async fn unspecified_ipv6_takes_ipv4_too() {
+        // Skipped where the host has no IPv6 at all
+        let Ok(sock) = bind_udp("[::]:0".parse().unwrap()) else {
+            return;
+        };
+        let port = sock.local_addr().unwrap().port();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&[42], ("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 4];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(2), sock.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], &[42]);
+    }
+
+    #[tokio::test]
+    async fn send_with_overrides_the_transport() {
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut sender = OscSender::new(udp, Transport::Udp);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = listener.local_addr().unwrap().to_string();
+
+        sender
+            .send_with(Some(Transport::TcpLength), &destination, &[1, 2])
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 6];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 2, 1, 2]);
+    }
+}
@@ -0,0 +1,459 @@
+use crate::odisc::main::editor;
+use crate::odisc::main::events;
+use crate::odisc::main::helpers::{self, Mapping};
+use crate::odisc::main::script;
+use lazy_static::lazy_static;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use rosc::OscType;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace, warn};
+
+// Named variables that mappings set and test:
+//   set_vars   "current_song=$1 is_playing=true count=3", applied when the row
+//              matches; $N is OSC argument N, true/false and numbers keep their
+//              type, anything else is text
+//   condition  an expression over the variables and `args`, e.g.
+//              `is_playing && guitar_preset != "12A"` or `args[0] > 0.5`;
+//              the row only matches while it is true. Unset variables are (),
+//              unequal to any value.
+// With PERSIST_VARIABLES the store is saved to variables.json and read back on
+// start. A file that can't be read is moved to variables.json.bad and the
+// store starts empty.
+
+const FILE_NAME: &str = "variables.json";
+// Changes are written at most this often
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+// Conditions are expressions, this only guards against huge ones
+const MAX_CONDITION_OPERATIONS: u64 = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+impl Value {
+    pub fn parse(text: &str) -> Value {
+        match text {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => text
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .map_or_else(|| Value::Text(text.to_string()), Value::Number),
+        }
+    }
+
+    pub fn from_arg(arg: &OscType) -> Option<Value> {
+        match arg {
+            OscType::Int(i) => Some(Value::Number(f64::from(*i))),
+            OscType::Long(l) => Some(Value::Number(*l as f64)),
+            OscType::Float(f) => Some(Value::Number(f64::from(*f))),
+            OscType::Double(d) => Some(Value::Number(*d)),
+            OscType::String(s) => Some(Value::Text(s.clone())),
+            OscType::Bool(b) => Some(Value::Bool(*b)),
+            _ => None,
+        }
+    }
+
+    pub fn from_dynamic(value: &Dynamic) -> Option<Value> {
+        if let Ok(b) = value.as_bool() {
+            Some(Value::Bool(b))
+        } else if let Ok(i) = value.as_int() {
+            Some(Value::Number(i as f64))
+        } else if let Ok(f) = value.as_float() {
+            Some(Value::Number(f))
+        } else if value.is_string() {
+            value.clone().into_string().ok().map(Value::Text)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_dynamic(&self) -> Dynamic {
+        match self {
+            Value::Bool(b) => Dynamic::from_bool(*b),
+            Value::Number(n) => Dynamic::from_float(*n),
+            Value::Text(s) => Dynamic::from(s.clone()),
+        }
+    }
+}
+
+// Where a set_vars value comes from
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Fixed(Value),
+    Arg(usize),
+}
+
+lazy_static! {
+    static ref VARS: RwLock<BTreeMap<String, Value>> = RwLock::new(BTreeMap::new());
+    static ref CHANGED: Notify = Notify::new();
+    static ref ENGINE: Engine = engine();
+    // Compiled conditions by expression, from the last mappings load
+    static ref CONDITIONS: RwLock<HashMap<String, Result<Arc<AST>, String>>> =
+        RwLock::new(HashMap::new());
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.set_max_operations(MAX_CONDITION_OPERATIONS);
+    engine.set_max_expr_depths(64, 32);
+    // Straight from the store, so unset names don't fail the whole condition.
+    // Rhai marks on_var volatile through the deprecated lint.
+    #[allow(deprecated)]
+    engine.on_var(|name, _, _| match name {
+        "args" => Ok(None),
+        _ => Ok(Some(get(name).map_or(Dynamic::UNIT, |v| v.to_dynamic()))),
+    });
+    engine
+}
+
+// Same rules as Rhai variable names, so conditions can use them
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name != "args"
+}
+
+fn parse_sets(text: &str) -> Result<Vec<(String, Source)>, String> {
+    text.split_whitespace()
+        .map(|set| {
+            let (name, value) = set
+                .split_once('=')
+                .ok_or_else(|| format!("set_vars entry '{set}' must be name=value"))?;
+            if !is_valid_name(name) {
+                return Err(format!(
+                    "Variable name '{name}' must be letters, digits and _ (not 'args')"
+                ));
+            }
+            let source = match value.strip_prefix('$') {
+                Some(index) => {
+                    let index = index
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|i| *i >= 1)
+                        .ok_or_else(|| format!("'{value}' is not an argument like '$1'"))?;
+                    Source::Arg(index - 1)
+                }
+                None => Source::Fixed(Value::parse(value)),
+            };
+            Ok((name.to_string(), source))
+        })
+        .collect()
+}
+
+pub fn validate_sets(text: &str) -> Result<(), String> {
+    parse_sets(text).map(|_| ())
+}
+
+pub fn compile_condition(expression: &str) -> Result<AST, String> {
+    ENGINE
+        .compile_expression(expression)
+        .map_err(|e| format!("Condition '{expression}': {e}"))
+}
+
+fn changed() {
+    CHANGED.notify_one();
+    events::emit("variables-changed", all());
+}
+
+pub fn all() -> BTreeMap<String, Value> {
+    VARS.read().unwrap().clone()
+}
+
+pub fn get(name: &str) -> Option<Value> {
+    VARS.read().unwrap().get(name).cloned()
+}
+
+pub fn set(name: &str, value: Value) -> Result<(), String> {
+    if !is_valid_name(name) {
+        return Err(format!("Invalid variable name '{name}'"));
+    }
+    let previous = VARS
+        .write()
+        .unwrap()
+        .insert(name.to_string(), value.clone());
+    if previous.as_ref() != Some(&value) {
+        trace!("{name} = {value:?}");
+        changed();
+    }
+    Ok(())
+}
+
+pub fn remove(name: &str) {
+    if VARS.write().unwrap().remove(name).is_some() {
+        changed();
+    }
+}
+
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn clear() {
+    let had_any = {
+        let mut vars = VARS.write().unwrap();
+        let had_any = !vars.is_empty();
+        vars.clear();
+        had_any
+    };
+    if had_any {
+        changed();
+    }
+}
+
+// A matched row's set_vars
+pub fn apply(sets: &str, args: &[OscType]) -> Result<(), String> {
+    for (name, source) in parse_sets(sets)? {
+        let value = match source {
+            Source::Fixed(value) => value,
+            Source::Arg(index) => args
+                .get(index)
+                .and_then(Value::from_arg)
+                .ok_or_else(|| format!("set_vars {name}: no usable OSC argument ${}", index + 1))?,
+        };
+        set(&name, value)?;
+    }
+    Ok(())
+}
+
+// Compiles every condition the mappings use; called whenever the table changes
+pub fn load_conditions(mappings: &[Mapping]) {
+    let mut conditions = HashMap::new();
+    for expression in mappings
+        .iter()
+        .filter_map(|m| m.condition.as_deref().filter(|c| !c.is_empty()))
+    {
+        if conditions.contains_key(expression) {
+            continue;
+        }
+        let compiled = compile_condition(expression).map(Arc::new);
+        if let Err(e) = &compiled {
+            error!("{e}");
+        }
+        conditions.insert(expression.to_string(), compiled);
+    }
+    *CONDITIONS.write().unwrap() = conditions;
+}
+
+pub fn condition_holds(expression: &str, args: &[OscType]) -> bool {
+    let ast = match CONDITIONS.read().unwrap().get(expression) {
+        Some(Ok(ast)) => ast.clone(),
+        // Already logged when it was compiled
+        Some(Err(_)) => return false,
+        None => match compile_condition(expression) {
+            Ok(ast) => Arc::new(ast),
+            Err(e) => {
+                error!("{e}");
+                return false;
+            }
+        },
+    };
+
+    let mut scope = Scope::new();
+    scope.push_constant(
+        "args",
+        args.iter().map(script::to_dynamic).collect::<Array>(),
+    );
+    match ENGINE.eval_ast_with_scope::<Dynamic>(&mut scope, &ast) {
+        Ok(value) => value.as_bool().unwrap_or_else(|t| {
+            debug!("Condition '{expression}' gave a {t}, not a bool");
+            false
+        }),
+        Err(e) => {
+            debug!("Condition '{expression}' is false: {e}");
+            false
+        }
+    }
+}
+
+fn file_path() -> PathBuf {
+    helpers::odisc_dir().join(FILE_NAME)
+}
+
+fn save(path: &Path) {
+    let result = serde_json::to_string_pretty(&all())
+        .map_err(|e| e.into())
+        .and_then(|json| editor::write_atomically(path, &json));
+    match result {
+        Ok(()) => trace!("Saved variables to {path:?}"),
+        Err(e) => error!("Could not save variables to {path:?}: {e}"),
+    }
+}
+
+fn restore(path: &Path) {
+    if !path.exists() {
+        return;
+    }
+    let saved = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            serde_json::from_str::<BTreeMap<String, Value>>(&json).map_err(|e| e.to_string())
+        });
+    match saved {
+        Ok(saved) => {
+            info!("Restored {} variable(s) from {path:?}", saved.len());
+            VARS.write().unwrap().extend(saved);
+            events::emit("variables-changed", all());
+        }
+        // Kept for inspection; the next save would overwrite it
+        Err(e) => {
+            let aside = path.with_file_name(format!("{FILE_NAME}.bad"));
+            match std::fs::rename(path, &aside) {
+                Ok(()) => warn!("Could not read {path:?}, moved to {aside:?}, starting empty: {e}"),
+                Err(move_error) => warn!(
+                    "Could not read {path:?}, starting empty: {e} (moving it aside failed: {move_error})"
+                ),
+            }
+        }
+    }
+}
+
+// Saves the store while alive, and once more when dropped
+pub struct Persistence {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Persistence {
+    fn drop(&mut self) {
+        self.task.abort();
+        save(&file_path());
+    }
+}
+
+pub fn start() -> Persistence {
+    restore(&file_path());
+    let task = tokio::spawn(async {
+        loop {
+            CHANGED.notified().await;
+            tokio::time::sleep(SAVE_DELAY).await;
+            save(&file_path());
+        }
+    });
+    Persistence { task }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The store is global; each test uses its own variable names
+    fn holds(expression: &str, args: &[OscType]) -> bool {
+        condition_holds(expression, args)
+    }
+
+    #[test]
+    fn parses_sets() {
+        assert_eq!(
+            parse_sets("song=$1  playing=true level=0.5 name=intro").unwrap(),
+            vec![
+                ("song".to_string(), Source::Arg(0)),
+                ("playing".to_string(), Source::Fixed(Value::Bool(true))),
+                ("level".to_string(), Source::Fixed(Value::Number(0.5))),
+                (
+                    "name".to_string(),
+                    Source::Fixed(Value::Text("intro".into()))
+                ),
+            ]
+        );
+        assert!(parse_sets("").unwrap().is_empty());
+        for bad in ["song", "1song=2", "args=1", "a-b=1", "song=$0", "song=$x"] {
+            assert!(parse_sets(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn applies_fixed_values_and_arguments() {
+        let args = [
+            OscType::Int(3),
+            OscType::String("verse".into()),
+            OscType::Nil,
+        ];
+        apply("t_apply_n=$1 t_apply_s=$2 t_apply_f=inf", &args).unwrap();
+        assert_eq!(get("t_apply_n"), Some(Value::Number(3.0)));
+        assert_eq!(get("t_apply_s"), Some(Value::Text("verse".into())));
+        // Not finite, so text
+        assert_eq!(get("t_apply_f"), Some(Value::Text("inf".into())));
+        assert!(apply("t_apply_missing=$4", &args).is_err());
+        assert!(apply("t_apply_nil=$3", &args).is_err());
+        assert_eq!(get("t_apply_missing"), None);
+    }
+
+    #[test]
+    fn conditions_compare_numbers_across_types() {
+        set("t_cond_count", Value::Number(3.0)).unwrap();
+        assert!(holds("t_cond_count == 3", &[]));
+        assert!(holds("t_cond_count == 3.0", &[]));
+        assert!(holds("t_cond_count > 2 && t_cond_count < 4", &[]));
+        assert!(!holds("t_cond_count == 4", &[]));
+    }
+
+    #[test]
+    fn unset_variables_match_nothing() {
+        assert!(!holds("t_cond_unset == 1", &[]));
+        assert!(!holds("t_cond_unset == \"12A\"", &[]));
+        assert!(holds("t_cond_unset != \"12A\"", &[]));
+        assert!(holds("t_cond_unset == ()", &[]));
+        // Not a bool, so false rather than an error
+        assert!(!holds("t_cond_unset", &[]));
+        set("t_cond_playing", Value::Bool(true)).unwrap();
+        assert!(holds("t_cond_playing && t_cond_preset != \"12A\"", &[]));
+    }
+
+    #[test]
+    fn conditions_see_the_arguments() {
+        assert!(holds("args[0] > 0.5", &[OscType::Float(0.75)]));
+        assert!(!holds("args[0] > 0.5", &[OscType::Float(0.25)]));
+        assert!(holds("args[0] == 1", &[OscType::Int(1)]));
+        assert!(holds(
+            "args[1] == \"go\"",
+            &[OscType::Int(0), OscType::String("go".into())]
+        ));
+        // Out of range fails the condition
+        assert!(!holds("args[0] > 0.5", &[]));
+        assert!(!holds("this is not rhai (", &[]));
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("odisc-vars-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn saves_and_restores() {
+        let dir = temp_dir("roundtrip");
+        let path = dir.join(FILE_NAME);
+        set("t_saved", Value::Text("kept".into())).unwrap();
+        save(&path);
+        remove("t_saved");
+        restore(&path);
+        assert_eq!(get("t_saved"), Some(Value::Text("kept".into())));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_file_is_moved_aside() {
+        let dir = temp_dir("bad");
+        let path = dir.join(FILE_NAME);
+        std::fs::write(&path, "{ not json").unwrap();
+        restore(&path);
+        assert!(!path.exists());
+        let aside = dir.join(format!("{FILE_NAME}.bad"));
+        assert_eq!(std::fs::read_to_string(aside).unwrap(), "{ not json");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
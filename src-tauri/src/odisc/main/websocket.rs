@@ -0,0 +1,481 @@
+use crate::odisc::main::engine_status;
+use crate::odisc::main::events::{self, EventSink};
+use crate::odisc::main::http_api::{origin_allowed, token_matches};
+use crate::odisc::main::monitor;
+use crate::odisc::main::transport::Inbound;
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use rosc::{OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+// WebSocket bridge for browser dashboards and tablet apps. Clients send JSON:
+// - {"address": "/song/next", "args": [1, "intro"]} goes through the same path
+//   as an OSC message on a listener named NAME (control, learn, mappings)
+// - {"subscribe": ["monitor", "status", "log"]} / {"unsubscribe": [...]}
+// and receive {"event": "...", "payload": ...} for their topics: "monitor"
+// is the monitor-events batches, "log" the backend log, "status" every other
+// engine event, starting with a "status" snapshot.
+// Messages skip the listeners' ALLOW and AUTH checks, so connecting needs the
+// TOKEN, as "Authorization: Bearer <TOKEN>" or ?token=<TOKEN> for browsers.
+// Like the HTTP API it must be set to bind anywhere but loopback. Upgrades
+// from pages on other sites are refused either way, unless their origin is in
+// ALLOWED_ORIGINS (see http_api::origin_allowed).
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct WebSocketConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_bind_address")]
+    pub address: String,
+    #[serde(default)]
+    pub token: Option<String>,
+    // Extra browser origins let in, e.g. "http://dashboard.local:3000"
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    // Listener name for osc_in_listener filters
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+fn default_port() -> u16 {
+    8091
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_name() -> String {
+    "websocket".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Topic {
+    Monitor,
+    Status,
+    Log,
+}
+
+fn topic(event: &str) -> Topic {
+    match event {
+        "monitor-events" => Topic::Monitor,
+        "backend-log" => Topic::Log,
+        _ => Topic::Status,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Request {
+    Message {
+        address: String,
+        #[serde(default)]
+        args: Vec<Value>,
+    },
+    Subscribe {
+        subscribe: Vec<Topic>,
+    },
+    Unsubscribe {
+        unsubscribe: Vec<Topic>,
+    },
+}
+
+type Event = Arc<(String, Value)>;
+
+// Forwards engine events to the connected clients
+struct Broadcast(broadcast::Sender<Event>);
+
+impl EventSink for Broadcast {
+    fn emit(&self, event: &str, payload: Value) {
+        if self.0.receiver_count() > 0 {
+            let _ = self.0.send(Arc::new((event.to_string(), payload)));
+        }
+    }
+}
+
+struct Shared {
+    token: Option<String>,
+    allowed_origins: Vec<String>,
+    name: Arc<str>,
+    inbound: mpsc::Sender<Inbound>,
+    events: broadcast::Sender<Event>,
+    // Errors once the server is dropped, so open connections close too
+    stopped: watch::Receiver<()>,
+}
+
+// JSON numbers become ints when they fit, floats otherwise
+pub fn osc_arg(value: &Value) -> Option<OscType> {
+    match value {
+        Value::String(s) => Some(OscType::String(s.clone())),
+        Value::Bool(b) => Some(OscType::Bool(*b)),
+        Value::Null => Some(OscType::Nil),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(i32::try_from(i).map_or(OscType::Long(i), OscType::Int)),
+            None => n.as_f64().map(|f| OscType::Float(f as f32)),
+        },
+        _ => None,
+    }
+}
+
+fn encode(event: &str, payload: &Value) -> Message {
+    Message::Text(
+        json!({ "event": event, "payload": payload })
+            .to_string()
+            .into(),
+    )
+}
+
+async fn apply_request(
+    text: &str,
+    source: SocketAddr,
+    shared: &Shared,
+    topics: &mut HashSet<Topic>,
+) -> Option<Message> {
+    let request = match serde_json::from_str::<Request>(text) {
+        Ok(request) => request,
+        Err(e) => return Some(encode("error", &json!(format!("Invalid request: {e}")))),
+    };
+    match request {
+        Request::Message { address, args } => {
+            if !address.starts_with('/') {
+                return Some(encode("error", &json!("address must start with '/'")));
+            }
+            let Some(args) = args.iter().map(osc_arg).collect::<Option<Vec<_>>>() else {
+                return Some(encode(
+                    "error",
+                    &json!("args must be strings, numbers, booleans or null"),
+                ));
+            };
+            let inbound = Inbound {
+                packet: OscPacket::Message(OscMessage {
+                    addr: address,
+                    args,
+                }),
+                source,
+                listener: shared.name.clone(),
+            };
+            // The backend is gone; the connection closes with the server
+            let _ = shared.inbound.send(inbound).await;
+            None
+        }
+        Request::Subscribe { subscribe } => {
+            let snapshot = subscribe.contains(&Topic::Status) && !topics.contains(&Topic::Status);
+            topics.extend(subscribe);
+            snapshot.then(|| encode("status", &json!(engine_status())))
+        }
+        Request::Unsubscribe { unsubscribe } => {
+            for topic in unsubscribe {
+                topics.remove(&topic);
+            }
+            None
+        }
+    }
+}
+
+async fn serve_socket(mut socket: WebSocket, source: SocketAddr, shared: Arc<Shared>) {
+    debug!(%source, "WebSocket client connected");
+    let mut events = shared.events.subscribe();
+    let mut topics = HashSet::new();
+    let mut stopped = shared.stopped.clone();
+    loop {
+        let message = tokio::select! {
+            _ = stopped.changed() => break,
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    match apply_request(&text, source, &shared, &mut topics).await {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            event = events.recv() => match event {
+                Ok(event) if topics.contains(&topic(&event.0)) => encode(&event.0, &event.1),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!(%source, "WebSocket client fell behind, skipped {skipped} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+    debug!(%source, "WebSocket client disconnected");
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+fn authorized(shared: &Shared, headers: &HeaderMap, query: &TokenQuery) -> bool {
+    let Some(token) = &shared.token else {
+        return true;
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or(query.token.as_deref())
+        .unwrap_or_default();
+    token_matches(given.as_bytes(), token.as_bytes())
+}
+
+// Checks come before the upgrade is looked at, so refused clients learn nothing more
+async fn handle(
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(shared): State<Arc<Shared>>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    if !origin_allowed(&headers, &shared.allowed_origins) {
+        warn!(%source, origin = ?headers.get(header::ORIGIN), "WebSocket client refused, origin not allowed");
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    if !authorized(&shared, &headers, &query) {
+        debug!(%source, "WebSocket client refused, missing or wrong token");
+        return (StatusCode::UNAUTHORIZED, "Missing or wrong token").into_response();
+    }
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(rejection) => return rejection.into_response(),
+    };
+    ws.on_upgrade(move |socket| serve_socket(socket, source, shared))
+}
+
+// Stops the server and its event forwarding when dropped
+pub struct WebSocketServer {
+    task: JoinHandle<()>,
+    sink: Arc<dyn EventSink>,
+    _stop: watch::Sender<()>,
+}
+
+impl Drop for WebSocketServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        events::remove_sink(&self.sink);
+    }
+}
+
+pub async fn start(
+    config: &WebSocketConfig,
+    inbound: mpsc::Sender<Inbound>,
+) -> Result<WebSocketServer, Box<dyn Error>> {
+    let ip: IpAddr = config
+        .address
+        .parse()
+        .map_err(|_| format!("WebSocket: invalid address '{}'", config.address))?;
+    let token = config.token.clone().filter(|t| !t.is_empty());
+    if token.is_none() && !ip.is_loopback() {
+        return Err(format!("WebSocket: a TOKEN is required to listen on {ip}").into());
+    }
+    let listener = TcpListener::bind(SocketAddr::new(ip, config.port)).await?;
+
+    let (events_tx, _) = broadcast::channel(256);
+    let sink: Arc<dyn EventSink> = Arc::new(Broadcast(events_tx.clone()));
+    events::add_sink(sink.clone());
+    // Monitor batches come from the flusher, which the headless binary
+    // doesn't otherwise start
+    tokio::spawn(monitor::run_flusher());
+
+    let (stop, stopped) = watch::channel(());
+    let shared = Arc::new(Shared {
+        token,
+        allowed_origins: config.allowed_origins.clone(),
+        name: config.name.as_str().into(),
+        inbound,
+        events: events_tx,
+        stopped,
+    });
+    let router = Router::new().fallback(handle).with_state(shared);
+    let task = tokio::spawn(async move {
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        if let Err(e) = axum::serve(listener, service).await {
+            warn!("WebSocket server stopped: {e}");
+        }
+    });
+    info!("WebSocket server on {}:{}", config.address, config.port);
+    Ok(WebSocketServer {
+        task,
+        sink,
+        _stop: stop,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn shared(token: Option<&str>) -> Shared {
+        Shared {
+            token: token.map(str::to_string),
+            allowed_origins: vec!["https://show.example".to_string()],
+            name: "websocket".into(),
+            inbound: mpsc::channel(1).0,
+            events: broadcast::channel(1).0,
+            stopped: watch::channel(()).1,
+        }
+    }
+
+    fn query(token: Option<&str>) -> TokenQuery {
+        TokenQuery {
+            token: token.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn upgrade_needs_the_token_when_set() {
+        let mut bearer = HeaderMap::new();
+        bearer.insert(header::AUTHORIZATION, "Bearer t0k".parse().unwrap());
+        let none = HeaderMap::new();
+
+        let open = shared(None);
+        assert!(authorized(&open, &none, &query(None)));
+
+        let locked = shared(Some("t0k"));
+        assert!(authorized(&locked, &bearer, &query(None)));
+        assert!(authorized(&locked, &none, &query(Some("t0k"))));
+        assert!(!authorized(&locked, &none, &query(None)));
+        assert!(!authorized(&locked, &none, &query(Some("t0"))));
+        let mut wrong = HeaderMap::new();
+        wrong.insert(header::AUTHORIZATION, "Bearer nope".parse().unwrap());
+        assert!(!authorized(&locked, &wrong, &query(None)));
+    }
+
+    fn upgrade(origin: Option<&str>, token: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder()
+            .uri(match token {
+                Some(token) => format!("/?token={token}"),
+                None => "/".to_string(),
+            })
+            .header(header::HOST, "127.0.0.1:8091")
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==");
+        if let Some(origin) = origin {
+            request = request.header(header::ORIGIN, origin);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 50000))));
+        request
+    }
+
+    async fn status_of(shared: Shared, request: Request<Body>) -> StatusCode {
+        let router = Router::new().fallback(handle).with_state(Arc::new(shared));
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn cross_origin_upgrades_are_refused() {
+        let evil = Some("https://evil.example");
+        assert_eq!(
+            status_of(shared(None), upgrade(evil, None)).await,
+            StatusCode::FORBIDDEN
+        );
+        // A valid token doesn't let another site in either
+        assert_eq!(
+            status_of(shared(Some("t0k")), upgrade(evil, Some("t0k"))).await,
+            StatusCode::FORBIDDEN
+        );
+        // A rebound name pointing at loopback is still another site
+        assert_eq!(
+            status_of(
+                shared(None),
+                upgrade(Some("http://evil.example:8091"), None)
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+
+        // Hyper only completes upgrades on real connections, so these stop at
+        // the upgrade itself (426) once past the checks
+        for origin in [
+            None,
+            Some("null"),
+            Some("http://localhost:5173"),
+            Some("http://127.0.0.1:8091"),
+            Some("https://show.example"),
+        ] {
+            assert_eq!(
+                status_of(shared(None), upgrade(origin, None)).await,
+                StatusCode::UPGRADE_REQUIRED,
+                "{origin:?}"
+            );
+        }
+        assert_eq!(
+            status_of(shared(Some("t0k")), upgrade(Some("null"), None)).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn origins() {
+        let headers = |pairs: &[(&str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(
+                    header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    value.parse().unwrap(),
+                );
+            }
+            headers
+        };
+        let none: &[String] = &[];
+        assert!(origin_allowed(&headers(&[]), none));
+        assert!(origin_allowed(
+            &headers(&[("sec-fetch-site", "same-origin")]),
+            none
+        ));
+        assert!(!origin_allowed(
+            &headers(&[("sec-fetch-site", "cross-site")]),
+            none
+        ));
+        assert!(origin_allowed(
+            &headers(&[("origin", "http://[::1]:3000")]),
+            none
+        ));
+        assert!(origin_allowed(
+            &headers(&[
+                ("origin", "http://10.0.0.5:3000"),
+                ("host", "10.0.0.5:8091")
+            ]),
+            none
+        ));
+        assert!(!origin_allowed(
+            &headers(&[("origin", "http://10.0.0.6"), ("host", "10.0.0.5:8091")]),
+            none
+        ));
+        assert!(!origin_allowed(
+            &headers(&[("origin", "http://desk.local"), ("host", "desk.local:8091")]),
+            none
+        ));
+        assert!(!origin_allowed(&headers(&[("origin", "garbage")]), none));
+    }
+}
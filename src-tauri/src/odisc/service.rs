@@ -0,0 +1,122 @@
+use crate::odisc::main;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+// Owns the backend task so it can be stopped, restarted and watched.
+// Registered as Tauri state; every state change is emitted as `backend-status`.
+
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BackendStatus {
+    Stopped,
+    Starting,
+    Running,
+    Stopping,
+    Failed { error: String },
+}
+
+struct RunningBackend {
+    handle: JoinHandle<()>,
+    shutdown: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+pub struct BackendService {
+    // Serializes start/stop so two commands can't race each other
+    lifecycle: tokio::sync::Mutex<Option<RunningBackend>>,
+    status: Mutex<Option<BackendStatus>>,
+}
+
+impl BackendService {
+    pub fn status(&self) -> BackendStatus {
+        self.status
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or(BackendStatus::Stopped)
+    }
+
+    fn set_status(&self, app_handle: &AppHandle, status: BackendStatus) {
+        *self.status.lock().unwrap() = Some(status.clone());
+        let _ = app_handle.emit("backend-status", status);
+    }
+
+    // Starting a running backend is a no-op, so the UI can call this freely
+    pub async fn start(&self, app_handle: AppHandle) -> Result<BackendStatus, String> {
+        let mut lifecycle = self.lifecycle.lock().await;
+        if lifecycle.as_ref().is_some_and(|b| !b.handle.is_finished()) {
+            return Ok(self.status());
+        }
+
+        self.set_status(&app_handle, BackendStatus::Starting);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let task_handle = app_handle.clone();
+        let handle = tokio::spawn(async move {
+            let result = main::backend(main::EngineOptions::default(), shutdown_rx, ready_tx).await;
+            let service = task_handle.state::<BackendService>();
+            match result {
+                Ok(()) => {
+                    info!("Backend stopped");
+                    service.set_status(&task_handle, BackendStatus::Stopped);
+                }
+                Err(e) => {
+                    error!("Error in backend: {e}");
+                    service.set_status(
+                        &task_handle,
+                        BackendStatus::Failed {
+                            error: e.to_string(),
+                        },
+                    );
+                }
+            }
+        });
+
+        // The sender is dropped without a value if the backend fails during startup
+        if ready_rx.await.is_ok() && !handle.is_finished() {
+            self.set_status(&app_handle, BackendStatus::Running);
+            info!("Backend started!");
+        }
+        *lifecycle = Some(RunningBackend {
+            handle,
+            shutdown: shutdown_tx,
+        });
+        Ok(self.status())
+    }
+
+    pub async fn stop(&self, app_handle: AppHandle) -> Result<BackendStatus, String> {
+        let mut lifecycle = self.lifecycle.lock().await;
+        let Some(running) = lifecycle.take() else {
+            return Ok(self.status());
+        };
+        if running.handle.is_finished() {
+            return Ok(self.status());
+        }
+
+        self.set_status(&app_handle, BackendStatus::Stopping);
+        let _ = running.shutdown.send(());
+        let abort = running.handle.abort_handle();
+        if tokio::time::timeout(STOP_TIMEOUT, running.handle)
+            .await
+            .is_err()
+        {
+            warn!("Backend did not stop within {STOP_TIMEOUT:?}, aborting");
+            abort.abort();
+            self.set_status(&app_handle, BackendStatus::Stopped);
+        }
+        Ok(self.status())
+    }
+
+    pub async fn restart(&self, app_handle: AppHandle) -> Result<BackendStatus, String> {
+        self.stop(app_handle.clone()).await?;
+        self.start(app_handle).await
+    }
+}